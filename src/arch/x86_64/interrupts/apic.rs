@@ -64,6 +64,7 @@ pub struct APIC {
     pub io_apic: IOAPIC,
     local_apic: VirtualPtr<u8>,
     pub cpus: Arc<[LAPIC]>,
+    source_overrides: Arc<[IOAPICSourceOverride]>,
 }
 
 unsafe extern "C" fn test(cpu: &limine::smp::Cpu) -> ! {
@@ -105,7 +106,7 @@ impl APIC {
         let mut lapic_ptr: VirtualPtr<u8> =
             VirtualPtr::from(madt.inner.local_apic_address as usize + hhdm_offset);
         let mut io_apic = None;
-        let mut io_apic_source_override = None;
+        let mut source_overrides: Vec<IOAPICSourceOverride> = Vec::new();
 
         // constant pointers are a lie anyways
         let mut ptr: VirtualPtr<u8> = VirtualPtr::from(madt.extra.unwrap().as_ptr());
@@ -132,12 +133,14 @@ impl APIC {
                     })
                 },
                 2 => unsafe {
-                    io_apic_source_override = Some(IOAPICSourceOverride {
+                    let source_override = IOAPICSourceOverride {
                         bus_source: ptr.add(2).read_unaligned(),
                         irq_source: ptr.add(3).read_unaligned(),
                         global_system_interrupt: ptr.add(4).cast::<u32>().read_unaligned(),
                         flags: ptr.add(8).cast::<u16>().read_unaligned(),
-                    })
+                    };
+
+                    source_overrides.push(source_override);
                 },
                 5 => {
                     lapic_ptr = unsafe {
@@ -152,7 +155,7 @@ impl APIC {
             ptr = unsafe { ptr.add(ptr.add(1).read_unaligned() as usize) };
         }
 
-        if io_apic.is_none() || io_apic_source_override.is_none() {
+        if io_apic.is_none() || source_overrides.is_empty() {
             return Err(());
         }
 
@@ -174,6 +177,7 @@ impl APIC {
             io_apic: io_apic.unwrap(),
             local_apic: lapic_ptr,
             cpus: cpus.into(),
+            source_overrides: source_overrides.into(),
         };
 
         // Enable APIC by setting bit 8 to 1
@@ -203,9 +207,23 @@ impl APIC {
         // Set and enable keyboard interrupt
         apic.set_interrupt(0x01, 0x01);
 
+        // Set and enable the PIT interrupt, which is usually wired to GSI 2 rather than 0
+        apic.set_interrupt(apic.irq_to_gsi(0), 0x00);
+
         return Ok(apic);
     }
 
+    // ISA IRQs are identity mapped to GSIs unless the MADT says otherwise
+    pub fn irq_to_gsi(&self, irq: u8) -> u8 {
+        return self
+            .source_overrides
+            .iter()
+            .find(|source_override| source_override.irq_source == irq)
+            .map_or(irq, |source_override| {
+                source_override.global_system_interrupt as u8
+            });
+    }
+
     pub fn read_ioapic(&self, reg: u32) -> u32 {
         unsafe {
             let ptr = self.io_apic.ptr;
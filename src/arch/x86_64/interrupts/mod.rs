@@ -46,6 +46,8 @@ static mut IDT: [IdtEntry; 256] = [IdtEntry::new(); 256];
 pub enum InterruptIndex {
     Timer = 32,
     Keyboard,
+    PrimaryAta = 46,
+    SecondaryAta,
 }
 
 impl InterruptIndex {
@@ -0,0 +1,114 @@
+use crate::mem::{pmm::pmm_alloc, PhysicalPtr, VirtualPtr};
+
+// A controller's memory mapped registers, reached through the HHDM
+#[derive(Clone, Copy)]
+pub struct Mmio {
+    base: VirtualPtr<u8>,
+}
+
+// SAFETY: The HHDM is mapped the same on every CPU and every access is a single volatile read or
+// write, keeping several of them in order is up to whoever locks the device
+unsafe impl Send for Mmio {}
+unsafe impl Sync for Mmio {}
+
+impl Mmio {
+    pub fn new(physical_address: u64) -> Self {
+        return Self {
+            base: PhysicalPtr::<u8>::from(physical_address as usize).to_higher_half(),
+        };
+    }
+
+    // A window into the registers starting at `offset`, for per port or per queue blocks
+    pub fn offset(&self, offset: usize) -> Self {
+        return Self {
+            base: unsafe { self.base.add(offset) },
+        };
+    }
+
+    pub fn read(&self, offset: usize) -> u32 {
+        return unsafe { self.base.add(offset).cast::<u32>().read_volatile() };
+    }
+
+    pub fn write(&self, offset: usize, value: u32) {
+        unsafe { self.base.add(offset).cast::<u32>().write_volatile(value) };
+    }
+
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        return unsafe { self.base.add(offset).read_volatile() };
+    }
+
+    pub fn write_u8(&self, offset: usize, value: u8) {
+        unsafe { self.base.add(offset).write_volatile(value) };
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        return unsafe { self.base.add(offset).cast::<u16>().read_volatile() };
+    }
+
+    pub fn write_u16(&self, offset: usize, value: u16) {
+        unsafe { self.base.add(offset).cast::<u16>().write_volatile(value) };
+    }
+
+    // 64-bit registers are accessed as two halves, low first
+    pub fn read_u64(&self, offset: usize) -> u64 {
+        return self.read(offset) as u64 | (self.read(offset + 4) as u64) << 32;
+    }
+
+    pub fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+}
+
+// Physically contiguous, zeroed memory a controller can DMA into
+pub struct DmaRegion {
+    physical: PhysicalPtr<u8>,
+    virt: VirtualPtr<u8>,
+    len: usize,
+}
+
+// SAFETY: The region owns its pages like a Box owns its allocation, writing needs `&mut self` and
+// `&self` only reads
+unsafe impl Send for DmaRegion {}
+unsafe impl Sync for DmaRegion {}
+
+impl DmaRegion {
+    pub fn new(pages: usize) -> Result<Self, ()> {
+        let physical = pmm_alloc(pages);
+
+        if physical.is_null() {
+            return Err(());
+        }
+
+        return Ok(Self {
+            physical,
+            virt: physical.to_higher_half(),
+            len: pages * crate::mem::PAGE_SIZE,
+        });
+    }
+
+    pub fn phys_addr(&self, offset: usize) -> u64 {
+        return (self.physical.addr() + offset) as u64;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    pub fn slice(&mut self, offset: usize, len: usize) -> &mut [u8] {
+        assert!(offset + len <= self.len);
+
+        return unsafe { core::slice::from_raw_parts_mut(self.virt.add(offset).as_raw_ptr(), len) };
+    }
+
+    // Reads a value the device wrote, without letting the compiler cache it between polls
+    pub fn read_volatile<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + core::mem::size_of::<T>() <= self.len);
+
+        return unsafe { self.virt.add(offset).cast::<T>().read_volatile() };
+    }
+}
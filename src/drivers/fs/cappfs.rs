@@ -0,0 +1,1228 @@
+// CappFS, the native CappuccinOS filesystem. Everything is little endian.
+//
+// | Block                      | Contents                                       |
+// |----------------------------|------------------------------------------------|
+// | 0                          | Superblock                                     |
+// | block_bitmap..inode_bitmap | One bit per block, set while the block is used |
+// | inode_bitmap..inode_table  | One bit per inode, set while the inode is used |
+// | inode_table..data_start    | 256 byte inodes, inode 0 is never used         |
+// | data_start..               | File data, directories and extent blocks       |
+//
+// Superblock:
+// | Offset | Size | Field                                          |
+// |--------|------|------------------------------------------------|
+// | 0      | 8    | Magic, "CAPPUCFS"                              |
+// | 8      | 4    | Version, 1                                     |
+// | 12     | 4    | Block size, a power of two from 1KiB to 64KiB  |
+// | 16     | 8    | Block count                                    |
+// | 24     | 8    | Free blocks                                    |
+// | 32     | 4    | Inode count                                    |
+// | 36     | 4    | Free inodes                                    |
+// | 40     | 8    | First block of the block bitmap                |
+// | 48     | 8    | First block of the inode bitmap                |
+// | 56     | 8    | First block of the inode table                 |
+// | 64     | 8    | First data block                               |
+// | 72     | 16   | Volume UUID                                    |
+// | 88     | 32   | Label, UTF-8 padded with zeroes                |
+// | 120    | 4    | CRC32 of bytes 0..120                          |
+//
+// Inode:
+// | Offset | Size | Field                                          |
+// |--------|------|------------------------------------------------|
+// | 0      | 2    | Mode, the same type and permission bits as unix|
+// | 2      | 2    | Link count, 0 when free                        |
+// | 4      | 4    | Owner uid                                      |
+// | 8      | 4    | Owner gid                                      |
+// | 12     | 4    | Flags, 0x1 means the data is inline            |
+// | 16     | 8    | Size in bytes                                  |
+// | 24     | 4    | Extent count                                   |
+// | 32     | 8    | Extent block, holding extents past the 13th    |
+// | 40     | 208  | 13 inline extents, or inline data              |
+//
+// An extent is a start block (8 bytes), a length in blocks (4 bytes) and 4 reserved bytes. The
+// extents cover the file in order. Directories are a list of 128 byte entries: inode (4 bytes,
+// 0 for an unused slot), the mode type of the inode shifted down 12 (1 byte), the name length
+// (1 byte), 2 reserved bytes and a name of up to 120 bytes.
+
+use core::ptr::NonNull;
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    drivers::storage::Partition,
+    libs::{crc32::crc32, sync::Mutex, uuid::Uuid},
+    log, LogLevel,
+};
+
+use super::vfs::{FileId, FsOps, UserCred, VNode, VNodeOperations, VNodeType, UIO};
+
+const SECTOR_SIZE: u64 = 512;
+
+const MAGIC: &[u8; 8] = b"CAPPUCFS";
+const VERSION: u32 = 1;
+const SUPERBLOCK_CHECKSUMMED: usize = 120;
+
+const DEFAULT_BLOCK_SIZE: u32 = 4096;
+// One inode for every this many bytes of disk when formatting
+const BYTES_PER_INODE: u64 = 16384;
+
+const INODE_SIZE: usize = 256;
+const ROOT_INODE: u32 = 1;
+
+const FLAG_INLINE_DATA: u32 = 0x1;
+
+const EXTENT_SIZE: usize = 16;
+const INLINE_EXTENTS: usize = 13;
+const INLINE_AREA: core::ops::Range<usize> = 40..40 + INLINE_EXTENTS * EXTENT_SIZE;
+
+const DIRECTORY_ENTRY_SIZE: usize = 128;
+const MAX_NAME_LENGTH: usize = 120;
+
+// File type bits of the mode field
+const S_IFMT: u16 = 0o170000;
+const S_IFSOCK: u16 = 0o140000;
+const S_IFLNK: u16 = 0o120000;
+const S_IFREG: u16 = 0o100000;
+const S_IFBLK: u16 = 0o060000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFCHR: u16 = 0o020000;
+
+// Permission bits, for the owner. Group and other are the same shifted down 3 and 6
+const S_IRUSR: u16 = 0o400;
+const S_IWUSR: u16 = 0o200;
+const S_IXUSR: u16 = 0o100;
+
+pub fn is_cappfs(superblock: &[u8]) -> bool {
+    superblock.get(0..8) == Some(MAGIC)
+}
+
+#[derive(Debug, Clone)]
+struct Superblock {
+    block_size: u32,
+    block_count: u64,
+    free_blocks: u64,
+    inode_count: u32,
+    free_inodes: u32,
+    block_bitmap: u64,
+    inode_bitmap: u64,
+    inode_table: u64,
+    data_start: u64,
+    uuid: Uuid,
+    label: [u8; 32],
+}
+
+impl Superblock {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() < 512 || !is_cappfs(bytes) {
+            return Err(());
+        }
+
+        let checksum = u32::from_le_bytes(bytes[120..124].try_into().unwrap());
+
+        if crc32(&bytes[..SUPERBLOCK_CHECKSUMMED]) != checksum {
+            return Err(());
+        }
+
+        if u32::from_le_bytes(bytes[8..12].try_into().unwrap()) != VERSION {
+            return Err(());
+        }
+
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        let superblock = Self {
+            block_size: u32_at(12),
+            block_count: u64_at(16),
+            free_blocks: u64_at(24),
+            inode_count: u32_at(32),
+            free_inodes: u32_at(36),
+            block_bitmap: u64_at(40),
+            inode_bitmap: u64_at(48),
+            inode_table: u64_at(56),
+            data_start: u64_at(64),
+            uuid: Uuid::from(<[u8; 16]>::try_from(&bytes[72..88]).unwrap()),
+            label: bytes[88..120].try_into().unwrap(),
+        };
+
+        if !superblock.block_size.is_power_of_two()
+            || !(1024..=65536).contains(&superblock.block_size)
+        {
+            return Err(());
+        }
+
+        // The regions have to be in order and fit on the volume
+        if !(1 <= superblock.block_bitmap
+            && superblock.block_bitmap < superblock.inode_bitmap
+            && superblock.inode_bitmap < superblock.inode_table
+            && superblock.inode_table < superblock.data_start
+            && superblock.data_start <= superblock.block_count)
+        {
+            return Err(());
+        }
+
+        return Ok(superblock);
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = alloc::vec![0u8; 512];
+
+        bytes[0..8].copy_from_slice(MAGIC);
+        bytes[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.block_size.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.block_count.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.free_blocks.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.inode_count.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.free_inodes.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.block_bitmap.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.inode_bitmap.to_le_bytes());
+        bytes[56..64].copy_from_slice(&self.inode_table.to_le_bytes());
+        bytes[64..72].copy_from_slice(&self.data_start.to_le_bytes());
+        bytes[72..88].copy_from_slice(&<[u8; 16]>::from(self.uuid));
+        bytes[88..120].copy_from_slice(&self.label);
+
+        let checksum = crc32(&bytes[..SUPERBLOCK_CHECKSUMMED]);
+        bytes[120..124].copy_from_slice(&checksum.to_le_bytes());
+
+        return bytes;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Extent {
+    start: u64,
+    length: u32,
+}
+
+impl Extent {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        return Self {
+            start: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            length: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+        };
+    }
+
+    fn write_bytes(&self, bytes: &mut [u8]) {
+        bytes[0..8].copy_from_slice(&self.start.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_le_bytes());
+        bytes[12..16].fill(0);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Inode {
+    mode: u16,
+    links: u16,
+    uid: u32,
+    gid: u32,
+    flags: u32,
+    size: u64,
+    extent_block: u64,
+    extents: Vec<Extent>,
+    // the target of an inline symlink
+    inline_data: Vec<u8>,
+}
+
+impl Inode {
+    fn new(mode: u16, cred: UserCred) -> Self {
+        return Self {
+            mode,
+            links: 1,
+            uid: cred.uid as u32,
+            gid: cred.gid as u32,
+            flags: 0,
+            size: 0,
+            extent_block: 0,
+            extents: Vec::new(),
+            inline_data: Vec::new(),
+        };
+    }
+
+    fn file_type(&self) -> u16 {
+        self.mode & S_IFMT
+    }
+
+    fn is_directory(&self) -> bool {
+        self.file_type() == S_IFDIR
+    }
+
+    fn vnode_type(&self) -> VNodeType {
+        match self.file_type() {
+            S_IFREG => VNodeType::Regular,
+            S_IFDIR => VNodeType::Directory,
+            S_IFLNK => VNodeType::Link,
+            S_IFBLK => VNodeType::Block,
+            S_IFCHR => VNodeType::Character,
+            S_IFSOCK => VNodeType::Socket,
+            _ => VNodeType::NON,
+        }
+    }
+
+    // Whether cred may do everything in `wanted`, which is a set of the owner permission bits
+    fn permits(&self, cred: UserCred, wanted: u16) -> bool {
+        // root can do anything, except run things nobody is allowed to run
+        if cred.uid == 0 {
+            return wanted & S_IXUSR == 0 || self.mode & 0o111 != 0 || self.is_directory();
+        }
+
+        let granted = if cred.uid as u32 == self.uid {
+            self.mode
+        } else if cred.gid as u32 == self.gid {
+            self.mode << 3
+        } else {
+            self.mode << 6
+        };
+
+        return granted & wanted == wanted;
+    }
+}
+
+#[derive(Debug)]
+struct DirectoryEntry {
+    inode: u32,
+    name: String,
+    // index of the slot in the directory
+    slot: usize,
+}
+
+pub struct CappFs {
+    partition: Partition,
+    superblock: Mutex<Superblock>,
+    block_size: usize,
+}
+
+impl CappFs {
+    pub fn new(partition: Partition) -> Result<Self, ()> {
+        let superblock = Superblock::from_bytes(&partition.read(0, 1)?)?;
+
+        if superblock.block_count * superblock.block_size as u64
+            > partition.sector_count() * SECTOR_SIZE
+        {
+            return Err(());
+        }
+
+        let cappfs = Self {
+            partition,
+            block_size: superblock.block_size as usize,
+            superblock: Mutex::new(superblock),
+        };
+
+        if !cappfs.read_inode(ROOT_INODE)?.is_directory() {
+            return Err(());
+        }
+
+        return Ok(cappfs);
+    }
+
+    // Writes an empty filesystem over the partition, holding just a root directory owned by root
+    pub fn format(partition: Partition, uuid: Uuid, label: &str) -> Result<Self, ()> {
+        let block_size = DEFAULT_BLOCK_SIZE as u64;
+        let block_count = partition.sector_count() * SECTOR_SIZE / block_size;
+
+        let inode_count = (block_count * block_size / BYTES_PER_INODE).clamp(16, u32::MAX as u64);
+
+        let bits_per_block = block_size * 8;
+        let block_bitmap_blocks = block_count.div_ceil(bits_per_block);
+        let inode_bitmap_blocks = inode_count.div_ceil(bits_per_block);
+        let inode_table_blocks = (inode_count * INODE_SIZE as u64).div_ceil(block_size);
+
+        let block_bitmap = 1;
+        let inode_bitmap = block_bitmap + block_bitmap_blocks;
+        let inode_table = inode_bitmap + inode_bitmap_blocks;
+        let data_start = inode_table + inode_table_blocks;
+
+        // room for the metadata and at least the root directory
+        if data_start + 1 > block_count {
+            return Err(());
+        }
+
+        let mut label_bytes = [0u8; 32];
+        let label = &label.as_bytes()[..label.len().min(32)];
+        label_bytes[..label.len()].copy_from_slice(label);
+
+        let superblock = Superblock {
+            block_size: block_size as u32,
+            block_count,
+            free_blocks: block_count - data_start,
+            inode_count: inode_count as u32,
+            // inode 0 is never handed out
+            free_inodes: inode_count as u32 - 1,
+            block_bitmap,
+            inode_bitmap,
+            inode_table,
+            data_start,
+            uuid,
+            label: label_bytes,
+        };
+
+        let cappfs = Self {
+            partition,
+            block_size: block_size as usize,
+            superblock: Mutex::new(superblock),
+        };
+
+        let zeroes = alloc::vec![0u8; cappfs.block_size];
+
+        for block in block_bitmap..data_start {
+            cappfs.write_block(block, &zeroes)?;
+        }
+
+        // The metadata blocks are in use from the start
+        for block in 0..data_start {
+            cappfs.set_bit(block_bitmap, block, true)?;
+        }
+
+        cappfs.set_bit(inode_bitmap, 0, true)?;
+
+        let root_number = cappfs.allocate_inode()?;
+
+        if root_number != ROOT_INODE {
+            return Err(());
+        }
+
+        let mut root = Inode::new(S_IFDIR | 0o755, UserCred { uid: 0, gid: 0 });
+        root.links = 2;
+
+        cappfs.write_inode(ROOT_INODE, &root)?;
+        cappfs.write_superblock()?;
+
+        log!(
+            LogLevel::Info,
+            "CappFS: Formatted {} blocks with {} inodes",
+            block_count,
+            inode_count
+        );
+
+        return Ok(cappfs);
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.superblock.lock().uuid
+    }
+
+    fn read_bytes(&self, offset: u64, len: usize) -> Result<Vec<u8>, ()> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let first_sector = offset / SECTOR_SIZE;
+        let last_sector = (offset + len as u64).div_ceil(SECTOR_SIZE);
+        let offset_in_sector = (offset % SECTOR_SIZE) as usize;
+
+        let sectors = self
+            .partition
+            .read(first_sector, (last_sector - first_sector) as usize)?;
+
+        return Ok(sectors
+            .get(offset_in_sector..offset_in_sector + len)
+            .ok_or(())?
+            .to_vec());
+    }
+
+    fn write_bytes(&self, offset: u64, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let first_sector = offset / SECTOR_SIZE;
+        let last_sector = (offset + bytes.len() as u64).div_ceil(SECTOR_SIZE);
+        let offset_in_sector = (offset % SECTOR_SIZE) as usize;
+
+        // Partial sectors at either end have to keep what was already in them
+        let mut sectors = match offset_in_sector == 0 && bytes.len() as u64 % SECTOR_SIZE == 0 {
+            true => alloc::vec![0u8; bytes.len()],
+            false => self
+                .partition
+                .read(first_sector, (last_sector - first_sector) as usize)?
+                .to_vec(),
+        };
+
+        sectors[offset_in_sector..offset_in_sector + bytes.len()].copy_from_slice(bytes);
+
+        return self.partition.write(first_sector, &sectors);
+    }
+
+    fn block_offset(&self, block: u64) -> Result<u64, ()> {
+        if block >= self.superblock.lock().block_count {
+            return Err(());
+        }
+
+        return Ok(block * self.block_size as u64);
+    }
+
+    fn read_block(&self, block: u64) -> Result<Vec<u8>, ()> {
+        return self.read_bytes(self.block_offset(block)?, self.block_size);
+    }
+
+    fn write_block(&self, block: u64, bytes: &[u8]) -> Result<(), ()> {
+        return self.write_bytes(self.block_offset(block)?, bytes);
+    }
+
+    fn write_superblock(&self) -> Result<(), ()> {
+        let bytes = self.superblock.lock().to_bytes();
+
+        return self.write_bytes(0, &bytes);
+    }
+
+    fn get_bit(&self, bitmap_start: u64, bit: u64) -> Result<bool, ()> {
+        let byte = self.read_bytes(self.block_offset(bitmap_start)? + bit / 8, 1)?[0];
+
+        return Ok(byte & (1 << (bit % 8)) != 0);
+    }
+
+    fn set_bit(&self, bitmap_start: u64, bit: u64, value: bool) -> Result<(), ()> {
+        let offset = self.block_offset(bitmap_start)? + bit / 8;
+        let mut byte = self.read_bytes(offset, 1)?[0];
+
+        match value {
+            true => byte |= 1 << (bit % 8),
+            false => byte &= !(1 << (bit % 8)),
+        }
+
+        return self.write_bytes(offset, &[byte]);
+    }
+
+    fn allocate_inode(&self) -> Result<u32, ()> {
+        let (inode_bitmap, inode_count) = {
+            let superblock = self.superblock.lock();
+            (superblock.inode_bitmap, superblock.inode_count)
+        };
+
+        let bits_per_block = self.block_size as u64 * 8;
+
+        for bitmap_block in 0..(inode_count as u64).div_ceil(bits_per_block) {
+            let bitmap = self.read_block(inode_bitmap + bitmap_block)?;
+
+            let Some(byte) = bitmap.iter().position(|&byte| byte != 0xFF) else {
+                continue;
+            };
+
+            let bit = bitmap[byte].trailing_ones() as u64;
+            let inode = bitmap_block * bits_per_block + byte as u64 * 8 + bit;
+
+            if inode >= inode_count as u64 {
+                break;
+            }
+
+            self.set_bit(inode_bitmap, inode, true)?;
+
+            self.superblock.lock().free_inodes -= 1;
+            self.write_superblock()?;
+
+            return Ok(inode as u32);
+        }
+
+        return Err(());
+    }
+
+    // Finds up to count free blocks in a row, starting the search at goal. The blocks are
+    // returned zeroed.
+    fn allocate_blocks(&self, goal: u64, count: u32) -> Result<Extent, ()> {
+        let (block_bitmap, block_count, data_start) = {
+            let superblock = self.superblock.lock();
+            (
+                superblock.block_bitmap,
+                superblock.block_count,
+                superblock.data_start,
+            )
+        };
+
+        let goal = goal.clamp(data_start, block_count - 1);
+
+        let mut start = None;
+
+        for block in (goal..block_count).chain(data_start..goal) {
+            if !self.get_bit(block_bitmap, block)? {
+                start = Some(block);
+                break;
+            }
+        }
+
+        let start = start.ok_or(())?;
+        let mut length = 0;
+
+        while length < count
+            && start + (length as u64) < block_count
+            && !self.get_bit(block_bitmap, start + length as u64)?
+        {
+            self.set_bit(block_bitmap, start + length as u64, true)?;
+            self.write_block(start + length as u64, &alloc::vec![0u8; self.block_size])?;
+            length += 1;
+        }
+
+        self.superblock.lock().free_blocks -= length as u64;
+        self.write_superblock()?;
+
+        return Ok(Extent { start, length });
+    }
+
+    fn inode_offset(&self, inode: u32) -> Result<u64, ()> {
+        let (inode_table, inode_count) = {
+            let superblock = self.superblock.lock();
+            (superblock.inode_table, superblock.inode_count)
+        };
+
+        if inode == 0 || inode >= inode_count {
+            return Err(());
+        }
+
+        return Ok(inode_table * self.block_size as u64 + inode as u64 * INODE_SIZE as u64);
+    }
+
+    fn read_inode(&self, inode_number: u32) -> Result<Inode, ()> {
+        let bytes = self.read_bytes(self.inode_offset(inode_number)?, INODE_SIZE)?;
+
+        let flags = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        let size = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+        let extent_count = u32::from_le_bytes(bytes[24..28].try_into().unwrap()) as usize;
+        let extent_block = u64::from_le_bytes(bytes[32..40].try_into().unwrap());
+
+        let mut inode = Inode {
+            mode: u16::from_le_bytes(bytes[0..2].try_into().unwrap()),
+            links: u16::from_le_bytes(bytes[2..4].try_into().unwrap()),
+            uid: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            gid: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            flags,
+            size,
+            extent_block,
+            extents: Vec::new(),
+            inline_data: Vec::new(),
+        };
+
+        if inode.links == 0 {
+            return Err(());
+        }
+
+        if flags & FLAG_INLINE_DATA != 0 {
+            if size as usize > INLINE_AREA.len() {
+                return Err(());
+            }
+
+            inode.inline_data =
+                bytes[INLINE_AREA.start..INLINE_AREA.start + size as usize].to_vec();
+
+            return Ok(inode);
+        }
+
+        for extent in bytes[INLINE_AREA]
+            .chunks_exact(EXTENT_SIZE)
+            .take(extent_count)
+        {
+            inode.extents.push(Extent::from_bytes(extent));
+        }
+
+        if extent_count > INLINE_EXTENTS {
+            if extent_count - INLINE_EXTENTS > self.block_size / EXTENT_SIZE {
+                return Err(());
+            }
+
+            let overflow = self.read_block(extent_block)?;
+
+            for extent in overflow
+                .chunks_exact(EXTENT_SIZE)
+                .take(extent_count - INLINE_EXTENTS)
+            {
+                inode.extents.push(Extent::from_bytes(extent));
+            }
+        }
+
+        return Ok(inode);
+    }
+
+    fn write_inode(&self, inode_number: u32, inode: &Inode) -> Result<(), ()> {
+        let mut bytes = alloc::vec![0u8; INODE_SIZE];
+
+        bytes[0..2].copy_from_slice(&inode.mode.to_le_bytes());
+        bytes[2..4].copy_from_slice(&inode.links.to_le_bytes());
+        bytes[4..8].copy_from_slice(&inode.uid.to_le_bytes());
+        bytes[8..12].copy_from_slice(&inode.gid.to_le_bytes());
+        bytes[12..16].copy_from_slice(&inode.flags.to_le_bytes());
+        bytes[16..24].copy_from_slice(&inode.size.to_le_bytes());
+        bytes[24..28].copy_from_slice(&(inode.extents.len() as u32).to_le_bytes());
+        bytes[32..40].copy_from_slice(&inode.extent_block.to_le_bytes());
+
+        if inode.flags & FLAG_INLINE_DATA != 0 {
+            bytes[INLINE_AREA.start..INLINE_AREA.start + inode.inline_data.len()]
+                .copy_from_slice(&inode.inline_data);
+        } else {
+            for (i, extent) in inode.extents.iter().take(INLINE_EXTENTS).enumerate() {
+                let start = INLINE_AREA.start + i * EXTENT_SIZE;
+                extent.write_bytes(&mut bytes[start..start + EXTENT_SIZE]);
+            }
+
+            if inode.extents.len() > INLINE_EXTENTS {
+                let mut overflow = alloc::vec![0u8; self.block_size];
+
+                for (i, extent) in inode.extents[INLINE_EXTENTS..].iter().enumerate() {
+                    extent.write_bytes(&mut overflow[i * EXTENT_SIZE..(i + 1) * EXTENT_SIZE]);
+                }
+
+                self.write_block(inode.extent_block, &overflow)?;
+            }
+        }
+
+        return self.write_bytes(self.inode_offset(inode_number)?, &bytes);
+    }
+
+    // The disk block holding block `index` of a file
+    fn map_block(&self, inode: &Inode, index: u64) -> Result<u64, ()> {
+        let mut first = 0;
+
+        for extent in inode.extents.iter() {
+            if index < first + extent.length as u64 {
+                return Ok(extent.start + (index - first));
+            }
+
+            first += extent.length as u64;
+        }
+
+        return Err(());
+    }
+
+    fn allocated_blocks(inode: &Inode) -> u64 {
+        inode
+            .extents
+            .iter()
+            .map(|extent| extent.length as u64)
+            .sum()
+    }
+
+    // Gives a file enough blocks for `length` bytes. New blocks go right after the last extent
+    // when they can, which grows that extent instead of adding one.
+    fn grow(&self, inode: &mut Inode, length: u64) -> Result<(), ()> {
+        let needed = length.div_ceil(self.block_size as u64);
+
+        while Self::allocated_blocks(inode) < needed {
+            let missing = (needed - Self::allocated_blocks(inode)).min(u32::MAX as u64) as u32;
+
+            let goal = match inode.extents.last() {
+                Some(last) => last.start + last.length as u64,
+                None => 0,
+            };
+
+            let extent = self.allocate_blocks(goal, missing)?;
+
+            match inode.extents.last_mut() {
+                Some(last) if last.start + last.length as u64 == extent.start => {
+                    last.length += extent.length;
+                }
+                _ => {
+                    if inode.extents.len() == INLINE_EXTENTS {
+                        inode.extent_block = self.allocate_blocks(goal, 1)?.start;
+                    }
+
+                    if inode.extents.len() >= INLINE_EXTENTS + self.block_size / EXTENT_SIZE {
+                        return Err(());
+                    }
+
+                    inode.extents.push(extent);
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn read_data(&self, inode: &Inode, offset: usize, len: usize) -> Result<Vec<u8>, ()> {
+        let end = offset.checked_add(len).ok_or(())?;
+
+        if end as u64 > inode.size {
+            return Err(());
+        }
+
+        if inode.flags & FLAG_INLINE_DATA != 0 {
+            return Ok(inode.inline_data[offset..end].to_vec());
+        }
+
+        let mut data = Vec::with_capacity(len);
+
+        while data.len() < len {
+            let position = offset + data.len();
+            let offset_in_block = position % self.block_size;
+            let to_copy = (self.block_size - offset_in_block).min(len - data.len());
+
+            let block = self.map_block(inode, (position / self.block_size) as u64)?;
+
+            data.extend(self.read_bytes(
+                block * self.block_size as u64 + offset_in_block as u64,
+                to_copy,
+            )?);
+        }
+
+        return Ok(data);
+    }
+
+    fn write_data(&self, inode: &mut Inode, offset: usize, bytes: &[u8]) -> Result<(), ()> {
+        if inode.flags & FLAG_INLINE_DATA != 0 {
+            return Err(());
+        }
+
+        let end = offset.checked_add(bytes.len()).ok_or(())?;
+
+        self.grow(inode, end as u64)?;
+
+        let mut written = 0;
+
+        while written < bytes.len() {
+            let position = offset + written;
+            let offset_in_block = position % self.block_size;
+            let to_copy = (self.block_size - offset_in_block).min(bytes.len() - written);
+
+            let block = self.map_block(inode, (position / self.block_size) as u64)?;
+
+            self.write_bytes(
+                block * self.block_size as u64 + offset_in_block as u64,
+                &bytes[written..written + to_copy],
+            )?;
+
+            written += to_copy;
+        }
+
+        inode.size = inode.size.max(end as u64);
+
+        return Ok(());
+    }
+
+    fn read_directory(&self, directory: &Inode) -> Result<Vec<DirectoryEntry>, ()> {
+        let bytes = self.read_data(directory, 0, directory.size as usize)?;
+
+        let mut entries = Vec::new();
+
+        for (slot, entry) in bytes.chunks_exact(DIRECTORY_ENTRY_SIZE).enumerate() {
+            let inode = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+
+            if inode == 0 {
+                continue;
+            }
+
+            let name_length = (entry[5] as usize).min(MAX_NAME_LENGTH);
+
+            entries.push(DirectoryEntry {
+                inode,
+                name: String::from_utf8_lossy(&entry[8..8 + name_length]).into_owned(),
+                slot,
+            });
+        }
+
+        return Ok(entries);
+    }
+
+    fn find_entry(&self, directory: &Inode, name: &str) -> Result<u32, ()> {
+        return self
+            .read_directory(directory)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.inode)
+            .ok_or(());
+    }
+
+    fn add_directory_entry(
+        &self,
+        directory: &mut Inode,
+        name: &str,
+        inode: u32,
+        mode: u16,
+    ) -> Result<(), ()> {
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH || name.contains('/') {
+            return Err(());
+        }
+
+        let entries = self.read_directory(directory)?;
+        let slot_count = directory.size as usize / DIRECTORY_ENTRY_SIZE;
+
+        // The first slot nobody is using, or a new one on the end
+        let slot = (0..slot_count)
+            .find(|slot| !entries.iter().any(|entry| entry.slot == *slot))
+            .unwrap_or(slot_count);
+
+        let mut entry = [0u8; DIRECTORY_ENTRY_SIZE];
+        entry[0..4].copy_from_slice(&inode.to_le_bytes());
+        entry[4] = ((mode & S_IFMT) >> 12) as u8;
+        entry[5] = name.len() as u8;
+        entry[8..8 + name.len()].copy_from_slice(name.as_bytes());
+
+        return self.write_data(directory, slot * DIRECTORY_ENTRY_SIZE, &entry);
+    }
+
+    fn create_node(
+        &self,
+        parent_number: u32,
+        parent: &mut Inode,
+        name: &str,
+        mode: u16,
+        cred: UserCred,
+    ) -> Result<(u32, Inode), ()> {
+        if !parent.is_directory()
+            || !parent.permits(cred, S_IWUSR | S_IXUSR)
+            || self.find_entry(parent, name).is_ok()
+        {
+            return Err(());
+        }
+
+        let inode_number = self.allocate_inode()?;
+        let mut inode = Inode::new(mode, cred);
+
+        if inode.is_directory() {
+            inode.links = 2;
+            parent.links += 1;
+        }
+
+        self.write_inode(inode_number, &inode)?;
+
+        self.add_directory_entry(parent, name, inode_number, mode)?;
+        self.write_inode(parent_number, parent)?;
+
+        return Ok((inode_number, inode));
+    }
+}
+
+impl FsOps for CappFs {
+    fn mount(&mut self, _path: &str, data: &mut *mut u8, _vfsp: NonNull<super::vfs::Vfs>) {
+        *data = core::ptr::addr_of!(*self) as *mut u8;
+    }
+
+    fn unmount(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {}
+
+    fn root(&mut self, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        let inode = self
+            .read_inode(ROOT_INODE)
+            .expect("CappFS root inode was readable at mount");
+
+        let root = CappNode {
+            inode_number: ROOT_INODE,
+            inode,
+        };
+
+        return VNode::new(Box::new(root), VNodeType::Directory, vfsp);
+    }
+
+    fn statfs(&mut self, _vfsp: NonNull<super::vfs::Vfs>) -> super::vfs::StatFs {
+        todo!("CAPPFS STATFS");
+    }
+
+    // Everything is written through, so there's nothing held back
+    fn sync(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {}
+
+    fn fid(&mut self, path: &str, _vfsp: NonNull<super::vfs::Vfs>) -> Option<FileId> {
+        let mut inode_number = ROOT_INODE;
+
+        for part in path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+        {
+            let inode = self.read_inode(inode_number).ok()?;
+
+            if !inode.is_directory() {
+                return None;
+            }
+
+            inode_number = self.find_entry(&inode, part).ok()?;
+        }
+
+        return FileId::new(&inode_number.to_le_bytes()).ok();
+    }
+
+    fn vget(&mut self, fid: FileId, vfsp: NonNull<super::vfs::Vfs>) -> Result<VNode, ()> {
+        let inode_number = u32::from_le_bytes(fid.as_bytes().try_into().map_err(|_| ())?);
+        let inode = self.read_inode(inode_number)?;
+
+        return Ok(CappNode {
+            inode_number,
+            inode,
+        }
+        .into_vnode(vfsp));
+    }
+}
+
+struct CappNode {
+    inode_number: u32,
+    inode: Inode,
+}
+
+impl CappNode {
+    fn get_fs<'a>(vp: NonNull<VNode>) -> &'a CappFs {
+        unsafe { &*(*vp.as_ptr()).parent_vfs.as_mut().data.cast::<CappFs>() }
+    }
+
+    fn into_vnode(self, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        let typ = self.inode.vnode_type();
+
+        return VNode::new(Box::new(self), typ, vfsp);
+    }
+
+    fn add_node(
+        &mut self,
+        name: &str,
+        mode: u16,
+        cred: UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<CappNode, ()> {
+        let cappfs = Self::get_fs(vp);
+
+        let (inode_number, inode) =
+            cappfs.create_node(self.inode_number, &mut self.inode, name, mode, cred)?;
+
+        return Ok(CappNode {
+            inode_number,
+            inode,
+        });
+    }
+}
+
+impl VNodeOperations for CappNode {
+    fn open(&mut self, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn close(&mut self, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn read(
+        &mut self,
+        count: usize,
+        offset: usize,
+        _f: u32,
+        c: UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        if self.inode.file_type() != S_IFREG || !self.inode.permits(c, S_IRUSR) {
+            return Err(());
+        }
+
+        let cappfs = Self::get_fs(vp);
+
+        return Ok(Arc::from(cappfs.read_data(&self.inode, offset, count)?));
+    }
+
+    fn write(&mut self, offset: usize, buf: &[u8], _f: u32, c: UserCred, vp: NonNull<VNode>) {
+        let cappfs = Self::get_fs(vp);
+
+        let result = (|| -> Result<(), ()> {
+            if self.inode.file_type() != S_IFREG || !self.inode.permits(c, S_IWUSR) {
+                return Err(());
+            }
+
+            cappfs.write_data(&mut self.inode, offset, buf)?;
+
+            return cappfs.write_inode(self.inode_number, &self.inode);
+        })();
+
+        if result.is_err() {
+            log!(
+                LogLevel::Error,
+                "CappFS: Failed to write to inode {}",
+                self.inode_number
+            );
+        }
+    }
+
+    fn ioctl(&mut self, _com: u32, _d: *mut u8, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn getattr(&mut self, _c: UserCred, vp: NonNull<VNode>) -> super::vfs::VAttr {
+        let cappfs = Self::get_fs(vp);
+
+        // CappFS doesn't keep any timestamps
+        return super::vfs::VAttr {
+            typ: self.inode.vnode_type(),
+            mode: self.inode.mode,
+            uid: self.inode.uid as u16,
+            gid: self.inode.gid as u16,
+            fs_id: 0,
+            node_id: self.inode_number,
+            link_count: self.inode.links,
+            size: self.inode.size.min(u32::MAX as u64) as u32,
+            block_size: cappfs.block_size as u32,
+            last_access: 0,
+            last_modify: 0,
+            last_chg: 0,
+            rdev: (),
+            used_blocks: self.inode.extents.iter().map(|extent| extent.length).sum(),
+        };
+    }
+
+    // Only the mode, owner and group can be changed, sizes change through write
+    fn setattr(&mut self, va: super::vfs::VAttr, c: UserCred, vp: NonNull<VNode>) {
+        let cappfs = Self::get_fs(vp);
+
+        // The owner may change the mode, but only root can give a file away
+        let changes_owner = va.uid as u32 != self.inode.uid || va.gid as u32 != self.inode.gid;
+
+        if c.uid != 0 && (c.uid as u32 != self.inode.uid || changes_owner) {
+            log!(
+                LogLevel::Warn,
+                "CappFS: Not allowed to change the attributes of inode {}",
+                self.inode_number
+            );
+            return;
+        }
+
+        // The file type can't be changed after the fact
+        self.inode.mode = self.inode.file_type() | (va.mode & !S_IFMT);
+        self.inode.uid = va.uid as u32;
+        self.inode.gid = va.gid as u32;
+
+        if cappfs.write_inode(self.inode_number, &self.inode).is_err() {
+            log!(
+                LogLevel::Error,
+                "CappFS: Failed to write inode {}",
+                self.inode_number
+            );
+        }
+    }
+
+    fn access(&mut self, m: u32, c: UserCred, _vp: NonNull<VNode>) -> Result<(), ()> {
+        // R_OK, W_OK and X_OK match the "other" permission bits, shifted up they are the owner ones
+        let wanted = ((m & 0o7) as u16) << 6;
+
+        if !self.inode.permits(c, wanted) {
+            return Err(());
+        }
+
+        return Ok(());
+    }
+
+    fn lookup(&mut self, nm: &str, c: UserCred, vp: NonNull<VNode>) -> Result<VNode, ()> {
+        // searching a directory needs execute permission on it
+        if !self.inode.is_directory() || !self.inode.permits(c, S_IXUSR) {
+            return Err(());
+        }
+
+        let cappfs = Self::get_fs(vp);
+
+        let inode_number = cappfs.find_entry(&self.inode, nm)?;
+        let inode = cappfs.read_inode(inode_number)?;
+
+        let node = CappNode {
+            inode_number,
+            inode,
+        };
+
+        return Ok(node.into_vnode(unsafe { (*vp.as_ptr()).parent_vfs }));
+    }
+
+    fn create(
+        &mut self,
+        nm: &str,
+        _va: super::vfs::VAttr,
+        _e: u32,
+        _m: u32,
+        c: UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        let node = self.add_node(nm, S_IFREG | 0o644, c, vp)?;
+
+        return Ok(node.into_vnode(unsafe { (*vp.as_ptr()).parent_vfs }));
+    }
+
+    fn link(
+        &mut self,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn rename(
+        &mut self,
+        _nm: &str,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn mkdir(
+        &mut self,
+        nm: &str,
+        _va: super::vfs::VAttr,
+        c: UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        let node = self.add_node(nm, S_IFDIR | 0o755, c, vp)?;
+
+        return Ok(node.into_vnode(unsafe { (*vp.as_ptr()).parent_vfs }));
+    }
+
+    fn readdir(&mut self, _uiop: *const UIO, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn symlink(
+        &mut self,
+        link_name: &str,
+        _va: super::vfs::VAttr,
+        target_name: &str,
+        c: UserCred,
+        vp: NonNull<VNode>,
+    ) {
+        let cappfs = Self::get_fs(vp);
+
+        let result = (|| -> Result<(), ()> {
+            let mut node = self.add_node(link_name, S_IFLNK | 0o777, c, vp)?;
+
+            // Short targets fit where the extents would go
+            if target_name.len() <= INLINE_AREA.len() {
+                node.inode.flags |= FLAG_INLINE_DATA;
+                node.inode.inline_data = target_name.as_bytes().to_vec();
+                node.inode.size = target_name.len() as u64;
+            } else {
+                cappfs.write_data(&mut node.inode, 0, target_name.as_bytes())?;
+            }
+
+            return cappfs.write_inode(node.inode_number, &node.inode);
+        })();
+
+        if result.is_err() {
+            log!(
+                LogLevel::Error,
+                "CappFS: Failed to create symlink {link_name}"
+            );
+        }
+    }
+
+    fn readlink(&mut self, uiop: *const UIO, _c: UserCred, vp: NonNull<VNode>) {
+        if self.inode.file_type() != S_IFLNK {
+            return;
+        }
+
+        let cappfs = Self::get_fs(vp);
+
+        if let Ok(target) = cappfs.read_data(&self.inode, 0, self.inode.size as usize) {
+            unsafe { UIO::uiomove(uiop, &target) };
+        }
+    }
+
+    fn fsync(&mut self, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn getxattr(
+        &mut self,
+        _name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        return Err(());
+    }
+
+    fn listxattr(&mut self, _c: UserCred, _vp: NonNull<VNode>) -> Result<Vec<String>, ()> {
+        return Ok(Vec::new());
+    }
+
+    fn setxattr(
+        &mut self,
+        _name: &str,
+        _value: &[u8],
+        _f: u32,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<(), ()> {
+        return Err(());
+    }
+
+    fn len(&self, _vp: NonNull<VNode>) -> usize {
+        self.inode.size as usize
+    }
+}
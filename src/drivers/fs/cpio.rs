@@ -0,0 +1,483 @@
+use core::{ops::Range, ptr::NonNull};
+
+use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{log, LogLevel};
+
+use super::vfs::{FileId, FsOps, UserCred, VNode, VNodeOperations, VNodeType, UIO};
+
+// SVR4 "newc" cpio, the magic is followed by 13 fields of 8 ascii hex digits each
+const NEWC_MAGIC: &[u8; 6] = b"070701";
+// Same as newc, but the check field holds a checksum of the file data
+const NEWC_CRC_MAGIC: &[u8; 6] = b"070702";
+const NEWC_HEADER_SIZE: usize = 110;
+const TRAILER_NAME: &str = "TRAILER!!!";
+
+// File type bits of the mode field
+const S_IFMT: u32 = 0o170000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+
+pub fn is_cpio(bytes: &[u8]) -> bool {
+    bytes.len() >= 6 && (&bytes[0..6] == NEWC_MAGIC || &bytes[0..6] == NEWC_CRC_MAGIC)
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct NewcHeader {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    file_size: u32,
+    dev_major: u32,
+    dev_minor: u32,
+    rdev_major: u32,
+    rdev_minor: u32,
+    name_size: u32,
+    check: u32,
+}
+
+impl NewcHeader {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() < NEWC_HEADER_SIZE || !is_cpio(bytes) {
+            return Err(());
+        }
+
+        let field = |i: usize| -> Result<u32, ()> {
+            let start = 6 + i * 8;
+            let digits = core::str::from_utf8(&bytes[start..start + 8]).map_err(|_| ())?;
+
+            u32::from_str_radix(digits, 16).map_err(|_| ())
+        };
+
+        return Ok(Self {
+            ino: field(0)?,
+            mode: field(1)?,
+            uid: field(2)?,
+            gid: field(3)?,
+            nlink: field(4)?,
+            mtime: field(5)?,
+            file_size: field(6)?,
+            dev_major: field(7)?,
+            dev_minor: field(8)?,
+            rdev_major: field(9)?,
+            rdev_minor: field(10)?,
+            name_size: field(11)?,
+            check: field(12)?,
+        });
+    }
+}
+
+#[allow(dead_code)]
+struct CpioEntry {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    // where the file data lives in the archive
+    data: Range<usize>,
+    parent: usize,
+    children: BTreeMap<String, usize>,
+}
+
+impl CpioEntry {
+    fn directory(parent: usize) -> Self {
+        return Self {
+            ino: 0,
+            mode: S_IFDIR | 0o755,
+            uid: 0,
+            gid: 0,
+            nlink: 2,
+            mtime: 0,
+            data: 0..0,
+            parent,
+            children: BTreeMap::new(),
+        };
+    }
+
+    fn vnode_type(&self) -> VNodeType {
+        match self.mode & S_IFMT {
+            S_IFREG => VNodeType::Regular,
+            S_IFDIR => VNodeType::Directory,
+            S_IFLNK => VNodeType::Link,
+            S_IFBLK => VNodeType::Block,
+            S_IFCHR => VNodeType::Character,
+            S_IFSOCK => VNodeType::Socket,
+            _ => VNodeType::NON,
+        }
+    }
+}
+
+// Read-only filesystem over a cpio archive, the archive is indexed once when it is created
+pub struct CpioFs {
+    archive: Cow<'static, [u8]>,
+    // entries[0] is always the root directory
+    entries: Vec<CpioEntry>,
+}
+
+impl CpioFs {
+    pub fn new(archive: Cow<'static, [u8]>) -> Result<Self, ()> {
+        if !is_cpio(&archive) {
+            return Err(());
+        }
+
+        let mut cpio = Self {
+            archive,
+            entries: Vec::new(),
+        };
+
+        cpio.entries.push(CpioEntry::directory(0));
+        cpio.index()?;
+
+        return Ok(cpio);
+    }
+
+    fn index(&mut self) -> Result<(), ()> {
+        let mut offset = 0;
+        // (dev_major, dev_minor, ino) -> entry, for hardlinks
+        let mut inodes: BTreeMap<(u32, u32, u32), Vec<usize>> = BTreeMap::new();
+
+        loop {
+            let header = NewcHeader::from_bytes(self.archive.get(offset..).ok_or(())?)?;
+
+            let name_start = offset + NEWC_HEADER_SIZE;
+            let name_end = name_start + header.name_size as usize;
+
+            // name_size includes the null terminator
+            let name = self
+                .archive
+                .get(name_start..name_end.saturating_sub(1))
+                .ok_or(())?;
+            let name = core::str::from_utf8(name).map_err(|_| ())?.to_string();
+
+            // The header and name, and the data are each padded to four bytes
+            let data_start = name_end.next_multiple_of(4);
+            let data_end = data_start + header.file_size as usize;
+
+            if data_end > self.archive.len() {
+                return Err(());
+            }
+
+            offset = data_end.next_multiple_of(4);
+
+            if name == TRAILER_NAME {
+                break;
+            }
+
+            let index = self.insert(&name, &header, data_start..data_end)?;
+
+            if header.nlink > 1 && header.mode & S_IFMT != S_IFDIR {
+                inodes
+                    .entry((header.dev_major, header.dev_minor, header.ino))
+                    .or_default()
+                    .push(index);
+            }
+        }
+
+        // Hardlinked files only store the data with the last link, share it with the rest
+        for links in inodes.values() {
+            let data = links
+                .iter()
+                .map(|&link| self.entries[link].data.clone())
+                .find(|data| !data.is_empty());
+
+            if let Some(data) = data {
+                for &link in links {
+                    self.entries[link].data = data.clone();
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Puts an entry in the tree, making any directories the archive never listed
+    fn insert(&mut self, path: &str, header: &NewcHeader, data: Range<usize>) -> Result<usize, ()> {
+        let parts = path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect::<Vec<&str>>();
+
+        let mut directory = 0;
+
+        for (i, &part) in parts.iter().enumerate() {
+            if part == ".." {
+                return Err(());
+            }
+
+            let is_last = i == parts.len() - 1;
+
+            let child = match self.entries[directory].children.get(part) {
+                Some(&child) => child,
+                None => {
+                    self.entries.push(CpioEntry::directory(directory));
+                    let child = self.entries.len() - 1;
+
+                    self.entries[directory]
+                        .children
+                        .insert(part.to_string(), child);
+
+                    child
+                }
+            };
+
+            if !is_last && self.entries[child].mode & S_IFMT != S_IFDIR {
+                log!(LogLevel::Warn, "cpio: {path} is inside of a non-directory");
+                return Err(());
+            }
+
+            directory = child;
+        }
+
+        // `directory` is the entry for the path now, which is the root for "."
+        let entry = &mut self.entries[directory];
+
+        entry.ino = header.ino;
+        entry.mode = header.mode;
+        entry.uid = header.uid;
+        entry.gid = header.gid;
+        entry.nlink = header.nlink;
+        entry.mtime = header.mtime;
+        entry.data = data;
+
+        return Ok(directory);
+    }
+
+    fn entry_data(&self, index: usize) -> &[u8] {
+        &self.archive[self.entries[index].data.clone()]
+    }
+
+    fn make_vnode(&self, index: usize, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        VNode::new(
+            Box::new(CpioNode { index }),
+            self.entries[index].vnode_type(),
+            vfsp,
+        )
+    }
+}
+
+impl FsOps for CpioFs {
+    fn mount(&mut self, _path: &str, data: &mut *mut u8, _vfsp: NonNull<super::vfs::Vfs>) {
+        *data = core::ptr::addr_of!(*self) as *mut u8;
+    }
+
+    fn unmount(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {}
+
+    fn root(&mut self, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        self.make_vnode(0, vfsp)
+    }
+
+    fn statfs(&mut self, _vfsp: NonNull<super::vfs::Vfs>) -> super::vfs::StatFs {
+        todo!("CPIO STATFS");
+    }
+
+    fn sync(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {
+        // read-only, nothing to write back
+    }
+
+    // The archive never changes once mounted, so the entry index is a stable id
+    fn fid(&mut self, path: &str, _vfsp: NonNull<super::vfs::Vfs>) -> Option<FileId> {
+        let mut index = 0;
+
+        for part in path.split('/') {
+            match part {
+                "" | "." => continue,
+                ".." => index = self.entries[index].parent,
+                name => index = *self.entries[index].children.get(name)?,
+            }
+        }
+
+        return FileId::new(&(index as u32).to_le_bytes()).ok();
+    }
+
+    fn vget(&mut self, fid: FileId, vfsp: NonNull<super::vfs::Vfs>) -> Result<VNode, ()> {
+        let index = u32::from_le_bytes(fid.as_bytes().try_into().map_err(|_| ())?) as usize;
+
+        if index >= self.entries.len() {
+            return Err(());
+        }
+
+        return Ok(self.make_vnode(index, vfsp));
+    }
+}
+
+struct CpioNode {
+    index: usize,
+}
+
+impl CpioNode {
+    fn get_fs<'a>(vp: NonNull<VNode>) -> &'a CpioFs {
+        unsafe { &*(*vp.as_ptr()).parent_vfs.as_mut().data.cast::<CpioFs>() }
+    }
+}
+
+impl VNodeOperations for CpioNode {
+    fn open(&mut self, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn close(&mut self, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn read(
+        &mut self,
+        count: usize,
+        offset: usize,
+        _f: u32,
+        _c: UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        let cpio = Self::get_fs(vp);
+
+        if cpio.entries[self.index].mode & S_IFMT == S_IFDIR {
+            return Err(());
+        }
+
+        let data = cpio.entry_data(self.index);
+
+        return Ok(Arc::from(data.get(offset..offset + count).ok_or(())?));
+    }
+
+    fn write(&mut self, _offset: usize, _buf: &[u8], _f: u32, _c: UserCred, _vp: NonNull<VNode>) {
+        log!(LogLevel::Warn, "cpio: Archives are read-only");
+    }
+
+    fn ioctl(&mut self, _com: u32, _d: *mut u8, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn getattr(&mut self, _c: UserCred, _vp: NonNull<VNode>) -> super::vfs::VAttr {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn setattr(&mut self, _va: super::vfs::VAttr, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn access(&mut self, _m: u32, _c: UserCred, _vp: NonNull<VNode>) -> Result<(), ()> {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn lookup(&mut self, nm: &str, _c: UserCred, vp: NonNull<VNode>) -> Result<VNode, ()> {
+        let cpio = Self::get_fs(vp);
+
+        let child = *cpio.entries[self.index].children.get(nm).ok_or(())?;
+
+        return Ok(cpio.make_vnode(child, unsafe { (*vp.as_ptr()).parent_vfs }));
+    }
+
+    fn create(
+        &mut self,
+        _nm: &str,
+        _va: super::vfs::VAttr,
+        _e: u32,
+        _m: u32,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        return Err(());
+    }
+
+    fn link(
+        &mut self,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        log!(LogLevel::Warn, "cpio: Archives are read-only");
+    }
+
+    fn rename(
+        &mut self,
+        _nm: &str,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        log!(LogLevel::Warn, "cpio: Archives are read-only");
+    }
+
+    fn mkdir(
+        &mut self,
+        _nm: &str,
+        _va: super::vfs::VAttr,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        return Err(());
+    }
+
+    fn readdir(&mut self, _uiop: *const UIO, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn symlink(
+        &mut self,
+        _link_name: &str,
+        _va: super::vfs::VAttr,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        log!(LogLevel::Warn, "cpio: Archives are read-only");
+    }
+
+    fn readlink(&mut self, uiop: *const UIO, _c: UserCred, vp: NonNull<VNode>) {
+        let cpio = Self::get_fs(vp);
+
+        if cpio.entries[self.index].mode & S_IFMT != S_IFLNK {
+            return;
+        }
+
+        // the link target is stored as the file data
+        unsafe { UIO::uiomove(uiop, cpio.entry_data(self.index)) };
+    }
+
+    fn fsync(&mut self, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn getxattr(
+        &mut self,
+        _name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        // newc has no room for extended attributes
+        return Err(());
+    }
+
+    fn listxattr(&mut self, _c: UserCred, _vp: NonNull<VNode>) -> Result<Vec<String>, ()> {
+        return Ok(Vec::new());
+    }
+
+    fn setxattr(
+        &mut self,
+        _name: &str,
+        _value: &[u8],
+        _f: u32,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<(), ()> {
+        return Err(());
+    }
+
+    fn len(&self, vp: NonNull<VNode>) -> usize {
+        let cpio = Self::get_fs(vp);
+
+        return cpio.entries[self.index].data.len();
+    }
+}
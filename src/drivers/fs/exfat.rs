@@ -0,0 +1,1263 @@
+use core::ptr::NonNull;
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{drivers::storage::Partition, libs::sync::Mutex, LogLevel};
+
+use super::{
+    fat::cache::FatCache,
+    vfs::{FileId, FsOps, VNode, VNodeOperations, VNodeType},
+};
+
+// Only devices with 512 byte sectors get probed for exFAT, exFAT sectors can be anywhere from 512
+// bytes to 4KiB, so everything in here is worked out in bytes and converted at the last moment
+const SECTOR_SIZE: u64 = 512;
+
+const ENTRY_SIZE: usize = 32;
+
+// Directory entry types, the top bit is set while the entry is in use
+const ENTRY_END_OF_DIRECTORY: u8 = 0x00;
+const ENTRY_IN_USE: u8 = 0x80;
+const ENTRY_ALLOCATION_BITMAP: u8 = 0x81;
+const ENTRY_UPCASE_TABLE: u8 = 0x82;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM_EXTENSION: u8 = 0xC0;
+const ENTRY_FILE_NAME: u8 = 0xC1;
+
+// GeneralSecondaryFlags of the stream extension entry
+const FLAG_ALLOCATION_POSSIBLE: u8 = 0x01;
+const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+
+const ATTRIBUTE_DIRECTORY: u16 = 0x10;
+const ATTRIBUTE_ARCHIVE: u16 = 0x20;
+
+// Each file name entry holds 15 UTF-16 characters
+const NAME_CHARACTERS_PER_ENTRY: usize = 15;
+const MAX_NAME_LENGTH: usize = 255;
+
+const END_OF_CHAIN: u32 = 0xFFFFFFFF;
+const BAD_CLUSTER: u32 = 0xFFFFFFF7;
+
+// The file id index used for the root directory, which has no entry set of its own
+const EXFAT_ROOT_FID_INDEX: u32 = 0x7FFFFFFF;
+// Set in the index of a file id when the directory holding the entry is contiguous
+const FID_NO_FAT_CHAIN: u32 = 0x80000000;
+
+pub fn is_exfat(boot_sector: &[u8]) -> bool {
+    boot_sector.get(3..11) == Some(b"EXFAT   ")
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct ExFatBootSector {
+    partition_offset: u64,
+    volume_length: u64,
+    fat_offset: u32,
+    fat_length: u32,
+    cluster_heap_offset: u32,
+    cluster_count: u32,
+    root_directory_cluster: u32,
+    volume_serial_number: u32,
+    file_system_revision: u16,
+    volume_flags: u16,
+    bytes_per_sector_shift: u8,
+    sectors_per_cluster_shift: u8,
+    number_of_fats: u8,
+}
+
+impl ExFatBootSector {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() < 512 || !is_exfat(bytes) {
+            return Err(());
+        }
+
+        // Where a FAT BPB would be has to be zeroed so FAT drivers don't mistake it for their own
+        if bytes[11..64].iter().any(|&byte| byte != 0) {
+            return Err(());
+        }
+
+        if u16::from_le_bytes(bytes[510..512].try_into().unwrap()) != 0xAA55 {
+            return Err(());
+        }
+
+        let boot_sector = Self {
+            partition_offset: u64::from_le_bytes(bytes[64..72].try_into().unwrap()),
+            volume_length: u64::from_le_bytes(bytes[72..80].try_into().unwrap()),
+            fat_offset: u32::from_le_bytes(bytes[80..84].try_into().unwrap()),
+            fat_length: u32::from_le_bytes(bytes[84..88].try_into().unwrap()),
+            cluster_heap_offset: u32::from_le_bytes(bytes[88..92].try_into().unwrap()),
+            cluster_count: u32::from_le_bytes(bytes[92..96].try_into().unwrap()),
+            root_directory_cluster: u32::from_le_bytes(bytes[96..100].try_into().unwrap()),
+            volume_serial_number: u32::from_le_bytes(bytes[100..104].try_into().unwrap()),
+            file_system_revision: u16::from_le_bytes(bytes[104..106].try_into().unwrap()),
+            volume_flags: u16::from_le_bytes(bytes[106..108].try_into().unwrap()),
+            bytes_per_sector_shift: bytes[108],
+            sectors_per_cluster_shift: bytes[109],
+            number_of_fats: bytes[110],
+        };
+
+        if !(9..=12).contains(&boot_sector.bytes_per_sector_shift) {
+            return Err(());
+        }
+
+        // Clusters are at most 32MiB
+        if boot_sector.bytes_per_sector_shift + boot_sector.sectors_per_cluster_shift > 25 {
+            return Err(());
+        }
+
+        if boot_sector.number_of_fats != 1 && boot_sector.number_of_fats != 2 {
+            return Err(());
+        }
+
+        if boot_sector.root_directory_cluster < 2
+            || boot_sector.root_directory_cluster > boot_sector.cluster_count + 1
+        {
+            return Err(());
+        }
+
+        return Ok(boot_sector);
+    }
+}
+
+// Where a file's data lives, this is what the stream extension entry describes
+#[derive(Clone, Copy, Debug)]
+struct Stream {
+    first_cluster: u32,
+    data_length: u64,
+    valid_data_length: u64,
+    // The clusters are one after another and the FAT isn't used for this file
+    no_fat_chain: bool,
+}
+
+// Where a file's entry set is, so it can be written back after the file changes
+#[derive(Clone, Copy, Debug)]
+struct EntrySetLocation {
+    directory: Stream,
+    index: usize,
+}
+
+#[derive(Debug)]
+struct DirectoryEntry {
+    name: String,
+    attributes: u16,
+    stream: Stream,
+    location: EntrySetLocation,
+}
+
+struct AllocationBitmap {
+    stream: Stream,
+    bits: Vec<u8>,
+}
+
+pub struct ExFatFs {
+    partition: Partition,
+    boot_sector: ExFatBootSector,
+    cluster_size: usize,
+    // byte offset of cluster 2 in the partition
+    cluster_heap_start: u64,
+    // The root directory has no entry set to keep its stream in, so it's kept here
+    root: Mutex<Stream>,
+    fat_cache: Mutex<FatCache>,
+    bitmap: Mutex<AllocationBitmap>,
+    // Maps a UTF-16 character to its upper case version, names compare case-insensitively
+    upcase_table: Vec<u16>,
+}
+
+impl ExFatFs {
+    pub fn new(partition: Partition) -> Result<Self, ()> {
+        let boot_bytes = partition.read(0, 1)?;
+
+        let boot_sector = ExFatBootSector::from_bytes(&boot_bytes)?;
+
+        let sector_shift = boot_sector.bytes_per_sector_shift as u32;
+        let cluster_size = 1usize << (sector_shift + boot_sector.sectors_per_cluster_shift as u32);
+
+        // TexFAT keeps two FATs and uses whichever ActiveFat says, everyone else only has one
+        let active_fat = match boot_sector.volume_flags & 0x01 {
+            0 => 0,
+            _ => boot_sector.fat_length as u64,
+        };
+
+        let fat_start =
+            ((boot_sector.fat_offset as u64 + active_fat) << sector_shift) / SECTOR_SIZE;
+        let sectors_per_fat =
+            ((boot_sector.fat_length as u64) << sector_shift).div_ceil(SECTOR_SIZE) as usize;
+
+        let fat_cache = FatCache::new(
+            partition.clone(),
+            fat_start,
+            sectors_per_fat,
+            1,
+            crate::KERNEL_FEATURES.fat_cache_size * 1024 / SECTOR_SIZE as usize,
+        );
+
+        let mut exfat = Self {
+            partition,
+            cluster_size,
+            cluster_heap_start: (boot_sector.cluster_heap_offset as u64) << sector_shift,
+            root: Mutex::new(Stream {
+                first_cluster: boot_sector.root_directory_cluster,
+                data_length: 0,
+                valid_data_length: 0,
+                no_fat_chain: false,
+            }),
+            boot_sector,
+            fat_cache: Mutex::new(fat_cache),
+            bitmap: Mutex::new(AllocationBitmap {
+                stream: Stream {
+                    first_cluster: 0,
+                    data_length: 0,
+                    valid_data_length: 0,
+                    no_fat_chain: false,
+                },
+                bits: Vec::new(),
+            }),
+            upcase_table: Vec::new(),
+        };
+
+        // The root directory has no stream extension, its size is however long its chain is
+        let mut root = *exfat.root.lock();
+        let root_length = (exfat.cluster_chain(&root)?.len() * cluster_size) as u64;
+        root.data_length = root_length;
+        root.valid_data_length = root_length;
+        exfat.root = Mutex::new(root);
+
+        let root_directory = exfat.read_stream(&root, 0, root_length as usize)?;
+
+        for entry in root_directory.chunks_exact(ENTRY_SIZE) {
+            match entry[0] {
+                ENTRY_END_OF_DIRECTORY => break,
+                ENTRY_ALLOCATION_BITMAP => {
+                    // bit 0 of the flags says which FAT the bitmap belongs to
+                    if entry[1] & 0x01 != (active_fat != 0) as u8 {
+                        continue;
+                    }
+
+                    let stream = Self::stream_from_bitmap_entry(entry);
+                    let bits = exfat.read_stream(&stream, 0, stream.data_length as usize)?;
+
+                    exfat.bitmap = Mutex::new(AllocationBitmap { stream, bits });
+                }
+                ENTRY_UPCASE_TABLE => {
+                    let stream = Self::stream_from_bitmap_entry(entry);
+                    let table = exfat.read_stream(&stream, 0, stream.data_length as usize)?;
+
+                    exfat.upcase_table = Self::expand_upcase_table(&table);
+                }
+                _ => {}
+            }
+        }
+
+        // Both of these are required, a volume without them is corrupt
+        if exfat.bitmap.lock().bits.is_empty() || exfat.upcase_table.is_empty() {
+            return Err(());
+        }
+
+        return Ok(exfat);
+    }
+
+    // The bitmap and up-case table entries share their layout for the part we care about
+    fn stream_from_bitmap_entry(entry: &[u8]) -> Stream {
+        let first_cluster = u32::from_le_bytes(entry[20..24].try_into().unwrap());
+        let data_length = u64::from_le_bytes(entry[24..32].try_into().unwrap());
+
+        return Stream {
+            first_cluster,
+            data_length,
+            valid_data_length: data_length,
+            no_fat_chain: false,
+        };
+    }
+
+    // The up-case table on disk is compressed, 0xFFFF followed by a count means that many
+    // characters map to themselves
+    fn expand_upcase_table(table: &[u8]) -> Vec<u16> {
+        let mut upcase_table = Vec::new();
+        let mut characters = table
+            .chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()));
+
+        while let Some(character) = characters.next() {
+            if character == 0xFFFF {
+                let count = characters.next().unwrap_or(0);
+
+                for _ in 0..count {
+                    let identity = upcase_table.len() as u16;
+                    upcase_table.push(identity);
+                }
+
+                continue;
+            }
+
+            upcase_table.push(character);
+        }
+
+        return upcase_table;
+    }
+
+    fn upcase(&self, character: u16) -> u16 {
+        return *self
+            .upcase_table
+            .get(character as usize)
+            .unwrap_or(&character);
+    }
+
+    fn name_hash(&self, name: &[u16]) -> u16 {
+        let mut hash: u16 = 0;
+
+        for &character in name {
+            let character = self.upcase(character);
+
+            for byte in character.to_le_bytes() {
+                hash = ((hash & 1) << 15)
+                    .wrapping_add(hash >> 1)
+                    .wrapping_add(byte as u16);
+            }
+        }
+
+        return hash;
+    }
+
+    fn names_equal(&self, a: &str, b: &str) -> bool {
+        let a = a.encode_utf16().map(|c| self.upcase(c));
+        let b = b.encode_utf16().map(|c| self.upcase(c));
+
+        return a.eq(b);
+    }
+
+    // Checksum over a whole entry set, skipping the checksum field itself
+    fn entry_set_checksum(entries: &[u8]) -> u16 {
+        let mut checksum: u16 = 0;
+
+        for (i, &byte) in entries.iter().enumerate() {
+            if i == 2 || i == 3 {
+                continue;
+            }
+
+            checksum = ((checksum & 1) << 15)
+                .wrapping_add(checksum >> 1)
+                .wrapping_add(byte as u16);
+        }
+
+        return checksum;
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        return self.cluster_heap_start + (cluster as u64 - 2) * self.cluster_size as u64;
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        return cluster >= 2 && cluster <= self.boot_sector.cluster_count + 1;
+    }
+
+    fn read_bytes(&self, offset: u64, len: usize) -> Result<Vec<u8>, ()> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let first_sector = offset / SECTOR_SIZE;
+        let last_sector = (offset + len as u64).div_ceil(SECTOR_SIZE);
+        let offset_in_sector = (offset % SECTOR_SIZE) as usize;
+
+        let sectors = self
+            .partition
+            .read(first_sector, (last_sector - first_sector) as usize)?;
+
+        return Ok(sectors
+            .get(offset_in_sector..offset_in_sector + len)
+            .ok_or(())?
+            .to_vec());
+    }
+
+    fn write_bytes(&self, offset: u64, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let first_sector = offset / SECTOR_SIZE;
+        let last_sector = (offset + bytes.len() as u64).div_ceil(SECTOR_SIZE);
+        let offset_in_sector = (offset % SECTOR_SIZE) as usize;
+
+        // Partial sectors at either end have to keep what was already in them
+        let mut sectors = self
+            .partition
+            .read(first_sector, (last_sector - first_sector) as usize)?
+            .to_vec();
+
+        sectors[offset_in_sector..offset_in_sector + bytes.len()].copy_from_slice(bytes);
+
+        return self.partition.write(first_sector, &sectors);
+    }
+
+    fn get_next_cluster(&self, cluster: u32) -> Result<u32, ()> {
+        let entry = self.fat_cache.lock().read(cluster as usize * 4, 4)?;
+
+        return Ok(u32::from_le_bytes(entry.try_into().unwrap()));
+    }
+
+    fn set_next_cluster(&self, cluster: u32, next: u32) -> Result<(), ()> {
+        return self
+            .fat_cache
+            .lock()
+            .write(cluster as usize * 4, &next.to_le_bytes());
+    }
+
+    // Every cluster a stream occupies, in order
+    fn cluster_chain(&self, stream: &Stream) -> Result<Vec<u32>, ()> {
+        let mut clusters = Vec::new();
+
+        if stream.first_cluster == 0 {
+            return Ok(clusters);
+        }
+
+        if stream.no_fat_chain {
+            let cluster_count = stream.data_length.div_ceil(self.cluster_size as u64) as u32;
+
+            for cluster in stream.first_cluster..stream.first_cluster + cluster_count {
+                if !self.is_valid_cluster(cluster) {
+                    return Err(());
+                }
+
+                clusters.push(cluster);
+            }
+
+            return Ok(clusters);
+        }
+
+        let mut cluster = stream.first_cluster;
+
+        while cluster != END_OF_CHAIN {
+            if !self.is_valid_cluster(cluster) || cluster == BAD_CLUSTER {
+                return Err(());
+            }
+
+            // A chain longer than the volume has looped back on itself
+            if clusters.len() > self.boot_sector.cluster_count as usize {
+                return Err(());
+            }
+
+            clusters.push(cluster);
+            cluster = self.get_next_cluster(cluster)?;
+        }
+
+        return Ok(clusters);
+    }
+
+    // Anything between the valid data length and the data length reads back as zeroes
+    fn read_stream(&self, stream: &Stream, offset: usize, len: usize) -> Result<Vec<u8>, ()> {
+        let end = offset.checked_add(len).ok_or(())?;
+
+        if end as u64 > stream.data_length {
+            return Err(());
+        }
+
+        let clusters = self.cluster_chain(stream)?;
+        let mut data = Vec::with_capacity(len);
+
+        while data.len() < len {
+            let position = offset + data.len();
+            let offset_in_cluster = position % self.cluster_size;
+            let to_copy = (self.cluster_size - offset_in_cluster).min(len - data.len());
+
+            if position as u64 >= stream.valid_data_length {
+                data.resize(data.len() + to_copy, 0);
+                continue;
+            }
+
+            let cluster = *clusters.get(position / self.cluster_size).ok_or(())?;
+
+            data.extend(self.read_bytes(
+                self.cluster_offset(cluster) + offset_in_cluster as u64,
+                to_copy,
+            )?);
+        }
+
+        // The last copy may have gone past the valid data length
+        let valid = (stream.valid_data_length as usize).saturating_sub(offset);
+        if valid < data.len() {
+            data[valid..].fill(0);
+        }
+
+        return Ok(data);
+    }
+
+    // Writes into space the stream already has allocated
+    fn write_stream(&self, stream: &Stream, offset: usize, bytes: &[u8]) -> Result<(), ()> {
+        if (offset + bytes.len()) as u64 > stream.data_length {
+            return Err(());
+        }
+
+        let clusters = self.cluster_chain(stream)?;
+        let mut written = 0;
+
+        while written < bytes.len() {
+            let position = offset + written;
+            let offset_in_cluster = position % self.cluster_size;
+            let to_copy = (self.cluster_size - offset_in_cluster).min(bytes.len() - written);
+
+            let cluster = *clusters.get(position / self.cluster_size).ok_or(())?;
+
+            self.write_bytes(
+                self.cluster_offset(cluster) + offset_in_cluster as u64,
+                &bytes[written..written + to_copy],
+            )?;
+
+            written += to_copy;
+        }
+
+        return Ok(());
+    }
+
+    fn is_cluster_free(&self, bitmap: &AllocationBitmap, cluster: u32) -> bool {
+        let bit = (cluster - 2) as usize;
+
+        return bitmap
+            .bits
+            .get(bit / 8)
+            .is_some_and(|byte| byte & (1 << (bit % 8)) == 0);
+    }
+
+    fn set_cluster_allocated(
+        &self,
+        bitmap: &mut AllocationBitmap,
+        cluster: u32,
+        allocated: bool,
+    ) -> Result<(), ()> {
+        let bit = (cluster - 2) as usize;
+        let byte = bitmap.bits.get_mut(bit / 8).ok_or(())?;
+
+        if allocated {
+            *byte |= 1 << (bit % 8);
+        } else {
+            *byte &= !(1 << (bit % 8));
+        }
+
+        // The bitmap is written through, only the byte that changed goes to disk
+        let byte = [*byte];
+        let stream = bitmap.stream;
+
+        return self.write_stream(&stream, bit / 8, &byte);
+    }
+
+    // Grabs a free cluster, preferring the one right after `after` so files stay contiguous
+    fn allocate_cluster(&self, after: Option<u32>) -> Result<u32, ()> {
+        let mut bitmap = self.bitmap.lock();
+
+        let first = after.map(|cluster| cluster + 1).unwrap_or(2);
+        let last = self.boot_sector.cluster_count + 1;
+
+        let cluster = (first..=last)
+            .chain(2..first)
+            .find(|&cluster| {
+                self.is_valid_cluster(cluster) && self.is_cluster_free(&bitmap, cluster)
+            })
+            .ok_or(())?;
+
+        self.set_cluster_allocated(&mut bitmap, cluster, true)?;
+
+        return Ok(cluster);
+    }
+
+    // Grows a stream so it has room for new_length bytes. Contiguous streams stay contiguous
+    // for as long as the next cluster is free, after that they're moved over to a FAT chain.
+    fn extend_stream(&self, stream: &mut Stream, new_length: u64) -> Result<(), ()> {
+        let mut clusters = self.cluster_chain(stream)?;
+        let needed = new_length.div_ceil(self.cluster_size as u64) as usize;
+
+        while clusters.len() < needed {
+            let last = clusters.last().copied();
+            let cluster = self.allocate_cluster(last)?;
+
+            match last {
+                None => {
+                    stream.first_cluster = cluster;
+                    stream.no_fat_chain = true;
+                }
+                Some(last) => {
+                    if stream.no_fat_chain && cluster != last + 1 {
+                        // Write out the chain the contiguous clusters never needed
+                        for pair in clusters.windows(2) {
+                            self.set_next_cluster(pair[0], pair[1])?;
+                        }
+
+                        stream.no_fat_chain = false;
+                    }
+
+                    if !stream.no_fat_chain {
+                        self.set_next_cluster(last, cluster)?;
+                    }
+                }
+            }
+
+            if !stream.no_fat_chain {
+                self.set_next_cluster(cluster, END_OF_CHAIN)?;
+            }
+
+            clusters.push(cluster);
+        }
+
+        stream.data_length = stream.data_length.max(new_length);
+
+        return Ok(());
+    }
+
+    // Parses every in use entry set in a directory, ones with a bad checksum are skipped
+    fn read_directory(&self, directory: &Stream) -> Result<Vec<DirectoryEntry>, ()> {
+        let bytes = self.read_stream(directory, 0, directory.data_length as usize)?;
+        let entries = bytes.chunks_exact(ENTRY_SIZE).collect::<Vec<&[u8]>>();
+
+        let mut directory_entries = Vec::new();
+        let mut index = 0;
+
+        while index < entries.len() {
+            let entry = entries[index];
+
+            if entry[0] == ENTRY_END_OF_DIRECTORY {
+                break;
+            }
+
+            if entry[0] != ENTRY_FILE {
+                index += 1;
+                continue;
+            }
+
+            let secondary_count = entry[1] as usize;
+
+            // A file needs at least a stream extension and one name entry
+            if secondary_count < 2 || index + secondary_count >= entries.len() {
+                index += 1;
+                continue;
+            }
+
+            let set = &bytes[index * ENTRY_SIZE..(index + secondary_count + 1) * ENTRY_SIZE];
+            let checksum = u16::from_le_bytes(entry[2..4].try_into().unwrap());
+
+            if Self::entry_set_checksum(set) != checksum
+                || entries[index + 1][0] != ENTRY_STREAM_EXTENSION
+            {
+                index += 1;
+                continue;
+            }
+
+            let attributes = u16::from_le_bytes(entry[4..6].try_into().unwrap());
+
+            let stream_entry = entries[index + 1];
+            let name_length = stream_entry[3] as usize;
+
+            let stream = Stream {
+                first_cluster: u32::from_le_bytes(stream_entry[20..24].try_into().unwrap()),
+                data_length: u64::from_le_bytes(stream_entry[24..32].try_into().unwrap()),
+                valid_data_length: u64::from_le_bytes(stream_entry[8..16].try_into().unwrap()),
+                no_fat_chain: stream_entry[1] & FLAG_NO_FAT_CHAIN != 0,
+            };
+
+            let mut name: Vec<u16> = Vec::with_capacity(name_length);
+
+            for name_entry in entries[index + 2..=index + secondary_count].iter() {
+                if name_entry[0] != ENTRY_FILE_NAME {
+                    break;
+                }
+
+                name.extend(
+                    name_entry[2..32]
+                        .chunks_exact(2)
+                        .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap())),
+                );
+            }
+
+            name.truncate(name_length);
+
+            directory_entries.push(DirectoryEntry {
+                name: String::from_utf16_lossy(&name),
+                attributes,
+                stream,
+                location: EntrySetLocation {
+                    directory: *directory,
+                    index,
+                },
+            });
+
+            index += secondary_count + 1;
+        }
+
+        return Ok(directory_entries);
+    }
+
+    fn find_entry(&self, directory: &Stream, name: &str) -> Result<DirectoryEntry, ()> {
+        return self
+            .read_directory(directory)?
+            .into_iter()
+            .find(|entry| self.names_equal(&entry.name, name))
+            .ok_or(());
+    }
+
+    // Builds the file, stream extension and name entries for a new file
+    fn build_entry_set(&self, name: &str, attributes: u16, stream: &Stream) -> Result<Vec<u8>, ()> {
+        let name: Vec<u16> = name.encode_utf16().collect();
+
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+            return Err(());
+        }
+
+        let name_entries = name.len().div_ceil(NAME_CHARACTERS_PER_ENTRY);
+        let mut set = alloc::vec![0u8; (name_entries + 2) * ENTRY_SIZE];
+
+        set[0] = ENTRY_FILE;
+        set[1] = (name_entries + 1) as u8;
+        set[4..6].copy_from_slice(&attributes.to_le_bytes());
+
+        let stream_entry = &mut set[ENTRY_SIZE..ENTRY_SIZE * 2];
+        stream_entry[0] = ENTRY_STREAM_EXTENSION;
+        stream_entry[3] = name.len() as u8;
+        stream_entry[4..6].copy_from_slice(&self.name_hash(&name).to_le_bytes());
+        Self::write_stream_entry(stream_entry, stream);
+
+        for (i, characters) in name.chunks(NAME_CHARACTERS_PER_ENTRY).enumerate() {
+            let name_entry = &mut set[(i + 2) * ENTRY_SIZE..(i + 3) * ENTRY_SIZE];
+            name_entry[0] = ENTRY_FILE_NAME;
+
+            for (j, character) in characters.iter().enumerate() {
+                name_entry[2 + j * 2..4 + j * 2].copy_from_slice(&character.to_le_bytes());
+            }
+        }
+
+        let checksum = Self::entry_set_checksum(&set);
+        set[2..4].copy_from_slice(&checksum.to_le_bytes());
+
+        return Ok(set);
+    }
+
+    fn write_stream_entry(stream_entry: &mut [u8], stream: &Stream) {
+        let mut flags = FLAG_ALLOCATION_POSSIBLE;
+
+        if stream.no_fat_chain {
+            flags |= FLAG_NO_FAT_CHAIN;
+        }
+
+        stream_entry[1] = flags;
+        stream_entry[8..16].copy_from_slice(&stream.valid_data_length.to_le_bytes());
+        stream_entry[20..24].copy_from_slice(&stream.first_cluster.to_le_bytes());
+        stream_entry[24..32].copy_from_slice(&stream.data_length.to_le_bytes());
+    }
+
+    // Writes a changed stream back into a file's entry set and fixes up the set checksum
+    fn update_entry_set(&self, location: &EntrySetLocation, stream: &Stream) -> Result<(), ()> {
+        let offset = location.index * ENTRY_SIZE;
+
+        let header = self.read_stream(&location.directory, offset, ENTRY_SIZE)?;
+        let set_length = (header[1] as usize + 1) * ENTRY_SIZE;
+
+        let mut set = self.read_stream(&location.directory, offset, set_length)?;
+
+        Self::write_stream_entry(&mut set[ENTRY_SIZE..ENTRY_SIZE * 2], stream);
+
+        let checksum = Self::entry_set_checksum(&set);
+        set[2..4].copy_from_slice(&checksum.to_le_bytes());
+
+        return self.write_stream(&location.directory, offset, &set);
+    }
+
+    // Finds room for count entries in a directory, growing it by a cluster if it's full. Returns
+    // the index of the first entry, directory is updated if it had to grow.
+    fn find_free_entries(&self, directory: &mut Stream, count: usize) -> Result<usize, ()> {
+        let bytes = self.read_stream(directory, 0, directory.data_length as usize)?;
+
+        let mut run_start = 0;
+        let mut run_length = 0;
+
+        for (index, entry) in bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+            if entry[0] & ENTRY_IN_USE != 0 {
+                run_length = 0;
+                continue;
+            }
+
+            if run_length == 0 {
+                run_start = index;
+            }
+
+            run_length += 1;
+
+            if run_length == count {
+                return Ok(run_start);
+            }
+        }
+
+        // Any free entries at the very end carry on into the new cluster
+        let index = match run_length {
+            0 => bytes.len() / ENTRY_SIZE,
+            _ => run_start,
+        };
+
+        let old_length = directory.data_length;
+        self.extend_stream(directory, old_length + self.cluster_size as u64)?;
+        directory.valid_data_length = directory.data_length;
+
+        // The new cluster has to read as the end of the directory
+        self.write_stream(
+            directory,
+            old_length as usize,
+            &alloc::vec![0u8; self.cluster_size],
+        )?;
+
+        return Ok(index);
+    }
+
+    fn stream_from_fid_directory(&self, cluster: u32, no_fat_chain: bool, length: u64) -> Stream {
+        return Stream {
+            first_cluster: cluster,
+            data_length: length,
+            valid_data_length: length,
+            no_fat_chain,
+        };
+    }
+}
+
+impl FsOps for ExFatFs {
+    fn mount(&mut self, _path: &str, data: &mut *mut u8, _vfsp: NonNull<super::vfs::Vfs>) {
+        *data = core::ptr::addr_of!(*self) as *mut u8;
+    }
+
+    fn unmount(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {
+        if self.fat_cache.lock().flush().is_err() {
+            crate::log!(
+                LogLevel::Error,
+                "exFAT: Failed to write back the FAT on unmount"
+            );
+        }
+
+        self.fat_cache.lock().clear();
+    }
+
+    fn root(&mut self, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        let root = ExFatNode {
+            stream: *self.root.lock(),
+            attributes: ATTRIBUTE_DIRECTORY,
+            location: None,
+        };
+
+        return VNode::new(Box::new(root), VNodeType::Directory, vfsp);
+    }
+
+    fn statfs(&mut self, _vfsp: NonNull<super::vfs::Vfs>) -> super::vfs::StatFs {
+        todo!("EXFAT STATFS");
+    }
+
+    fn sync(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {
+        // the bitmap and entry sets are written through, only the FAT is held back
+        if self.fat_cache.lock().flush().is_err() {
+            crate::log!(LogLevel::Error, "exFAT: Failed to write back the FAT");
+        }
+    }
+
+    // Like FAT, an exFAT file id points at the entry set: the directory's first cluster, the
+    // entry index (with the directory's NoFatChain flag in the top bit) and the directory length
+    fn fid(&mut self, path: &str, _vfsp: NonNull<super::vfs::Vfs>) -> Option<FileId> {
+        let mut stream = *self.root.lock();
+        let mut location: Option<EntrySetLocation> = None;
+        let mut is_directory = true;
+
+        for part in path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+        {
+            // only directories can have something looked up in them
+            if !is_directory {
+                return None;
+            }
+
+            let entry = self.find_entry(&stream, part).ok()?;
+
+            stream = entry.stream;
+            location = Some(entry.location);
+            is_directory = entry.attributes & ATTRIBUTE_DIRECTORY != 0;
+        }
+
+        let (directory, index) = match location {
+            Some(location) => {
+                let mut index = location.index as u32;
+
+                if location.directory.no_fat_chain {
+                    index |= FID_NO_FAT_CHAIN;
+                }
+
+                (location.directory, index)
+            }
+            None => (*self.root.lock(), EXFAT_ROOT_FID_INDEX),
+        };
+
+        let mut bytes = [0u8; 16];
+        bytes[0..4].copy_from_slice(&directory.first_cluster.to_le_bytes());
+        bytes[4..8].copy_from_slice(&index.to_le_bytes());
+        bytes[8..16].copy_from_slice(&directory.data_length.to_le_bytes());
+
+        return FileId::new(&bytes).ok();
+    }
+
+    fn vget(&mut self, fid: FileId, vfsp: NonNull<super::vfs::Vfs>) -> Result<VNode, ()> {
+        let bytes = fid.as_bytes();
+
+        if bytes.len() != 16 {
+            return Err(());
+        }
+
+        let cluster = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let index = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let length = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+
+        if index == EXFAT_ROOT_FID_INDEX {
+            return Ok(self.root(vfsp));
+        }
+
+        let directory =
+            self.stream_from_fid_directory(cluster, index & FID_NO_FAT_CHAIN != 0, length);
+        let index = (index & !FID_NO_FAT_CHAIN) as usize;
+
+        let entry = self
+            .read_directory(&directory)?
+            .into_iter()
+            .find(|entry| entry.location.index == index)
+            .ok_or(())?;
+
+        return Ok(ExFatNode::from_entry(&entry).into_vnode(vfsp));
+    }
+}
+
+struct ExFatNode {
+    stream: Stream,
+    attributes: u16,
+    // None for the root directory
+    location: Option<EntrySetLocation>,
+}
+
+impl ExFatNode {
+    fn from_entry(entry: &DirectoryEntry) -> Self {
+        return Self {
+            stream: entry.stream,
+            attributes: entry.attributes,
+            location: Some(entry.location),
+        };
+    }
+
+    fn into_vnode(self, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        let typ = match self.is_directory() {
+            true => VNodeType::Directory,
+            false => VNodeType::Regular,
+        };
+
+        return VNode::new(Box::new(self), typ, vfsp);
+    }
+
+    fn is_directory(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+
+    fn get_fs<'a>(vp: NonNull<VNode>) -> &'a ExFatFs {
+        unsafe { &*(*vp.as_ptr()).parent_vfs.as_mut().data.cast::<ExFatFs>() }
+    }
+
+    // A root node only has a copy of the root stream, which may have grown through another one
+    fn refresh_root(&mut self, exfat: &ExFatFs) {
+        if self.location.is_none() {
+            self.stream = *exfat.root.lock();
+        }
+    }
+
+    // Adds a new entry set to this directory
+    fn add_entry(
+        &mut self,
+        name: &str,
+        attributes: u16,
+        stream: Stream,
+        vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        let exfat = Self::get_fs(vp);
+
+        self.refresh_root(exfat);
+
+        if !self.is_directory() || exfat.find_entry(&self.stream, name).is_ok() {
+            return Err(());
+        }
+
+        let set = exfat.build_entry_set(name, attributes, &stream)?;
+
+        let old_length = self.stream.data_length;
+        let index = exfat.find_free_entries(&mut self.stream, set.len() / ENTRY_SIZE)?;
+
+        if self.stream.data_length != old_length {
+            match self.location {
+                Some(ref location) => exfat.update_entry_set(location, &self.stream)?,
+                None => *exfat.root.lock() = self.stream,
+            }
+        }
+
+        exfat.write_stream(&self.stream, index * ENTRY_SIZE, &set)?;
+
+        let node = ExFatNode {
+            stream,
+            attributes,
+            location: Some(EntrySetLocation {
+                directory: self.stream,
+                index,
+            }),
+        };
+
+        return Ok(node.into_vnode(unsafe { (*vp.as_ptr()).parent_vfs }));
+    }
+}
+
+impl VNodeOperations for ExFatNode {
+    fn open(&mut self, _f: u32, _c: super::vfs::UserCred, _vp: NonNull<VNode>) {}
+
+    fn close(&mut self, _f: u32, _c: super::vfs::UserCred, _vp: NonNull<VNode>) {}
+
+    fn read(
+        &mut self,
+        count: usize,
+        offset: usize,
+        _f: u32,
+        _c: super::vfs::UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        if self.is_directory() {
+            return Err(());
+        }
+
+        let exfat = Self::get_fs(vp);
+
+        return Ok(Arc::from(exfat.read_stream(&self.stream, offset, count)?));
+    }
+
+    fn write(
+        &mut self,
+        offset: usize,
+        buf: &[u8],
+        _f: u32,
+        _c: super::vfs::UserCred,
+        vp: NonNull<VNode>,
+    ) {
+        let exfat = Self::get_fs(vp);
+
+        let result = (|| -> Result<(), ()> {
+            let location = self.location.ok_or(())?;
+
+            if self.is_directory() {
+                return Err(());
+            }
+
+            let end = (offset + buf.len()) as u64;
+
+            if end > self.stream.data_length {
+                exfat.extend_stream(&mut self.stream, end)?;
+            }
+
+            // Whatever was past the valid data length is garbage on disk, it has to be zeroed
+            // before the valid data length can move past it
+            if offset as u64 > self.stream.valid_data_length {
+                let gap = offset - self.stream.valid_data_length as usize;
+                let valid_data_length = self.stream.valid_data_length as usize;
+
+                exfat.write_stream(&self.stream, valid_data_length, &alloc::vec![0u8; gap])?;
+            }
+
+            exfat.write_stream(&self.stream, offset, buf)?;
+
+            self.stream.valid_data_length = self.stream.valid_data_length.max(end);
+
+            return exfat.update_entry_set(&location, &self.stream);
+        })();
+
+        if result.is_err() {
+            crate::log!(LogLevel::Error, "exFAT: Failed to write to file");
+        }
+    }
+
+    fn ioctl(
+        &mut self,
+        _com: u32,
+        _d: *mut u8,
+        _f: u32,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn getattr(&mut self, _c: super::vfs::UserCred, _vp: NonNull<VNode>) -> super::vfs::VAttr {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn setattr(&mut self, _va: super::vfs::VAttr, _c: super::vfs::UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn access(&mut self, _m: u32, _c: super::vfs::UserCred, _vp: NonNull<VNode>) -> Result<(), ()> {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn lookup(
+        &mut self,
+        nm: &str,
+        _c: super::vfs::UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        if !self.is_directory() {
+            return Err(());
+        }
+
+        let exfat = Self::get_fs(vp);
+
+        self.refresh_root(exfat);
+
+        let entry = exfat.find_entry(&self.stream, nm)?;
+
+        return Ok(ExFatNode::from_entry(&entry).into_vnode(unsafe { (*vp.as_ptr()).parent_vfs }));
+    }
+
+    fn create(
+        &mut self,
+        nm: &str,
+        _va: super::vfs::VAttr,
+        _e: u32,
+        _m: u32,
+        _c: super::vfs::UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        let stream = Stream {
+            first_cluster: 0,
+            data_length: 0,
+            valid_data_length: 0,
+            no_fat_chain: false,
+        };
+
+        return self.add_entry(nm, ATTRIBUTE_ARCHIVE, stream, vp);
+    }
+
+    fn link(
+        &mut self,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        crate::log!(LogLevel::Warn, "exFAT: Hard links are not supported");
+    }
+
+    fn rename(
+        &mut self,
+        _nm: &str,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn mkdir(
+        &mut self,
+        nm: &str,
+        _va: super::vfs::VAttr,
+        _c: super::vfs::UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        let exfat = Self::get_fs(vp);
+
+        let mut stream = Stream {
+            first_cluster: 0,
+            data_length: 0,
+            valid_data_length: 0,
+            no_fat_chain: false,
+        };
+
+        // A new directory gets one zeroed cluster, which reads as an empty directory
+        exfat.extend_stream(&mut stream, exfat.cluster_size as u64)?;
+        stream.valid_data_length = stream.data_length;
+        exfat.write_stream(&stream, 0, &alloc::vec![0u8; exfat.cluster_size])?;
+
+        return self.add_entry(nm, ATTRIBUTE_DIRECTORY, stream, vp);
+    }
+
+    fn readdir(
+        &mut self,
+        _uiop: *const super::vfs::UIO,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn symlink(
+        &mut self,
+        _link_name: &str,
+        _va: super::vfs::VAttr,
+        _target_name: &str,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        crate::log!(LogLevel::Warn, "exFAT: Symlinks are not supported");
+    }
+
+    fn readlink(
+        &mut self,
+        _uiop: *const super::vfs::UIO,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        crate::log!(LogLevel::Warn, "exFAT: Symlinks are not supported");
+    }
+
+    fn fsync(&mut self, _c: super::vfs::UserCred, vp: NonNull<VNode>) {
+        let exfat = Self::get_fs(vp);
+
+        if exfat.fat_cache.lock().flush().is_err() {
+            crate::log!(LogLevel::Error, "exFAT: Failed to write back the FAT");
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _name: &str,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        // exFAT has nowhere to store extended attributes
+        return Err(());
+    }
+
+    fn listxattr(
+        &mut self,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<Vec<String>, ()> {
+        return Ok(Vec::new());
+    }
+
+    fn setxattr(
+        &mut self,
+        _name: &str,
+        _value: &[u8],
+        _f: u32,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<(), ()> {
+        return Err(());
+    }
+
+    fn len(&self, _vp: NonNull<VNode>) -> usize {
+        self.stream.data_length as usize
+    }
+}
+
+impl core::fmt::Debug for ExFatFs {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ExFatFs")
+            .field("boot_sector", &self.boot_sector)
+            .field("cluster_size", &self.cluster_size.to_string())
+            .finish()
+    }
+}
@@ -0,0 +1,1233 @@
+use core::ptr::NonNull;
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+
+use crate::{drivers::storage::Partition, libs::sync::Mutex, log, LogLevel};
+
+use super::vfs::{FileId, FsOps, UserCred, VNode, VNodeOperations, VNodeType, UIO};
+
+const SECTOR_SIZE: u64 = 512;
+
+// The superblock always sits 1024 bytes into the partition, whatever the block size is
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT2_MAGIC: u16 = 0xEF53;
+
+const ROOT_INODE: u32 = 2;
+
+// Revision 0 filesystems have fixed size inodes and no feature flags
+const GOOD_OLD_REVISION: u32 = 0;
+const GOOD_OLD_INODE_SIZE: u16 = 128;
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+
+// Directory entries carry a file type byte instead of the high byte of the name length
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+// sparse_super, large_file and btree_dir (which stays readable as a normal directory)
+const SUPPORTED_RO_COMPAT: u32 = 0x0001 | 0x0002 | 0x0004;
+
+const DIRECT_BLOCKS: usize = 12;
+const SINGLE_INDIRECT: usize = 12;
+const DOUBLE_INDIRECT: usize = 13;
+const TRIPLE_INDIRECT: usize = 14;
+
+// Symlinks shorter than this keep their target in i_block instead of in a data block
+const FAST_SYMLINK_MAX: usize = 60;
+
+// File type bits of the mode field
+const S_IFMT: u16 = 0o170000;
+const S_IFSOCK: u16 = 0o140000;
+const S_IFLNK: u16 = 0o120000;
+const S_IFREG: u16 = 0o100000;
+const S_IFBLK: u16 = 0o060000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFCHR: u16 = 0o020000;
+
+// The file type byte in directory entries
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+
+pub fn is_ext2(superblock: &[u8]) -> bool {
+    superblock
+        .get(56..58)
+        .is_some_and(|magic| u16::from_le_bytes(magic.try_into().unwrap()) == EXT2_MAGIC)
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct Superblock {
+    inodes_count: u32,
+    blocks_count: u32,
+    free_blocks_count: u32,
+    free_inodes_count: u32,
+    first_data_block: u32,
+    log_block_size: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    state: u16,
+    rev_level: u32,
+    first_ino: u32,
+    inode_size: u16,
+    feature_compat: u32,
+    feature_incompat: u32,
+    feature_ro_compat: u32,
+    volume_name: [u8; 16],
+}
+
+impl Superblock {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() < 1024 || !is_ext2(bytes) {
+            return Err(());
+        }
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+
+        let rev_level = u32_at(76);
+
+        let mut superblock = Self {
+            inodes_count: u32_at(0),
+            blocks_count: u32_at(4),
+            free_blocks_count: u32_at(12),
+            free_inodes_count: u32_at(16),
+            first_data_block: u32_at(20),
+            log_block_size: u32_at(24),
+            blocks_per_group: u32_at(32),
+            inodes_per_group: u32_at(40),
+            state: u16_at(58),
+            rev_level,
+            first_ino: GOOD_OLD_FIRST_INODE,
+            inode_size: GOOD_OLD_INODE_SIZE,
+            feature_compat: 0,
+            feature_incompat: 0,
+            feature_ro_compat: 0,
+            volume_name: [0; 16],
+        };
+
+        if rev_level != GOOD_OLD_REVISION {
+            superblock.first_ino = u32_at(84);
+            superblock.inode_size = u16_at(88);
+            superblock.feature_compat = u32_at(92);
+            superblock.feature_incompat = u32_at(96);
+            superblock.feature_ro_compat = u32_at(100);
+            superblock.volume_name = bytes[120..136].try_into().unwrap();
+        }
+
+        // Block sizes run from 1KiB to 64KiB
+        if superblock.log_block_size > 6 {
+            return Err(());
+        }
+
+        if superblock.blocks_per_group == 0
+            || superblock.inodes_per_group == 0
+            || superblock.blocks_per_group > (1024 << superblock.log_block_size) * 8
+        {
+            return Err(());
+        }
+
+        if superblock.inode_size < GOOD_OLD_INODE_SIZE
+            || !superblock.inode_size.is_power_of_two()
+            || superblock.inode_size as u32 > 1024 << superblock.log_block_size
+        {
+            return Err(());
+        }
+
+        return Ok(superblock);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlockGroupDescriptor {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks_count: u16,
+    free_inodes_count: u16,
+    used_dirs_count: u16,
+}
+
+impl BlockGroupDescriptor {
+    const SIZE: usize = 32;
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        return Self {
+            block_bitmap: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            inode_bitmap: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            inode_table: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            free_blocks_count: u16::from_le_bytes(bytes[12..14].try_into().unwrap()),
+            free_inodes_count: u16::from_le_bytes(bytes[14..16].try_into().unwrap()),
+            used_dirs_count: u16::from_le_bytes(bytes[16..18].try_into().unwrap()),
+        };
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+struct Inode {
+    mode: u16,
+    uid: u16,
+    gid: u16,
+    size: u64,
+    links_count: u16,
+    // Counted in 512 byte sectors, not in filesystem blocks
+    blocks: u32,
+    flags: u32,
+    block: [u32; 15],
+    file_acl: u32,
+}
+
+impl Inode {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut block = [0u32; 15];
+
+        for (i, pointer) in bytes[40..100].chunks_exact(4).enumerate() {
+            block[i] = u32::from_le_bytes(pointer.try_into().unwrap());
+        }
+
+        let mode = u16::from_le_bytes(bytes[0..2].try_into().unwrap());
+        let size_low = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as u64;
+        // i_dir_acl doubles as the top half of the size for regular files
+        let size_high = match mode & S_IFMT {
+            S_IFREG => u32::from_le_bytes(bytes[108..112].try_into().unwrap()) as u64,
+            _ => 0,
+        };
+
+        return Self {
+            mode,
+            uid: u16::from_le_bytes(bytes[2..4].try_into().unwrap()),
+            size: size_high << 32 | size_low,
+            gid: u16::from_le_bytes(bytes[24..26].try_into().unwrap()),
+            links_count: u16::from_le_bytes(bytes[26..28].try_into().unwrap()),
+            blocks: u32::from_le_bytes(bytes[28..32].try_into().unwrap()),
+            flags: u32::from_le_bytes(bytes[32..36].try_into().unwrap()),
+            block,
+            file_acl: u32::from_le_bytes(bytes[104..108].try_into().unwrap()),
+        };
+    }
+
+    // Only the fields the driver changes are written, the rest of the on-disk inode is kept
+    fn write_bytes(&self, bytes: &mut [u8]) {
+        bytes[0..2].copy_from_slice(&self.mode.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.uid.to_le_bytes());
+        bytes[4..8].copy_from_slice(&(self.size as u32).to_le_bytes());
+        bytes[24..26].copy_from_slice(&self.gid.to_le_bytes());
+        bytes[26..28].copy_from_slice(&self.links_count.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.blocks.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.flags.to_le_bytes());
+
+        for (i, pointer) in self.block.iter().enumerate() {
+            bytes[40 + i * 4..44 + i * 4].copy_from_slice(&pointer.to_le_bytes());
+        }
+
+        if self.mode & S_IFMT == S_IFREG {
+            bytes[108..112].copy_from_slice(&((self.size >> 32) as u32).to_le_bytes());
+        }
+    }
+
+    fn new(mode: u16) -> Self {
+        return Self {
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            links_count: 1,
+            blocks: 0,
+            flags: 0,
+            block: [0; 15],
+            file_acl: 0,
+        };
+    }
+
+    fn vnode_type(&self) -> VNodeType {
+        match self.mode & S_IFMT {
+            S_IFREG => VNodeType::Regular,
+            S_IFDIR => VNodeType::Directory,
+            S_IFLNK => VNodeType::Link,
+            S_IFBLK => VNodeType::Block,
+            S_IFCHR => VNodeType::Character,
+            S_IFSOCK => VNodeType::Socket,
+            _ => VNodeType::NON,
+        }
+    }
+
+    fn is_directory(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+#[derive(Debug)]
+struct DirectoryEntry {
+    inode: u32,
+    name: String,
+    // Byte offset of the entry in the directory, and how much room it takes up there
+    offset: usize,
+    rec_len: usize,
+}
+
+pub struct Ext2Fs {
+    partition: Partition,
+    superblock: Mutex<Superblock>,
+    groups: Mutex<Vec<BlockGroupDescriptor>>,
+    block_size: usize,
+    // first block of the block group descriptor table
+    group_table_block: u32,
+    // Set when the volume has features we can read but not safely write
+    read_only: bool,
+}
+
+impl Ext2Fs {
+    pub fn new(partition: Partition) -> Result<Self, ()> {
+        let superblock_bytes = partition.read(
+            SUPERBLOCK_OFFSET / SECTOR_SIZE,
+            (1024 / SECTOR_SIZE) as usize,
+        )?;
+
+        let superblock = Superblock::from_bytes(&superblock_bytes)?;
+
+        if superblock.feature_incompat & !INCOMPAT_FILETYPE != 0 {
+            log!(
+                LogLevel::Error,
+                "ext2: Unsupported incompatible features {:#X}",
+                superblock.feature_incompat
+            );
+            return Err(());
+        }
+
+        let read_only = superblock.feature_ro_compat & !SUPPORTED_RO_COMPAT != 0;
+
+        if read_only {
+            log!(
+                LogLevel::Warn,
+                "ext2: Mounting read-only, unsupported features {:#X}",
+                superblock.feature_ro_compat
+            );
+        }
+
+        let block_size = 1024usize << superblock.log_block_size;
+        let group_count = superblock
+            .blocks_count
+            .div_ceil(superblock.blocks_per_group) as usize;
+
+        if group_count == 0
+            || group_count
+                != superblock
+                    .inodes_count
+                    .div_ceil(superblock.inodes_per_group) as usize
+        {
+            return Err(());
+        }
+
+        let mut ext2 = Self {
+            partition,
+            group_table_block: superblock.first_data_block + 1,
+            superblock: Mutex::new(superblock),
+            groups: Mutex::new(Vec::new()),
+            block_size,
+            read_only,
+        };
+
+        let table = ext2.read_bytes(
+            ext2.group_table_block as u64 * block_size as u64,
+            group_count * BlockGroupDescriptor::SIZE,
+        )?;
+
+        ext2.groups = Mutex::new(
+            table
+                .chunks_exact(BlockGroupDescriptor::SIZE)
+                .map(BlockGroupDescriptor::from_bytes)
+                .collect(),
+        );
+
+        if !ext2.read_inode(ROOT_INODE)?.is_directory() {
+            return Err(());
+        }
+
+        return Ok(ext2);
+    }
+
+    fn read_bytes(&self, offset: u64, len: usize) -> Result<Vec<u8>, ()> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let first_sector = offset / SECTOR_SIZE;
+        let last_sector = (offset + len as u64).div_ceil(SECTOR_SIZE);
+        let offset_in_sector = (offset % SECTOR_SIZE) as usize;
+
+        let sectors = self
+            .partition
+            .read(first_sector, (last_sector - first_sector) as usize)?;
+
+        return Ok(sectors
+            .get(offset_in_sector..offset_in_sector + len)
+            .ok_or(())?
+            .to_vec());
+    }
+
+    fn write_bytes(&self, offset: u64, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        if self.read_only {
+            return Err(());
+        }
+
+        let first_sector = offset / SECTOR_SIZE;
+        let last_sector = (offset + bytes.len() as u64).div_ceil(SECTOR_SIZE);
+        let offset_in_sector = (offset % SECTOR_SIZE) as usize;
+
+        // Partial sectors at either end have to keep what was already in them
+        let mut sectors = self
+            .partition
+            .read(first_sector, (last_sector - first_sector) as usize)?
+            .to_vec();
+
+        sectors[offset_in_sector..offset_in_sector + bytes.len()].copy_from_slice(bytes);
+
+        return self.partition.write(first_sector, &sectors);
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, ()> {
+        if block >= self.superblock.lock().blocks_count {
+            return Err(());
+        }
+
+        return self.read_bytes(block as u64 * self.block_size as u64, self.block_size);
+    }
+
+    fn write_block(&self, block: u32, bytes: &[u8]) -> Result<(), ()> {
+        if block >= self.superblock.lock().blocks_count || bytes.len() > self.block_size {
+            return Err(());
+        }
+
+        return self.write_bytes(block as u64 * self.block_size as u64, bytes);
+    }
+
+    fn inode_offset(&self, inode: u32) -> Result<u64, ()> {
+        let (inodes_count, inodes_per_group, inode_size) = {
+            let superblock = self.superblock.lock();
+            (
+                superblock.inodes_count,
+                superblock.inodes_per_group,
+                superblock.inode_size,
+            )
+        };
+
+        if inode == 0 || inode > inodes_count {
+            return Err(());
+        }
+
+        let group = ((inode - 1) / inodes_per_group) as usize;
+        let index = ((inode - 1) % inodes_per_group) as u64;
+
+        let inode_table = self.groups.lock().get(group).ok_or(())?.inode_table;
+
+        return Ok(inode_table as u64 * self.block_size as u64 + index * inode_size as u64);
+    }
+
+    fn read_inode(&self, inode: u32) -> Result<Inode, ()> {
+        let bytes = self.read_bytes(self.inode_offset(inode)?, GOOD_OLD_INODE_SIZE as usize)?;
+
+        return Ok(Inode::from_bytes(&bytes));
+    }
+
+    fn write_inode(&self, inode_number: u32, inode: &Inode) -> Result<(), ()> {
+        let offset = self.inode_offset(inode_number)?;
+        let mut bytes = self.read_bytes(offset, GOOD_OLD_INODE_SIZE as usize)?;
+
+        inode.write_bytes(&mut bytes);
+
+        return self.write_bytes(offset, &bytes);
+    }
+
+    fn pointers_per_block(&self) -> usize {
+        self.block_size / 4
+    }
+
+    // Works out which i_block slot a file block hangs off, and the index into each level of
+    // indirect block on the way down to it
+    fn block_path(&self, index: usize) -> Result<(usize, Vec<usize>), ()> {
+        let pointers = self.pointers_per_block();
+
+        if index < DIRECT_BLOCKS {
+            return Ok((index, Vec::new()));
+        }
+
+        let index = index - DIRECT_BLOCKS;
+
+        if index < pointers {
+            return Ok((SINGLE_INDIRECT, alloc::vec![index]));
+        }
+
+        let index = index - pointers;
+
+        if index < pointers * pointers {
+            return Ok((
+                DOUBLE_INDIRECT,
+                alloc::vec![index / pointers, index % pointers],
+            ));
+        }
+
+        let index = index - pointers * pointers;
+
+        if index < pointers * pointers * pointers {
+            return Ok((
+                TRIPLE_INDIRECT,
+                alloc::vec![
+                    index / (pointers * pointers),
+                    (index / pointers) % pointers,
+                    index % pointers
+                ],
+            ));
+        }
+
+        return Err(());
+    }
+
+    // Finds the disk block holding block `index` of a file. 0 is a hole unless allocate is set,
+    // in which case any missing data or indirect blocks are allocated on the way.
+    fn map_block(
+        &self,
+        inode_number: u32,
+        inode: &mut Inode,
+        index: usize,
+        allocate: bool,
+    ) -> Result<u32, ()> {
+        let (slot, offsets) = self.block_path(index)?;
+        let group = self.inode_group(inode_number);
+
+        let mut block = inode.block[slot];
+
+        if block == 0 {
+            if !allocate {
+                return Ok(0);
+            }
+
+            block = self.allocate_block(group)?;
+            inode.block[slot] = block;
+            inode.blocks += (self.block_size as u64 / SECTOR_SIZE) as u32;
+        }
+
+        for offset in offsets {
+            let mut indirect = self.read_block(block)?;
+            let pointer = &mut indirect[offset * 4..offset * 4 + 4];
+            let next = u32::from_le_bytes(pointer.try_into().unwrap());
+
+            if next != 0 {
+                block = next;
+                continue;
+            }
+
+            if !allocate {
+                return Ok(0);
+            }
+
+            let new_block = self.allocate_block(group)?;
+            pointer.copy_from_slice(&new_block.to_le_bytes());
+            self.write_block(block, &indirect)?;
+
+            inode.blocks += (self.block_size as u64 / SECTOR_SIZE) as u32;
+            block = new_block;
+        }
+
+        return Ok(block);
+    }
+
+    fn read_data(
+        &self,
+        inode_number: u32,
+        inode: &Inode,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, ()> {
+        let end = offset.checked_add(len).ok_or(())?;
+
+        if end as u64 > inode.size {
+            return Err(());
+        }
+
+        let mut inode = *inode;
+        let mut data = Vec::with_capacity(len);
+
+        while data.len() < len {
+            let position = offset + data.len();
+            let offset_in_block = position % self.block_size;
+            let to_copy = (self.block_size - offset_in_block).min(len - data.len());
+
+            let block =
+                self.map_block(inode_number, &mut inode, position / self.block_size, false)?;
+
+            // Holes read back as zeroes
+            if block == 0 {
+                data.resize(data.len() + to_copy, 0);
+                continue;
+            }
+
+            data.extend(self.read_bytes(
+                block as u64 * self.block_size as u64 + offset_in_block as u64,
+                to_copy,
+            )?);
+        }
+
+        return Ok(data);
+    }
+
+    // Writes file data, allocating blocks as needed. The inode is updated in memory, callers
+    // write it back once they're done with it.
+    fn write_data(
+        &self,
+        inode_number: u32,
+        inode: &mut Inode,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), ()> {
+        let mut written = 0;
+
+        while written < bytes.len() {
+            let position = offset + written;
+            let offset_in_block = position % self.block_size;
+            let to_copy = (self.block_size - offset_in_block).min(bytes.len() - written);
+
+            let block = self.map_block(inode_number, inode, position / self.block_size, true)?;
+
+            self.write_bytes(
+                block as u64 * self.block_size as u64 + offset_in_block as u64,
+                &bytes[written..written + to_copy],
+            )?;
+
+            written += to_copy;
+        }
+
+        inode.size = inode.size.max((offset + bytes.len()) as u64);
+
+        return Ok(());
+    }
+
+    fn inode_group(&self, inode: u32) -> usize {
+        ((inode - 1) / self.superblock.lock().inodes_per_group) as usize
+    }
+
+    // Writes the free counts of a group back into the descriptor table
+    fn write_group(&self, group: usize, descriptor: &BlockGroupDescriptor) -> Result<(), ()> {
+        let offset = self.group_table_block as u64 * self.block_size as u64
+            + (group * BlockGroupDescriptor::SIZE) as u64;
+
+        let mut counts = [0u8; 6];
+        counts[0..2].copy_from_slice(&descriptor.free_blocks_count.to_le_bytes());
+        counts[2..4].copy_from_slice(&descriptor.free_inodes_count.to_le_bytes());
+        counts[4..6].copy_from_slice(&descriptor.used_dirs_count.to_le_bytes());
+
+        return self.write_bytes(offset + 12, &counts);
+    }
+
+    fn write_superblock_counts(&self) -> Result<(), ()> {
+        let mut counts = [0u8; 8];
+
+        {
+            let superblock = self.superblock.lock();
+            counts[0..4].copy_from_slice(&superblock.free_blocks_count.to_le_bytes());
+            counts[4..8].copy_from_slice(&superblock.free_inodes_count.to_le_bytes());
+        }
+
+        return self.write_bytes(SUPERBLOCK_OFFSET + 12, &counts);
+    }
+
+    // Sets the first clear bit in a bitmap block, returning its index
+    fn claim_bit(&self, bitmap_block: u32, bit_count: usize) -> Result<Option<usize>, ()> {
+        let mut bitmap = self.read_block(bitmap_block)?;
+
+        for bit in 0..bit_count.min(self.block_size * 8) {
+            if bitmap[bit / 8] & (1 << (bit % 8)) != 0 {
+                continue;
+            }
+
+            bitmap[bit / 8] |= 1 << (bit % 8);
+
+            // only the byte that changed needs to go back to disk
+            self.write_bytes(
+                bitmap_block as u64 * self.block_size as u64 + (bit / 8) as u64,
+                &bitmap[bit / 8..bit / 8 + 1],
+            )?;
+
+            return Ok(Some(bit));
+        }
+
+        return Ok(None);
+    }
+
+    // Allocates a zeroed block, starting the search in the given group to keep files together
+    fn allocate_block(&self, preferred_group: usize) -> Result<u32, ()> {
+        if self.read_only {
+            return Err(());
+        }
+
+        let (first_data_block, blocks_per_group, blocks_count) = {
+            let superblock = self.superblock.lock();
+            (
+                superblock.first_data_block,
+                superblock.blocks_per_group,
+                superblock.blocks_count,
+            )
+        };
+
+        let group_count = self.groups.lock().len();
+
+        for group in (preferred_group..group_count).chain(0..preferred_group) {
+            let descriptor = self.groups.lock()[group];
+
+            if descriptor.free_blocks_count == 0 {
+                continue;
+            }
+
+            let group_start = first_data_block + group as u32 * blocks_per_group;
+            let group_blocks = blocks_per_group.min(blocks_count - group_start) as usize;
+
+            let Some(bit) = self.claim_bit(descriptor.block_bitmap, group_blocks)? else {
+                continue;
+            };
+
+            let descriptor = {
+                let mut groups = self.groups.lock();
+                groups[group].free_blocks_count -= 1;
+                groups[group]
+            };
+            self.write_group(group, &descriptor)?;
+
+            self.superblock.lock().free_blocks_count -= 1;
+            self.write_superblock_counts()?;
+
+            let block = group_start + bit as u32;
+
+            self.write_block(block, &alloc::vec![0u8; self.block_size])?;
+
+            return Ok(block);
+        }
+
+        return Err(());
+    }
+
+    // Allocates an inode number. Directories go to whichever group has the most free inodes to
+    // spread them out, everything else stays in its parent's group if it can.
+    fn allocate_inode(&self, parent_group: usize, directory: bool) -> Result<u32, ()> {
+        if self.read_only {
+            return Err(());
+        }
+
+        let (inodes_per_group, first_ino) = {
+            let superblock = self.superblock.lock();
+            (superblock.inodes_per_group, superblock.first_ino)
+        };
+
+        let group_count = self.groups.lock().len();
+
+        let start_group = match directory {
+            true => (0..group_count)
+                .max_by_key(|&group| self.groups.lock()[group].free_inodes_count)
+                .unwrap_or(0),
+            false => parent_group,
+        };
+
+        for group in (start_group..group_count).chain(0..start_group) {
+            let descriptor = self.groups.lock()[group];
+
+            if descriptor.free_inodes_count == 0 {
+                continue;
+            }
+
+            let Some(bit) = self.claim_bit(descriptor.inode_bitmap, inodes_per_group as usize)?
+            else {
+                continue;
+            };
+
+            let inode = group as u32 * inodes_per_group + bit as u32 + 1;
+
+            // The first few inodes are reserved, their bits should already be set
+            if inode < first_ino {
+                return Err(());
+            }
+
+            let descriptor = {
+                let mut groups = self.groups.lock();
+                groups[group].free_inodes_count -= 1;
+
+                if directory {
+                    groups[group].used_dirs_count += 1;
+                }
+
+                groups[group]
+            };
+            self.write_group(group, &descriptor)?;
+
+            self.superblock.lock().free_inodes_count -= 1;
+            self.write_superblock_counts()?;
+
+            return Ok(inode);
+        }
+
+        return Err(());
+    }
+
+    fn has_file_type(&self) -> bool {
+        self.superblock.lock().feature_incompat & INCOMPAT_FILETYPE != 0
+    }
+
+    fn read_directory(&self, inode_number: u32, inode: &Inode) -> Result<Vec<DirectoryEntry>, ()> {
+        let bytes = self.read_data(inode_number, inode, 0, inode.size as usize)?;
+        let has_file_type = self.has_file_type();
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        while offset + 8 <= bytes.len() {
+            let entry_inode = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+            let rec_len =
+                u16::from_le_bytes(bytes[offset + 4..offset + 6].try_into().unwrap()) as usize;
+            let name_len = match has_file_type {
+                true => bytes[offset + 6] as usize,
+                false => {
+                    u16::from_le_bytes(bytes[offset + 6..offset + 8].try_into().unwrap()) as usize
+                }
+            };
+
+            // Entries never cross a block boundary, anything that does is corrupt
+            if rec_len < 8 + name_len
+                || rec_len % 4 != 0
+                || offset % self.block_size + rec_len > self.block_size
+            {
+                return Err(());
+            }
+
+            // An inode of 0 is an unused entry
+            if entry_inode != 0 {
+                entries.push(DirectoryEntry {
+                    inode: entry_inode,
+                    name: String::from_utf8_lossy(&bytes[offset + 8..offset + 8 + name_len])
+                        .into_owned(),
+                    offset,
+                    rec_len,
+                });
+            }
+
+            offset += rec_len;
+        }
+
+        return Ok(entries);
+    }
+
+    fn find_entry(&self, inode_number: u32, inode: &Inode, name: &str) -> Result<u32, ()> {
+        return self
+            .read_directory(inode_number, inode)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.inode)
+            .ok_or(());
+    }
+
+    fn entry_bytes(&self, inode: u32, name: &str, file_type: u8, rec_len: usize) -> Vec<u8> {
+        let mut entry = alloc::vec![0u8; 8 + name.len()];
+
+        entry[0..4].copy_from_slice(&inode.to_le_bytes());
+        entry[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+        entry[6] = name.len() as u8;
+
+        if self.has_file_type() {
+            entry[7] = file_type;
+        }
+
+        entry[8..].copy_from_slice(name.as_bytes());
+
+        return entry;
+    }
+
+    // Adds a name to a directory, either in the slack after an existing entry or in a new block
+    fn add_directory_entry(
+        &self,
+        directory_number: u32,
+        directory: &mut Inode,
+        name: &str,
+        inode: u32,
+        file_type: u8,
+    ) -> Result<(), ()> {
+        if name.is_empty() || name.len() > 255 || name.contains('/') {
+            return Err(());
+        }
+
+        let needed = (8 + name.len()).next_multiple_of(4);
+
+        for entry in self.read_directory(directory_number, directory)? {
+            let used = (8 + entry.name.len()).next_multiple_of(4);
+
+            if entry.rec_len - used < needed {
+                continue;
+            }
+
+            // Shrink the existing entry to fit and hand the rest of its space to the new one
+            let mut shrunk = [0u8; 2];
+            shrunk.copy_from_slice(&(used as u16).to_le_bytes());
+            self.write_data(directory_number, directory, entry.offset + 4, &shrunk)?;
+
+            let new_entry = self.entry_bytes(inode, name, file_type, entry.rec_len - used);
+            return self.write_data(directory_number, directory, entry.offset + used, &new_entry);
+        }
+
+        // No room anywhere, so the directory grows by a block holding just this entry
+        let offset = directory.size as usize;
+        let mut block = alloc::vec![0u8; self.block_size];
+        let new_entry = self.entry_bytes(inode, name, file_type, self.block_size);
+        block[..new_entry.len()].copy_from_slice(&new_entry);
+
+        return self.write_data(directory_number, directory, offset, &block);
+    }
+
+    // Creates a new inode and links it into a directory
+    fn create_node(
+        &self,
+        parent_number: u32,
+        parent: &mut Inode,
+        name: &str,
+        mode: u16,
+    ) -> Result<(u32, Inode), ()> {
+        if !parent.is_directory() || self.find_entry(parent_number, parent, name).is_ok() {
+            return Err(());
+        }
+
+        let directory = mode & S_IFMT == S_IFDIR;
+        let inode_number = self.allocate_inode(self.inode_group(parent_number), directory)?;
+        let mut inode = Inode::new(mode);
+
+        // A reused inode still has its old times and dtime on disk
+        let inode_size = self.superblock.lock().inode_size as usize;
+        self.write_bytes(
+            self.inode_offset(inode_number)?,
+            &alloc::vec![0u8; inode_size],
+        )?;
+
+        if directory {
+            // "." and ".." are the only entries, ".." takes up the rest of the block
+            let dot = self.entry_bytes(inode_number, ".", FT_DIR, 12);
+            let dot_dot = self.entry_bytes(parent_number, "..", FT_DIR, self.block_size - 12);
+
+            let mut block = alloc::vec![0u8; self.block_size];
+            block[..dot.len()].copy_from_slice(&dot);
+            block[12..12 + dot_dot.len()].copy_from_slice(&dot_dot);
+
+            self.write_data(inode_number, &mut inode, 0, &block)?;
+
+            // one link from the parent and one from "."
+            inode.links_count = 2;
+            parent.links_count += 1;
+        }
+
+        self.write_inode(inode_number, &inode)?;
+
+        let file_type = match directory {
+            true => FT_DIR,
+            false => FT_REG_FILE,
+        };
+
+        self.add_directory_entry(parent_number, parent, name, inode_number, file_type)?;
+        self.write_inode(parent_number, parent)?;
+
+        return Ok((inode_number, inode));
+    }
+}
+
+impl FsOps for Ext2Fs {
+    fn mount(&mut self, _path: &str, data: &mut *mut u8, _vfsp: NonNull<super::vfs::Vfs>) {
+        *data = core::ptr::addr_of!(*self) as *mut u8;
+    }
+
+    fn unmount(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {}
+
+    fn root(&mut self, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        let inode = self
+            .read_inode(ROOT_INODE)
+            .expect("ext2 root inode was readable at mount");
+
+        let root = Ext2Node {
+            inode_number: ROOT_INODE,
+            inode,
+        };
+
+        return VNode::new(Box::new(root), VNodeType::Directory, vfsp);
+    }
+
+    fn statfs(&mut self, _vfsp: NonNull<super::vfs::Vfs>) -> super::vfs::StatFs {
+        todo!("EXT2 STATFS");
+    }
+
+    // Bitmaps, descriptors and inodes are all written through, so there's nothing held back
+    fn sync(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {}
+
+    fn fid(&mut self, path: &str, _vfsp: NonNull<super::vfs::Vfs>) -> Option<FileId> {
+        let mut inode_number = ROOT_INODE;
+
+        for part in path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+        {
+            let inode = self.read_inode(inode_number).ok()?;
+
+            if !inode.is_directory() {
+                return None;
+            }
+
+            inode_number = self.find_entry(inode_number, &inode, part).ok()?;
+        }
+
+        return FileId::new(&inode_number.to_le_bytes()).ok();
+    }
+
+    fn vget(&mut self, fid: FileId, vfsp: NonNull<super::vfs::Vfs>) -> Result<VNode, ()> {
+        let inode_number = u32::from_le_bytes(fid.as_bytes().try_into().map_err(|_| ())?);
+        let inode = self.read_inode(inode_number)?;
+
+        // a deleted inode has no links left
+        if inode.links_count == 0 {
+            return Err(());
+        }
+
+        return Ok(Ext2Node {
+            inode_number,
+            inode,
+        }
+        .into_vnode(vfsp));
+    }
+}
+
+struct Ext2Node {
+    inode_number: u32,
+    inode: Inode,
+}
+
+impl Ext2Node {
+    fn get_fs<'a>(vp: NonNull<VNode>) -> &'a Ext2Fs {
+        unsafe { &*(*vp.as_ptr()).parent_vfs.as_mut().data.cast::<Ext2Fs>() }
+    }
+
+    fn into_vnode(self, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        let typ = self.inode.vnode_type();
+
+        return VNode::new(Box::new(self), typ, vfsp);
+    }
+
+    fn add_node(&mut self, name: &str, mode: u16, vp: NonNull<VNode>) -> Result<VNode, ()> {
+        let ext2 = Self::get_fs(vp);
+
+        let (inode_number, inode) =
+            ext2.create_node(self.inode_number, &mut self.inode, name, mode)?;
+
+        let node = Ext2Node {
+            inode_number,
+            inode,
+        };
+
+        return Ok(node.into_vnode(unsafe { (*vp.as_ptr()).parent_vfs }));
+    }
+}
+
+impl VNodeOperations for Ext2Node {
+    fn open(&mut self, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn close(&mut self, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn read(
+        &mut self,
+        count: usize,
+        offset: usize,
+        _f: u32,
+        _c: UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        if self.inode.mode & S_IFMT != S_IFREG {
+            return Err(());
+        }
+
+        let ext2 = Self::get_fs(vp);
+
+        return Ok(Arc::from(ext2.read_data(
+            self.inode_number,
+            &self.inode,
+            offset,
+            count,
+        )?));
+    }
+
+    fn write(&mut self, offset: usize, buf: &[u8], _f: u32, _c: UserCred, vp: NonNull<VNode>) {
+        let ext2 = Self::get_fs(vp);
+
+        let result = (|| -> Result<(), ()> {
+            if self.inode.mode & S_IFMT != S_IFREG {
+                return Err(());
+            }
+
+            ext2.write_data(self.inode_number, &mut self.inode, offset, buf)?;
+
+            return ext2.write_inode(self.inode_number, &self.inode);
+        })();
+
+        if result.is_err() {
+            log!(
+                LogLevel::Error,
+                "ext2: Failed to write to inode {}",
+                self.inode_number
+            );
+        }
+    }
+
+    fn ioctl(&mut self, _com: u32, _d: *mut u8, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn getattr(&mut self, _c: UserCred, _vp: NonNull<VNode>) -> super::vfs::VAttr {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn setattr(&mut self, _va: super::vfs::VAttr, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn access(&mut self, _m: u32, _c: UserCred, _vp: NonNull<VNode>) -> Result<(), ()> {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn lookup(&mut self, nm: &str, _c: UserCred, vp: NonNull<VNode>) -> Result<VNode, ()> {
+        if !self.inode.is_directory() {
+            return Err(());
+        }
+
+        let ext2 = Self::get_fs(vp);
+
+        let inode_number = ext2.find_entry(self.inode_number, &self.inode, nm)?;
+        let inode = ext2.read_inode(inode_number)?;
+
+        let node = Ext2Node {
+            inode_number,
+            inode,
+        };
+
+        return Ok(node.into_vnode(unsafe { (*vp.as_ptr()).parent_vfs }));
+    }
+
+    fn create(
+        &mut self,
+        nm: &str,
+        _va: super::vfs::VAttr,
+        _e: u32,
+        _m: u32,
+        _c: UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        return self.add_node(nm, S_IFREG | 0o644, vp);
+    }
+
+    fn link(
+        &mut self,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn rename(
+        &mut self,
+        _nm: &str,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn mkdir(
+        &mut self,
+        nm: &str,
+        _va: super::vfs::VAttr,
+        _c: UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        return self.add_node(nm, S_IFDIR | 0o755, vp);
+    }
+
+    fn readdir(&mut self, _uiop: *const UIO, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn symlink(
+        &mut self,
+        _link_name: &str,
+        _va: super::vfs::VAttr,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn readlink(&mut self, uiop: *const UIO, _c: UserCred, vp: NonNull<VNode>) {
+        if self.inode.mode & S_IFMT != S_IFLNK {
+            return;
+        }
+
+        let size = self.inode.size as usize;
+
+        // Fast symlinks have no data blocks, the target is stored in i_block itself. An
+        // extended attribute block is counted in i_blocks, so it has to be taken off first.
+        let acl_sectors = match self.inode.file_acl {
+            0 => 0,
+            _ => (Self::get_fs(vp).block_size as u64 / SECTOR_SIZE) as u32,
+        };
+
+        if size < FAST_SYMLINK_MAX && self.inode.blocks == acl_sectors {
+            let mut target = Vec::with_capacity(FAST_SYMLINK_MAX);
+
+            for pointer in self.inode.block.iter() {
+                target.extend_from_slice(&pointer.to_le_bytes());
+            }
+
+            unsafe { UIO::uiomove(uiop, &target[..size]) };
+            return;
+        }
+
+        let ext2 = Self::get_fs(vp);
+
+        if let Ok(target) = ext2.read_data(self.inode_number, &self.inode, 0, size) {
+            unsafe { UIO::uiomove(uiop, &target) };
+        }
+    }
+
+    fn fsync(&mut self, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn getxattr(
+        &mut self,
+        _name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        // TODO: extended attribute blocks
+        return Err(());
+    }
+
+    fn listxattr(&mut self, _c: UserCred, _vp: NonNull<VNode>) -> Result<Vec<String>, ()> {
+        return Ok(Vec::new());
+    }
+
+    fn setxattr(
+        &mut self,
+        _name: &str,
+        _value: &[u8],
+        _f: u32,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<(), ()> {
+        return Err(());
+    }
+
+    fn len(&self, _vp: NonNull<VNode>) -> usize {
+        self.inode.size as usize
+    }
+}
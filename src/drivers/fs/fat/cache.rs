@@ -0,0 +1,175 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::drivers::storage::Partition;
+
+const SECTOR_SIZE: usize = 512;
+
+struct CachedSector {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+// Caches the first FAT a sector at a time. Sectors are loaded the first time they're touched and
+// the least recently used one is thrown out once the cache is full. Changes only go to disk on
+// flush, where they're written to every copy of the FAT.
+pub struct FatCache {
+    partition: Partition,
+    fat_start: u64,
+    sectors_per_fat: usize,
+    fat_count: usize,
+    // Sector index relative to the start of the FAT
+    sectors: BTreeMap<usize, CachedSector>,
+    max_sectors: usize,
+    // Bumped on every access, sectors remember when they were last used
+    clock: u64,
+}
+
+impl FatCache {
+    pub fn new(
+        partition: Partition,
+        fat_start: u64,
+        sectors_per_fat: usize,
+        fat_count: usize,
+        max_sectors: usize,
+    ) -> Self {
+        return Self {
+            partition,
+            fat_start,
+            sectors_per_fat,
+            fat_count,
+            sectors: BTreeMap::new(),
+            max_sectors: max_sectors.max(1),
+            clock: 0,
+        };
+    }
+
+    fn sector(&mut self, sector: usize) -> Result<&mut CachedSector, ()> {
+        if sector >= self.sectors_per_fat {
+            return Err(());
+        }
+
+        self.clock += 1;
+
+        if !self.sectors.contains_key(&sector) {
+            if self.sectors.len() >= self.max_sectors {
+                self.evict()?;
+            }
+
+            let data = self
+                .partition
+                .read(self.fat_start + sector as u64, 1)?
+                .to_vec();
+
+            self.sectors.insert(
+                sector,
+                CachedSector {
+                    data,
+                    dirty: false,
+                    last_used: 0,
+                },
+            );
+        }
+
+        let cached = self.sectors.get_mut(&sector).unwrap();
+        cached.last_used = self.clock;
+
+        return Ok(cached);
+    }
+
+    // Drops the least recently used sector, writing it back first if it has changes
+    fn evict(&mut self) -> Result<(), ()> {
+        let oldest = self
+            .sectors
+            .iter()
+            .min_by_key(|(_, cached)| cached.last_used)
+            .map(|(&sector, _)| sector);
+
+        if let Some(sector) = oldest {
+            if self.sectors[&sector].dirty {
+                self.write_back(sector)?;
+            }
+
+            self.sectors.remove(&sector);
+        }
+
+        return Ok(());
+    }
+
+    fn write_back(&mut self, sector: usize) -> Result<(), ()> {
+        let cached = self.sectors.get_mut(&sector).ok_or(())?;
+
+        for copy in 0..self.fat_count {
+            let copy_start = self.fat_start + (copy * self.sectors_per_fat) as u64;
+
+            self.partition
+                .write(copy_start + sector as u64, &cached.data)?;
+        }
+
+        cached.dirty = false;
+
+        return Ok(());
+    }
+
+    // offset is in bytes from the start of the FAT, the range may straddle two sectors
+    pub fn read(&mut self, offset: usize, len: usize) -> Result<Vec<u8>, ()> {
+        let mut bytes = Vec::with_capacity(len);
+
+        while bytes.len() < len {
+            let position = offset + bytes.len();
+            let offset_in_sector = position % SECTOR_SIZE;
+            let to_copy = (SECTOR_SIZE - offset_in_sector).min(len - bytes.len());
+
+            let cached = self.sector(position / SECTOR_SIZE)?;
+            bytes.extend_from_slice(&cached.data[offset_in_sector..offset_in_sector + to_copy]);
+        }
+
+        return Ok(bytes);
+    }
+
+    pub fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), ()> {
+        let mut written = 0;
+
+        while written < bytes.len() {
+            let position = offset + written;
+            let offset_in_sector = position % SECTOR_SIZE;
+            let to_copy = (SECTOR_SIZE - offset_in_sector).min(bytes.len() - written);
+
+            let cached = self.sector(position / SECTOR_SIZE)?;
+            cached.data[offset_in_sector..offset_in_sector + to_copy]
+                .copy_from_slice(&bytes[written..written + to_copy]);
+            cached.dirty = true;
+
+            written += to_copy;
+        }
+
+        return Ok(());
+    }
+
+    // Forces a sector to be written to every copy on the next flush, even if it didn't change
+    pub fn mark_dirty(&mut self, sector: usize) -> Result<(), ()> {
+        self.sector(sector)?.dirty = true;
+
+        return Ok(());
+    }
+
+    pub fn flush(&mut self) -> Result<(), ()> {
+        let dirty_sectors = self
+            .sectors
+            .iter()
+            .filter(|(_, cached)| cached.dirty)
+            .map(|(&sector, _)| sector)
+            .collect::<Vec<usize>>();
+
+        for sector in dirty_sectors {
+            self.write_back(sector)?;
+        }
+
+        return Ok(());
+    }
+
+    // Forgets everything, callers flush first if they want to keep their changes
+    pub fn clear(&mut self) {
+        self.sectors.clear();
+    }
+}
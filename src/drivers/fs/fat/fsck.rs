@@ -0,0 +1,412 @@
+use alloc::{format, string::String, vec::Vec};
+
+use crate::LogLevel;
+
+use super::{FatFs, FatType, FileEntry, FileEntryAttributes};
+
+#[derive(Debug)]
+pub enum FsckProblem {
+    // Clusters that are marked as used, but no file or directory owns them
+    LostClusters {
+        first: u32,
+        count: usize,
+    },
+    // Two chains run into the same cluster, path is whichever reached it second
+    CrossLinked {
+        cluster: u32,
+        path: String,
+    },
+    SizeMismatch {
+        path: String,
+        file_size: u32,
+        chain_length: usize,
+    },
+    // A FAT sector that differs between the first FAT and one of its copies
+    FatCopyMismatch {
+        copy: usize,
+        sector: usize,
+    },
+    BadLfnChecksum {
+        path: String,
+    },
+    StaleFreeCount {
+        recorded: u32,
+        actual: u32,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub problems: Vec<FsckProblem>,
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+struct FsckState {
+    // The first FAT, decoded, repairs are made to this and written out at the end
+    fat: Vec<u32>,
+    // Whether a file or directory has claimed the cluster
+    owned: Vec<bool>,
+    fat_dirty: bool,
+    repair: bool,
+    problems: Vec<FsckProblem>,
+}
+
+// Every LFN entry stores this checksum of the 8.3 name it belongs to
+fn lfn_checksum(short_name: &[u8]) -> u8 {
+    let mut sum: u8 = 0;
+
+    for &byte in short_name {
+        sum = ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte);
+    }
+
+    return sum;
+}
+
+impl FatFs {
+    // Checks the volume for consistency, if repair is set every problem found is also fixed on
+    // disk. The FAT is always checked through the first copy, which is the one that gets used.
+    pub fn fsck(&mut self, repair: bool) -> Result<FsckReport, ()> {
+        // Anything still sitting in the cache has to be on disk for the copies to be compared
+        self.flush_fat()?;
+
+        let fat_bytes = self.read_fat_copy(0)?;
+
+        // There are two reserved entries before the first data cluster
+        let entry_count = self.total_clusters + 2;
+        let mut fat = Vec::with_capacity(entry_count);
+
+        for cluster in 0..entry_count {
+            fat.push(
+                self.decode_fat_entry(&fat_bytes, cluster, self.fat_entry_offset(cluster))
+                    .ok_or(())?,
+            );
+        }
+
+        let mut state = FsckState {
+            fat,
+            owned: alloc::vec![false; entry_count],
+            fat_dirty: false,
+            repair,
+            problems: Vec::new(),
+        };
+
+        let original_fat = state.fat.clone();
+        let mut mismatched_sectors: Vec<usize> = Vec::new();
+
+        for copy in 1..self.bpb.fat_count as usize {
+            let copy_bytes = self.read_fat_copy(copy)?;
+
+            for sector in 0..self.sectors_per_fat {
+                let range = sector * 512..(sector + 1) * 512;
+
+                if copy_bytes[range.clone()] != fat_bytes[range] {
+                    state
+                        .problems
+                        .push(FsckProblem::FatCopyMismatch { copy, sector });
+                    mismatched_sectors.push(sector);
+                }
+            }
+        }
+
+        // The FAT12/16 root directory isn't in the data region, so it has no chain to claim
+        let root_clusters = match self.fat_type {
+            FatType::Fat32(_) => {
+                Some(self.claim_chain(self.root_cluster() as u32, "/", &mut state))
+            }
+            _ => None,
+        };
+
+        self.check_directory(root_clusters.as_deref(), "", &mut state)?;
+
+        let mut lost: Option<(u32, usize)> = None;
+
+        for cluster in 2..entry_count {
+            let entry = state.fat[cluster];
+
+            if entry == 0 || self.is_bad_cluster(entry) || state.owned[cluster] {
+                continue;
+            }
+
+            match lost {
+                Some((_, ref mut count)) => *count += 1,
+                None => lost = Some((cluster as u32, 1)),
+            }
+
+            if repair {
+                state.fat[cluster] = 0;
+                state.fat_dirty = true;
+            }
+        }
+
+        if let Some((first, count)) = lost {
+            state
+                .problems
+                .push(FsckProblem::LostClusters { first, count });
+        }
+
+        // Repairs go through the FAT cache, which writes every sector it touches to all copies
+        if repair && (state.fat_dirty || !mismatched_sectors.is_empty()) {
+            for (cluster, &entry) in state.fat.iter().enumerate() {
+                if entry != original_fat[cluster] {
+                    self.set_next_cluster(cluster, entry)?;
+                }
+            }
+
+            for &sector in mismatched_sectors.iter() {
+                self.fat_cache.lock().mark_dirty(sector)?;
+            }
+
+            self.flush_fat()?;
+        }
+
+        if let FatType::Fat32(ebpb) = self.fat_type {
+            let free_clusters = state.fat[2..].iter().filter(|&&entry| entry == 0).count() as u32;
+
+            if let Some(ref mut fs_info) = self.fs_info {
+                let recorded = fs_info.last_known_free_cluster;
+
+                // 0xFFFFFFFF means the count was never worked out
+                if recorded != u32::MAX && recorded != free_clusters {
+                    state.problems.push(FsckProblem::StaleFreeCount {
+                        recorded,
+                        actual: free_clusters,
+                    });
+
+                    if repair {
+                        let mut sector =
+                            self.partition.read(ebpb.fsinfo_sector as u64, 1)?.to_vec();
+
+                        sector[488..492].copy_from_slice(&free_clusters.to_le_bytes());
+
+                        self.partition.write(ebpb.fsinfo_sector as u64, &sector)?;
+
+                        fs_info.last_known_free_cluster = free_clusters;
+                    }
+                }
+            }
+        }
+
+        for problem in state.problems.iter() {
+            crate::log!(LogLevel::Warn, "FAT: {problem:?}");
+        }
+
+        return Ok(FsckReport {
+            repaired: repair && !state.problems.is_empty(),
+            problems: state.problems,
+        });
+    }
+
+    fn read_fat_copy(&self, copy: usize) -> Result<Vec<u8>, ()> {
+        let start = self.fat_start + (copy * self.sectors_per_fat) as u64;
+        let mut fat_bytes = Vec::with_capacity(512 * self.sectors_per_fat);
+
+        for i in 0..self.sectors_per_fat {
+            fat_bytes.extend_from_slice(&self.partition.read(start + i as u64, 1)?);
+        }
+
+        return Ok(fat_bytes);
+    }
+
+    // Follows a chain through the in memory FAT, marking every cluster as owned. A chain that
+    // runs into a cluster someone else owns is cut off right before it when repairing.
+    fn claim_chain(&self, start: u32, path: &str, state: &mut FsckState) -> Vec<u32> {
+        let mut clusters = Vec::new();
+        let mut cluster = start;
+
+        while !self.is_end_of_chain(cluster) && (cluster as usize) < state.owned.len() {
+            if state.owned[cluster as usize] {
+                state.problems.push(FsckProblem::CrossLinked {
+                    cluster,
+                    path: String::from(path),
+                });
+
+                if state.repair {
+                    if let Some(&last) = clusters.last() {
+                        state.fat[last as usize] = self.end_of_chain();
+                        state.fat_dirty = true;
+                    }
+                }
+
+                break;
+            }
+
+            state.owned[cluster as usize] = true;
+            clusters.push(cluster);
+
+            cluster = state.fat[cluster as usize];
+        }
+
+        return clusters;
+    }
+
+    // `clusters` is the directory's chain, or None for the FAT12/16 root directory
+    fn check_directory(
+        &self,
+        clusters: Option<&[u32]>,
+        path: &str,
+        state: &mut FsckState,
+    ) -> Result<(), ()> {
+        let directory = match clusters {
+            Some(clusters) => {
+                let mut directory = Vec::with_capacity(clusters.len() * self.cluster_size);
+
+                for &cluster in clusters {
+                    directory.extend_from_slice(&self.read_cluster(cluster as usize)?);
+                }
+
+                directory
+            }
+            None => self
+                .partition
+                .read(self.root_dir_start() as u64, self.root_dir_sectors())?
+                .to_vec(),
+        };
+
+        // (index, checksum) of the LFN entries in front of the next short entry
+        let mut long_filename: Vec<(usize, u8)> = Vec::new();
+
+        for (index, bytes) in directory.chunks_exact(32).enumerate() {
+            if bytes[0] == 0x00 {
+                break;
+            }
+
+            if bytes[0] == 0xE5 {
+                long_filename.clear();
+                continue;
+            }
+
+            if bytes[11] == FileEntryAttributes::LongFileName as u8 {
+                long_filename.push((index, bytes[13]));
+                continue;
+            }
+
+            let entry_bytes: [u8; core::mem::size_of::<FileEntry>()] = bytes.try_into().unwrap();
+            let file_entry: FileEntry = unsafe { core::mem::transmute(entry_bytes) };
+
+            let name = String::from_utf8_lossy(&file_entry.file_name);
+            let extension = String::from_utf8_lossy(&file_entry.extension);
+            let entry_path = match extension.trim_end().is_empty() {
+                true => format!("{path}/{}", name.trim_end()),
+                false => format!("{path}/{}.{}", name.trim_end(), extension.trim_end()),
+            };
+
+            let checksum = lfn_checksum(&bytes[0..11]);
+
+            if long_filename
+                .iter()
+                .any(|&(_, lfn_checksum)| lfn_checksum != checksum)
+            {
+                state.problems.push(FsckProblem::BadLfnChecksum {
+                    path: entry_path.clone(),
+                });
+
+                // The short name is still good, so drop the long name that no longer matches it
+                if state.repair {
+                    for &(lfn_index, _) in long_filename.iter() {
+                        self.write_directory_bytes(clusters, lfn_index, 0, &[0xE5])?;
+                    }
+                }
+            }
+
+            long_filename.clear();
+
+            // "." and ".." point back up the tree, and volume labels own no clusters
+            if bytes[0] == b'.' || file_entry.attributes & FileEntryAttributes::VolumeId as u8 != 0
+            {
+                continue;
+            }
+
+            if file_entry.attributes & FileEntryAttributes::Directory as u8 != 0 {
+                if file_entry.cluster() == 0 {
+                    continue;
+                }
+
+                let chain = self.claim_chain(file_entry.cluster(), &entry_path, state);
+
+                self.check_directory(Some(&chain), &entry_path, state)?;
+
+                continue;
+            }
+
+            if file_entry.cluster() == 0 && file_entry.file_size == 0 {
+                continue;
+            }
+
+            let chain = self.claim_chain(file_entry.cluster(), &entry_path, state);
+            let expected_length = (file_entry.file_size as usize).div_ceil(self.cluster_size);
+
+            if chain.len() == expected_length {
+                continue;
+            }
+
+            state.problems.push(FsckProblem::SizeMismatch {
+                path: entry_path,
+                file_size: file_entry.file_size,
+                chain_length: chain.len(),
+            });
+
+            if !state.repair {
+                continue;
+            }
+
+            if chain.len() > expected_length {
+                // Give the extra clusters back, they turn into free space instead of lost clusters
+                for &cluster in chain[expected_length..].iter() {
+                    state.fat[cluster as usize] = 0;
+                    state.owned[cluster as usize] = false;
+                }
+
+                match expected_length {
+                    0 => self
+                        .write_directory_bytes(clusters, index, 20, &[0; 2])
+                        .and_then(|_| self.write_directory_bytes(clusters, index, 26, &[0; 2]))?,
+                    _ => state.fat[chain[expected_length - 1] as usize] = self.end_of_chain(),
+                }
+
+                state.fat_dirty = true;
+            } else {
+                // The data past the end of the chain is gone, so the file shrinks to fit
+                let file_size = (chain.len() * self.cluster_size) as u32;
+
+                self.write_directory_bytes(clusters, index, 28, &file_size.to_le_bytes())?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Patches bytes into entry `index` of a directory, starting offset bytes into the entry
+    fn write_directory_bytes(
+        &self,
+        clusters: Option<&[u32]>,
+        index: usize,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), ()> {
+        let entry_offset = index * 32;
+
+        let sector = match clusters {
+            Some(clusters) => {
+                let cluster = *clusters.get(entry_offset / self.cluster_size).ok_or(())?;
+
+                self.cluster_to_sector(cluster as usize) + (entry_offset % self.cluster_size) / 512
+            }
+            None => self.root_dir_start() + entry_offset / 512,
+        };
+
+        let mut data = self.partition.read(sector as u64, 1)?.to_vec();
+
+        let start = entry_offset % 512 + offset;
+        data.get_mut(start..start + bytes.len())
+            .ok_or(())?
+            .copy_from_slice(bytes);
+
+        return self.partition.write(sector as u64, &data);
+    }
+}
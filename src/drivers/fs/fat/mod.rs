@@ -1,5 +1,8 @@
 use core::ptr::NonNull;
 
+pub(super) mod cache;
+mod fsck;
+
 use alloc::{
     boxed::Box,
     string::{String, ToString},
@@ -7,9 +10,12 @@ use alloc::{
     vec::Vec,
 };
 
-use crate::{drivers::storage::Partition, LogLevel};
+use crate::{drivers::storage::Partition, libs::sync::Mutex, LogLevel};
+
+use cache::FatCache;
+pub use fsck::{FsckProblem, FsckReport};
 
-use super::vfs::{FsOps, VNode, VNodeOperations};
+use super::vfs::{FileId, FsOps, VNode, VNodeOperations};
 
 // The first Cluster (perhaps 0xF0FFFF0F) is the FAT ID
 // The second cluster stores the end-of-cluster-chain marker
@@ -29,6 +35,11 @@ const EOC_12: u32 = 0x0FF8;
 const EOC_16: u32 = 0xFFF8;
 const EOC_32: u32 = 0x0FFFFFF8;
 
+// Bad Cluster
+const BAD_12: u32 = 0x0FF7;
+const BAD_16: u32 = 0xFFF7;
+const BAD_32: u32 = 0x0FFFFFF7;
+
 #[derive(Clone, Copy, Debug)]
 enum FatType {
     Fat12(Fat16EBPB),
@@ -174,14 +185,14 @@ impl FileEntry {
 pub struct FatFs {
     partition: Partition,
     // FAT info
-    #[allow(dead_code)]
     fs_info: Option<FSInfo>,
-    fat: Option<Arc<[u32]>>,
+    fat_cache: Mutex<FatCache>,
     bpb: BIOSParameterBlock,
     fat_start: u64,
     fat_type: FatType,
     cluster_size: usize,
     sectors_per_fat: usize,
+    total_clusters: usize,
 }
 
 impl FatFs {
@@ -271,29 +282,145 @@ impl FatFs {
 
         let cluster_size = bpb.sectors_per_cluster as usize * 512;
 
+        let fat_cache = FatCache::new(
+            partition.clone(),
+            fat_start,
+            sectors_per_fat,
+            bpb.fat_count as usize,
+            // fat_cache_size is in KiB
+            crate::KERNEL_FEATURES.fat_cache_size * 1024 / 512,
+        );
+
         return Ok(Self {
             partition,
             fs_info,
-            fat: None,
+            fat_cache: Mutex::new(fat_cache),
             bpb,
             fat_start,
             fat_type,
             cluster_size,
             sectors_per_fat,
+            total_clusters: total_clusters as usize,
         });
     }
 
-    fn find_entry_in_directory(&self, cluster: usize, name: &str) -> Result<FileEntry, ()> {
+    fn root_cluster(&self) -> usize {
+        match self.fat_type {
+            FatType::Fat32(ebpb) => ebpb.root_dir_cluster as usize,
+            _ => self.sector_to_cluster(self.root_dir_start()),
+        }
+    }
+
+    // The first sector of the FAT12/16 root directory area
+    fn root_dir_start(&self) -> usize {
+        self.bpb.reserved_sectors as usize + (self.bpb.fat_count as usize * self.sectors_per_fat)
+    }
+
+    fn root_dir_sectors(&self) -> usize {
+        (((self.bpb.root_directory_count * 32) + (self.bpb.bytes_per_sector - 1))
+            / self.bpb.bytes_per_sector) as usize
+    }
+
+    fn is_end_of_chain(&self, cluster: u32) -> bool {
+        // Free and reserved clusters can't be part of a chain either, treat them as the end so
+        // a damaged FAT doesn't send us off reading random sectors
+        if cluster < 2 {
+            return true;
+        }
+
+        match self.fat_type {
+            FatType::Fat12(_) => cluster >= EOC_12,
+            FatType::Fat16(_) => cluster >= EOC_16,
+            FatType::Fat32(_) => cluster >= EOC_32,
+        }
+    }
+
+    fn is_bad_cluster(&self, cluster: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat12(_) => cluster == BAD_12,
+            FatType::Fat16(_) => cluster == BAD_16,
+            FatType::Fat32(_) => cluster == BAD_32,
+        }
+    }
+
+    // The value written into the FAT to end a chain
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12(_) => 0x0FFF,
+            FatType::Fat16(_) => 0xFFFF,
+            FatType::Fat32(_) => 0x0FFFFFFF,
+        }
+    }
+
+    // Reads every entry of a directory into one buffer. On FAT12 and FAT16 the root directory
+    // is a fixed area in between the FATs and the data region, every other directory is a
+    // cluster chain.
+    fn read_directory(&self, cluster: usize) -> Result<Vec<u8>, ()> {
+        if !matches!(self.fat_type, FatType::Fat32(_)) && cluster == self.root_cluster() {
+            return Ok(self
+                .partition
+                .read(self.root_dir_start() as u64, self.root_dir_sectors())?
+                .to_vec());
+        }
+
+        let mut directory = Vec::new();
+        let mut cluster = cluster as u32;
+        let mut clusters_read = 0;
+
+        while !self.is_end_of_chain(cluster) {
+            // A chain can't be longer than the volume, if it is then it loops back on itself
+            if clusters_read > self.total_clusters {
+                return Err(());
+            }
+
+            directory.extend_from_slice(&self.read_cluster(cluster as usize)?);
+
+            cluster = self.get_next_cluster(cluster as usize);
+            clusters_read += 1;
+        }
+
+        return Ok(directory);
+    }
+
+    // Reads the short entry at index in the directory, deleted entries and LFN entries are not valid targets
+    fn read_entry_in_directory(&self, cluster: usize, index: usize) -> Result<FileEntry, ()> {
+        let data_sector = self.read_directory(cluster)?;
+
+        let bytes: [u8; core::mem::size_of::<FileEntry>()] = data_sector
+            .get((index * 32)..((index + 1) * 32))
+            .ok_or(())?
+            .try_into()
+            .unwrap();
+
+        if bytes[0] == 0x00
+            || bytes[0] == 0xE5
+            || bytes[11] == FileEntryAttributes::LongFileName as u8
+        {
+            return Err(());
+        }
+
+        return Ok(unsafe { core::mem::transmute(bytes) });
+    }
+
+    // Returns the entry along with its index in the directory, the index is what file ids are built from
+    fn find_entry_in_directory(
+        &self,
+        cluster: usize,
+        name: &str,
+    ) -> Result<(FileEntry, usize), ()> {
         let mut i: usize = 0;
         // Long file name is stored outsize because long filename and the real entry on separate entries
         let mut long_filename: Vec<LongFileName> = Vec::new();
         let mut long_filename_string: Option<String> = None;
 
-        let data_sector = self.read_cluster(cluster)?;
+        let data_sector = self.read_directory(cluster)?;
 
         loop {
             let bytes: [u8; core::mem::size_of::<FileEntry>()] =
-                data_sector[(i * 32)..((i + 1) * 32)].try_into().unwrap();
+                match data_sector.get((i * 32)..((i + 1) * 32)) {
+                    Some(bytes) => bytes.try_into().unwrap(),
+                    None => break,
+                };
             let first_byte = bytes[0];
 
             let file_entry: FileEntry;
@@ -377,7 +504,7 @@ impl FatFs {
                 continue;
             }
 
-            return Ok(file_entry);
+            return Ok((file_entry, i - 1));
         }
 
         return Err(());
@@ -392,9 +519,7 @@ impl FatFs {
 
     fn cluster_to_sector(&self, cluster: usize) -> usize {
         let fat_size = self.sectors_per_fat;
-        let root_dir_sectors = ((self.bpb.root_directory_count * 32)
-            + (self.bpb.bytes_per_sector - 1))
-            / self.bpb.bytes_per_sector;
+        let root_dir_sectors = self.root_dir_sectors();
 
         let first_data_sector = self.bpb.reserved_sectors as usize
             + (self.bpb.fat_count as usize * fat_size)
@@ -407,9 +532,7 @@ impl FatFs {
 
     fn sector_to_cluster(&self, sector: usize) -> usize {
         let fat_size = self.sectors_per_fat;
-        let root_dir_sectors = ((self.bpb.root_directory_count * 32)
-            + (self.bpb.bytes_per_sector - 1))
-            / self.bpb.bytes_per_sector;
+        let root_dir_sectors = self.root_dir_sectors();
 
         let first_data_sector = self.bpb.reserved_sectors as usize
             + (self.bpb.fat_count as usize * fat_size)
@@ -420,123 +543,182 @@ impl FatFs {
         .wrapping_add(2);
     }
 
-    fn get_next_cluster(&self, cluster: usize) -> u32 {
-        if crate::KERNEL_FEATURES.fat_in_mem {
-            return match self.fat_type {
-                FatType::Fat12(_) => self.fat.as_ref().unwrap()[cluster] & 0x0FFF,
-                FatType::Fat16(_) => self.fat.as_ref().unwrap()[cluster] & 0xFFFF,
-                FatType::Fat32(_) => self.fat.as_ref().unwrap()[cluster] & 0x0FFFFFFF,
-            };
-        } else {
-            let fat_entry_size = match self.fat_type {
-                FatType::Fat12(_) => 2, // 12 bits per entry
-                FatType::Fat16(_) => 2, // 16 bits per entry
-                FatType::Fat32(_) => 4, // 28 bits per entry
-            };
-            let entry_offset = cluster * fat_entry_size;
-            let entry_offset_in_sector = entry_offset % 512;
+    // FAT12 entries are 12 bits packed into 1.5 bytes, so where an entry is depends on the type
+    fn fat_entry_offset(&self, cluster: usize) -> usize {
+        match self.fat_type {
+            FatType::Fat12(_) => cluster + (cluster / 2),
+            FatType::Fat16(_) => cluster * 2,
+            FatType::Fat32(_) => cluster * 4,
+        }
+    }
 
-            // needs two incase we "straddle a sector"
-            let sector_data = self
-                .partition
-                .read(self.fat_start + entry_offset as u64 / 512, 2)
-                .expect("Failed to read from FAT!");
+    // Decodes the entry for cluster out of fat_bytes, which start at entry_offset's sector
+    fn decode_fat_entry(&self, fat_bytes: &[u8], cluster: usize, offset: usize) -> Option<u32> {
+        match self.fat_type {
+            FatType::Fat12(_) => {
+                let entry =
+                    u16::from_le_bytes(fat_bytes.get(offset..offset + 2)?.try_into().unwrap());
 
-            match self.fat_type {
-                FatType::Fat12(_) => {
-                    let cluster_entry_bytes: [u8; 2] = sector_data
-                        [entry_offset_in_sector..entry_offset_in_sector + 2]
-                        .try_into()
-                        .unwrap();
-                    return (u16::from_le_bytes(cluster_entry_bytes) & 0x0FFF) as u32;
-                }
-                FatType::Fat16(_) => {
-                    let cluster_entry_bytes: [u8; 2] = sector_data
-                        [entry_offset_in_sector..entry_offset_in_sector + 2]
-                        .try_into()
-                        .unwrap();
-                    return (u16::from_le_bytes(cluster_entry_bytes)) as u32;
-                }
-                FatType::Fat32(_) => {
-                    let cluster_entry_bytes: [u8; 4] = sector_data
-                        [entry_offset_in_sector..entry_offset_in_sector + 4]
-                        .try_into()
-                        .unwrap();
-                    return u32::from_le_bytes(cluster_entry_bytes) & 0x0FFFFFFF;
+                // Odd clusters are in the top 12 bits, even ones in the bottom 12 bits
+                if cluster & 1 == 1 {
+                    Some((entry >> 4) as u32)
+                } else {
+                    Some((entry & 0x0FFF) as u32)
                 }
             }
+            FatType::Fat16(_) => Some(u16::from_le_bytes(
+                fat_bytes.get(offset..offset + 2)?.try_into().unwrap(),
+            ) as u32),
+            FatType::Fat32(_) => Some(
+                u32::from_le_bytes(fat_bytes.get(offset..offset + 4)?.try_into().unwrap())
+                    & 0x0FFFFFFF,
+            ),
         }
     }
-}
 
-impl FsOps for FatFs {
-    fn mount(&mut self, _path: &str, data: &mut *mut u8, _vfsp: NonNull<super::vfs::Vfs>) {
-        let bytes_per_fat = 512 * self.sectors_per_fat;
+    // The opposite of decode_fat_entry, FAT12 entries share a byte with their neighbour and the top
+    // four bits of FAT32 entries are reserved, so both keep the bits that aren't theirs
+    fn encode_fat_entry(
+        &self,
+        fat_bytes: &mut [u8],
+        cluster: usize,
+        offset: usize,
+        value: u32,
+    ) -> Option<()> {
+        match self.fat_type {
+            FatType::Fat12(_) => {
+                let bytes = fat_bytes.get_mut(offset..offset + 2)?;
+                let entry = u16::from_le_bytes((&*bytes).try_into().unwrap());
+
+                let entry = if cluster & 1 == 1 {
+                    (entry & 0x000F) | ((value as u16 & 0x0FFF) << 4)
+                } else {
+                    (entry & 0xF000) | (value as u16 & 0x0FFF)
+                };
 
-        let mut fat: Option<Arc<[u32]>> = None;
+                bytes.copy_from_slice(&entry.to_le_bytes());
+            }
+            FatType::Fat16(_) => {
+                fat_bytes
+                    .get_mut(offset..offset + 2)?
+                    .copy_from_slice(&(value as u16).to_le_bytes());
+            }
+            FatType::Fat32(_) => {
+                let bytes = fat_bytes.get_mut(offset..offset + 4)?;
+                let entry = u32::from_le_bytes((&*bytes).try_into().unwrap());
 
-        if crate::KERNEL_FEATURES.fat_in_mem {
-            let cluster_bytes = match self.fat_type {
-                FatType::Fat32(_) => 4,
-                _ => 2,
-            };
+                let entry = (entry & 0xF0000000) | (value & 0x0FFFFFFF);
 
-            let mut fat_vec: Vec<u32> = Vec::with_capacity(bytes_per_fat / cluster_bytes);
-
-            for i in 0..self.sectors_per_fat {
-                let sector = self
-                    .partition
-                    .read(self.fat_start + i as u64, 1)
-                    .expect("Failed to read FAT");
-                for j in 0..(512 / cluster_bytes) {
-                    match self.fat_type {
-                        FatType::Fat32(_) => fat_vec.push(u32::from_le_bytes(
-                            sector[j * cluster_bytes..(j * cluster_bytes + cluster_bytes)]
-                                .try_into()
-                                .unwrap(),
-                        )),
-                        _ => fat_vec.push(u16::from_le_bytes(
-                            sector[j * cluster_bytes..(j * cluster_bytes + cluster_bytes)]
-                                .try_into()
-                                .unwrap(),
-                        ) as u32),
-                    }
-                }
+                bytes.copy_from_slice(&entry.to_le_bytes());
             }
+        }
 
-            fat = Some(Arc::from(fat_vec));
-        } else {
-            crate::log!(
-                LogLevel::Warn,
-                "FAT is not being stored in memory, this feature is experimental and file reads are expected to be slower."
-            )
+        return Some(());
+    }
+
+    fn fat_entry_size(&self) -> usize {
+        match self.fat_type {
+            // a FAT12 entry is spread over two bytes
+            FatType::Fat12(_) | FatType::Fat16(_) => 2,
+            FatType::Fat32(_) => 4,
         }
+    }
+
+    fn get_next_cluster(&self, cluster: usize) -> u32 {
+        let entry_offset = self.fat_entry_offset(cluster);
+
+        let entry_bytes = self
+            .fat_cache
+            .lock()
+            .read(entry_offset, self.fat_entry_size())
+            .expect("Failed to read from FAT!");
+
+        // Out of range clusters read as free, which ends the chain
+        return self.decode_fat_entry(&entry_bytes, cluster, 0).unwrap_or(0);
+    }
+
+    fn set_next_cluster(&self, cluster: usize, value: u32) -> Result<(), ()> {
+        let entry_offset = self.fat_entry_offset(cluster);
+        let mut fat_cache = self.fat_cache.lock();
+
+        let mut entry_bytes = fat_cache.read(entry_offset, self.fat_entry_size())?;
 
-        self.fat = fat;
+        self.encode_fat_entry(&mut entry_bytes, cluster, 0, value)
+            .ok_or(())?;
 
+        return fat_cache.write(entry_offset, &entry_bytes);
+    }
+
+    // Writes every change made to the FAT out to all of its copies
+    pub fn flush_fat(&self) -> Result<(), ()> {
+        return self.fat_cache.lock().flush();
+    }
+}
+
+impl FsOps for FatFs {
+    fn mount(&mut self, _path: &str, data: &mut *mut u8, _vfsp: NonNull<super::vfs::Vfs>) {
         *data = core::ptr::addr_of!(*self) as *mut u8;
     }
 
     fn unmount(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {
-        self.fat = None;
+        if self.flush_fat().is_err() {
+            crate::log!(
+                LogLevel::Error,
+                "FAT: Failed to write back the FAT on unmount"
+            );
+        }
+
+        self.fat_cache.lock().clear();
     }
 
     fn root(&mut self, vfsp: NonNull<super::vfs::Vfs>) -> super::vfs::VNode {
-        let root_cluster = match self.fat_type {
-            FatType::Fat32(ebpb) => ebpb.root_dir_cluster as usize,
-            _ => self.sector_to_cluster(
-                self.bpb.reserved_sectors as usize
-                    + (self.bpb.fat_count as usize * self.sectors_per_fat),
-            ),
-        };
-
-        let file = File::Dir(root_cluster);
+        let file = File::Dir(self.root_cluster());
 
         return VNode::new(Box::new(file), super::vfs::VNodeType::Directory, vfsp);
     }
 
-    fn fid(&mut self, _path: &str, _vfsp: NonNull<super::vfs::Vfs>) -> Option<super::vfs::FileId> {
-        todo!("FAT FID");
+    // A FAT file id is the cluster of the directory holding the entry, followed by the index of
+    // the entry in that directory. The root directory has no entry, so it uses FAT_ROOT_FID_INDEX.
+    fn fid(&mut self, path: &str, _vfsp: NonNull<super::vfs::Vfs>) -> Option<FileId> {
+        let mut directory = self.root_cluster();
+        let mut fid = (directory, FAT_ROOT_FID_INDEX);
+
+        for part in path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+        {
+            // Whatever we are looking in has to be a directory
+            if fid.1 != FAT_ROOT_FID_INDEX {
+                let entry = self.read_entry_in_directory(fid.0, fid.1).ok()?;
+
+                if entry.attributes & FileEntryAttributes::Directory as u8 == 0 {
+                    return None;
+                }
+
+                directory = entry.cluster() as usize;
+
+                // ".." entries that point at the root directory store cluster 0
+                if directory == 0 {
+                    directory = self.root_cluster();
+                }
+            }
+
+            let (entry, index) = self.find_entry_in_directory(directory, part).ok()?;
+
+            fid = if entry.attributes & FileEntryAttributes::Directory as u8 != 0
+                && (entry.cluster() == 0 || entry.cluster() as usize == self.root_cluster())
+            {
+                (self.root_cluster(), FAT_ROOT_FID_INDEX)
+            } else {
+                (directory, index)
+            };
+        }
+
+        let mut bytes = [0u8; 8];
+        bytes[0..4].copy_from_slice(&(fid.0 as u32).to_le_bytes());
+        bytes[4..8].copy_from_slice(&(fid.1 as u32).to_le_bytes());
+
+        return FileId::new(&bytes).ok();
     }
 
     fn statfs(&mut self, _vfsp: NonNull<super::vfs::Vfs>) -> super::vfs::StatFs {
@@ -544,24 +726,63 @@ impl FsOps for FatFs {
     }
 
     fn sync(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {
-        todo!("FAT SYNC");
+        if self.flush_fat().is_err() {
+            crate::log!(LogLevel::Error, "FAT: Failed to write back the FAT");
+        }
     }
 
-    fn vget(
-        &mut self,
-        _fid: super::vfs::FileId,
-        _vfsp: NonNull<super::vfs::Vfs>,
-    ) -> super::vfs::VNode {
-        todo!("FAT VGET");
+    fn vget(&mut self, fid: FileId, vfsp: NonNull<super::vfs::Vfs>) -> Result<VNode, ()> {
+        let bytes = fid.as_bytes();
+
+        if bytes.len() != 8 {
+            return Err(());
+        }
+
+        let directory = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        let index = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+
+        if index == FAT_ROOT_FID_INDEX {
+            if directory != self.root_cluster() {
+                return Err(());
+            }
+
+            return Ok(self.root(vfsp));
+        }
+
+        let file_entry = self.read_entry_in_directory(directory, index)?;
+
+        return Ok(File::from_entry(file_entry).into_vnode(vfsp));
     }
 }
 
+// Index used in file ids to refer to the root directory, which has no directory entry of its own
+const FAT_ROOT_FID_INDEX: usize = u32::MAX as usize;
+
 enum File {
     Archive(FileEntry),
     // directory cluster
     Dir(usize),
 }
 
+impl File {
+    fn from_entry(file_entry: FileEntry) -> Self {
+        if file_entry.attributes == FileEntryAttributes::Directory as u8 {
+            File::Dir(file_entry.cluster() as usize)
+        } else {
+            File::Archive(file_entry)
+        }
+    }
+
+    fn into_vnode(self, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        let file_typ = match self {
+            File::Dir(_) => super::vfs::VNodeType::Directory,
+            File::Archive(_) => super::vfs::VNodeType::Regular,
+        };
+
+        return VNode::new(Box::new(self), file_typ, vfsp);
+    }
+}
+
 impl VNodeOperations for File {
     fn open(&mut self, _f: u32, _c: super::vfs::UserCred, _vp: NonNull<VNode>) {}
 
@@ -612,22 +833,8 @@ impl VNodeOperations for File {
 
                     cluster = unsafe { (*fat_fs).get_next_cluster(cluster as usize) };
 
-                    match unsafe { (*fat_fs).fat_type } {
-                        FatType::Fat12(_) => {
-                            if cluster >= EOC_12 {
-                                break;
-                            }
-                        }
-                        FatType::Fat16(_) => {
-                            if cluster >= EOC_16 {
-                                break;
-                            }
-                        }
-                        FatType::Fat32(_) => {
-                            if cluster >= EOC_32 {
-                                break;
-                            }
-                        }
+                    if unsafe { (*fat_fs).is_end_of_chain(cluster) } {
+                        break;
                     }
                 }
 
@@ -667,7 +874,7 @@ impl VNodeOperations for File {
         todo!("VNODE OPERATIONS");
     }
 
-    fn access(&mut self, _m: u32, _c: super::vfs::UserCred, _vp: NonNull<VNode>) {
+    fn access(&mut self, _m: u32, _c: super::vfs::UserCred, _vp: NonNull<VNode>) -> Result<(), ()> {
         todo!("VNODE OPERATIONS");
     }
 
@@ -681,23 +888,9 @@ impl VNodeOperations for File {
 
         match self {
             File::Dir(directory) => unsafe {
-                let file_entry = (*fat_fs).find_entry_in_directory(*directory, nm)?;
+                let (file_entry, _) = (*fat_fs).find_entry_in_directory(*directory, nm)?;
 
-                let file_typ = if file_entry.attributes == FileEntryAttributes::Directory as u8 {
-                    crate::drivers::fs::vfs::VNodeType::Directory
-                } else {
-                    crate::drivers::fs::vfs::VNodeType::Regular
-                };
-
-                let file = if file_entry.attributes == FileEntryAttributes::Directory as u8 {
-                    File::Dir(file_entry.cluster() as usize)
-                } else {
-                    File::Archive(file_entry)
-                };
-
-                let vnode = VNode::new(Box::new(file), file_typ, (*vp.as_ptr()).parent_vfs);
-
-                Ok(vnode)
+                Ok(File::from_entry(file_entry).into_vnode((*vp.as_ptr()).parent_vfs))
             },
             _ => panic!("tried to lookup on a file"),
         }
@@ -775,8 +968,42 @@ impl VNodeOperations for File {
         todo!("VNODE OPERATIONS");
     }
 
-    fn fsync(&mut self, _c: super::vfs::UserCred, _vp: NonNull<VNode>) {
-        todo!("VNODE OPERATIONS");
+    fn fsync(&mut self, _c: super::vfs::UserCred, vp: NonNull<VNode>) {
+        let fat_fs = unsafe { (*vp.as_ptr()).parent_vfs.as_mut().data.cast::<FatFs>() };
+
+        // file data isn't buffered anywhere, so the FAT is all there is to write out
+        if unsafe { (*fat_fs).flush_fat() }.is_err() {
+            crate::log!(LogLevel::Error, "FAT: Failed to write back the FAT");
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _name: &str,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        // FAT has nowhere to store extended attributes
+        return Err(());
+    }
+
+    fn listxattr(
+        &mut self,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<Vec<String>, ()> {
+        return Ok(Vec::new());
+    }
+
+    fn setxattr(
+        &mut self,
+        _name: &str,
+        _value: &[u8],
+        _f: u32,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<(), ()> {
+        return Err(());
     }
 
     fn len(&self, _vp: NonNull<VNode>) -> usize {
@@ -7,6 +7,8 @@ const HEADER_SIZE: usize = 2;
 
 struct Chunk<'a> {
     data: Cow<'a, [u8]>,
+    // how many bytes the chunk takes up in the table, which stays the same after decompression
+    disk_size: usize,
 }
 
 impl Chunk<'_> {
@@ -22,9 +24,16 @@ impl Chunk<'_> {
         self.header() & 0x8000 == 0
     }
 
-    fn decompress(&mut self, decompressor: &dyn Fn(&[u8]) -> Result<Vec<u8>, ()>) {
+    fn decompress(
+        &mut self,
+        decompressor: &dyn Fn(&[u8]) -> Result<Vec<u8>, ()>,
+    ) -> Result<(), ()> {
         if self.is_compressed() {
-            let decompressed_data = decompressor(&self.data[HEADER_SIZE..]).unwrap();
+            let decompressed_data = decompressor(&self.data[HEADER_SIZE..])?;
+
+            if decompressed_data.len() > 0x7FFF {
+                return Err(());
+            }
 
             let header = decompressed_data.len() as u16 | 0x8000;
 
@@ -32,6 +41,8 @@ impl Chunk<'_> {
 
             self.data = Cow::Owned(data);
         }
+
+        return Ok(());
     }
 }
 
@@ -68,7 +79,8 @@ impl<'a, F> ChunkReader<'a, F>
 where
     F: Fn(&[u8]) -> Result<Vec<u8>, ()>,
 {
-    pub fn new(data: &'a [u8], decompressor: F) -> Self {
+    // Borrowed data stays borrowed, owned data is split up into owned chunks
+    pub fn new(data: Cow<'a, [u8]>, decompressor: F) -> Result<Self, ()> {
         let mut chunks: Vec<Chunk<'_>> = Vec::new();
 
         let mut offset = 0;
@@ -77,69 +89,82 @@ where
                 break;
             }
 
+            let header = data.get(offset..offset + HEADER_SIZE).ok_or(())?;
             let length =
-                (u16::from_le_bytes(data[offset..offset + HEADER_SIZE].try_into().unwrap())
-                    & 0x7FFF) as usize
-                    + HEADER_SIZE;
+                (u16::from_le_bytes(header.try_into().unwrap()) & 0x7FFF) as usize + HEADER_SIZE;
+
+            let chunk_data = match data {
+                Cow::Borrowed(data) => Cow::Borrowed(data.get(offset..offset + length).ok_or(())?),
+                Cow::Owned(ref data) => {
+                    Cow::Owned(data.get(offset..offset + length).ok_or(())?.to_vec())
+                }
+            };
 
             chunks.push(Chunk {
-                data: Cow::Borrowed(&data[offset..offset + length]),
+                data: chunk_data,
+                disk_size: length,
             });
 
             offset += length;
         }
 
-        Self {
+        return Ok(Self {
             chunks,
             decompressor,
-        }
+        });
     }
 
-    pub fn get_slice(&mut self, mut chunk: u64, mut offset: u16, size: usize) -> Vec<u8> {
+    // `chunk` is the byte offset of the chunk inside of the table as stored on disk, `offset` is
+    // into the decompressed data and may run past the end of that chunk
+    pub fn get_slice(&mut self, chunk: u64, offset: usize, size: usize) -> Result<Vec<u8>, ()> {
         // handle cases where the chunks arent aligned to CHUNK_SIZE (they're compressed and are doing stupid things)
-        {
+        let mut chunk_idx = {
             let mut chunk_idx = 0;
             let mut total_length = 0;
 
             while total_length != chunk {
+                if total_length > chunk {
+                    // doesn't point at the start of a chunk
+                    return Err(());
+                }
+
+                total_length += self.chunks.get(chunk_idx).ok_or(())?.disk_size as u64;
                 chunk_idx += 1;
-                total_length += (self.chunks[0].len() + HEADER_SIZE) as u64;
             }
 
-            chunk = chunk_idx;
-        }
+            chunk_idx
+        };
 
-        let mut chunks_to_read = 1;
-        {
-            let mut available_bytes = {
-                self.chunks[chunk as usize].decompress(&self.decompressor);
-                self.chunks[chunk as usize][offset as usize..].len()
-            };
+        let mut offset = offset;
+
+        // skip over whole chunks if the offset goes past the first one
+        loop {
+            let current = self.chunks.get_mut(chunk_idx).ok_or(())?;
+            current.decompress(&self.decompressor)?;
 
-            while available_bytes < size {
-                self.chunks[chunk as usize + chunks_to_read].decompress(&self.decompressor);
-                available_bytes += self.chunks[chunk as usize + chunks_to_read].len();
-                chunks_to_read += 1;
+            if offset < current.len() {
+                break;
             }
-        }
 
-        let mut data = Vec::new();
+            offset -= current.len();
+            chunk_idx += 1;
+        }
 
-        for i in chunk as usize..chunk as usize + chunks_to_read {
-            self.chunks[i].decompress(&self.decompressor);
+        let mut data = Vec::with_capacity(size);
 
-            let block_start = offset as usize + HEADER_SIZE;
-            let mut block_end = self.chunks[i].len() + HEADER_SIZE;
+        while data.len() < size {
+            let current = self.chunks.get_mut(chunk_idx).ok_or(())?;
+            current.decompress(&self.decompressor)?;
 
-            if (block_end - block_start) > size {
-                block_end = block_start + size;
-            }
+            let block_start = offset + HEADER_SIZE;
+            let block_end = (current.len() + HEADER_SIZE).min(block_start + size - data.len());
 
-            data.extend(self.chunks[i][block_start..block_end].iter());
+            data.extend_from_slice(current.data.get(block_start..block_end).ok_or(())?);
 
             offset = 0;
+            chunk_idx += 1;
         }
 
-        data
+        return Ok(data);
     }
 }
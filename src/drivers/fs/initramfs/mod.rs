@@ -1,26 +1,73 @@
 mod chunk_reader;
+mod source;
 mod superblock;
+mod xattr;
 
 use core::{fmt::Debug, mem::MaybeUninit, ptr::NonNull};
 
-use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use alloc::{borrow::Cow, boxed::Box, string::String, sync::Arc, vec::Vec};
 
-use super::vfs::{FsOps, VNode, VNodeOperations, VNodeType};
+pub use source::SquashfsSource;
 
-pub fn init() -> Squashfs<'static> {
+use super::vfs::{FileId, FsOps, VNode, VNodeOperations, VNodeType};
+
+// Metadata blocks are never bigger than 8KiB once decompressed
+const METADATA_BLOCK_SIZE: usize = 8192;
+
+#[derive(Debug, PartialEq)]
+enum InitramfsFormat {
+    Squashfs,
+    Cpio,
+    GzipCpio,
+}
+
+impl InitramfsFormat {
+    fn detect(image: &[u8]) -> Option<Self> {
+        if image.len() >= 4 && u32::from_le_bytes(image[0..4].try_into().unwrap()) == 0x73717368 {
+            return Some(Self::Squashfs);
+        }
+
+        if super::cpio::is_cpio(image) {
+            return Some(Self::Cpio);
+        }
+
+        // we only find out if there's a cpio archive inside once it's decompressed
+        if crate::libs::gzip::is_gzip(image) {
+            return Some(Self::GzipCpio);
+        }
+
+        return None;
+    }
+}
+
+pub fn init() -> Box<dyn FsOps> {
     let initramfs = crate::libs::limine::get_module("initramfs.img");
 
     assert!(initramfs.is_some(), "initramfs was not found!");
 
     let initramfs = initramfs.unwrap();
 
-    let squashfs = Squashfs::new(initramfs.addr());
+    let image: &'static [u8] =
+        unsafe { core::slice::from_raw_parts(initramfs.addr(), initramfs.size() as usize) };
+
+    let format = InitramfsFormat::detect(image);
 
-    assert!(squashfs.is_ok(), "Initramfs is corrupt!");
+    crate::log!(crate::LogLevel::Trace, "Initramfs format: {format:?}");
+
+    let initramfs: Result<Box<dyn FsOps>, ()> = match format {
+        Some(InitramfsFormat::Squashfs) => Squashfs::new(SquashfsSource::Memory(image))
+            .map(|squashfs| Box::new(squashfs) as Box<dyn FsOps>),
+        Some(InitramfsFormat::Cpio) => super::cpio::CpioFs::new(Cow::Borrowed(image))
+            .map(|cpio| Box::new(cpio) as Box<dyn FsOps>),
+        Some(InitramfsFormat::GzipCpio) => crate::libs::gzip::uncompress_gzip(image)
+            .and_then(|archive| super::cpio::CpioFs::new(Cow::Owned(archive)))
+            .map(|cpio| Box::new(cpio) as Box<dyn FsOps>),
+        None => Err(()),
+    };
 
-    let squashfs = squashfs.unwrap();
+    assert!(initramfs.is_ok(), "Initramfs is corrupt!");
 
-    return squashfs;
+    return initramfs.unwrap();
 }
 
 #[repr(u8)]
@@ -38,38 +85,50 @@ enum Table {
 // #[derive(Debug)]
 pub struct Squashfs<'a> {
     pub superblock: superblock::SquashfsSuperblock,
-    start: *mut u8,
-    decompressor: Box<dyn Fn(&'a [u8]) -> Result<Vec<u8>, ()>>,
-    data_table: &'a [u8],
+    source: SquashfsSource<'a>,
+    decompressor: Box<dyn Fn(&[u8]) -> Result<Vec<u8>, ()>>,
     inode_table: chunk_reader::ChunkReader<'a, Box<dyn Fn(&[u8]) -> Result<Vec<u8>, ()>>>,
     directory_table: chunk_reader::ChunkReader<'a, Box<dyn Fn(&[u8]) -> Result<Vec<u8>, ()>>>,
-    fragment_table: Option<&'a [u8]>,
-    export_table: Option<&'a [u8]>,
-    id_table: &'a [u8],
-    xattr_table: Option<&'a [u8]>,
+    fragment_table: Option<Cow<'a, [u8]>>,
+    export_table: Option<Cow<'a, [u8]>>,
+    id_table: Cow<'a, [u8]>,
+    xattr_table: Option<Cow<'a, [u8]>>,
+    // Read once when the image is opened, an image without a readable root is rejected
+    root: Option<Inode>,
+    // Set when an optional table was damaged and had to be left out
+    degraded: bool,
 }
 
-impl Squashfs<'_> {
-    fn new(ptr: *mut u8) -> Result<Squashfs<'static>, ()> {
-        // crate::log_info!("Parsing initramfs at {:p}", ptr);
+impl<'a> Squashfs<'a> {
+    pub fn new(source: SquashfsSource<'a>) -> Result<Squashfs<'a>, ()> {
+        let superblock_bytes =
+            source.read(0, core::mem::size_of::<superblock::SquashfsSuperblock>())?;
 
-        // 40 is the offset for bytes used by the archive in the superblock
-        let length = unsafe { u64::from_le(*(ptr.add(40).cast::<u64>())) as usize };
+        let superblock = superblock::SquashfsSuperblock::new(&superblock_bytes)?;
 
-        let squashfs_data: &[u8] = unsafe { core::slice::from_raw_parts(ptr, length) };
+        let length = superblock.bytes_used;
 
-        let superblock = superblock::SquashfsSuperblock::new(squashfs_data)?;
+        // Make sure the image is really as big as the superblock claims before going any further
+        source.read(length - 1, 1)?;
 
-        let decompressor = match superblock.compressor() {
-            superblock::SquashfsCompressionType::Gzip => {
-                Box::new(crate::libs::gzip::uncompress_data)
+        let decompressor: fn(&[u8]) -> Result<Vec<u8>, ()> = match superblock.compressor() {
+            superblock::SquashfsCompressionType::Gzip => crate::libs::gzip::uncompress_data,
+            compressor => {
+                crate::log!(
+                    crate::LogLevel::Error,
+                    "Unsupported SquashFS decompressor {compressor:?}"
+                );
+                return Err(());
             }
-            compressor => panic!("Unsupported SquashFS decompressor {compressor:?}"),
         };
 
-        // The easy part with none of this metadata nonesense
-        let data_table = &squashfs_data[core::mem::size_of::<superblock::SquashfsSuperblock>()
-            ..superblock.inode_table as usize];
+        // Data blocks sit between the superblock and the inode table and are only read when a
+        // file is, so an image on a disk doesn't have to be copied into memory first
+        if superblock.inode_table < core::mem::size_of::<superblock::SquashfsSuperblock>() as u64 {
+            return Err(());
+        }
+
+        let mut degraded = false;
 
         let mut tables: Vec<(Table, u64)> = Vec::new();
 
@@ -77,74 +136,138 @@ impl Squashfs<'_> {
         tables.push((Table::Inode, superblock.inode_table));
         tables.push((Table::Dir, superblock.dir_table));
 
-        if superblock.frag_table != u64::MAX {
-            tables.push((Table::Frag, superblock.frag_table));
-        }
+        // The optional tables have to fall in between their neighbours, if they don't then the
+        // image is damaged and we carry on without them
+        let mut last_offset = superblock.dir_table;
+
+        for (table, offset) in [
+            (Table::Frag, superblock.frag_table),
+            (Table::Export, superblock.export_table),
+        ] {
+            if offset == u64::MAX {
+                continue;
+            }
 
-        if superblock.export_table != u64::MAX {
-            tables.push((Table::Export, superblock.export_table));
+            if offset < last_offset || offset > superblock.id_table {
+                degraded = true;
+                continue;
+            }
+
+            tables.push((table, offset));
+            last_offset = offset;
         }
 
         tables.push((Table::ID, superblock.id_table));
 
         if superblock.xattr_table != u64::MAX {
-            tables.push((Table::Xattr, superblock.xattr_table));
+            if superblock.xattr_table > superblock.id_table && superblock.xattr_table < length {
+                tables.push((Table::Xattr, superblock.xattr_table));
+            } else {
+                degraded = true;
+            }
         }
 
         let mut inode_table: MaybeUninit<
-            chunk_reader::ChunkReader<'static, Box<dyn Fn(&[u8]) -> Result<Vec<u8>, ()>>>,
+            chunk_reader::ChunkReader<'a, Box<dyn Fn(&[u8]) -> Result<Vec<u8>, ()>>>,
         > = MaybeUninit::uninit();
         let mut directory_table: MaybeUninit<
-            chunk_reader::ChunkReader<'static, Box<dyn Fn(&[u8]) -> Result<Vec<u8>, ()>>>,
+            chunk_reader::ChunkReader<'a, Box<dyn Fn(&[u8]) -> Result<Vec<u8>, ()>>>,
         > = MaybeUninit::uninit();
         let mut fragment_table = None;
         let mut export_table = None;
-        let mut id_table: &[u8] = &[];
+        let mut id_table: Cow<'a, [u8]> = Cow::Borrowed(&[]);
         let mut xattr_table = None;
 
         for (i, &(table, offset)) in tables.iter().enumerate() {
-            let whole_table = if i == tables.len() - 1 {
-                &squashfs_data[offset as usize..]
+            let table_end = if i == tables.len() - 1 {
+                length
             } else {
-                &squashfs_data[offset as usize..tables[i + 1].1 as usize]
+                tables[i + 1].1
             };
 
+            let whole_table =
+                source.read(offset, table_end.checked_sub(offset).ok_or(())? as usize)?;
+
             match table {
                 Table::Inode => {
                     inode_table = MaybeUninit::new(chunk_reader::ChunkReader::new(
                         whole_table,
-                        decompressor.clone(),
-                    ));
+                        Box::new(decompressor) as Box<dyn Fn(&[u8]) -> Result<Vec<u8>, ()>>,
+                    )?);
                 }
                 Table::Dir => {
                     directory_table = MaybeUninit::new(chunk_reader::ChunkReader::new(
                         whole_table,
-                        decompressor.clone(),
-                    ));
+                        Box::new(decompressor) as Box<dyn Fn(&[u8]) -> Result<Vec<u8>, ()>>,
+                    )?);
                 }
                 Table::Frag => {
                     fragment_table = Some(whole_table);
                 }
-                Table::Export => export_table = Some(whole_table),
+                Table::Export => {
+                    if Self::pointers_in_bounds(&whole_table, length) {
+                        export_table = Some(whole_table);
+                    } else {
+                        degraded = true;
+                    }
+                }
                 Table::ID => id_table = whole_table,
-                Table::Xattr => xattr_table = Some(whole_table),
+                Table::Xattr => {
+                    // the block pointers come after a 16 byte header
+                    if whole_table.len() >= 16
+                        && Self::pointers_in_bounds(&whole_table[16..], length)
+                    {
+                        xattr_table = Some(whole_table);
+                    } else {
+                        degraded = true;
+                    }
+                }
             }
         }
 
-        return Ok(Squashfs {
+        let mut squashfs = Squashfs {
             superblock,
-            start: ptr,
-            decompressor,
-            data_table,
+            source,
+            decompressor: Box::new(decompressor),
             inode_table: unsafe { inode_table.assume_init() },
             directory_table: unsafe { directory_table.assume_init() },
             fragment_table,
             export_table,
             id_table,
             xattr_table,
-        });
+            root: None,
+            degraded,
+        };
+
+        // Without a root directory there is nothing to mount
+        let root = squashfs.read_inode(superblock.root_inode)?;
+
+        if root.vnode_type() != VNodeType::Directory {
+            return Err(());
+        }
+
+        squashfs.root = Some(root);
+
+        if squashfs.degraded {
+            crate::log!(
+                crate::LogLevel::Warn,
+                "Squashfs image is damaged, mounting without some of its tables"
+            );
+        }
+
+        return Ok(squashfs);
+    }
+
+    // Lookup tables are a list of u64 pointers to metadata blocks, which all need to land in the image
+    fn pointers_in_bounds(table: &[u8], bytes_used: u64) -> bool {
+        return table.len() % 8 == 0
+            && table
+                .chunks_exact(8)
+                .all(|pointer| u64::from_le_bytes(pointer.try_into().unwrap()) < bytes_used);
     }
+}
 
+impl Squashfs<'_> {
     #[inline(always)]
     fn get_inode_block_offset(&self, inode: u64) -> (u64, u16) {
         let inode_block = (inode >> 16) & 0x0000FFFFFFFFFFFF;
@@ -153,32 +276,234 @@ impl Squashfs<'_> {
         (inode_block, inode_offset)
     }
 
-    fn read_root_dir(&mut self) -> Inode {
-        self.read_inode(self.superblock.root_inode)
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+
+    fn read_root_dir(&self) -> Inode {
+        // always there once `new` has returned
+        self.root.unwrap()
     }
 
-    fn read_inode(&mut self, inode: u64) -> Inode {
+    fn read_inode(&mut self, inode: u64) -> Result<Inode, ()> {
         let (inode_block, inode_offset) = self.get_inode_block_offset(inode);
 
-        let file_type = InodeFileType::from(u16::from_le_bytes(
+        let file_type = InodeFileType::try_from(u16::from_le_bytes(
             self.inode_table
-                .get_slice(inode_block, inode_offset, 2)
+                .get_slice(inode_block, inode_offset as usize, 2)?
                 .try_into()
                 .unwrap(),
-        ));
+        ))?;
 
         let inode_size = match file_type {
             InodeFileType::BasicDirectory => core::mem::size_of::<BasicDirectoryInode>(),
             InodeFileType::ExtendedDirectory => core::mem::size_of::<ExtendedDirectoryInode>(),
-            InodeFileType::BasicFile => core::mem::size_of::<BasicFileInode>(),
-            inode_type => unimplemented!("Inode type {inode_type:?}"),
+            InodeFileType::BasicFile => BasicFileInode::SIZE,
+            InodeFileType::ExtendedFile => ExtendedFileInode::SIZE,
+            // TODO: symlinks, devices, pipes and sockets
+            _ => return Err(()),
         };
 
-        let inode_bytes: &[u8] = &self
+        let inode_bytes: &[u8] =
+            &self
+                .inode_table
+                .get_slice(inode_block, inode_offset as usize, inode_size)?;
+
+        let mut inode = Inode::try_from(inode_bytes)?;
+
+        // The block list follows the inode, only where it starts is kept
+        let block_list = inode_block << 16 | (inode_offset as u64 + inode_size as u64);
+
+        match inode {
+            Inode::BasicFile(ref mut file) => file.block_list = block_list,
+            Inode::ExtendedFile(ref mut file) => file.block_list = block_list,
+            _ => {}
+        }
+
+        return Ok(inode);
+    }
+
+    // Tail end packing, the ends of several files share one fragment block
+    fn read_fragment(&self, frag_idx: u32) -> Result<Vec<u8>, ()> {
+        let fragment_table = self.fragment_table.as_ref().ok_or(())?;
+
+        if frag_idx >= self.superblock.frag_count {
+            return Err(());
+        }
+
+        // The table holds pointers to the metadata blocks the 16 byte fragment entries are in
+        let entry_offset = frag_idx as usize * 16;
+        let block_idx = entry_offset / METADATA_BLOCK_SIZE;
+        let offset_in_block = entry_offset % METADATA_BLOCK_SIZE;
+
+        let block_pointer = u64::from_le_bytes(
+            fragment_table
+                .get(block_idx * 8..block_idx * 8 + 8)
+                .ok_or(())?
+                .try_into()
+                .unwrap(),
+        );
+
+        let block = self.read_metadata_block(block_pointer)?;
+        let entry = block.get(offset_in_block..offset_in_block + 16).ok_or(())?;
+
+        // start, size and 4 unused bytes, bit 24 of the size marks an uncompressed fragment block
+        let fragment_start = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+        let fragment_size = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+        let fragment_compressed = fragment_size & 1 << 24 == 0;
+        let fragment_size = (fragment_size & 0x00FFFFFF) as u64;
+
+        if fragment_start + fragment_size > self.superblock.bytes_used {
+            return Err(());
+        }
+
+        let data_table_raw = self.source.read(fragment_start, fragment_size as usize)?;
+
+        return self.get_decompressed_table(&data_table_raw, (false, Some(fragment_compressed)));
+    }
+
+    // Reads `count` bytes at `offset` out of the full data blocks of a file, only the blocks
+    // that are covered get read and decompressed
+    fn read_blocks(
+        &mut self,
+        blocks_start: u64,
+        block_list: u64,
+        block_count: usize,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<u8>, ()> {
+        let block_size = self.superblock.block_size as usize;
+
+        let (list_block, list_offset) = self.get_inode_block_offset(block_list);
+        let block_sizes: Vec<u32> = self
             .inode_table
-            .get_slice(inode_block, inode_offset, inode_size);
+            .get_slice(list_block, list_offset as usize, block_count * 4)?
+            .chunks_exact(4)
+            .map(|size| u32::from_le_bytes(size.try_into().unwrap()))
+            .collect();
+
+        let first_block = offset / block_size;
+        let last_block = (offset + count).div_ceil(block_size).min(block_count);
+
+        // Blocks are stored back to back, so the ones before tell us where the first one is
+        let mut position = blocks_start
+            + block_sizes[..first_block]
+                .iter()
+                .map(|size| (size & 0x00FFFFFF) as u64)
+                .sum::<u64>();
+
+        let mut data: Vec<u8> = Vec::with_capacity(count);
+
+        for (index, &size) in block_sizes
+            .iter()
+            .enumerate()
+            .take(last_block)
+            .skip(first_block)
+        {
+            let disk_size = (size & 0x00FFFFFF) as usize;
+
+            if position + disk_size as u64 > self.superblock.bytes_used {
+                return Err(());
+            }
+
+            // A size of zero is a sparse block, bit 24 marks a block that's stored uncompressed
+            let block = if disk_size == 0 {
+                alloc::vec![0u8; block_size]
+            } else if size & 1 << 24 != 0 {
+                self.source.read(position, disk_size)?.to_vec()
+            } else {
+                (self.decompressor)(&self.source.read(position, disk_size)?)?
+            };
+
+            position += disk_size as u64;
+
+            let block_offset = index * block_size;
+            let start = offset.saturating_sub(block_offset);
+            let end = (offset + count - block_offset).min(block.len());
+
+            data.extend_from_slice(block.get(start..end).ok_or(())?);
+        }
 
-        Inode::from(inode_bytes)
+        return Ok(data);
+    }
+
+    // Reads a lone metadata block at an absolute offset in the image, this is how the lookup
+    // tables (export, id, fragment) store their entries
+    fn read_metadata_block(&self, offset: u64) -> Result<Vec<u8>, ()> {
+        if offset >= self.superblock.bytes_used {
+            return Err(());
+        }
+
+        let header_bytes = self.source.read(offset, 2)?;
+        let header = u16::from_le_bytes(header_bytes.as_ref().try_into().unwrap());
+        let size = (header & 0x7FFF) as usize;
+
+        let bytes = self.source.read(offset + 2, size)?;
+
+        if header & 0x8000 == 0 {
+            return (self.decompressor)(&bytes);
+        }
+
+        return Ok(bytes.to_vec());
+    }
+
+    // Translates an inode number into an inode reference through the export table
+    fn export_lookup(&self, inode_num: u32) -> Result<u64, ()> {
+        let export_table = self.export_table.as_ref().ok_or(())?;
+
+        if inode_num == 0 || inode_num > self.superblock.inode_count {
+            return Err(());
+        }
+
+        // inode numbers start at one
+        let entry_offset = (inode_num - 1) as usize * core::mem::size_of::<u64>();
+        let block_idx = entry_offset / METADATA_BLOCK_SIZE;
+        let offset_in_block = entry_offset % METADATA_BLOCK_SIZE;
+
+        let block_pointer = u64::from_le_bytes(
+            export_table
+                .get(block_idx * 8..block_idx * 8 + 8)
+                .ok_or(())?
+                .try_into()
+                .unwrap(),
+        );
+
+        let block = self.read_metadata_block(block_pointer)?;
+
+        return Ok(u64::from_le_bytes(
+            block
+                .get(offset_in_block..offset_in_block + 8)
+                .ok_or(())?
+                .try_into()
+                .unwrap(),
+        ));
+    }
+
+    fn resolve_path(&mut self, path: &str) -> Result<Inode, ()> {
+        let mut inode = self.read_root_dir();
+
+        for part in path.split('/') {
+            match part {
+                "" | "." => continue,
+                ".." => {
+                    let parent_inode = match inode {
+                        Inode::BasicDirectory(dir) => dir.parent_inode,
+                        Inode::ExtendedDirectory(dir) => dir.parent_inode,
+                        _ => return Err(()),
+                    };
+
+                    // The root directory's parent is inode_count + 1
+                    if parent_inode > self.superblock.inode_count {
+                        continue;
+                    }
+
+                    inode = self.read_inode(self.export_lookup(parent_inode)?)?;
+                }
+                name => inode = self.find_entry_in_directory(inode, name)?,
+            }
+        }
+
+        return Ok(inode);
     }
 
     fn find_entry_in_directory(&mut self, dir: Inode, name: &str) -> Result<Inode, ()> {
@@ -205,14 +530,16 @@ impl Squashfs<'_> {
 
         let (directory_block, directory_offset) = self.get_inode_block_offset(dir_inode as u64);
 
+        let directory_offset = directory_offset as usize;
+
         let mut directory_table_header = {
             let bytes: &[u8] = &self.directory_table.get_slice(
                 directory_block,
                 directory_offset,
                 core::mem::size_of::<DirectoryTableHeader>(),
-            );
+            )?;
 
-            DirectoryTableHeader::from(bytes)
+            DirectoryTableHeader::try_from(bytes)?
         };
 
         let mut offset = core::mem::size_of::<DirectoryTableHeader>();
@@ -224,11 +551,11 @@ impl Squashfs<'_> {
                 directory_table_header = {
                     let bytes: &[u8] = &self.directory_table.get_slice(
                         directory_block,
-                        directory_offset + offset as u16,
+                        directory_offset + offset,
                         core::mem::size_of::<DirectoryTableHeader>(),
-                    );
+                    )?;
 
-                    DirectoryTableHeader::from(bytes)
+                    DirectoryTableHeader::try_from(bytes)?
                 };
 
                 i = 0;
@@ -243,22 +570,19 @@ impl Squashfs<'_> {
 
             let name_size = u16::from_le_bytes(
                 self.directory_table
-                    .get_slice(
-                        directory_block,
-                        directory_offset + (offset as u16 + 6),
-                            2
-                    )
+                    .get_slice(directory_block, directory_offset + offset + 6, 2)?
                     .try_into()
                     .unwrap(),
             ) as usize
             // the name is stored off-by-one
                 + 1;
 
-            let directory_entry = DirectoryTableEntry::from_bytes(&self.directory_table.get_slice(
-                directory_block,
-                directory_offset + offset as u16,
-                8 + name_size,
-            ));
+            let directory_entry =
+                DirectoryTableEntry::from_bytes(&self.directory_table.get_slice(
+                    directory_block,
+                    directory_offset + offset,
+                    8 + name_size,
+                )?)?;
 
             offset += 8 + name_size;
 
@@ -266,7 +590,7 @@ impl Squashfs<'_> {
                 let directory_entry_inode = (directory_table_header.start as usize) << 16
                     | (directory_entry.offset as usize);
 
-                return Ok(self.read_inode(directory_entry_inode as u64));
+                return self.read_inode(directory_entry_inode as u64);
             }
 
             i += 1;
@@ -281,11 +605,11 @@ impl Squashfs<'_> {
         &self,
         table: &[u8],
         metadata_block: (bool, Option<bool>),
-    ) -> Vec<u8> {
+    ) -> Result<Vec<u8>, ()> {
         // the bottom 15 bits, I think the last bit indicates whether the data is uncompressed
-        let header = u16::from_le_bytes(table[0..2].try_into().unwrap());
+        let header = u16::from_le_bytes(table.get(0..2).ok_or(())?.try_into().unwrap());
         let table_is_compressed = if !metadata_block.0 {
-            metadata_block.1.unwrap()
+            metadata_block.1.ok_or(())?
         } else {
             header & 0x8000 == 0
         };
@@ -299,24 +623,12 @@ impl Squashfs<'_> {
         let bytes = if metadata_block.0 { &table[2..] } else { table };
 
         if table_is_compressed {
-            match self.superblock.compressor() {
-                superblock::SquashfsCompressionType::Gzip => {
-                    buffer.extend_from_slice(
-                        &crate::libs::gzip::uncompress_data(bytes).unwrap_or(bytes.to_vec()),
-                    );
-                }
-                _ => {
-                    crate::log!(
-                        crate::LogLevel::Error,
-                        "Unsupported squashfs compression type"
-                    )
-                }
-            }
+            buffer.extend_from_slice(&(self.decompressor)(bytes)?);
         } else {
             buffer.extend(bytes);
         }
 
-        return buffer;
+        return Ok(buffer);
     }
 }
 
@@ -338,8 +650,13 @@ impl<'a> FsOps for Squashfs<'a> {
         return VNode::new(Box::new(root_dir), VNodeType::Directory, vfsp);
     }
 
-    fn fid(&mut self, _path: &str, _vfsp: NonNull<super::vfs::Vfs>) -> Option<super::vfs::FileId> {
-        todo!();
+    fn fid(&mut self, path: &str, _vfsp: NonNull<super::vfs::Vfs>) -> Option<FileId> {
+        // Without the export table there is no way to get from an inode number back to an inode
+        self.export_table.as_ref()?;
+
+        let inode = self.resolve_path(path).ok()?;
+
+        return FileId::new(&inode.header().inode_num.to_le_bytes()).ok();
     }
 
     fn statfs(&mut self, _vfsp: NonNull<super::vfs::Vfs>) -> super::vfs::StatFs {
@@ -350,12 +667,12 @@ impl<'a> FsOps for Squashfs<'a> {
         todo!();
     }
 
-    fn vget(
-        &mut self,
-        _fid: super::vfs::FileId,
-        _vfsp: NonNull<super::vfs::Vfs>,
-    ) -> super::vfs::VNode {
-        todo!();
+    fn vget(&mut self, fid: FileId, vfsp: NonNull<super::vfs::Vfs>) -> Result<VNode, ()> {
+        let inode_num = u32::from_le_bytes(fid.as_bytes().try_into().map_err(|_| ())?);
+
+        let inode = self.read_inode(self.export_lookup(inode_num)?)?;
+
+        return Ok(VNode::new(Box::new(inode), inode.vnode_type(), vfsp));
     }
 }
 
@@ -363,23 +680,76 @@ impl<'a> FsOps for Squashfs<'a> {
 enum Inode {
     BasicFile(BasicFileInode),
     BasicDirectory(BasicDirectoryInode),
+    ExtendedFile(ExtendedFileInode),
     ExtendedDirectory(ExtendedDirectoryInode),
 }
 
-impl From<&[u8]> for Inode {
-    fn from(value: &[u8]) -> Self {
-        let file_type = InodeFileType::from(u16::from_le_bytes(value[0..2].try_into().unwrap()));
+impl Inode {
+    fn header(&self) -> &InodeHeader {
+        match self {
+            Inode::BasicFile(file) => &file.header,
+            Inode::BasicDirectory(dir) => &dir.header,
+            Inode::ExtendedFile(file) => &file.header,
+            Inode::ExtendedDirectory(dir) => &dir.header,
+        }
+    }
+
+    // Only extended inodes can carry extended attributes
+    fn xattr_index(&self) -> Option<u32> {
+        let xattr_index = match self {
+            Inode::ExtendedFile(file) => file.xattr_index,
+            Inode::ExtendedDirectory(dir) => dir.xattr_index,
+            _ => return None,
+        };
+
+        if xattr_index == u32::MAX {
+            return None;
+        }
+
+        return Some(xattr_index);
+    }
+
+    fn vnode_type(&self) -> VNodeType {
+        match self {
+            Inode::BasicDirectory(_) | Inode::ExtendedDirectory(_) => VNodeType::Directory,
+            Inode::BasicFile(_) | Inode::ExtendedFile(_) => VNodeType::Regular,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for Inode {
+    type Error = ();
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let file_type = InodeFileType::try_from(u16::from_le_bytes(
+            value.get(0..2).ok_or(())?.try_into().unwrap(),
+        ))?;
+
+        let inode_size = match file_type {
+            InodeFileType::BasicDirectory => core::mem::size_of::<BasicDirectoryInode>(),
+            InodeFileType::ExtendedDirectory => core::mem::size_of::<ExtendedDirectoryInode>(),
+            InodeFileType::BasicFile => BasicFileInode::SIZE,
+            InodeFileType::ExtendedFile => ExtendedFileInode::SIZE,
+            _ => return Err(()),
+        };
+
+        if value.len() < inode_size {
+            return Err(());
+        }
 
-        match file_type {
+        return Ok(match file_type {
             InodeFileType::BasicDirectory => {
-                Inode::BasicDirectory(BasicDirectoryInode::from_bytes(value))
+                Inode::BasicDirectory(BasicDirectoryInode::from_bytes(value)?)
             }
             InodeFileType::ExtendedDirectory => {
-                Inode::ExtendedDirectory(ExtendedDirectoryInode::from_bytes(value))
+                Inode::ExtendedDirectory(ExtendedDirectoryInode::from_bytes(value)?)
             }
-            InodeFileType::BasicFile => Inode::BasicFile(BasicFileInode::from_bytes(value)),
-            _ => unimplemented!("Inode from bytes"),
-        }
+            InodeFileType::BasicFile => Inode::BasicFile(BasicFileInode::from_bytes(value)?),
+            InodeFileType::ExtendedFile => {
+                Inode::ExtendedFile(ExtendedFileInode::from_bytes(value)?)
+            }
+            _ => unreachable!(),
+        });
     }
 }
 
@@ -396,87 +766,63 @@ impl VNodeOperations for Inode {
         _c: super::vfs::UserCred,
         vp: NonNull<VNode>,
     ) -> Result<Arc<[u8]>, ()> {
-        let squashfs = unsafe { (*vp.as_ptr()).parent_vfs.as_mut().data.cast::<Squashfs>() };
+        let squashfs = unsafe { &mut *(*vp.as_ptr()).parent_vfs.as_mut().data.cast::<Squashfs>() };
+
+        let (file_size, blocks_start, block_list, frag_idx, fragment_offset) = match self {
+            Inode::BasicFile(file) => (
+                file.file_size as usize,
+                file.block_start as u64,
+                file.block_list,
+                file.frag_idx,
+                file.block_offset as usize,
+            ),
+            Inode::ExtendedFile(file) => (
+                file.file_size as usize,
+                file.blocks_start,
+                file.block_list,
+                file.frag_idx,
+                file.block_offset as usize,
+            ),
+            _ => return Err(()),
+        };
 
-        match self {
-            Inode::BasicFile(file) => unsafe {
-                // TODO: is this really how you're supposed to do this?
-                let mut block_data: Vec<u8> = Vec::with_capacity(count);
-
-                let data_table: Vec<u8>;
-
-                let block_offset = if file.frag_idx == u32::MAX {
-                    data_table = (*squashfs).get_decompressed_table(
-                        (*squashfs).data_table,
-                        (
-                            false,
-                            Some(!(*squashfs).superblock.features().uncompressed_data_blocks),
-                        ),
-                    );
-
-                    file.block_offset as usize
-                } else {
-                    // Tail end packing
-                    let fragment_table = (*squashfs).get_decompressed_table(
-                        (*squashfs).fragment_table.unwrap(),
-                        (
-                            false,
-                            Some(!(*squashfs).superblock.features().uncompressed_fragments),
-                        ),
-                    );
-
-                    let fragment_pointer = ((*squashfs).start as u64
-                        + u64::from_le_bytes(
-                            fragment_table[file.frag_idx as usize..(file.frag_idx + 8) as usize]
-                                .try_into()
-                                .unwrap(),
-                        )) as *mut u8;
-
-                    // build array since fragment_pointer is not guaranteed to be 0x02 aligned
-                    // We add two since fragment_pointer points to the beginning of the fragment block,
-                    // Which is a metadata block, and we get the size, but that excludes the two header bytes,
-                    // And since we are building the array due to unaligned pointer shenanigans we need to
-                    // include the header bytes otherwise we are short by two bytes
-                    let fragment_block_size =
-                        (u16::from_le(core::ptr::read_unaligned(fragment_pointer.cast::<u16>()))
-                            & 0x7FFF)
-                            + 2;
-
-                    let mut fragment_block_raw = Vec::new();
-                    for i in 0..fragment_block_size as usize {
-                        fragment_block_raw.push(core::ptr::read_unaligned(fragment_pointer.add(i)))
-                    }
+        if offset.checked_add(count).ok_or(())? > file_size {
+            return Err(());
+        }
 
-                    let fragment_block =
-                        (*squashfs).get_decompressed_table(&fragment_block_raw, (true, None));
+        let block_size = squashfs.superblock.block_size as usize;
+
+        // Whatever doesn't fill a whole block is packed into a fragment, if the file has one
+        let block_count = match frag_idx {
+            u32::MAX => file_size.div_ceil(block_size),
+            _ => file_size / block_size,
+        };
+        let blocks_end = (block_count * block_size).min(file_size);
 
-                    let fragment_start =
-                        u64::from_le_bytes(fragment_block[0..8].try_into().unwrap());
-                    let fragment_size =
-                        u32::from_le_bytes(fragment_block[8..12].try_into().unwrap());
-                    let fragment_compressed = fragment_size & 1 << 24 == 0;
-                    let fragment_size = fragment_size & 0xFEFFFFFF;
+        let mut data: Vec<u8> = Vec::with_capacity(count);
 
-                    let data_table_raw = core::slice::from_raw_parts(
-                        ((*squashfs).start as u64 + fragment_start) as *mut u8,
-                        fragment_size as usize,
-                    )
-                    .to_vec();
+        if offset < blocks_end {
+            let len = (offset + count).min(blocks_end) - offset;
 
-                    data_table = (*squashfs).get_decompressed_table(
-                        &data_table_raw,
-                        (false, Some(fragment_compressed)),
-                    );
+            data.extend(squashfs.read_blocks(
+                blocks_start,
+                block_list,
+                block_count,
+                offset,
+                len,
+            )?);
+        }
 
-                    file.block_offset as usize
-                } + offset;
+        if offset + count > blocks_end {
+            let fragment = squashfs.read_fragment(frag_idx)?;
 
-                block_data.extend(&data_table[block_offset..(block_offset + count)]);
+            let start = fragment_offset + offset.max(blocks_end) - blocks_end;
+            let end = fragment_offset + offset + count - blocks_end;
 
-                return Ok(Arc::from(block_data));
-            },
-            _ => panic!("Tried to open non-file"),
+            data.extend_from_slice(fragment.get(start..end).ok_or(())?);
         }
+
+        return Ok(Arc::from(data));
     }
 
     fn write(
@@ -509,7 +855,7 @@ impl VNodeOperations for Inode {
         todo!()
     }
 
-    fn access(&mut self, _m: u32, _c: super::vfs::UserCred, _vp: NonNull<VNode>) {
+    fn access(&mut self, _m: u32, _c: super::vfs::UserCred, _vp: NonNull<VNode>) -> Result<(), ()> {
         todo!()
     }
 
@@ -524,19 +870,67 @@ impl VNodeOperations for Inode {
         match self {
             Inode::BasicDirectory(_) | Inode::ExtendedDirectory(_) => unsafe {
                 let inode = (*squashfs).find_entry_in_directory(*self, nm)?;
-                let vnode_type = match inode {
-                    Inode::BasicDirectory(_) | Inode::ExtendedDirectory(_) => VNodeType::Directory,
-                    Inode::BasicFile(_) => VNodeType::Regular,
-                };
 
-                let vnode = VNode::new(Box::new(inode), vnode_type, (*vp.as_ptr()).parent_vfs);
+                let vnode = VNode::new(
+                    Box::new(inode),
+                    inode.vnode_type(),
+                    (*vp.as_ptr()).parent_vfs,
+                );
 
                 return Ok(vnode);
             },
-            _ => panic!("tried to lookup on non directory"),
+            _ => return Err(()),
         }
     }
 
+    fn getxattr(
+        &mut self,
+        name: &str,
+        _c: super::vfs::UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        let squashfs = unsafe { (*vp.as_ptr()).parent_vfs.as_mut().data.cast::<Squashfs>() };
+
+        let xattr_index = self.xattr_index().ok_or(())?;
+
+        let xattrs = unsafe { (*squashfs).read_xattrs(xattr_index)? };
+
+        return xattrs
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| Arc::from(value))
+            .ok_or(());
+    }
+
+    fn listxattr(
+        &mut self,
+        _c: super::vfs::UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<Vec<String>, ()> {
+        let squashfs = unsafe { (*vp.as_ptr()).parent_vfs.as_mut().data.cast::<Squashfs>() };
+
+        let xattr_index = match self.xattr_index() {
+            Some(xattr_index) => xattr_index,
+            None => return Ok(Vec::new()),
+        };
+
+        let xattrs = unsafe { (*squashfs).read_xattrs(xattr_index)? };
+
+        return Ok(xattrs.into_iter().map(|(key, _)| key).collect());
+    }
+
+    fn setxattr(
+        &mut self,
+        _name: &str,
+        _value: &[u8],
+        _f: u32,
+        _c: super::vfs::UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<(), ()> {
+        // squashfs is read-only
+        return Err(());
+    }
+
     fn create(
         &mut self,
         _nm: &str,
@@ -616,7 +1010,9 @@ impl VNodeOperations for Inode {
     fn len(&self, _vp: NonNull<VNode>) -> usize {
         match self {
             Inode::BasicFile(file) => file.file_size as usize,
-            _ => panic!("idk"),
+            Inode::ExtendedFile(file) => file.file_size as usize,
+            Inode::BasicDirectory(dir) => dir.file_size as usize,
+            Inode::ExtendedDirectory(dir) => dir.file_size as usize,
         }
     }
 }
@@ -641,6 +1037,7 @@ macro_rules! inode_enum_try_into {
 }
 
 inode_enum_try_into!(BasicFileInode, BasicFile);
+inode_enum_try_into!(ExtendedFileInode, ExtendedFile);
 inode_enum_try_into!(BasicDirectoryInode, BasicDirectory);
 inode_enum_try_into!(ExtendedDirectoryInode, ExtendedDirectory);
 
@@ -654,18 +1051,22 @@ struct InodeHeader {
 }
 
 impl InodeHeader {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let file_type = u16::from_le_bytes(bytes[0..2].try_into().unwrap()).into();
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() < core::mem::size_of::<Self>() {
+            return Err(());
+        }
+
+        let file_type = u16::from_le_bytes(bytes[0..2].try_into().unwrap()).try_into()?;
         let mtime = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
         let inode_num = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
 
-        return Self {
+        return Ok(Self {
             // squashfs,
             file_type,
             _reserved: [0; 3],
             mtime,
             inode_num,
-        };
+        });
     }
 }
 
@@ -681,22 +1082,22 @@ struct BasicDirectoryInode {
 }
 
 impl BasicDirectoryInode {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let header = InodeHeader::from_bytes(bytes);
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        let header = InodeHeader::from_bytes(bytes)?;
         let block_index = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
         let link_count = u32::from_le_bytes(bytes[20..24].try_into().unwrap());
         let file_size = u16::from_le_bytes(bytes[24..26].try_into().unwrap());
         let block_offset = u16::from_le_bytes(bytes[26..28].try_into().unwrap());
         let parent_inode = u32::from_le_bytes(bytes[28..32].try_into().unwrap());
 
-        return Self {
+        return Ok(Self {
             header,
             block_index,
             link_count,
             file_size,
             block_offset,
             parent_inode,
-        };
+        });
     }
 
     // #[allow(dead_code)]
@@ -774,8 +1175,8 @@ struct ExtendedDirectoryInode {
 }
 
 impl ExtendedDirectoryInode {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let header = InodeHeader::from_bytes(bytes);
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        let header = InodeHeader::from_bytes(bytes)?;
         let link_count = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
         let file_size = u32::from_le_bytes(bytes[20..24].try_into().unwrap());
         let block_index = u32::from_le_bytes(bytes[24..28].try_into().unwrap());
@@ -784,7 +1185,7 @@ impl ExtendedDirectoryInode {
         let block_offset = u16::from_le_bytes(bytes[34..36].try_into().unwrap());
         let xattr_index = u32::from_le_bytes(bytes[36..40].try_into().unwrap());
 
-        return Self {
+        return Ok(Self {
             header,
             link_count,
             file_size,
@@ -793,7 +1194,7 @@ impl ExtendedDirectoryInode {
             index_count,
             block_offset,
             xattr_index,
-        };
+        });
     }
 }
 
@@ -805,26 +1206,72 @@ struct BasicFileInode {
     frag_idx: u32,     // 8
     block_offset: u32, // 12
     file_size: u32,    // 16
-                       // block_sizes: *const u32,
+    // Inode table reference to the block sizes, filled in by `read_inode`
+    block_list: u64,
 }
 
 impl BasicFileInode {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let header = InodeHeader::from_bytes(bytes);
+    // On disk, without the block sizes that follow
+    const SIZE: usize = 32;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        let header = InodeHeader::from_bytes(bytes)?;
         let block_start = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
         let frag_idx = u32::from_le_bytes(bytes[20..24].try_into().unwrap());
         let block_offset = u32::from_le_bytes(bytes[24..28].try_into().unwrap());
         let file_size = u32::from_le_bytes(bytes[28..32].try_into().unwrap());
-        // let block_sizes = bytes[32..].as_ptr() as *const u32;
 
-        return Self {
+        return Ok(Self {
             header,
             block_start,
             frag_idx,
             block_offset,
             file_size,
-            // block_sizes,
-        };
+            block_list: 0,
+        });
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct ExtendedFileInode {
+    header: InodeHeader,
+    blocks_start: u64,
+    file_size: u64,
+    sparse: u64,
+    link_count: u32,
+    frag_idx: u32,
+    block_offset: u32,
+    xattr_index: u32,
+    // Inode table reference to the block sizes, filled in by `read_inode`
+    block_list: u64,
+}
+
+impl ExtendedFileInode {
+    // On disk, without the block sizes that follow
+    const SIZE: usize = 56;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        let header = InodeHeader::from_bytes(bytes)?;
+        let blocks_start = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+        let file_size = u64::from_le_bytes(bytes[24..32].try_into().unwrap());
+        let sparse = u64::from_le_bytes(bytes[32..40].try_into().unwrap());
+        let link_count = u32::from_le_bytes(bytes[40..44].try_into().unwrap());
+        let frag_idx = u32::from_le_bytes(bytes[44..48].try_into().unwrap());
+        let block_offset = u32::from_le_bytes(bytes[48..52].try_into().unwrap());
+        let xattr_index = u32::from_le_bytes(bytes[52..56].try_into().unwrap());
+
+        return Ok(Self {
+            header,
+            blocks_start,
+            file_size,
+            sparse,
+            link_count,
+            frag_idx,
+            block_offset,
+            xattr_index,
+            block_list: 0,
+        });
     }
 }
 
@@ -836,18 +1283,29 @@ struct DirectoryTableHeader {
     inode_num: u32,
 }
 
-impl From<&[u8]> for DirectoryTableHeader {
-    fn from(value: &[u8]) -> Self {
-        // count is off by 1 entry
+impl TryFrom<&[u8]> for DirectoryTableHeader {
+    type Error = ();
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() < core::mem::size_of::<Self>() {
+            return Err(());
+        }
+
+        // count is off by 1 entry, a directory run has at most 256 entries
         let entry_count = u32::from_le_bytes(value[0..4].try_into().unwrap()) + 1;
+
+        if entry_count > 256 {
+            return Err(());
+        }
+
         let start = u32::from_le_bytes(value[4..8].try_into().unwrap());
         let inode_num = u32::from_le_bytes(value[8..12].try_into().unwrap());
 
-        return Self {
+        return Ok(Self {
             entry_count,
             start,
             inode_num,
-        };
+        });
     }
 }
 
@@ -862,22 +1320,27 @@ struct DirectoryTableEntry {
 }
 
 impl DirectoryTableEntry {
-    fn from_bytes(bytes: &[u8]) -> Self {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() < 8 {
+            return Err(());
+        }
+
         let offset = u16::from_le_bytes(bytes[0..2].try_into().unwrap());
         let inode_offset = i16::from_le_bytes(bytes[2..4].try_into().unwrap());
-        let inode_type = u16::from_le_bytes(bytes[4..6].try_into().unwrap()).into();
+        let inode_type = u16::from_le_bytes(bytes[4..6].try_into().unwrap()).try_into()?;
         let name_size = u16::from_le_bytes(bytes[6..8].try_into().unwrap());
-        let name = String::from_utf8(bytes[8..((name_size as usize) + 1) + 8].to_vec()).unwrap();
+        let name_bytes = bytes.get(8..((name_size as usize) + 1) + 8).ok_or(())?;
+        let name = String::from_utf8(name_bytes.to_vec()).map_err(|_| ())?;
         // let name = core::str::from_utf8(&bytes[8..((name_size as usize) + 1) + 8])
         //     .expect("Failed to make DirectoryHeader name");
 
-        return Self {
+        return Ok(Self {
             offset,
             inode_offset,
             inode_type,
             name_size,
             name,
-        };
+        });
     }
 }
 
@@ -899,9 +1362,11 @@ enum InodeFileType {
     ExtendedSocked = 13,
 }
 
-impl From<u16> for InodeFileType {
-    fn from(value: u16) -> Self {
-        match value {
+impl TryFrom<u16> for InodeFileType {
+    type Error = ();
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        let file_type = match value {
             1 => Self::BasicDirectory,
             2 => Self::BasicFile,
             3 => Self::BasicSymlink,
@@ -915,7 +1380,9 @@ impl From<u16> for InodeFileType {
             11 => Self::ExtendedBlockDevice,
             12 => Self::ExtendedPipe,
             13 => Self::ExtendedSocked,
-            _ => panic!("Unexpected Inode file type {value}!"),
-        }
+            _ => return Err(()),
+        };
+
+        return Ok(file_type);
     }
 }
@@ -0,0 +1,81 @@
+use alloc::{borrow::Cow, sync::Arc, vec::Vec};
+use core::cell::RefCell;
+
+use crate::drivers::{
+    fs::vfs::File,
+    storage::{BlockDevice, Partition},
+};
+
+// Where the bytes of a squashfs image come from. Memory backed images are read in place,
+// everything else is copied out of the backing store as it's needed.
+pub enum SquashfsSource<'a> {
+    Memory(&'a [u8]),
+    BlockDevice(Arc<dyn BlockDevice>),
+    Partition(Partition),
+    File(RefCell<File>),
+}
+
+impl<'a> SquashfsSource<'a> {
+    pub fn read(&self, offset: u64, len: usize) -> Result<Cow<'a, [u8]>, ()> {
+        match self {
+            SquashfsSource::Memory(data) => {
+                let data: &'a [u8] = data;
+
+                let end = (offset as usize).checked_add(len).ok_or(())?;
+
+                return data.get(offset as usize..end).map(Cow::Borrowed).ok_or(());
+            }
+            SquashfsSource::BlockDevice(block_device) => {
+                let sector_size = block_device.sector_size() as u64;
+
+                return Self::read_sectors(offset, len, sector_size, |sector, sector_count| {
+                    block_device.read(sector, sector_count)
+                });
+            }
+            SquashfsSource::Partition(partition) => {
+                let sector_size = partition.block_device().sector_size() as u64;
+
+                return Self::read_sectors(offset, len, sector_size, |sector, sector_count| {
+                    partition.read(sector, sector_count)
+                });
+            }
+            SquashfsSource::File(file) => {
+                if len == 0 {
+                    return Ok(Cow::Owned(Vec::new()));
+                }
+
+                let data = file.borrow_mut().read(len, offset as usize, 0)?;
+
+                return Ok(Cow::Owned(data.to_vec()));
+            }
+        }
+    }
+
+    // Reads the sectors spanning [offset, offset + len) and cuts out the requested bytes
+    fn read_sectors<F>(
+        offset: u64,
+        len: usize,
+        sector_size: u64,
+        read: F,
+    ) -> Result<Cow<'a, [u8]>, ()>
+    where
+        F: Fn(u64, usize) -> Result<Arc<[u8]>, ()>,
+    {
+        if len == 0 {
+            return Ok(Cow::Owned(Vec::new()));
+        }
+
+        let first_sector = offset / sector_size;
+        let last_sector = (offset + len as u64).div_ceil(sector_size);
+        let offset_in_sector = (offset % sector_size) as usize;
+
+        let sectors = read(first_sector, (last_sector - first_sector) as usize)?;
+
+        return Ok(Cow::Owned(
+            sectors
+                .get(offset_in_sector..offset_in_sector + len)
+                .ok_or(())?
+                .to_vec(),
+        ));
+    }
+}
@@ -9,16 +9,18 @@ pub enum SquashfsCompressionType {
     Zstd = 6,
 }
 
-impl From<u16> for SquashfsCompressionType {
-    fn from(value: u16) -> Self {
+impl TryFrom<u16> for SquashfsCompressionType {
+    type Error = ();
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
-            1 => Self::Gzip,
-            2 => Self::Lzma,
-            3 => Self::Lzo,
-            4 => Self::Xz,
-            5 => Self::Lz4,
-            6 => Self::Zstd,
-            _ => panic!("Unexpected Squashfs compression type!"),
+            1 => Ok(Self::Gzip),
+            2 => Ok(Self::Lzma),
+            3 => Ok(Self::Lzo),
+            4 => Ok(Self::Xz),
+            5 => Ok(Self::Lz4),
+            6 => Ok(Self::Zstd),
+            _ => Err(()),
         }
     }
 }
@@ -60,10 +62,10 @@ pub struct SquashfsFeatures {
 #[derive(Clone, Copy, Debug)]
 pub struct SquashfsSuperblock {
     magic: u32,                          // 0x73717368
-    inode_count: u32,                    // 0x02
+    pub inode_count: u32,                // 0x02
     mod_time: u32,                       // varies
     pub block_size: u32,                 // 0x20000
-    frag_count: u32,                     // 0x01
+    pub frag_count: u32,                 // 0x01
     compressor: SquashfsCompressionType, // GZIP
     block_log: u16,                      // 0x11
     flags: u16,                          // 0xC0
@@ -71,7 +73,7 @@ pub struct SquashfsSuperblock {
     ver_major: u16,                      // 0x04
     ver_minor: u16,                      // 0x00
     pub root_inode: u64,                 //
-    bytes_used: u64,                     // 0x0103
+    pub bytes_used: u64,                 // 0x0103
     pub id_table: u64,                   // 0x00FB
     pub xattr_table: u64,                // 0xFFFFFFFFFFFFFFFF
     pub inode_table: u64,                // 0x7B
@@ -82,13 +84,17 @@ pub struct SquashfsSuperblock {
 
 impl SquashfsSuperblock {
     pub fn new(bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() < core::mem::size_of::<Self>() {
+            return Err(());
+        }
+
         let superblock = Self {
             magic: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
             inode_count: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
             mod_time: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
             block_size: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
             frag_count: u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
-            compressor: u16::from_le_bytes(bytes[20..22].try_into().unwrap()).into(),
+            compressor: u16::from_le_bytes(bytes[20..22].try_into().unwrap()).try_into()?,
             block_log: u16::from_le_bytes(bytes[22..24].try_into().unwrap()),
             flags: u16::from_le_bytes(bytes[24..26].try_into().unwrap()),
             id_count: u16::from_le_bytes(bytes[26..28].try_into().unwrap()),
@@ -132,6 +138,23 @@ impl SquashfsSuperblock {
             return Err(());
         }
 
+        // The tables we can't do without have to be in order and inside of the image, the
+        // optional ones are checked when the image is opened so a bad one can be left out
+        let superblock_size = core::mem::size_of::<Self>() as u64;
+        let bytes_used = superblock.bytes_used;
+
+        if superblock.inode_table < superblock_size
+            || superblock.dir_table < superblock.inode_table
+            || superblock.id_table < superblock.dir_table
+            || superblock.id_table >= bytes_used
+        {
+            return Err(());
+        }
+
+        if superblock.root_inode >> 16 >= superblock.dir_table - superblock.inode_table {
+            return Err(());
+        }
+
         return Ok(superblock);
     }
 
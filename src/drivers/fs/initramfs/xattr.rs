@@ -0,0 +1,209 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use super::{Squashfs, METADATA_BLOCK_SIZE};
+
+// The xattr id table starts with this header, followed by the pointers to the metadata blocks
+// that hold the id entries
+const XATTR_ID_TABLE_HEADER_SIZE: usize = 16;
+const XATTR_ID_ENTRY_SIZE: usize = 16;
+
+// Set in the key type when the value is stored somewhere else and the entry only has a reference
+const XATTR_VALUE_OUT_OF_LINE: u16 = 0x0100;
+
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum XattrPrefix {
+    User = 0,
+    Trusted = 1,
+    Security = 2,
+}
+
+impl TryFrom<u16> for XattrPrefix {
+    type Error = ();
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value & !XATTR_VALUE_OUT_OF_LINE {
+            0 => Ok(Self::User),
+            1 => Ok(Self::Trusted),
+            2 => Ok(Self::Security),
+            _ => Err(()),
+        }
+    }
+}
+
+impl XattrPrefix {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::User => "user.",
+            Self::Trusted => "trusted.",
+            Self::Security => "security.",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct XattrIdEntry {
+    // location of the first key, relative to the start of the key/value metadata blocks
+    xattr_ref: u64,
+    count: u32,
+    _size: u32,
+}
+
+impl From<&[u8]> for XattrIdEntry {
+    fn from(value: &[u8]) -> Self {
+        let xattr_ref = u64::from_le_bytes(value[0..8].try_into().unwrap());
+        let count = u32::from_le_bytes(value[8..12].try_into().unwrap());
+        let _size = u32::from_le_bytes(value[12..16].try_into().unwrap());
+
+        return Self {
+            xattr_ref,
+            count,
+            _size,
+        };
+    }
+}
+
+// Reads sequentially through a run of metadata blocks, entries are allowed to straddle blocks
+pub(super) struct MetadataCursor {
+    block: u64,
+    offset: usize,
+    data: Vec<u8>,
+}
+
+impl Squashfs<'_> {
+    // reference is the usual (block << 16 | offset) pair, where block is relative to base
+    pub(super) fn metadata_cursor(&self, base: u64, reference: u64) -> Result<MetadataCursor, ()> {
+        let block = base + (reference >> 16);
+        let offset = (reference & 0xFFFF) as usize;
+
+        let data = self.read_metadata_block(block)?;
+
+        if offset > data.len() {
+            return Err(());
+        }
+
+        return Ok(MetadataCursor {
+            block,
+            offset,
+            data,
+        });
+    }
+
+    pub(super) fn read_metadata(
+        &self,
+        cursor: &mut MetadataCursor,
+        size: usize,
+    ) -> Result<Vec<u8>, ()> {
+        let mut bytes = Vec::with_capacity(size);
+
+        while bytes.len() < size {
+            if cursor.offset == cursor.data.len() {
+                let header = self.source.read(cursor.block, 2)?;
+                let block_size =
+                    (u16::from_le_bytes(header.as_ref().try_into().unwrap()) & 0x7FFF) as u64;
+
+                cursor.block += 2 + block_size;
+                cursor.data = self.read_metadata_block(cursor.block)?;
+                cursor.offset = 0;
+
+                if cursor.data.is_empty() {
+                    return Err(());
+                }
+            }
+
+            let to_copy = (size - bytes.len()).min(cursor.data.len() - cursor.offset);
+
+            bytes.extend_from_slice(&cursor.data[cursor.offset..cursor.offset + to_copy]);
+            cursor.offset += to_copy;
+        }
+
+        return Ok(bytes);
+    }
+
+    fn read_xattr_id(&self, xattr_index: u32) -> Result<XattrIdEntry, ()> {
+        let xattr_table = self.xattr_table.as_ref().ok_or(())?;
+
+        let xattr_ids = u32::from_le_bytes(xattr_table.get(8..12).ok_or(())?.try_into().unwrap());
+
+        if xattr_index >= xattr_ids {
+            return Err(());
+        }
+
+        let entry_offset = xattr_index as usize * XATTR_ID_ENTRY_SIZE;
+        let block_idx = entry_offset / METADATA_BLOCK_SIZE;
+        let offset_in_block = entry_offset % METADATA_BLOCK_SIZE;
+
+        let pointer_offset = XATTR_ID_TABLE_HEADER_SIZE + block_idx * 8;
+        let block_pointer = u64::from_le_bytes(
+            xattr_table
+                .get(pointer_offset..pointer_offset + 8)
+                .ok_or(())?
+                .try_into()
+                .unwrap(),
+        );
+
+        let block = self.read_metadata_block(block_pointer)?;
+
+        return Ok(XattrIdEntry::from(
+            block
+                .get(offset_in_block..offset_in_block + XATTR_ID_ENTRY_SIZE)
+                .ok_or(())?,
+        ));
+    }
+
+    // Returns every (name, value) pair stored under xattr_index
+    pub(super) fn read_xattrs(&self, xattr_index: u32) -> Result<Vec<(String, Vec<u8>)>, ()> {
+        let xattr_table = self.xattr_table.as_ref().ok_or(())?;
+
+        // Start of the key/value metadata blocks
+        let kv_start = u64::from_le_bytes(xattr_table.get(0..8).ok_or(())?.try_into().unwrap());
+
+        let id_entry = self.read_xattr_id(xattr_index)?;
+
+        let mut cursor = self.metadata_cursor(kv_start, id_entry.xattr_ref)?;
+        let mut xattrs = Vec::with_capacity(id_entry.count as usize);
+
+        for _ in 0..id_entry.count {
+            let key_header = self.read_metadata(&mut cursor, 4)?;
+            let key_type = u16::from_le_bytes(key_header[0..2].try_into().unwrap());
+            let name_size = u16::from_le_bytes(key_header[2..4].try_into().unwrap()) as usize;
+
+            let prefix = XattrPrefix::try_from(key_type)?;
+            let name = self.read_metadata(&mut cursor, name_size)?;
+            let name = core::str::from_utf8(&name).map_err(|_| ())?;
+
+            let value_size = u32::from_le_bytes(
+                self.read_metadata(&mut cursor, 4)?
+                    .as_slice()
+                    .try_into()
+                    .unwrap(),
+            ) as usize;
+            let mut value = self.read_metadata(&mut cursor, value_size)?;
+
+            if key_type & XATTR_VALUE_OUT_OF_LINE != 0 {
+                // The value is a reference to where the real value is stored
+                let value_ref = u64::from_le_bytes(value.as_slice().try_into().map_err(|_| ())?);
+
+                let mut value_cursor = self.metadata_cursor(kv_start, value_ref)?;
+                let value_size = u32::from_le_bytes(
+                    self.read_metadata(&mut value_cursor, 4)?
+                        .as_slice()
+                        .try_into()
+                        .unwrap(),
+                ) as usize;
+
+                value = self.read_metadata(&mut value_cursor, value_size)?;
+            }
+
+            let mut key = prefix.as_str().to_string();
+            key.push_str(name);
+
+            xattrs.push((key, value));
+        }
+
+        return Ok(xattrs);
+    }
+}
@@ -0,0 +1,989 @@
+use core::ptr::NonNull;
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{drivers::storage::Partition, log, LogLevel};
+
+use super::vfs::{FileId, FsOps, UserCred, VNode, VNodeOperations, VNodeType, UIO};
+
+// Volume descriptors are always in 2048 byte sectors starting at sector 16, whatever the
+// logical block size of the volume is
+const DESCRIPTOR_SECTOR_SIZE: u64 = 2048;
+const FIRST_DESCRIPTOR: u64 = 16;
+const STANDARD_IDENTIFIER: &[u8; 5] = b"CD001";
+
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+// Directory record flags
+const FLAG_DIRECTORY: u8 = 0x02;
+// The file carries on in the next record
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+// Rock Ridge NM and SL flags
+const RR_CONTINUE: u8 = 0x01;
+const RR_CURRENT: u8 = 0x02;
+const RR_PARENT: u8 = 0x04;
+const RR_ROOT: u8 = 0x08;
+
+// File type bits of the mode field
+const S_IFMT: u32 = 0o170000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+
+// Stops a CE chain that points back at itself from being followed forever
+const MAX_CONTINUATIONS: usize = 16;
+
+// The file id used for the root directory, which isn't in any other directory
+const ROOT_FID: [u8; 12] = [0; 12];
+
+pub fn is_iso9660(descriptor: &[u8]) -> bool {
+    descriptor.get(1..6) == Some(STANDARD_IDENTIFIER)
+}
+
+// The byte offset of the first volume descriptor, where is_iso9660 should look
+pub const fn descriptor_offset() -> u64 {
+    FIRST_DESCRIPTOR * DESCRIPTOR_SECTOR_SIZE
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NameEncoding {
+    Iso,
+    // UCS-2 big endian names from a Joliet supplementary descriptor
+    Joliet,
+    RockRidge,
+}
+
+#[derive(Debug, Clone, Default)]
+struct RockRidge {
+    mode: Option<u32>,
+    links: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    name: Option<String>,
+    symlink: Option<String>,
+    // CL, the directory was moved elsewhere to get around the depth limit
+    child_link: Option<u32>,
+    // RE, this is the moved directory and shouldn't show up where it really is
+    relocated: bool,
+}
+
+#[derive(Debug, Clone)]
+struct DirectoryRecord {
+    name: String,
+    flags: u8,
+    // (block, length) of each extent, in order
+    extents: Vec<(u32, u32)>,
+    rock_ridge: RockRidge,
+    // where in the parent directory the first record for this file is
+    offset: u32,
+}
+
+impl DirectoryRecord {
+    fn size(&self) -> u64 {
+        self.extents.iter().map(|&(_, length)| length as u64).sum()
+    }
+
+    fn is_directory(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+
+    fn vnode_type(&self) -> VNodeType {
+        match self.rock_ridge.mode.map(|mode| mode & S_IFMT) {
+            Some(S_IFREG) => VNodeType::Regular,
+            Some(S_IFDIR) => VNodeType::Directory,
+            Some(S_IFLNK) => VNodeType::Link,
+            Some(S_IFBLK) => VNodeType::Block,
+            Some(S_IFCHR) => VNodeType::Character,
+            Some(S_IFSOCK) => VNodeType::Socket,
+            Some(_) => VNodeType::NON,
+            None if self.is_directory() => VNodeType::Directory,
+            None => VNodeType::Regular,
+        }
+    }
+
+    // Without Rock Ridge everything belongs to root and can be read by anyone
+    fn mode(&self) -> u32 {
+        match self.rock_ridge.mode {
+            Some(mode) => mode,
+            None if self.is_directory() => S_IFDIR | 0o555,
+            None => S_IFREG | 0o444,
+        }
+    }
+
+    fn permits(&self, cred: UserCred, wanted: u32) -> bool {
+        // root can do anything, except run things nobody is allowed to run
+        if cred.uid == 0 {
+            return wanted & 0o100 == 0 || self.mode() & 0o111 != 0 || self.is_directory();
+        }
+
+        let granted = if cred.uid as u32 == self.rock_ridge.uid.unwrap_or(0) {
+            self.mode()
+        } else if cred.gid as u32 == self.rock_ridge.gid.unwrap_or(0) {
+            self.mode() << 3
+        } else {
+            self.mode() << 6
+        };
+
+        return granted & wanted == wanted;
+    }
+}
+
+#[derive(Debug)]
+struct PathTableEntry {
+    extent: u32,
+    // 1 based index of the parent directory, the root is its own parent
+    parent: u16,
+    name: String,
+}
+
+#[derive(Debug)]
+pub struct Iso9660Fs {
+    partition: Partition,
+    // Everything read at mount time belongs to the disc that was in the drive back then
+    media_generation: u64,
+    block_size: u32,
+    volume_blocks: u32,
+    encoding: NameEncoding,
+    // bytes to skip at the start of every system use area, from the SP entry
+    susp_skip: usize,
+    root: DirectoryRecord,
+    path_table: Vec<PathTableEntry>,
+    volume_id: String,
+}
+
+impl Iso9660Fs {
+    pub fn new(partition: Partition) -> Result<Self, ()> {
+        let media_generation = partition.block_device().media_generation();
+
+        let mut primary: Option<Vec<u8>> = None;
+        let mut joliet: Option<Vec<u8>> = None;
+
+        // The set is short, anything longer than this has lost its terminator
+        for index in 0..64 {
+            let descriptor = Self::read_partition_bytes(
+                &partition,
+                (FIRST_DESCRIPTOR + index) * DESCRIPTOR_SECTOR_SIZE,
+                DESCRIPTOR_SECTOR_SIZE as usize,
+            )?;
+
+            if !is_iso9660(&descriptor) {
+                return Err(());
+            }
+
+            match descriptor[0] {
+                DESCRIPTOR_PRIMARY if primary.is_none() => primary = Some(descriptor),
+                DESCRIPTOR_SUPPLEMENTARY if Self::is_joliet(&descriptor) => {
+                    joliet = Some(descriptor)
+                }
+                DESCRIPTOR_TERMINATOR => break,
+                _ => {}
+            }
+        }
+
+        let primary = primary.ok_or(())?;
+
+        let mut iso = Self {
+            partition,
+            media_generation,
+            block_size: u16::from_le_bytes(primary[128..130].try_into().unwrap()) as u32,
+            volume_blocks: u32::from_le_bytes(primary[80..84].try_into().unwrap()),
+            encoding: NameEncoding::Iso,
+            susp_skip: 0,
+            root: Self::root_record(&primary)?,
+            path_table: Vec::new(),
+            volume_id: String::from_utf8_lossy(&primary[40..72])
+                .trim_end()
+                .to_string(),
+        };
+
+        if !matches!(iso.block_size, 512 | 1024 | 2048) {
+            return Err(());
+        }
+
+        // Rock Ridge lives on the primary hierarchy, and has everything Joliet has plus POSIX
+        // attributes, so it wins if both are there
+        if let Some(skip) = iso.find_susp()? {
+            iso.encoding = NameEncoding::RockRidge;
+            iso.susp_skip = skip;
+        } else if let Some(ref joliet) = joliet {
+            iso.encoding = NameEncoding::Joliet;
+            iso.root = Self::root_record(joliet)?;
+        }
+
+        let descriptor = match iso.encoding {
+            NameEncoding::Joliet => joliet.as_ref().unwrap(),
+            _ => &primary,
+        };
+
+        iso.path_table = iso.read_path_table(descriptor)?;
+
+        log!(
+            LogLevel::Debug,
+            "ISO9660: Volume \"{}\" using {:?} names",
+            iso.volume_id,
+            iso.encoding
+        );
+
+        return Ok(iso);
+    }
+
+    // Joliet is a supplementary descriptor whose escape sequences select UCS-2 level 1, 2 or 3
+    fn is_joliet(descriptor: &[u8]) -> bool {
+        matches!(&descriptor[88..91], b"%/@" | b"%/C" | b"%/E")
+    }
+
+    fn root_record(descriptor: &[u8]) -> Result<DirectoryRecord, ()> {
+        let record = &descriptor[156..190];
+
+        if record[0] != 34 || record[25] & FLAG_DIRECTORY == 0 {
+            return Err(());
+        }
+
+        return Ok(DirectoryRecord {
+            name: String::new(),
+            flags: record[25],
+            extents: alloc::vec![(
+                u32::from_le_bytes(record[2..6].try_into().unwrap()),
+                u32::from_le_bytes(record[10..14].try_into().unwrap()),
+            )],
+            rock_ridge: RockRidge::default(),
+            offset: 0,
+        });
+    }
+
+    // CDs have 2048 byte sectors, an image on a hard disk has 512 byte ones
+    fn read_partition_bytes(partition: &Partition, offset: u64, len: usize) -> Result<Vec<u8>, ()> {
+        let sector_size = partition.block_device().sector_size() as u64;
+
+        let first_sector = offset / sector_size;
+        let last_sector = (offset + len as u64).div_ceil(sector_size);
+        let offset_in_sector = (offset % sector_size) as usize;
+
+        let sectors = partition.read(first_sector, (last_sector - first_sector) as usize)?;
+
+        return Ok(sectors
+            .get(offset_in_sector..offset_in_sector + len)
+            .ok_or(())?
+            .to_vec());
+    }
+
+    fn read_blocks(&self, block: u32, len: usize) -> Result<Vec<u8>, ()> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        if block as u64 + (len as u64).div_ceil(self.block_size as u64) > self.volume_blocks as u64
+        {
+            return Err(());
+        }
+
+        return self.read_bytes(block as u64 * self.block_size as u64, len);
+    }
+
+    // Reads from a disc that has been swapped out since the mount would hand back the wrong data
+    fn read_bytes(&self, offset: u64, len: usize) -> Result<Vec<u8>, ()> {
+        if self.partition.block_device().media_generation() != self.media_generation {
+            log!(
+                LogLevel::Warn,
+                "ISO9660: The disc holding \"{}\" was changed",
+                self.volume_id
+            );
+            return Err(());
+        }
+
+        return Self::read_partition_bytes(&self.partition, offset, len);
+    }
+
+    // The SP entry in the root's "." record says whether SUSP (and so Rock Ridge) is in use, and
+    // how many bytes to skip in every system use area
+    fn find_susp(&self) -> Result<Option<usize>, ()> {
+        let (block, length) = self.root.extents[0];
+        let directory = self.read_blocks(block, length.min(self.block_size) as usize)?;
+
+        let record_length = directory[0] as usize;
+        let name_length = *directory.get(32).ok_or(())? as usize;
+
+        let system_use_start = 33 + name_length + (name_length + 1) % 2;
+        let system_use = directory
+            .get(system_use_start..record_length)
+            .unwrap_or(&[]);
+
+        if system_use.len() >= 7
+            && &system_use[0..2] == b"SP"
+            && system_use[4] == 0xBE
+            && system_use[5] == 0xEF
+        {
+            return Ok(Some(system_use[6] as usize));
+        }
+
+        return Ok(None);
+    }
+
+    fn read_path_table(&self, descriptor: &[u8]) -> Result<Vec<PathTableEntry>, ()> {
+        let size = u32::from_le_bytes(descriptor[132..136].try_into().unwrap()) as usize;
+        // The type L table is little endian, which saves converting everything
+        let location = u32::from_le_bytes(descriptor[140..144].try_into().unwrap());
+
+        let table = self.read_blocks(location, size)?;
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        while offset + 8 <= table.len() {
+            let name_length = table[offset] as usize;
+
+            if name_length == 0 {
+                break;
+            }
+
+            let name = table.get(offset + 8..offset + 8 + name_length).ok_or(())?;
+
+            let parent = u16::from_le_bytes(table[offset + 6..offset + 8].try_into().unwrap());
+
+            // Parents always come before their children
+            if parent == 0 || parent as usize > entries.len() + 1 {
+                return Err(());
+            }
+
+            entries.push(PathTableEntry {
+                extent: u32::from_le_bytes(table[offset + 2..offset + 6].try_into().unwrap()),
+                parent,
+                name: match entries.is_empty() {
+                    // The root's name is a single 0 byte
+                    true => String::new(),
+                    false => self.decode_name(name, NameEncoding::Iso),
+                },
+            });
+
+            offset += 8 + name_length + name_length % 2;
+        }
+
+        if entries.first().map(|root| root.extent) != Some(self.root.extents[0].0) {
+            return Err(());
+        }
+
+        return Ok(entries);
+    }
+
+    fn decode_name(&self, name: &[u8], fallback: NameEncoding) -> String {
+        let encoding = match self.encoding {
+            NameEncoding::Joliet => NameEncoding::Joliet,
+            _ => fallback,
+        };
+
+        let name = match encoding {
+            NameEncoding::Joliet => {
+                let characters = name
+                    .chunks_exact(2)
+                    .map(|bytes| u16::from_be_bytes(bytes.try_into().unwrap()))
+                    .collect::<Vec<u16>>();
+
+                String::from_utf16_lossy(&characters)
+            }
+            _ => String::from_utf8_lossy(name).into_owned(),
+        };
+
+        // Drop the ";1" version number, and the trailing dot of names with no extension
+        let name = match name.rfind(';') {
+            Some(index) => &name[..index],
+            None => &name,
+        };
+
+        return name.strip_suffix('.').unwrap_or(name).to_string();
+    }
+
+    // Parses the SUSP entries of a record, following CE continuations
+    fn parse_rock_ridge(&self, system_use: &[u8]) -> Result<RockRidge, ()> {
+        let mut rock_ridge = RockRidge::default();
+        let mut area = system_use.to_vec();
+        let mut continuations = 0;
+
+        let mut name = String::new();
+        let mut has_name = false;
+        let mut symlink = String::new();
+        let mut has_symlink = false;
+        // Whether the last SL component was cut short and carries on in the next one
+        let mut component_continues = false;
+
+        loop {
+            let mut continuation: Option<(u32, u32, u32)> = None;
+            let mut offset = 0;
+
+            while offset + 4 <= area.len() {
+                let signature = &area[offset..offset + 2];
+                let length = area[offset + 2] as usize;
+
+                if length < 4 || offset + length > area.len() {
+                    break;
+                }
+
+                let entry = &area[offset..offset + length];
+
+                match signature {
+                    b"PX" if length >= 36 => {
+                        let both_endian =
+                            |at: usize| u32::from_le_bytes(entry[at..at + 4].try_into().unwrap());
+
+                        rock_ridge.mode = Some(both_endian(4));
+                        rock_ridge.links = Some(both_endian(12));
+                        rock_ridge.uid = Some(both_endian(20));
+                        rock_ridge.gid = Some(both_endian(28));
+                    }
+                    b"NM" if length >= 5 => {
+                        // "." and ".." are never given names of their own
+                        if entry[4] & (RR_CURRENT | RR_PARENT) == 0 {
+                            name.push_str(&String::from_utf8_lossy(&entry[5..]));
+                            has_name = true;
+                        }
+                    }
+                    b"SL" if length >= 5 => {
+                        let mut component = 5;
+
+                        while component + 2 <= entry.len() {
+                            let flags = entry[component];
+                            let component_length = entry[component + 1] as usize;
+                            let content = entry
+                                .get(component + 2..component + 2 + component_length)
+                                .ok_or(())?;
+
+                            if has_symlink && !component_continues && !symlink.ends_with('/') {
+                                symlink.push('/');
+                            }
+
+                            if flags & RR_CURRENT != 0 {
+                                symlink.push('.');
+                            } else if flags & RR_PARENT != 0 {
+                                symlink.push_str("..");
+                            } else if flags & RR_ROOT != 0 {
+                                symlink.push('/');
+                            } else {
+                                symlink.push_str(&String::from_utf8_lossy(content));
+                            }
+
+                            has_symlink = true;
+                            component_continues = flags & RR_CONTINUE != 0;
+                            component += 2 + component_length;
+                        }
+                    }
+                    b"CL" if length >= 12 => {
+                        rock_ridge.child_link =
+                            Some(u32::from_le_bytes(entry[4..8].try_into().unwrap()));
+                    }
+                    b"RE" => rock_ridge.relocated = true,
+                    b"CE" if length >= 28 => {
+                        continuation = Some((
+                            u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+                            u32::from_le_bytes(entry[12..16].try_into().unwrap()),
+                            u32::from_le_bytes(entry[20..24].try_into().unwrap()),
+                        ));
+                    }
+                    b"ST" => break,
+                    _ => {}
+                }
+
+                offset += length;
+            }
+
+            let Some((block, offset, length)) = continuation else {
+                break;
+            };
+
+            continuations += 1;
+
+            if continuations > MAX_CONTINUATIONS {
+                return Err(());
+            }
+
+            let blocks = self.read_blocks(block, (offset + length) as usize)?;
+            area = blocks[offset as usize..].to_vec();
+        }
+
+        if has_name {
+            rock_ridge.name = Some(name);
+        }
+
+        if has_symlink {
+            rock_ridge.symlink = Some(symlink);
+        }
+
+        return Ok(rock_ridge);
+    }
+
+    fn read_directory(&self, directory: &DirectoryRecord) -> Result<Vec<DirectoryRecord>, ()> {
+        let mut records: Vec<DirectoryRecord> = Vec::new();
+        let mut directory_offset = 0u32;
+
+        for &(block, length) in directory.extents.iter() {
+            let bytes = self.read_blocks(block, length as usize)?;
+            let mut offset = 0;
+
+            while offset < bytes.len() {
+                let record_length = bytes[offset] as usize;
+
+                // Records never cross a block, the rest of this one is padding
+                if record_length == 0 {
+                    offset = (offset / self.block_size as usize + 1) * self.block_size as usize;
+                    continue;
+                }
+
+                let record = bytes.get(offset..offset + record_length).ok_or(())?;
+                let record_offset = directory_offset + offset as u32;
+                offset += record_length;
+
+                if record.len() < 34 {
+                    return Err(());
+                }
+
+                let name_length = record[32] as usize;
+                let raw_name = record.get(33..33 + name_length).ok_or(())?;
+
+                // "." and ".."
+                if name_length == 1 && (raw_name[0] == 0 || raw_name[0] == 1) {
+                    continue;
+                }
+
+                let flags = record[25];
+                let extent = (
+                    u32::from_le_bytes(record[2..6].try_into().unwrap()),
+                    u32::from_le_bytes(record[10..14].try_into().unwrap()),
+                );
+
+                // The second and later extents of a file get added to the record before them
+                if let Some(previous) = records.last_mut() {
+                    if previous.flags & FLAG_MULTI_EXTENT != 0 {
+                        previous.extents.push(extent);
+                        previous.flags = flags;
+                        continue;
+                    }
+                }
+
+                let rock_ridge = match self.encoding {
+                    NameEncoding::RockRidge => {
+                        let system_use_start =
+                            33 + name_length + (name_length + 1) % 2 + self.susp_skip;
+
+                        self.parse_rock_ridge(record.get(system_use_start..).unwrap_or(&[]))?
+                    }
+                    _ => RockRidge::default(),
+                };
+
+                if rock_ridge.relocated {
+                    continue;
+                }
+
+                let name = match rock_ridge.name {
+                    Some(ref name) => name.clone(),
+                    None => self.decode_name(raw_name, NameEncoding::Iso),
+                };
+
+                let mut record = DirectoryRecord {
+                    name,
+                    flags,
+                    extents: alloc::vec![extent],
+                    rock_ridge,
+                    offset: record_offset,
+                };
+
+                // The real directory is somewhere else, its "." record says how big it is
+                if let Some(child) = record.rock_ridge.child_link {
+                    let dot = self.read_blocks(child, 34)?;
+
+                    record.flags |= FLAG_DIRECTORY;
+                    record.extents =
+                        alloc::vec![(child, u32::from_le_bytes(dot[10..14].try_into().unwrap()))];
+                }
+
+                records.push(record);
+            }
+
+            directory_offset += length;
+        }
+
+        return Ok(records);
+    }
+
+    fn names_equal(&self, a: &str, b: &str) -> bool {
+        match self.encoding {
+            // Plain ISO names are all upper case on disk, so they're matched without case
+            NameEncoding::Iso => a.eq_ignore_ascii_case(b),
+            _ => a == b,
+        }
+    }
+
+    fn find_entry(&self, directory: &DirectoryRecord, name: &str) -> Result<DirectoryRecord, ()> {
+        return self
+            .read_directory(directory)?
+            .into_iter()
+            .find(|record| self.names_equal(&record.name, name))
+            .ok_or(());
+    }
+
+    // Walks the path table to find a directory's extent without reading every directory on the
+    // way. Rock Ridge names aren't in the path table, so this only works for ISO and Joliet.
+    fn path_table_lookup(&self, parts: &[&str]) -> Option<u32> {
+        if self.encoding == NameEncoding::RockRidge {
+            return None;
+        }
+
+        let mut parent = 1;
+
+        for part in parts {
+            let index = self
+                .path_table
+                .iter()
+                .enumerate()
+                .position(|(index, entry)| {
+                    index != 0 && entry.parent == parent && self.names_equal(&entry.name, part)
+                })?;
+
+            parent = index as u16 + 1;
+        }
+
+        return Some(self.path_table[parent as usize - 1].extent);
+    }
+
+    fn make_fid(directory: &DirectoryRecord, record: &DirectoryRecord) -> Option<FileId> {
+        let (block, length) = directory.extents[0];
+
+        let mut bytes = [0u8; 12];
+        bytes[0..4].copy_from_slice(&block.to_le_bytes());
+        bytes[4..8].copy_from_slice(&length.to_le_bytes());
+        bytes[8..12].copy_from_slice(&record.offset.to_le_bytes());
+
+        return FileId::new(&bytes).ok();
+    }
+}
+
+impl FsOps for Iso9660Fs {
+    fn mount(&mut self, _path: &str, data: &mut *mut u8, _vfsp: NonNull<super::vfs::Vfs>) {
+        *data = core::ptr::addr_of!(*self) as *mut u8;
+    }
+
+    fn unmount(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {}
+
+    fn root(&mut self, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        let root = Iso9660Node {
+            record: self.root.clone(),
+        };
+
+        return VNode::new(Box::new(root), VNodeType::Directory, vfsp);
+    }
+
+    fn statfs(&mut self, _vfsp: NonNull<super::vfs::Vfs>) -> super::vfs::StatFs {
+        todo!("ISO9660 STATFS");
+    }
+
+    // ISO9660 is read-only, there's never anything to write back
+    fn sync(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {}
+
+    // A file id is the first extent of the directory holding the file, and where in that
+    // directory the file's record is
+    fn fid(&mut self, path: &str, _vfsp: NonNull<super::vfs::Vfs>) -> Option<FileId> {
+        let parts = path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect::<Vec<&str>>();
+
+        let Some((last, parents)) = parts.split_last() else {
+            return FileId::new(&ROOT_FID).ok();
+        };
+
+        let directory = match self.path_table_lookup(parents) {
+            Some(extent) => {
+                // The path table doesn't have sizes, the directory's "." record does
+                let dot = self.read_blocks(extent, 34).ok()?;
+
+                DirectoryRecord {
+                    extents: alloc::vec![(
+                        extent,
+                        u32::from_le_bytes(dot[10..14].try_into().unwrap())
+                    )],
+                    ..self.root.clone()
+                }
+            }
+            None => {
+                let mut directory = self.root.clone();
+
+                for part in parents {
+                    directory = self.find_entry(&directory, part).ok()?;
+
+                    if !directory.is_directory() {
+                        return None;
+                    }
+                }
+
+                directory
+            }
+        };
+
+        let record = self.find_entry(&directory, last).ok()?;
+
+        return Self::make_fid(&directory, &record);
+    }
+
+    fn vget(&mut self, fid: FileId, vfsp: NonNull<super::vfs::Vfs>) -> Result<VNode, ()> {
+        let bytes = fid.as_bytes();
+
+        if bytes.len() != ROOT_FID.len() {
+            return Err(());
+        }
+
+        if bytes == ROOT_FID {
+            return Ok(self.root(vfsp));
+        }
+
+        let directory = DirectoryRecord {
+            extents: alloc::vec![(
+                u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+                u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            )],
+            ..self.root.clone()
+        };
+        let offset = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+
+        let record = self
+            .read_directory(&directory)?
+            .into_iter()
+            .find(|record| record.offset == offset)
+            .ok_or(())?;
+
+        return Ok(Iso9660Node { record }.into_vnode(vfsp));
+    }
+}
+
+struct Iso9660Node {
+    record: DirectoryRecord,
+}
+
+impl Iso9660Node {
+    fn get_fs<'a>(vp: NonNull<VNode>) -> &'a Iso9660Fs {
+        unsafe { &*(*vp.as_ptr()).parent_vfs.as_mut().data.cast::<Iso9660Fs>() }
+    }
+
+    fn into_vnode(self, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        let typ = self.record.vnode_type();
+
+        return VNode::new(Box::new(self), typ, vfsp);
+    }
+}
+
+impl VNodeOperations for Iso9660Node {
+    fn open(&mut self, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn close(&mut self, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn read(
+        &mut self,
+        count: usize,
+        offset: usize,
+        _f: u32,
+        _c: UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        if self.record.is_directory() || self.record.rock_ridge.symlink.is_some() {
+            return Err(());
+        }
+
+        let end = offset.checked_add(count).ok_or(())?;
+
+        if end as u64 > self.record.size() {
+            return Err(());
+        }
+
+        let iso = Self::get_fs(vp);
+
+        let mut data = Vec::with_capacity(count);
+        let mut extent_start = 0usize;
+
+        // Each extent starts on a block boundary, so they're read one at a time
+        for &(block, length) in self.record.extents.iter() {
+            let extent_end = extent_start + length as usize;
+
+            if extent_end > offset && extent_start < end {
+                let from = offset.max(extent_start) - extent_start;
+                let to = end.min(extent_end) - extent_start;
+
+                let bytes = iso.read_bytes(
+                    block as u64 * iso.block_size as u64 + from as u64,
+                    to - from,
+                )?;
+
+                data.extend(bytes);
+            }
+
+            extent_start = extent_end;
+        }
+
+        return Ok(Arc::from(data));
+    }
+
+    fn write(&mut self, _offset: usize, _buf: &[u8], _f: u32, _c: UserCred, _vp: NonNull<VNode>) {
+        log!(LogLevel::Warn, "ISO9660: Volumes are read-only");
+    }
+
+    fn ioctl(&mut self, _com: u32, _d: *mut u8, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn getattr(&mut self, _c: UserCred, vp: NonNull<VNode>) -> super::vfs::VAttr {
+        let iso = Self::get_fs(vp);
+        let size = self.record.size();
+
+        // The recording date isn't kept around, so there are no timestamps
+        return super::vfs::VAttr {
+            typ: self.record.vnode_type(),
+            mode: self.record.mode() as u16,
+            uid: self.record.rock_ridge.uid.unwrap_or(0) as u16,
+            gid: self.record.rock_ridge.gid.unwrap_or(0) as u16,
+            fs_id: 0,
+            node_id: self.record.extents.first().map_or(0, |&(block, _)| block),
+            link_count: self.record.rock_ridge.links.unwrap_or(1) as u16,
+            size: size.min(u32::MAX as u64) as u32,
+            block_size: iso.block_size,
+            last_access: 0,
+            last_modify: 0,
+            last_chg: 0,
+            rdev: (),
+            used_blocks: size.div_ceil(iso.block_size as u64) as u32,
+        };
+    }
+
+    fn setattr(&mut self, _va: super::vfs::VAttr, _c: UserCred, _vp: NonNull<VNode>) {
+        log!(LogLevel::Warn, "ISO9660: Volumes are read-only");
+    }
+
+    fn access(&mut self, m: u32, c: UserCred, _vp: NonNull<VNode>) -> Result<(), ()> {
+        // Nothing on the volume can be written, whatever its mode says
+        if m & 0o2 != 0 {
+            return Err(());
+        }
+
+        // R_OK and X_OK match the "other" permission bits, shifted up they are the owner ones
+        if !self.record.permits(c, (m & 0o7) << 6) {
+            return Err(());
+        }
+
+        return Ok(());
+    }
+
+    fn lookup(&mut self, nm: &str, _c: UserCred, vp: NonNull<VNode>) -> Result<VNode, ()> {
+        if !self.record.is_directory() {
+            return Err(());
+        }
+
+        let iso = Self::get_fs(vp);
+
+        let record = iso.find_entry(&self.record, nm)?;
+
+        return Ok(Iso9660Node { record }.into_vnode(unsafe { (*vp.as_ptr()).parent_vfs }));
+    }
+
+    fn create(
+        &mut self,
+        _nm: &str,
+        _va: super::vfs::VAttr,
+        _e: u32,
+        _m: u32,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        return Err(());
+    }
+
+    fn link(
+        &mut self,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        log!(LogLevel::Warn, "ISO9660: Volumes are read-only");
+    }
+
+    fn rename(
+        &mut self,
+        _nm: &str,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        log!(LogLevel::Warn, "ISO9660: Volumes are read-only");
+    }
+
+    fn mkdir(
+        &mut self,
+        _nm: &str,
+        _va: super::vfs::VAttr,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        return Err(());
+    }
+
+    fn readdir(&mut self, _uiop: *const UIO, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn symlink(
+        &mut self,
+        _link_name: &str,
+        _va: super::vfs::VAttr,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        log!(LogLevel::Warn, "ISO9660: Volumes are read-only");
+    }
+
+    fn readlink(&mut self, uiop: *const UIO, _c: UserCred, _vp: NonNull<VNode>) {
+        // Only Rock Ridge has symlinks, the target comes from the SL entries
+        if let Some(ref target) = self.record.rock_ridge.symlink {
+            unsafe { UIO::uiomove(uiop, target.as_bytes()) };
+        }
+    }
+
+    fn fsync(&mut self, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn getxattr(
+        &mut self,
+        _name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        return Err(());
+    }
+
+    fn listxattr(&mut self, _c: UserCred, _vp: NonNull<VNode>) -> Result<Vec<String>, ()> {
+        return Ok(Vec::new());
+    }
+
+    fn setxattr(
+        &mut self,
+        _name: &str,
+        _value: &[u8],
+        _f: u32,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<(), ()> {
+        return Err(());
+    }
+
+    fn len(&self, _vp: NonNull<VNode>) -> usize {
+        match self.record.rock_ridge.symlink {
+            Some(ref target) => target.len(),
+            None => self.record.size() as usize,
+        }
+    }
+}
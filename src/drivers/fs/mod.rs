@@ -1,4 +1,208 @@
+pub mod cappfs;
+pub mod cpio;
 pub mod devfs;
+pub mod exfat;
+pub mod ext2;
 pub mod fat;
 pub mod initramfs;
+pub mod iso9660;
+pub mod tar;
 pub mod vfs;
+
+use alloc::{boxed::Box, format, string::String};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::LogLevel;
+
+use self::vfs::{add_vfs, FsOps};
+use super::storage::Partition;
+
+const GPT_LINUX_DATA: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+const GPT_EFI_SYSTEM: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+const GPT_BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+
+const MBR_LINUX: u8 = 0x83;
+const MBR_EFI_SYSTEM: u8 = 0xEF;
+// ESP, FAT32 (CHS and LBA), FAT16 (LBA and large) and exFAT/NTFS
+const MBR_DATA_TYPES: [u8; 6] = [0xEF, 0x0B, 0x0C, 0x0E, 0x06, 0x07];
+
+// Set once an ESP is mounted at /mnt, any other one is just another data volume
+static ESP_MOUNTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsType {
+    CappFs,
+    Fat,
+    ExFat,
+    Ext2,
+    Iso9660,
+}
+
+// Works out which driver a partition needs from its boot sector. exFAT is checked first since
+// its boot sector still starts with a jump and ends with 0xAA55 like a FAT one does.
+pub fn detect_fs(partition: &Partition) -> Option<FsType> {
+    let sector_size = partition.block_device().sector_size() as u64;
+
+    // Every driver but the ISO9660 one assumes 512 byte sectors, so that's all bigger sectors can
+    // hold as far as we're concerned
+    if sector_size != 512 {
+        return partition
+            .read(iso9660::descriptor_offset() / sector_size, 1)
+            .is_ok_and(|descriptor| iso9660::is_iso9660(&descriptor))
+            .then_some(FsType::Iso9660);
+    }
+
+    let boot_sector = partition.read(0, 1).ok()?;
+
+    if cappfs::is_cappfs(&boot_sector) {
+        return Some(FsType::CappFs);
+    }
+
+    if exfat::is_exfat(&boot_sector) {
+        return Some(FsType::ExFat);
+    }
+
+    // ext2 leaves the boot sector alone, its superblock is 1024 bytes in
+    if partition
+        .read(2, 2)
+        .is_ok_and(|superblock| ext2::is_ext2(&superblock))
+    {
+        return Some(FsType::Ext2);
+    }
+
+    // Hybrid ISOs carry an MBR too, so this has to come before the FAT check
+    if partition
+        .read(iso9660::descriptor_offset() / sector_size, 1)
+        .is_ok_and(|descriptor| iso9660::is_iso9660(&descriptor))
+    {
+        return Some(FsType::Iso9660);
+    }
+
+    if boot_sector.get(510..512) == Some(&[0x55, 0xAA]) {
+        return Some(FsType::Fat);
+    }
+
+    return None;
+}
+
+// Decides where a freshly scanned partition lives in the VFS, Linux partitions become /sysroot,
+// the first ESP is put at /mnt and every other data volume at /mnt/<name>.
+pub fn mount_partition(name: &str, partition: Partition) {
+    let (is_sysroot, is_data) = match partition {
+        Partition::GPTPartition((entry, _)) => (
+            entry.partition_type_guid == GPT_LINUX_DATA,
+            // exFAT volumes are normally tagged as basic data rather than as an ESP
+            entry.partition_type_guid == GPT_EFI_SYSTEM
+                || entry.partition_type_guid == GPT_BASIC_DATA,
+        ),
+        Partition::MBRPartition((entry, _)) => (
+            entry.partition_type == MBR_LINUX,
+            MBR_DATA_TYPES.contains(&entry.partition_type),
+        ),
+        // Unpartitioned media (CDs) can only hold data
+        Partition::Disk(_) => (false, true),
+    };
+
+    if is_sysroot {
+        mount_sysroot(partition);
+    } else if is_data {
+        mount_data(name, partition);
+    }
+}
+
+fn mount_sysroot(partition: Partition) {
+    let sysroot: Result<Box<dyn FsOps>, ()> = match detect_fs(&partition) {
+        Some(FsType::Ext2) => ext2::Ext2Fs::new(partition).map(|fs| Box::new(fs) as Box<dyn FsOps>),
+        Some(FsType::CappFs) => {
+            cappfs::CappFs::new(partition).map(|fs| Box::new(fs) as Box<dyn FsOps>)
+        }
+        // The volume takes its UUID from the partition, we have no RNG yet, so only GPT
+        // partitions with 512 byte sectors can be formatted
+        None if crate::KERNEL_FEATURES.format_sysroot
+            && partition.block_device().sector_size() == 512 =>
+        {
+            match partition {
+                Partition::GPTPartition((entry, _)) => {
+                    cappfs::CappFs::format(partition, entry.unique_partition_guid, "CappuccinOS")
+                        .map(|fs| Box::new(fs) as Box<dyn FsOps>)
+                }
+                Partition::MBRPartition(_) | Partition::Disk(_) => return,
+            }
+        }
+        _ => return,
+    };
+
+    match sysroot {
+        Ok(sysroot) => {
+            let _ = add_vfs("/sysroot", sysroot);
+        }
+        Err(_) => crate::log!(LogLevel::Error, "Failed to mount /sysroot"),
+    }
+}
+
+// The boot files are expected at /mnt, so only the ESP may go there
+fn data_mount_point(name: &str, partition: &Partition) -> String {
+    let is_esp = match partition {
+        Partition::GPTPartition((entry, _)) => entry.partition_type_guid == GPT_EFI_SYSTEM,
+        Partition::MBRPartition((entry, _)) => entry.partition_type == MBR_EFI_SYSTEM,
+        Partition::Disk(_) => false,
+    };
+
+    if is_esp && !ESP_MOUNTED.load(Ordering::Acquire) {
+        return String::from("/mnt");
+    }
+
+    return format!("/mnt/{name}");
+}
+
+fn add_data_vfs(mount_point: &str, fs: Box<dyn FsOps>) {
+    if add_vfs(mount_point, fs).is_ok() && mount_point == "/mnt" {
+        ESP_MOUNTED.store(true, Ordering::Release);
+    }
+}
+
+fn mount_data(name: &str, partition: Partition) {
+    let mount_point = data_mount_point(name, &partition);
+
+    match detect_fs(&partition) {
+        Some(FsType::ExFat) => {
+            if let Ok(exfat_fs) = exfat::ExFatFs::new(partition) {
+                add_data_vfs(&mount_point, Box::new(exfat_fs));
+            }
+
+            return;
+        }
+        Some(FsType::Iso9660) => {
+            if let Ok(iso_fs) = iso9660::Iso9660Fs::new(partition) {
+                add_data_vfs(&mount_point, Box::new(iso_fs));
+            }
+
+            return;
+        }
+        // FAT is the fallback for anything with a boot sector, which only works with 512 byte
+        // sectors
+        Some(FsType::Fat) | None if partition.block_device().sector_size() == 512 => {}
+        _ => return,
+    }
+
+    let fat_fs = fat::FatFs::new(partition);
+
+    if fat_fs.is_err() {
+        return;
+    }
+
+    let mut fat_fs = fat_fs.unwrap();
+
+    // Only report for now, repairs are left to an explicit fsck
+    if let Ok(report) = fat_fs.fsck(false) {
+        if !report.is_clean() {
+            crate::log!(
+                LogLevel::Warn,
+                "{name} has {} problem(s), run fsck with repair",
+                report.problems.len()
+            );
+        }
+    }
+
+    add_data_vfs(&mount_point, Box::new(fat_fs));
+}
@@ -0,0 +1,757 @@
+use core::{ops::Range, ptr::NonNull};
+
+use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{log, LogLevel};
+
+use super::vfs::{
+    add_vfs, vfs_open, FileId, FsOps, UserCred, VNode, VNodeOperations, VNodeType, UIO,
+};
+
+const BLOCK_SIZE: usize = 512;
+
+// POSIX ustar has "ustar\0" followed by "00", old GNU tar has "ustar " followed by " \0"
+const USTAR_MAGIC: &[u8; 5] = b"ustar";
+
+// Type flags
+const TYPE_REGULAR: u8 = b'0';
+// Really old archives use a null byte for regular files
+const TYPE_REGULAR_OLD: u8 = 0;
+const TYPE_HARDLINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_CHARACTER: u8 = b'3';
+const TYPE_BLOCK: u8 = b'4';
+const TYPE_DIRECTORY: u8 = b'5';
+const TYPE_FIFO: u8 = b'6';
+const TYPE_CONTIGUOUS: u8 = b'7';
+// pax headers for the next entry, and for every entry after this one
+const TYPE_PAX: u8 = b'x';
+const TYPE_PAX_GLOBAL: u8 = b'g';
+// GNU long names, the data is the name of the next entry
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+const TYPE_GNU_LONG_LINK: u8 = b'K';
+
+// File type bits of the mode field
+const S_IFMT: u32 = 0o170000;
+const S_IFIFO: u32 = 0o010000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+
+pub fn is_tar(bytes: &[u8]) -> bool {
+    bytes.len() >= BLOCK_SIZE
+        && &bytes[257..262] == USTAR_MAGIC
+        && TarHeader::checksum_valid(&bytes[..BLOCK_SIZE])
+}
+
+// Mounts a tar, or gzipped tar, that was loaded as a limine module
+pub fn mount_module(module_name: &str, mount_point: &str) -> Result<(), ()> {
+    let module = crate::libs::limine::get_module(module_name).ok_or(())?;
+
+    let image: &'static [u8] =
+        unsafe { core::slice::from_raw_parts(module.addr(), module.size() as usize) };
+
+    return add_vfs(
+        mount_point,
+        Box::new(TarFs::new(unpack(Cow::Borrowed(image))?)?),
+    );
+}
+
+// Mounts every tar module in limine.conf, at the path in its `module_cmdline` or at
+// /mnt/<name> when it doesn't have one
+pub fn mount_modules() {
+    for module in crate::libs::limine::get_modules() {
+        let Ok(path) = core::str::from_utf8(module.path()) else {
+            continue;
+        };
+
+        // Already mounted as /
+        if path.contains("initramfs.img") {
+            continue;
+        }
+
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+
+        let image: &'static [u8] =
+            unsafe { core::slice::from_raw_parts(module.addr(), module.size() as usize) };
+
+        let archive = match unpack(Cow::Borrowed(image)) {
+            Ok(archive) if is_tar(&archive) => archive,
+            _ => continue,
+        };
+
+        let mount_point = match core::str::from_utf8(module.cmdline()) {
+            Ok(cmdline) if cmdline.starts_with('/') => cmdline.to_string(),
+            _ => format!("/mnt/{}", archive_stem(file_name)),
+        };
+
+        let result = TarFs::new(archive).and_then(|tar_fs| add_vfs(&mount_point, Box::new(tar_fs)));
+
+        if result.is_err() {
+            log!(LogLevel::Error, "tar: Failed to mount module {file_name}");
+        }
+    }
+}
+
+// Mounts a tar, or gzipped tar, that's a file on an already mounted filesystem
+pub fn mount_file(path: &str, mount_point: &str) -> Result<(), ()> {
+    let data = vfs_open(path)?
+        .open(0, UserCred { uid: 0, gid: 0 })
+        .read_all(0, 0)?;
+
+    let archive = unpack(Cow::Owned(data.to_vec()))?;
+
+    return add_vfs(mount_point, Box::new(TarFs::new(archive)?));
+}
+
+// The name a mounted archive is known by, `tools.tar.gz` becomes `tools`
+pub fn archive_stem(file_name: &str) -> &str {
+    return file_name.split('.').next().unwrap_or(file_name);
+}
+
+fn unpack(image: Cow<'static, [u8]>) -> Result<Cow<'static, [u8]>, ()> {
+    if crate::libs::gzip::is_gzip(&image) {
+        return Ok(Cow::Owned(crate::libs::gzip::uncompress_gzip(&image)?));
+    }
+
+    return Ok(image);
+}
+
+// Numeric fields are octal text, unless the top bit of the first byte is set, then the rest is a
+// big endian binary number (a GNU extension for sizes over 8GiB)
+fn parse_number(field: &[u8]) -> Result<u64, ()> {
+    if field.first().is_some_and(|&byte| byte & 0x80 != 0) {
+        let mut value: u64 = (field[0] & 0x7F) as u64;
+
+        for &byte in &field[1..] {
+            value = value.checked_mul(256).ok_or(())? | byte as u64;
+        }
+
+        return Ok(value);
+    }
+
+    let digits = core::str::from_utf8(field).map_err(|_| ())?;
+    let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+
+    if digits.is_empty() {
+        return Ok(0);
+    }
+
+    return u64::from_str_radix(digits, 8).map_err(|_| ());
+}
+
+// Strings are null terminated, unless they fill the whole field
+fn parse_string(field: &[u8]) -> Result<String, ()> {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+
+    return Ok(core::str::from_utf8(&field[..end])
+        .map_err(|_| ())?
+        .to_string());
+}
+
+#[derive(Debug, Clone, Default)]
+struct PaxOverrides {
+    path: Option<String>,
+    link_path: Option<String>,
+    size: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mtime: Option<u64>,
+}
+
+impl PaxOverrides {
+    // Records look like "<length> <key>=<value>\n", where length counts the whole record
+    fn parse(&mut self, data: &[u8]) -> Result<(), ()> {
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let space = data[offset..]
+                .iter()
+                .position(|&byte| byte == b' ')
+                .ok_or(())?;
+
+            let length: usize = core::str::from_utf8(&data[offset..offset + space])
+                .map_err(|_| ())?
+                .parse()
+                .map_err(|_| ())?;
+
+            if length <= space + 1 || offset + length > data.len() {
+                return Err(());
+            }
+
+            let record = core::str::from_utf8(&data[offset + space + 1..offset + length - 1])
+                .map_err(|_| ())?;
+            let (key, value) = record.split_once('=').ok_or(())?;
+
+            match key {
+                "path" => self.path = Some(value.to_string()),
+                "linkpath" => self.link_path = Some(value.to_string()),
+                "size" => self.size = Some(value.parse().map_err(|_| ())?),
+                "uid" => self.uid = Some(value.parse().map_err(|_| ())?),
+                "gid" => self.gid = Some(value.parse().map_err(|_| ())?),
+                // mtime can have a fractional part, we only care about the seconds
+                "mtime" => {
+                    let seconds = value.split('.').next().unwrap_or("0");
+                    self.mtime = Some(seconds.parse().map_err(|_| ())?)
+                }
+                _ => {}
+            }
+
+            offset += length;
+        }
+
+        return Ok(());
+    }
+
+    // Values from a local header win over the global ones
+    fn merge(&self, local: &PaxOverrides) -> PaxOverrides {
+        return PaxOverrides {
+            path: local.path.clone().or(self.path.clone()),
+            link_path: local.link_path.clone().or(self.link_path.clone()),
+            size: local.size.or(self.size),
+            uid: local.uid.or(self.uid),
+            gid: local.gid.or(self.gid),
+            mtime: local.mtime.or(self.mtime),
+        };
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct TarHeader {
+    name: String,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u64,
+    type_flag: u8,
+    link_name: String,
+}
+
+impl TarHeader {
+    fn checksum_valid(block: &[u8]) -> bool {
+        let Ok(recorded) = parse_number(&block[148..156]) else {
+            return false;
+        };
+
+        // The checksum field itself counts as eight spaces
+        let sum: u64 = block
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| match i {
+                148..156 => b' ' as u64,
+                _ => byte as u64,
+            })
+            .sum();
+
+        return sum == recorded;
+    }
+
+    fn from_bytes(block: &[u8]) -> Result<Self, ()> {
+        if block.len() < BLOCK_SIZE || !Self::checksum_valid(block) {
+            return Err(());
+        }
+
+        let mut name = parse_string(&block[0..100])?;
+
+        // ustar splits long names, the prefix goes in front with a slash between them
+        if &block[257..262] == USTAR_MAGIC && &block[263..265] == b"00" {
+            let prefix = parse_string(&block[345..500])?;
+
+            if !prefix.is_empty() {
+                name = alloc::format!("{prefix}/{name}");
+            }
+        }
+
+        return Ok(Self {
+            name,
+            mode: parse_number(&block[100..108])? as u32,
+            uid: parse_number(&block[108..116])? as u32,
+            gid: parse_number(&block[116..124])? as u32,
+            size: parse_number(&block[124..136])?,
+            mtime: parse_number(&block[136..148])?,
+            type_flag: block[156],
+            link_name: parse_string(&block[157..257])?,
+        });
+    }
+
+    // tar keeps the type separately from the mode, which often only has the permissions
+    fn file_type(&self) -> u32 {
+        match self.type_flag {
+            TYPE_DIRECTORY => S_IFDIR,
+            TYPE_SYMLINK => S_IFLNK,
+            TYPE_CHARACTER => S_IFCHR,
+            TYPE_BLOCK => S_IFBLK,
+            TYPE_FIFO => S_IFIFO,
+            _ => S_IFREG,
+        }
+    }
+}
+
+#[allow(dead_code)]
+struct TarEntry {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    // where the file data lives in the archive
+    data: Range<usize>,
+    link_target: Option<String>,
+    parent: usize,
+    children: BTreeMap<String, usize>,
+}
+
+impl TarEntry {
+    fn directory(parent: usize) -> Self {
+        return Self {
+            mode: S_IFDIR | 0o755,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            data: 0..0,
+            link_target: None,
+            parent,
+            children: BTreeMap::new(),
+        };
+    }
+
+    fn vnode_type(&self) -> VNodeType {
+        match self.mode & S_IFMT {
+            S_IFREG => VNodeType::Regular,
+            S_IFDIR => VNodeType::Directory,
+            S_IFLNK => VNodeType::Link,
+            S_IFBLK => VNodeType::Block,
+            S_IFCHR => VNodeType::Character,
+            _ => VNodeType::NON,
+        }
+    }
+}
+
+// Read-only filesystem over a tar archive, the archive is indexed once when it is created
+pub struct TarFs {
+    archive: Cow<'static, [u8]>,
+    // entries[0] is always the root directory
+    entries: Vec<TarEntry>,
+}
+
+impl TarFs {
+    pub fn new(archive: Cow<'static, [u8]>) -> Result<Self, ()> {
+        if !is_tar(&archive) {
+            return Err(());
+        }
+
+        let mut tar = Self {
+            archive,
+            entries: Vec::new(),
+        };
+
+        tar.entries.push(TarEntry::directory(0));
+        tar.index()?;
+
+        return Ok(tar);
+    }
+
+    fn index(&mut self) -> Result<(), ()> {
+        let mut offset = 0;
+        let mut global = PaxOverrides::default();
+        let mut local = PaxOverrides::default();
+        // (entry, path it links to), hardlinks can only be resolved once everything is indexed
+        let mut hardlinks: Vec<(usize, String)> = Vec::new();
+
+        while offset + BLOCK_SIZE <= self.archive.len() {
+            let block = &self.archive[offset..offset + BLOCK_SIZE];
+
+            // The archive ends with two zero blocks, some writers only bother with one
+            if block.iter().all(|&byte| byte == 0) {
+                break;
+            }
+
+            let header = TarHeader::from_bytes(block)?;
+            let overrides = global.merge(&local);
+
+            let size = overrides.size.unwrap_or(header.size) as usize;
+            let data_start = offset + BLOCK_SIZE;
+            let data_end = data_start.checked_add(size).ok_or(())?;
+
+            if data_end > self.archive.len() {
+                return Err(());
+            }
+
+            offset = data_end.next_multiple_of(BLOCK_SIZE);
+
+            let data = &self.archive[data_start..data_end];
+
+            match header.type_flag {
+                TYPE_PAX => {
+                    local.parse(data)?;
+                    continue;
+                }
+                TYPE_PAX_GLOBAL => {
+                    global.parse(data)?;
+                    continue;
+                }
+                TYPE_GNU_LONG_NAME => {
+                    local.path = Some(parse_string(data)?);
+                    continue;
+                }
+                TYPE_GNU_LONG_LINK => {
+                    local.link_path = Some(parse_string(data)?);
+                    continue;
+                }
+                _ => {}
+            }
+
+            local = PaxOverrides::default();
+
+            let name = overrides.path.unwrap_or(header.name.clone());
+            let link_name = overrides.link_path.unwrap_or(header.link_name.clone());
+
+            let data = match header.type_flag {
+                TYPE_REGULAR | TYPE_REGULAR_OLD | TYPE_CONTIGUOUS => data_start..data_end,
+                _ => 0..0,
+            };
+
+            let entry = TarEntry {
+                mode: header.file_type() | (header.mode & 0o7777),
+                uid: overrides.uid.unwrap_or(header.uid),
+                gid: overrides.gid.unwrap_or(header.gid),
+                mtime: overrides.mtime.unwrap_or(header.mtime),
+                data,
+                link_target: match header.type_flag {
+                    TYPE_SYMLINK => Some(link_name.clone()),
+                    _ => None,
+                },
+                parent: 0,
+                children: BTreeMap::new(),
+            };
+
+            let index = self.insert(&name, entry)?;
+
+            if header.type_flag == TYPE_HARDLINK {
+                hardlinks.push((index, link_name));
+            }
+        }
+
+        // A hardlink shares everything about the file it points at apart from its name
+        for (index, target) in hardlinks {
+            let Some(target) = self.resolve(&target) else {
+                log!(LogLevel::Warn, "tar: Hardlink to missing file {target}");
+                continue;
+            };
+
+            if self.entries[target].mode & S_IFMT == S_IFDIR {
+                continue;
+            }
+
+            self.entries[index].mode = self.entries[target].mode;
+            self.entries[index].data = self.entries[target].data.clone();
+            self.entries[index].link_target = self.entries[target].link_target.clone();
+        }
+
+        return Ok(());
+    }
+
+    // Puts an entry in the tree, making any directories the archive never listed
+    fn insert(&mut self, path: &str, entry: TarEntry) -> Result<usize, ()> {
+        let parts = path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect::<Vec<&str>>();
+
+        let mut directory = 0;
+
+        for (i, &part) in parts.iter().enumerate() {
+            if part == ".." {
+                return Err(());
+            }
+
+            let is_last = i == parts.len() - 1;
+
+            let child = match self.entries[directory].children.get(part) {
+                Some(&child) => child,
+                None => {
+                    self.entries.push(TarEntry::directory(directory));
+                    let child = self.entries.len() - 1;
+
+                    self.entries[directory]
+                        .children
+                        .insert(part.to_string(), child);
+
+                    child
+                }
+            };
+
+            if !is_last && self.entries[child].mode & S_IFMT != S_IFDIR {
+                log!(LogLevel::Warn, "tar: {path} is inside of a non-directory");
+                return Err(());
+            }
+
+            directory = child;
+        }
+
+        // `directory` is the entry for the path now, which is the root for "./"
+        let parent = self.entries[directory].parent;
+        let children = core::mem::take(&mut self.entries[directory].children);
+
+        self.entries[directory] = TarEntry {
+            parent,
+            children,
+            ..entry
+        };
+
+        return Ok(directory);
+    }
+
+    fn resolve(&self, path: &str) -> Option<usize> {
+        let mut index = 0;
+
+        for part in path.split('/') {
+            match part {
+                "" | "." => continue,
+                ".." => index = self.entries[index].parent,
+                name => index = *self.entries[index].children.get(name)?,
+            }
+        }
+
+        return Some(index);
+    }
+
+    fn entry_data(&self, index: usize) -> &[u8] {
+        &self.archive[self.entries[index].data.clone()]
+    }
+
+    fn make_vnode(&self, index: usize, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        VNode::new(
+            Box::new(TarNode { index }),
+            self.entries[index].vnode_type(),
+            vfsp,
+        )
+    }
+}
+
+impl FsOps for TarFs {
+    fn mount(&mut self, _path: &str, data: &mut *mut u8, _vfsp: NonNull<super::vfs::Vfs>) {
+        *data = core::ptr::addr_of!(*self) as *mut u8;
+    }
+
+    fn unmount(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {}
+
+    fn root(&mut self, vfsp: NonNull<super::vfs::Vfs>) -> VNode {
+        self.make_vnode(0, vfsp)
+    }
+
+    fn statfs(&mut self, _vfsp: NonNull<super::vfs::Vfs>) -> super::vfs::StatFs {
+        todo!("TAR STATFS");
+    }
+
+    fn sync(&mut self, _vfsp: NonNull<super::vfs::Vfs>) {
+        // read-only, nothing to write back
+    }
+
+    // The archive never changes once mounted, so the entry index is a stable id
+    fn fid(&mut self, path: &str, _vfsp: NonNull<super::vfs::Vfs>) -> Option<FileId> {
+        let index = self.resolve(path)?;
+
+        return FileId::new(&(index as u32).to_le_bytes()).ok();
+    }
+
+    fn vget(&mut self, fid: FileId, vfsp: NonNull<super::vfs::Vfs>) -> Result<VNode, ()> {
+        let index = u32::from_le_bytes(fid.as_bytes().try_into().map_err(|_| ())?) as usize;
+
+        if index >= self.entries.len() {
+            return Err(());
+        }
+
+        return Ok(self.make_vnode(index, vfsp));
+    }
+}
+
+struct TarNode {
+    index: usize,
+}
+
+impl TarNode {
+    fn get_fs<'a>(vp: NonNull<VNode>) -> &'a TarFs {
+        unsafe { &*(*vp.as_ptr()).parent_vfs.as_mut().data.cast::<TarFs>() }
+    }
+}
+
+impl VNodeOperations for TarNode {
+    fn open(&mut self, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn close(&mut self, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn read(
+        &mut self,
+        count: usize,
+        offset: usize,
+        _f: u32,
+        _c: UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        let tar = Self::get_fs(vp);
+
+        if tar.entries[self.index].mode & S_IFMT != S_IFREG {
+            return Err(());
+        }
+
+        let data = tar.entry_data(self.index);
+
+        return Ok(Arc::from(data.get(offset..offset + count).ok_or(())?));
+    }
+
+    fn write(&mut self, _offset: usize, _buf: &[u8], _f: u32, _c: UserCred, _vp: NonNull<VNode>) {
+        log!(LogLevel::Warn, "tar: Archives are read-only");
+    }
+
+    fn ioctl(&mut self, _com: u32, _d: *mut u8, _f: u32, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn getattr(&mut self, _c: UserCred, _vp: NonNull<VNode>) -> super::vfs::VAttr {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn setattr(&mut self, _va: super::vfs::VAttr, _c: UserCred, _vp: NonNull<VNode>) {
+        log!(LogLevel::Warn, "tar: Archives are read-only");
+    }
+
+    fn access(&mut self, _m: u32, _c: UserCred, _vp: NonNull<VNode>) -> Result<(), ()> {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn lookup(&mut self, nm: &str, _c: UserCred, vp: NonNull<VNode>) -> Result<VNode, ()> {
+        let tar = Self::get_fs(vp);
+        let entry = &tar.entries[self.index];
+
+        if entry.mode & S_IFMT != S_IFDIR {
+            return Err(());
+        }
+
+        let index = match nm {
+            "." => self.index,
+            ".." => entry.parent,
+            name => *entry.children.get(name).ok_or(())?,
+        };
+
+        return Ok(tar.make_vnode(index, unsafe { (*vp.as_ptr()).parent_vfs }));
+    }
+
+    fn create(
+        &mut self,
+        _nm: &str,
+        _va: super::vfs::VAttr,
+        _e: u32,
+        _m: u32,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        return Err(());
+    }
+
+    fn link(
+        &mut self,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        log!(LogLevel::Warn, "tar: Archives are read-only");
+    }
+
+    fn rename(
+        &mut self,
+        _nm: &str,
+        _target_dir: *mut VNode,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        log!(LogLevel::Warn, "tar: Archives are read-only");
+    }
+
+    fn mkdir(
+        &mut self,
+        _nm: &str,
+        _va: super::vfs::VAttr,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<VNode, ()> {
+        return Err(());
+    }
+
+    fn readdir(&mut self, _uiop: *const UIO, _c: UserCred, _vp: NonNull<VNode>) {
+        todo!("VNODE OPERATIONS");
+    }
+
+    fn symlink(
+        &mut self,
+        _link_name: &str,
+        _va: super::vfs::VAttr,
+        _target_name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) {
+        log!(LogLevel::Warn, "tar: Archives are read-only");
+    }
+
+    fn readlink(&mut self, uiop: *const UIO, _c: UserCred, vp: NonNull<VNode>) {
+        let tar = Self::get_fs(vp);
+
+        // the target is the link name in the header, symlinks have no data of their own
+        if let Some(ref target) = tar.entries[self.index].link_target {
+            unsafe { UIO::uiomove(uiop, target.as_bytes()) };
+        }
+    }
+
+    fn fsync(&mut self, _c: UserCred, _vp: NonNull<VNode>) {}
+
+    fn getxattr(
+        &mut self,
+        _name: &str,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<Arc<[u8]>, ()> {
+        // TODO: SCHILY.xattr pax records
+        return Err(());
+    }
+
+    fn listxattr(&mut self, _c: UserCred, _vp: NonNull<VNode>) -> Result<Vec<String>, ()> {
+        return Ok(Vec::new());
+    }
+
+    fn setxattr(
+        &mut self,
+        _name: &str,
+        _value: &[u8],
+        _f: u32,
+        _c: UserCred,
+        _vp: NonNull<VNode>,
+    ) -> Result<(), ()> {
+        return Err(());
+    }
+
+    fn len(&self, vp: NonNull<VNode>) -> usize {
+        let tar = Self::get_fs(vp);
+        let entry = &tar.entries[self.index];
+
+        match entry.link_target {
+            Some(ref target) if entry.mode & S_IFMT == S_IFLNK => target.len(),
+            _ => entry.data.len(),
+        }
+    }
+}
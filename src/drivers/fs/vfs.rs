@@ -127,6 +127,11 @@ impl Vfs {
         let vfsp = self.as_ptr();
 
         self.fs.as_mut().unwrap().as_mut().sync(vfsp);
+
+        // Filesystems write through the buffer cache, so their data isn't on disk until it's flushed
+        if crate::drivers::storage::cache::sync_all().is_err() {
+            crate::log!(LogLevel::Error, "Failed to flush the block cache");
+        }
     }
 
     pub fn fid(&mut self, path: &str) -> Option<FileId> {
@@ -137,7 +142,7 @@ impl Vfs {
         self.fs.as_mut().unwrap().as_mut().fid(path, vfsp)
     }
 
-    pub fn vget(&mut self, fid: FileId) -> VNode {
+    pub fn vget(&mut self, fid: FileId) -> Result<VNode, ()> {
         assert!(self.fs.is_some(), "FsOps is null!");
 
         let vfsp = self.as_ptr();
@@ -153,16 +158,47 @@ pub trait FsOps {
     fn root(&mut self, vfsp: NonNull<Vfs>) -> VNode;
     fn statfs(&mut self, vfsp: NonNull<Vfs>) -> StatFs;
     fn sync(&mut self, vfsp: NonNull<Vfs>);
+    // Returns a handle that stays valid for as long as the file exists on the filesystem,
+    // the handle can be turned back into a VNode with vget, without knowing the path.
     fn fid(&mut self, path: &str, vfsp: NonNull<Vfs>) -> Option<FileId>;
-    // idk how the fuck you're supposed to accomplish this
-    // good luck I guess.
-    fn vget(&mut self, fid: FileId, vfsp: NonNull<Vfs>) -> VNode;
+    fn vget(&mut self, fid: FileId, vfsp: NonNull<Vfs>) -> Result<VNode, ()>;
 }
 
-#[allow(unused)]
+pub const MAX_FID_SIZE: usize = 16;
+
+// NFS style file handle, the contents are opaque to everyone but the filesystem that made it
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct FileId {
     len: u16,
-    data: u8,
+    data: [u8; MAX_FID_SIZE],
+}
+
+impl FileId {
+    pub fn new(bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() > MAX_FID_SIZE {
+            return Err(());
+        }
+
+        let mut data = [0u8; MAX_FID_SIZE];
+        data[..bytes.len()].copy_from_slice(bytes);
+
+        return Ok(Self {
+            len: bytes.len() as u16,
+            data,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
 }
 
 #[allow(unused)]
@@ -309,6 +345,16 @@ impl TreeNode {
         return Ok(self.children.get_mut(name).unwrap());
     }
 
+    fn add_child(&mut self, name: &str, vnode: VNode) {
+        let child_node = TreeNode {
+            vnode,
+            parent: Some(self.as_ptr()),
+            children: BTreeMap::new(),
+        };
+
+        self.children.insert(name.to_string(), child_node);
+    }
+
     fn read(&mut self, count: usize, offset: usize, f: u32) -> Result<Arc<[u8]>, ()> {
         self.get_vnode_mut()
             .read(count, offset, f, UserCred { uid: 0, gid: 0 })
@@ -437,7 +483,7 @@ impl VNode {
         self.inode.as_mut().setattr(va, c, vp)
     }
 
-    pub fn access(&mut self, m: u32, c: UserCred) {
+    pub fn access(&mut self, m: u32, c: UserCred) -> Result<(), ()> {
         let vp = self.as_ptr();
 
         self.inode.as_mut().access(m, c, vp)
@@ -508,6 +554,24 @@ impl VNode {
         self.inode.as_mut().fsync(c, vp)
     }
 
+    pub fn getxattr(&mut self, name: &str, c: UserCred) -> Result<Arc<[u8]>, ()> {
+        let vp = self.as_ptr();
+
+        self.inode.as_mut().getxattr(name, c, vp)
+    }
+
+    pub fn listxattr(&mut self, c: UserCred) -> Result<Vec<String>, ()> {
+        let vp = self.as_ptr();
+
+        self.inode.as_mut().listxattr(c, vp)
+    }
+
+    pub fn setxattr(&mut self, name: &str, value: &[u8], f: u32, c: UserCred) -> Result<(), ()> {
+        let vp = self.as_ptr();
+
+        self.inode.as_mut().setxattr(name, value, f, c, vp)
+    }
+
     // pub fn inactive(&mut self, c: UserCred) {
     //     let vp = self.as_ptr();
 
@@ -551,6 +615,10 @@ pub union NodeData {
     stream_data: (), // Stream
 }
 
+// setxattr flags, with neither set the attribute is created or replaced
+pub const XATTR_CREATE: u32 = 0x1;
+pub const XATTR_REPLACE: u32 = 0x2;
+
 #[derive(Clone, Copy)]
 pub struct UserCred {
     pub uid: u16,
@@ -568,6 +636,12 @@ pub struct IoVec {
     iov_len: usize,
 }
 
+impl IoVec {
+    pub fn new(iov_base: *mut u8, iov_len: usize) -> Self {
+        return Self { iov_base, iov_len };
+    }
+}
+
 #[allow(unused)]
 pub struct UIO {
     iov: *mut IoVec,
@@ -579,6 +653,70 @@ pub struct UIO {
     residual_count: u32,
 }
 
+impl UIO {
+    /// # Safety
+    /// `iov` has to point to `iov_count` valid IoVecs that outlive the UIO
+    pub unsafe fn new(iov: *mut IoVec, iov_count: u32) -> Self {
+        let total_len = (0..iov_count as usize)
+            .map(|i| (*iov.add(i)).iov_len)
+            .sum::<usize>();
+
+        return Self {
+            iov,
+            iov_count,
+            offset: 0,
+            seg_flag: 0,
+            file_mode: 0,
+            max_offset: total_len,
+            residual_count: total_len as u32,
+        };
+    }
+
+    pub fn residual_count(&self) -> usize {
+        self.residual_count as usize
+    }
+
+    /// Copies as much of bytes as still fits into the io vectors, returns how many bytes were copied
+    ///
+    /// # Safety
+    /// uiop has to point to a valid UIO whose io vectors are valid for writes
+    pub unsafe fn uiomove(uiop: *const UIO, bytes: &[u8]) -> usize {
+        let uio = &mut *(uiop as *mut UIO);
+
+        let mut copied = 0;
+        let mut skip = uio.offset;
+
+        for i in 0..uio.iov_count as usize {
+            if copied == bytes.len() {
+                break;
+            }
+
+            let iov = &*uio.iov.add(i);
+
+            if skip >= iov.iov_len {
+                skip -= iov.iov_len;
+                continue;
+            }
+
+            let to_copy = (iov.iov_len - skip).min(bytes.len() - copied);
+
+            core::ptr::copy_nonoverlapping(
+                bytes.as_ptr().add(copied),
+                iov.iov_base.add(skip),
+                to_copy,
+            );
+
+            copied += to_copy;
+            skip = 0;
+        }
+
+        uio.offset += copied;
+        uio.residual_count -= copied as u32;
+
+        return copied;
+    }
+}
+
 pub trait VNodeOperations {
     fn open(&mut self, f: u32, c: UserCred, vp: NonNull<VNode>);
     fn close(&mut self, f: u32, c: UserCred, vp: NonNull<VNode>);
@@ -595,7 +733,8 @@ pub trait VNodeOperations {
     // fn select(&mut self, w: IODirection, c: UserCred, vp: NonNull<VNode>);
     fn getattr(&mut self, c: UserCred, vp: NonNull<VNode>) -> VAttr;
     fn setattr(&mut self, va: VAttr, c: UserCred, vp: NonNull<VNode>);
-    fn access(&mut self, m: u32, c: UserCred, vp: NonNull<VNode>);
+    // `m` is a set of R_OK (4), W_OK (2) and X_OK (1), Err if any of them isn't granted
+    fn access(&mut self, m: u32, c: UserCred, vp: NonNull<VNode>) -> Result<(), ()>;
     fn lookup(&mut self, nm: &str, c: UserCred, vp: NonNull<VNode>) -> Result<VNode, ()>;
     fn create(
         &mut self,
@@ -627,6 +766,17 @@ pub trait VNodeOperations {
     );
     fn readlink(&mut self, uiop: *const UIO, c: UserCred, vp: NonNull<VNode>);
     fn fsync(&mut self, c: UserCred, vp: NonNull<VNode>);
+    // Extended attribute names include their namespace, eg. "security.capability"
+    fn getxattr(&mut self, name: &str, c: UserCred, vp: NonNull<VNode>) -> Result<Arc<[u8]>, ()>;
+    fn listxattr(&mut self, c: UserCred, vp: NonNull<VNode>) -> Result<Vec<String>, ()>;
+    fn setxattr(
+        &mut self,
+        name: &str,
+        value: &[u8],
+        f: u32,
+        c: UserCred,
+        vp: NonNull<VNode>,
+    ) -> Result<(), ()>;
     // fn inactive(&mut self, c: UserCred, vp: NonNull<VNode>);
     // fn bmap(&mut self, block_number: u32, bnp: (), vp: NonNull<VNode>) -> VNode;
     // fn strategy(&mut self, bp: (), vp: NonNull<VNode>);
@@ -652,22 +802,22 @@ pub trait VNodeOperations {
 
 #[allow(unused)]
 pub struct VAttr {
-    typ: VNode,
-    mode: u16,
-    uid: u16,
-    gid: u16,
-    fs_id: u32,
-    node_id: u32,
-    link_count: u16,
-    size: u32,
-    block_size: u32,
-    last_access: u32,
-    last_modify: u32,
+    pub typ: VNodeType,
+    pub mode: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub fs_id: u32,
+    pub node_id: u32,
+    pub link_count: u16,
+    pub size: u32,
+    pub block_size: u32,
+    pub last_access: u32,
+    pub last_modify: u32,
     // got no clue
-    last_chg: u32,
+    pub last_chg: u32,
     // the device???
-    rdev: (),
-    used_blocks: u32,
+    pub rdev: (),
+    pub used_blocks: u32,
 }
 
 pub fn add_vfs(mount_point: &str, fs_ops: Box<dyn FsOps>) -> Result<(), ()> {
@@ -690,13 +840,23 @@ pub fn add_vfs(mount_point: &str, fs_ops: Box<dyn FsOps>) -> Result<(), ()> {
             return Err(());
         }
 
-        if vfs_open(mount_point).is_err() {
-            return Err(());
-        }
+        match vfs_open(mount_point) {
+            Ok(file) => file.get_vnode_mut().vfs_mounted_here = Some(vfsp),
+            // Nothing to cover, so the mount point only exists in the node tree, as the root of
+            // the new filesystem
+            Err(()) => {
+                let (parent, name) = mount_point.rsplit_once('/').ok_or(())?;
+
+                if name.is_empty() {
+                    return Err(());
+                }
 
-        let file = vfs_open(mount_point)?;
+                let parent = vfs_open(parent)?;
+                let root = vfs.fs.as_mut().unwrap().as_mut().root(vfsp);
 
-        file.get_vnode_mut().vfs_mounted_here = Some(vfsp);
+                parent.add_child(name, root);
+            }
+        }
     }
 
     vfs.mount(mount_point);
@@ -768,6 +928,19 @@ pub fn del_vfs(mount_point: &str) -> Result<(), ()> {
             return Err(());
         }
 
+        // A mount point that was made up by add_vfs goes away with the filesystem
+        if let Some((parent, name)) = mount_point.rsplit_once('/') {
+            if let Ok(parent) = vfs_open(parent) {
+                parent.children.retain(|child_name, child| {
+                    child_name != name
+                        || unsafe { child.vnode.parent_vfs.as_ref() }
+                            .mount_point
+                            .as_deref()
+                            != Some(mount_point)
+                });
+            }
+        }
+
         unsafe { ROOT_VFS.del_vfs(mount_point) };
     }
 
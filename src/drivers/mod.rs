@@ -1,7 +1,10 @@
 pub mod acpi;
+pub mod dma;
 pub mod fs;
 pub mod keyboard;
 pub mod pci;
+pub mod pit;
 pub mod serial;
 pub mod storage;
 pub mod video;
+pub mod virtio;
@@ -26,6 +26,18 @@ fn read_pci_config(bus: u8, device: u8, func: u8, offset: u8) -> u32 {
     return data;
 }
 
+fn write_pci_config(bus: u8, device: u8, func: u8, offset: u8, value: u32) {
+    let mut address: u32 = 0;
+    address |= 1 << 31; // Enable bit
+    address |= (bus as u32) << 16; // Set Bus Number
+    address |= (device as u32) << 11; // Set Device Number
+    address |= (func as u32) << 8; // Set Function number
+    address |= (offset & 0xFC) as u32; // Set Register offset
+
+    outl(PCI_CONFIG_PORT, address);
+    outl(PCI_DATA_PORT, value);
+}
+
 #[inline]
 fn read_pci_vendor_id(bus: u8, device: u8, func: u8) -> u16 {
     return (read_pci_config(bus, device, func, 0x00) & 0xFFFF) as u16;
@@ -78,7 +90,7 @@ pub fn _get_pci_bar_addresses(bus: u8, device: u8, func: u8) -> (u32, u32, u32,
     (bar0, bar1, bar2, bar3, bar4, bar5)
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct PciDevice {
     pub bus: u8,
     pub device: u8,
@@ -117,6 +129,95 @@ impl PciDevice {
     }
 }
 
+impl PciDevice {
+    pub fn read_config(&self, offset: u8) -> u32 {
+        return read_pci_config(self.bus, self.device, self.func, offset);
+    }
+
+    pub fn write_config(&self, offset: u8, value: u32) {
+        write_pci_config(self.bus, self.device, self.func, offset, value);
+    }
+
+    pub fn read_config_u8(&self, offset: u8) -> u8 {
+        return (self.read_config(offset & 0xFC) >> ((offset & 0x3) * 8)) as u8;
+    }
+
+    // (capability ID, config space offset) for every entry in the capability list
+    pub fn capabilities(&self) -> Vec<(u8, u8)> {
+        let mut capabilities = Vec::new();
+
+        // Status register bit 4, the device has a capability list
+        if (self.read_config(0x04) >> 16) & 0x10 == 0 {
+            return capabilities;
+        }
+
+        let mut offset = self.read_config_u8(0x34) & 0xFC;
+
+        // There are only 48 dwords of capability space, anything longer is a loop
+        while offset != 0 && capabilities.len() < 48 {
+            capabilities.push((self.read_config_u8(offset), offset));
+            offset = self.read_config_u8(offset + 1) & 0xFC;
+        }
+
+        return capabilities;
+    }
+
+    // Raw BAR register, the low bits are still the type flags
+    pub fn bar(&self, bar: u8) -> u32 {
+        assert!(bar < 6);
+
+        return self.read_config(0x10 + bar * 4);
+    }
+
+    // Physical address of a memory BAR, 64-bit BARs take the next BAR as the high half
+    pub fn memory_bar(&self, bar: u8) -> Option<u64> {
+        let low = self.bar(bar);
+
+        // I/O space BAR
+        if low & 0x1 != 0 {
+            return None;
+        }
+
+        let mut address = (low & 0xFFFFFFF0) as u64;
+
+        if (low >> 1) & 0x3 == 0x2 {
+            address |= (self.bar(bar + 1) as u64) << 32;
+        }
+
+        return Some(address);
+    }
+
+    pub fn interrupt_line(&self) -> u8 {
+        return (self.read_config(0x3C) & 0xFF) as u8;
+    }
+
+    // Lets the device decode I/O and memory accesses and master the bus for DMA
+    pub fn enable_bus_mastering(&self) {
+        let command = self.read_config(0x04);
+
+        self.write_config(0x04, command | 0x7);
+    }
+}
+
+// Every function that was found with the given class and subclass
+pub fn find_devices(class_code: u8, subclass_code: u8) -> Vec<PciDevice> {
+    return PCI_DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.class_code == class_code && device.subclass_code == subclass_code)
+        .copied()
+        .collect();
+}
+
+pub fn find_devices_by_vendor(vendor_id: u16) -> Vec<PciDevice> {
+    return PCI_DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.vendor_id == vendor_id)
+        .copied()
+        .collect();
+}
+
 impl core::fmt::Display for PciDevice {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         return write!(f, "Bus: {} Device: {} Function: {} VendorID: {:#X} DeviceID: {:#X} ClassCode: {:#04X} SubclassCode: {:#04X} ProgIF: {:#04X}",
@@ -0,0 +1,41 @@
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::{
+    interrupts::{self, idt_set_gate, InterruptIndex},
+    io::outb,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const PIT_CHANNEL_0_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+
+const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+pub const TICKS_PER_SECOND: u64 = 100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub extern "x86-interrupt" fn timer_interrupt_handler() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+
+    interrupts::signal_end_of_interrupt();
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn init() {
+    idt_set_gate(
+        InterruptIndex::Timer.as_u8(),
+        timer_interrupt_handler as usize,
+    );
+
+    let divisor = (PIT_BASE_FREQUENCY / TICKS_PER_SECOND as u32) as u16;
+
+    // Channel 0, lobyte/hibyte access, mode 3 (square wave)
+    outb(PIT_COMMAND_PORT, 0x36);
+    outb(PIT_CHANNEL_0_PORT, divisor as u8);
+    outb(PIT_CHANNEL_0_PORT, (divisor >> 8) as u8);
+}
+
+// Timer ticks since the PIT was set up, there are `TICKS_PER_SECOND` of them a second
+pub fn ticks() -> u64 {
+    return TICKS.load(Ordering::Relaxed);
+}
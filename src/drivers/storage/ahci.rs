@@ -0,0 +1,434 @@
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use crate::{
+    drivers::{
+        dma::{DmaRegion, Mmio},
+        fs::mount_partition,
+        pci::{find_devices, PciDevice},
+        storage::registry::{partitions_of, register_disk},
+    },
+    libs::sync::Mutex,
+    mem::{LabelBytes, PAGE_SIZE},
+    LogLevel,
+};
+
+use super::BlockDevice;
+
+const AHCI_SECTOR_SIZE: usize = 512;
+
+// Generic host control registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_PI: usize = 0x0C;
+const HBA_VS: usize = 0x10;
+
+const HBA_GHC_AHCI_ENABLE: u32 = 1 << 31;
+
+// Port registers, each port has 0x80 bytes starting at 0x100
+const PORT_CLB: usize = 0x00;
+const PORT_CLBU: usize = 0x04;
+const PORT_FB: usize = 0x08;
+const PORT_FBU: usize = 0x0C;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SERR: usize = 0x30;
+const PORT_CI: usize = 0x38;
+
+const PORT_CMD_ST: u32 = 1 << 0;
+const PORT_CMD_FRE: u32 = 1 << 4;
+const PORT_CMD_FR: u32 = 1 << 14;
+const PORT_CMD_CR: u32 = 1 << 15;
+
+const PORT_IS_TFES: u32 = 1 << 30;
+
+const PORT_TFD_ERR: u32 = 0x01;
+const PORT_TFD_DRQ: u32 = 0x08;
+const PORT_TFD_BSY: u32 = 0x80;
+
+const SATA_SIG_ATA: u32 = 0x00000101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+
+// The command list is 32 headers of 32 bytes, the received FIS area goes right after it in the
+// same page
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+// The command FIS lives at the start of the command table and the PRDT at 0x80
+const PRDT_OFFSET: usize = 0x80;
+const PRDT_ENTRY_COUNT: usize = 8;
+
+// Transfers go through a bounce buffer of this many pages, bigger requests are split up
+const DMA_BUFFER_PAGES: usize = 16;
+const PRD_MAX_BYTES: usize = DMA_BUFFER_PAGES * PAGE_SIZE / PRDT_ENTRY_COUNT;
+
+// Loop iterations to wait for the HBA before giving up
+const SPIN_TIMEOUT: usize = 10_000_000;
+
+struct PortState {
+    // Command list and received FIS
+    command_list: DmaRegion,
+    command_table: DmaRegion,
+    buffer: DmaRegion,
+}
+
+pub struct AhciPort {
+    registers: Mmio,
+    port: u8,
+    sector_count: u64,
+    // Only command slot 0 is used, so commands on a port go one at a time
+    state: Mutex<PortState>,
+}
+
+impl AhciPort {
+    fn new(hba: Mmio, port: u8) -> Result<Self, ()> {
+        let registers = hba.offset(0x100 + port as usize * 0x80);
+
+        let state = PortState {
+            command_list: DmaRegion::new(1)?,
+            command_table: DmaRegion::new(1)?,
+            buffer: DmaRegion::new(DMA_BUFFER_PAGES)?,
+        };
+
+        let mut port = Self {
+            registers,
+            port,
+            sector_count: 0,
+            state: Mutex::new(state),
+        };
+
+        port.rebase()?;
+
+        let identify = port.identify()?;
+
+        let lba48_sectors = u64::from_le_bytes(identify[200..208].try_into().unwrap());
+        let lba28_sectors = u32::from_le_bytes(identify[120..124].try_into().unwrap()) as u64;
+
+        // Word 83 bit 10 says whether the 48-bit sector count is there
+        let supports_lba48 = u16::from_le_bytes([identify[166], identify[167]]) & (1 << 10) != 0;
+
+        port.sector_count = if supports_lba48 && lba48_sectors != 0 {
+            lba48_sectors
+        } else {
+            lba28_sectors
+        };
+
+        return Ok(port);
+    }
+
+    fn stop(&self) -> Result<(), ()> {
+        let cmd = self.registers.read(PORT_CMD);
+        self.registers
+            .write(PORT_CMD, cmd & !(PORT_CMD_ST | PORT_CMD_FRE));
+
+        return self
+            .spin_until(|| self.registers.read(PORT_CMD) & (PORT_CMD_CR | PORT_CMD_FR) == 0);
+    }
+
+    fn start(&self) -> Result<(), ()> {
+        self.spin_until(|| self.registers.read(PORT_CMD) & PORT_CMD_CR == 0)?;
+
+        let cmd = self.registers.read(PORT_CMD);
+        self.registers.write(PORT_CMD, cmd | PORT_CMD_FRE);
+        self.registers
+            .write(PORT_CMD, cmd | PORT_CMD_FRE | PORT_CMD_ST);
+
+        return Ok(());
+    }
+
+    // Points the port at our command list and FIS receive area instead of whatever the firmware
+    // left behind
+    fn rebase(&self) -> Result<(), ()> {
+        self.stop()?;
+
+        let mut state = self.state.lock();
+
+        let command_list = state.command_list.phys_addr(0);
+        let received_fis = state.command_list.phys_addr(RECEIVED_FIS_OFFSET);
+
+        self.registers.write(PORT_CLB, command_list as u32);
+        self.registers.write(PORT_CLBU, (command_list >> 32) as u32);
+        self.registers.write(PORT_FB, received_fis as u32);
+        self.registers.write(PORT_FBU, (received_fis >> 32) as u32);
+
+        // Command header 0 points at our command table, the rest are never used
+        let command_table = state.command_table.phys_addr(0);
+        let header = state.command_list.slice(0, 32);
+        header.fill(0);
+        header[8..12].copy_from_slice(&(command_table as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&((command_table >> 32) as u32).to_le_bytes());
+
+        drop(state);
+
+        // Clear any errors and interrupts from before we took over, we poll so keep them off
+        self.registers.write(PORT_SERR, 0xFFFFFFFF);
+        self.registers.write(PORT_IS, 0xFFFFFFFF);
+        self.registers.write(PORT_IE, 0);
+
+        return self.start();
+    }
+
+    fn spin_until<F: Fn() -> bool>(&self, condition: F) -> Result<(), ()> {
+        for _ in 0..SPIN_TIMEOUT {
+            if condition() {
+                return Ok(());
+            }
+
+            core::hint::spin_loop();
+        }
+
+        crate::log!(LogLevel::Error, "AHCI: Port {} timed out", self.port);
+
+        return Err(());
+    }
+
+    // Runs one command on slot 0 with `byte_count` bytes of the bounce buffer as its data
+    fn issue(
+        &self,
+        state: &mut PortState,
+        command: u8,
+        lba: u64,
+        sector_count: u16,
+        byte_count: usize,
+        write: bool,
+    ) -> Result<(), ()> {
+        self.spin_until(|| self.registers.read(PORT_TFD) & (PORT_TFD_BSY | PORT_TFD_DRQ) == 0)?;
+
+        let prd_count = byte_count.div_ceil(PRD_MAX_BYTES);
+
+        // Command header: FIS length in dwords, the write bit and the PRDT length
+        let header = state.command_list.slice(0, 32);
+        let flags = 5 | (write as u32) << 6 | (prd_count as u32) << 16;
+        header[0..4].copy_from_slice(&flags.to_le_bytes());
+        header[4..8].fill(0);
+
+        let table = state
+            .command_table
+            .slice(0, PRDT_OFFSET + PRDT_ENTRY_COUNT * 16);
+        table.fill(0);
+
+        // Host to device register FIS
+        let fis = &mut table[0..20];
+        fis[0] = FIS_TYPE_REG_H2D;
+        fis[1] = 0x80; // This is a command, not a control update
+        fis[2] = command;
+        fis[4] = lba as u8;
+        fis[5] = (lba >> 8) as u8;
+        fis[6] = (lba >> 16) as u8;
+        fis[7] = 1 << 6; // LBA mode
+        fis[8] = (lba >> 24) as u8;
+        fis[9] = (lba >> 32) as u8;
+        fis[10] = (lba >> 40) as u8;
+        fis[12..14].copy_from_slice(&sector_count.to_le_bytes());
+
+        for i in 0..prd_count {
+            let offset = i * PRD_MAX_BYTES;
+            let len = (byte_count - offset).min(PRD_MAX_BYTES);
+            let address = state.buffer.phys_addr(offset);
+
+            let prd = &mut table[PRDT_OFFSET + i * 16..PRDT_OFFSET + (i + 1) * 16];
+            prd[0..4].copy_from_slice(&(address as u32).to_le_bytes());
+            prd[4..8].copy_from_slice(&((address >> 32) as u32).to_le_bytes());
+            // Byte count is stored minus one
+            prd[12..16].copy_from_slice(&((len - 1) as u32).to_le_bytes());
+        }
+
+        self.registers.write(PORT_IS, 0xFFFFFFFF);
+        self.registers.write(PORT_CI, 1);
+
+        let result = self.spin_until(|| {
+            self.registers.read(PORT_CI) & 1 == 0
+                || self.registers.read(PORT_IS) & PORT_IS_TFES != 0
+        });
+
+        if result.is_err()
+            || self.registers.read(PORT_IS) & PORT_IS_TFES != 0
+            || self.registers.read(PORT_TFD) & PORT_TFD_ERR != 0
+        {
+            crate::log!(
+                LogLevel::Error,
+                "AHCI: Command {command:#04X} failed on port {} (TFD {:#X})",
+                self.port,
+                self.registers.read(PORT_TFD)
+            );
+
+            // Restarting the port clears the error state for the next command
+            let _ = self.stop();
+            self.registers.write(PORT_SERR, 0xFFFFFFFF);
+            let _ = self.start();
+
+            return Err(());
+        }
+
+        return Ok(());
+    }
+
+    fn identify(&self) -> Result<[u8; AHCI_SECTOR_SIZE], ()> {
+        let mut state = self.state.lock();
+
+        self.issue(&mut state, ATA_CMD_IDENTIFY, 0, 0, AHCI_SECTOR_SIZE, false)?;
+
+        let mut identify = [0u8; AHCI_SECTOR_SIZE];
+        identify.copy_from_slice(state.buffer.slice(0, AHCI_SECTOR_SIZE));
+
+        return Ok(identify);
+    }
+}
+
+impl BlockDevice for AhciPort {
+    fn sector_count(&self) -> u64 {
+        return self.sector_count;
+    }
+
+    fn read(&self, sector: u64, sector_count: usize) -> Result<Arc<[u8]>, ()> {
+        if (sector + sector_count as u64) > self.sector_count {
+            return Err(());
+        }
+
+        let mut data = vec![0u8; sector_count * AHCI_SECTOR_SIZE];
+        let max_sectors = DMA_BUFFER_PAGES * PAGE_SIZE / AHCI_SECTOR_SIZE;
+
+        let mut state = self.state.lock();
+
+        for (i, chunk) in data.chunks_mut(max_sectors * AHCI_SECTOR_SIZE).enumerate() {
+            let lba = sector + (i * max_sectors) as u64;
+            let count = chunk.len() / AHCI_SECTOR_SIZE;
+
+            self.issue(
+                &mut state,
+                ATA_CMD_READ_DMA_EXT,
+                lba,
+                count as u16,
+                chunk.len(),
+                false,
+            )?;
+
+            chunk.copy_from_slice(state.buffer.slice(0, chunk.len()));
+        }
+
+        return Ok(Arc::from(data));
+    }
+
+    fn write(&self, sector: u64, data: &[u8]) -> Result<(), ()> {
+        if data.len() % AHCI_SECTOR_SIZE != 0
+            || sector + (data.len() / AHCI_SECTOR_SIZE) as u64 > self.sector_count
+        {
+            return Err(());
+        }
+
+        let max_sectors = DMA_BUFFER_PAGES * PAGE_SIZE / AHCI_SECTOR_SIZE;
+
+        let mut state = self.state.lock();
+
+        for (i, chunk) in data.chunks(max_sectors * AHCI_SECTOR_SIZE).enumerate() {
+            let lba = sector + (i * max_sectors) as u64;
+            let count = chunk.len() / AHCI_SECTOR_SIZE;
+
+            state.buffer.slice(0, chunk.len()).copy_from_slice(chunk);
+
+            self.issue(
+                &mut state,
+                ATA_CMD_WRITE_DMA_EXT,
+                lba,
+                count as u16,
+                chunk.len(),
+                true,
+            )?;
+        }
+
+        return Ok(());
+    }
+}
+
+pub fn init() {
+    // Mass storage controller, SATA, AHCI 1.0 programming interface
+    for device in find_devices(0x01, 0x06) {
+        if device.prog_if != 0x01 {
+            continue;
+        }
+
+        ahci_initialize(device);
+    }
+}
+
+fn ahci_initialize(device: PciDevice) {
+    // ABAR, the HBA's memory registers
+    let Some(abar) = device.memory_bar(5) else {
+        crate::log!(LogLevel::Error, "AHCI: Controller has no memory BAR");
+        return;
+    };
+
+    device.enable_bus_mastering();
+
+    let hba = Mmio::new(abar);
+
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | HBA_GHC_AHCI_ENABLE);
+
+    let version = hba.read(HBA_VS);
+    let ports_implemented = hba.read(HBA_PI);
+
+    crate::log!(
+        LogLevel::Trace,
+        "AHCI: Controller v{}.{} with {} port(s) at {abar:#X}",
+        version >> 16,
+        (version >> 8) & 0xFF,
+        (hba.read(HBA_CAP) & 0x1F) + 1
+    );
+
+    let mut disks: Vec<AhciPort> = Vec::new();
+
+    for port in 0..32 {
+        if ports_implemented & (1 << port) == 0 {
+            continue;
+        }
+
+        let registers = hba.offset(0x100 + port * 0x80);
+
+        let status = registers.read(PORT_SSTS);
+
+        // A device is present with phy communication established, and the link is active
+        if status & 0xF != 0x3 || (status >> 8) & 0xF != 0x1 {
+            continue;
+        }
+
+        // ATAPI, port multipliers and the like aren't handled here
+        if registers.read(PORT_SIG) != SATA_SIG_ATA {
+            continue;
+        }
+
+        match AhciPort::new(hba, port as u8) {
+            Ok(disk) => disks.push(disk),
+            Err(_) => crate::log!(LogLevel::Error, "AHCI: Failed to set up port {port}"),
+        }
+    }
+
+    for disk in disks {
+        let sectors = disk.sector_count();
+        let port = disk.port;
+
+        let name = match register_disk("sd", Arc::new(disk)) {
+            Ok(name) => name,
+            Err(_) => {
+                crate::log!(LogLevel::Error, "AHCI: Failed to register port {port}");
+                continue;
+            }
+        };
+
+        crate::log!(
+            LogLevel::Trace,
+            "AHCI: Port {port} is {name}, {} sectors ({})",
+            sectors,
+            ((sectors as usize) * AHCI_SECTOR_SIZE).label_bytes()
+        );
+
+        for (name, partition) in partitions_of(&name) {
+            mount_partition(&name, partition);
+        }
+    }
+}
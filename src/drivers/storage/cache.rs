@@ -0,0 +1,343 @@
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::{drivers::pit, libs::sync::Mutex, LogLevel};
+
+use super::BlockDevice;
+
+// Dirty buffers are written out once they are this old, checked from the idle loop and whenever
+// the cache is used
+const WRITEBACK_INTERVAL: u64 = 5 * pit::TICKS_PER_SECOND;
+
+// Below this much free heap the cache gives half of itself back
+const LOW_MEMORY_THRESHOLD: usize = 512 * 1024;
+
+type BufferKey = (usize, u64);
+
+struct Buffer {
+    data: Box<[u8]>,
+    dirty: bool,
+    last_used: u64,
+}
+
+struct BufferCache {
+    buffers: BTreeMap<BufferKey, Buffer>,
+    // last_used -> key, the first entry is the least recently used buffer
+    lru: BTreeMap<u64, BufferKey>,
+    devices: BTreeMap<usize, Arc<dyn BlockDevice>>,
+    clock: u64,
+    size: usize,
+    last_writeback: u64,
+}
+
+static BUFFER_CACHE: Mutex<BufferCache> = Mutex::new(BufferCache::new());
+static NEXT_DEVICE_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl BufferCache {
+    const fn new() -> Self {
+        return Self {
+            buffers: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            devices: BTreeMap::new(),
+            clock: 0,
+            size: 0,
+            last_writeback: 0,
+        };
+    }
+
+    fn capacity() -> usize {
+        return crate::KERNEL_FEATURES.block_cache_size * 1024;
+    }
+
+    fn touch(&mut self, key: BufferKey) {
+        self.clock += 1;
+
+        let buffer = self.buffers.get_mut(&key).unwrap();
+
+        self.lru.remove(&buffer.last_used);
+        buffer.last_used = self.clock;
+        self.lru.insert(self.clock, key);
+    }
+
+    fn insert(&mut self, key: BufferKey, data: &[u8], dirty: bool) {
+        if let Some(buffer) = self.buffers.get_mut(&key) {
+            buffer.data.copy_from_slice(data);
+            buffer.dirty |= dirty;
+
+            self.touch(key);
+            return;
+        }
+
+        self.make_room(data.len());
+
+        self.clock += 1;
+        self.size += data.len();
+        self.lru.insert(self.clock, key);
+        self.buffers.insert(
+            key,
+            Buffer {
+                data: data.into(),
+                dirty,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    // Evicts least recently used buffers until `size` more bytes fit. A dirty buffer that can't
+    // be written back stays cached and the next one in line goes instead, if nothing can go the
+    // cache runs over its capacity rather than losing data.
+    fn make_room(&mut self, size: usize) {
+        let mut capacity = Self::capacity();
+
+        if crate::mem::ALLOCATOR.lock().free_bytes() < LOW_MEMORY_THRESHOLD {
+            capacity = capacity.min(self.size / 2);
+        }
+
+        let mut next_tick = 0;
+
+        while self.size + size > capacity {
+            let Some((&tick, &key)) = self.lru.range(next_tick..).next() else {
+                break;
+            };
+
+            next_tick = tick + 1;
+
+            let _ = self.evict(key);
+        }
+    }
+
+    // The buffer is only dropped once it's safely on disk
+    fn evict(&mut self, key: BufferKey) -> Result<(), ()> {
+        let buffer = &self.buffers[&key];
+
+        if buffer.dirty {
+            if let Err(()) = self.devices[&key.0].write(key.1, &buffer.data) {
+                crate::log!(
+                    LogLevel::Error,
+                    "Block cache: Failed to write back sector {} of device {}",
+                    key.1,
+                    key.0
+                );
+
+                return Err(());
+            }
+        }
+
+        let buffer = self.buffers.remove(&key).unwrap();
+
+        self.lru.remove(&buffer.last_used);
+        self.size -= buffer.data.len();
+
+        return Ok(());
+    }
+
+    // Drops every buffer of a device without writing anything back, for when its media is gone
+    fn invalidate(&mut self, device: usize) {
+        let keys: Vec<BufferKey> = self
+            .buffers
+            .keys()
+            .filter(|key| key.0 == device)
+            .copied()
+            .collect();
+
+        for key in keys {
+            let buffer = self.buffers.remove(&key).unwrap();
+
+            self.lru.remove(&buffer.last_used);
+            self.size -= buffer.data.len();
+        }
+    }
+
+    // Writes every dirty buffer (of one device, or all of them) back, merging runs of
+    // consecutive sectors into a single write
+    fn flush(&mut self, device: Option<usize>) -> Result<(), ()> {
+        let mut result = Ok(());
+        let mut run: Vec<u8> = Vec::new();
+        let mut run_start: Option<BufferKey> = None;
+        let mut run_keys: Vec<BufferKey> = Vec::new();
+
+        let keys: Vec<BufferKey> = self
+            .buffers
+            .iter()
+            .filter(|(key, buffer)| buffer.dirty && device.map_or(true, |device| key.0 == device))
+            .map(|(&key, _)| key)
+            .collect();
+
+        for key in keys {
+            if let Some(start) = run_start {
+                if start.0 != key.0 || start.1 + run_keys.len() as u64 != key.1 {
+                    result = result.and(self.write_run(start, &run, &run_keys));
+                    run.clear();
+                    run_keys.clear();
+                    run_start = None;
+                }
+            }
+
+            run_start.get_or_insert(key);
+            run.extend_from_slice(&self.buffers[&key].data);
+            run_keys.push(key);
+        }
+
+        if let Some(start) = run_start {
+            result = result.and(self.write_run(start, &run, &run_keys));
+        }
+
+        return result;
+    }
+
+    fn write_run(&mut self, start: BufferKey, data: &[u8], keys: &[BufferKey]) -> Result<(), ()> {
+        self.devices[&start.0].write(start.1, data)?;
+
+        for key in keys {
+            self.buffers.get_mut(key).unwrap().dirty = false;
+        }
+
+        return Ok(());
+    }
+
+    // Flushing needs the heap and the device locks, so it can't happen in the timer interrupt
+    // itself and runs from the idle loop or the next cache access instead
+    fn writeback_if_due(&mut self) {
+        let now = pit::ticks();
+
+        if now.saturating_sub(self.last_writeback) < WRITEBACK_INTERVAL {
+            return;
+        }
+
+        self.last_writeback = now;
+
+        if self.flush(None).is_err() {
+            crate::log!(LogLevel::Warn, "Block cache: Periodic write back failed");
+        }
+    }
+}
+
+// A block device whose sectors go through the shared buffer cache. Writes only reach the disk
+// when the buffer is evicted, on `sync` or on the periodic write back.
+pub struct CachedBlockDevice {
+    id: usize,
+    inner: Arc<dyn BlockDevice>,
+    media_generation: AtomicU64,
+}
+
+impl CachedBlockDevice {
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        let id = NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed);
+
+        BUFFER_CACHE.lock().devices.insert(id, inner.clone());
+
+        return Self {
+            id,
+            media_generation: AtomicU64::new(inner.media_generation()),
+            inner,
+        };
+    }
+
+    fn check_media(&self, cache: &mut BufferCache) {
+        let generation = self.inner.media_generation();
+
+        if self.media_generation.swap(generation, Ordering::AcqRel) != generation {
+            cache.invalidate(self.id);
+        }
+    }
+
+    pub fn sync(&self) -> Result<(), ()> {
+        return BUFFER_CACHE.lock().flush(Some(self.id));
+    }
+}
+
+impl BlockDevice for CachedBlockDevice {
+    fn sector_count(&self) -> u64 {
+        return self.inner.sector_count();
+    }
+
+    fn sector_size(&self) -> usize {
+        return self.inner.sector_size();
+    }
+
+    fn media_generation(&self) -> u64 {
+        return self.inner.media_generation();
+    }
+
+    fn read(&self, sector: u64, sector_count: usize) -> Result<Arc<[u8]>, ()> {
+        let sector_size = self.sector_size();
+        let mut cache = BUFFER_CACHE.lock();
+
+        cache.writeback_if_due();
+        self.check_media(&mut cache);
+
+        let mut data = vec![0u8; sector_count * sector_size];
+        let mut i = 0;
+
+        while i < sector_count {
+            let key = (self.id, sector + i as u64);
+
+            if let Some(buffer) = cache.buffers.get(&key) {
+                data[i * sector_size..(i + 1) * sector_size].copy_from_slice(&buffer.data);
+                cache.touch(key);
+                i += 1;
+                continue;
+            }
+
+            // Read the whole run of missing sectors in one go
+            let mut misses = 1;
+            while i + misses < sector_count
+                && !cache
+                    .buffers
+                    .contains_key(&(self.id, sector + (i + misses) as u64))
+            {
+                misses += 1;
+            }
+
+            let run = self.inner.read(sector + i as u64, misses);
+
+            // The read may be what noticed the media changing
+            self.check_media(&mut cache);
+
+            let run = run?;
+
+            for (j, sector_data) in run.chunks_exact(sector_size).enumerate() {
+                data[(i + j) * sector_size..(i + j + 1) * sector_size].copy_from_slice(sector_data);
+                cache.insert((self.id, sector + (i + j) as u64), sector_data, false);
+            }
+
+            i += misses;
+        }
+
+        return Ok(Arc::from(data));
+    }
+
+    fn write(&self, sector: u64, data: &[u8]) -> Result<(), ()> {
+        let sector_size = self.sector_size();
+
+        if data.len() % sector_size != 0
+            || sector + (data.len() / sector_size) as u64 > self.sector_count()
+        {
+            return Err(());
+        }
+
+        let mut cache = BUFFER_CACHE.lock();
+
+        cache.writeback_if_due();
+
+        for (i, sector_data) in data.chunks_exact(sector_size).enumerate() {
+            cache.insert((self.id, sector + i as u64), sector_data, true);
+        }
+
+        return Ok(());
+    }
+}
+
+// Called by the idle loop so dirty buffers reach the disk even when nothing uses the cache
+pub fn writeback_if_due() {
+    if pit::ticks().saturating_sub(BUFFER_CACHE.lock().last_writeback) < WRITEBACK_INTERVAL {
+        return;
+    }
+
+    BUFFER_CACHE.lock().writeback_if_due();
+}
+
+// Writes every dirty buffer on every device back to disk
+pub fn sync_all() -> Result<(), ()> {
+    return BUFFER_CACHE.lock().flush(None);
+}
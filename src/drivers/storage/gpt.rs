@@ -0,0 +1,308 @@
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use crate::{libs::crc32::crc32, libs::uuid::Uuid, LogLevel};
+
+use super::{
+    partitions::{read_gpt, GPT_SIGNATURE},
+    BlockDevice, GPTHeader, GPTPartitionEntry,
+};
+
+const GPT_REVISION: u32 = 0x00010000;
+const GPT_HEADER_SIZE: u32 = 92;
+const GPT_ENTRY_SIZE: u32 = 128;
+const GPT_ENTRY_COUNT: u32 = 128;
+
+// An in memory copy of a GPT that can be edited and written back out. Partitions handed out by
+// `scan_partitions` before a `write` keep the old bounds, the disk needs to be rescanned.
+pub struct GptTable {
+    device: Arc<dyn BlockDevice>,
+    disk_guid: Uuid,
+    first_usable_block: u64,
+    last_usable_block: u64,
+    entry_size: u32,
+    // One slot per entry in the on disk array, so indices stay stable across edits
+    entries: Vec<Option<GPTPartitionEntry>>,
+}
+
+impl GptTable {
+    // Lays out an empty table covering the whole disk, nothing touches the disk until `write`
+    pub fn new(device: Arc<dyn BlockDevice>, disk_guid: Uuid) -> Result<Self, ()> {
+        let block_device = device.as_ref();
+
+        let table_sectors = table_sectors(block_device, GPT_ENTRY_COUNT, GPT_ENTRY_SIZE);
+        let sector_count = block_device.sector_count();
+
+        // MBR, both headers and both entry arrays, with at least one sector left over
+        if sector_count < 3 + table_sectors * 2 {
+            return Err(());
+        }
+
+        return Ok(Self {
+            device,
+            disk_guid,
+            first_usable_block: 2 + table_sectors,
+            last_usable_block: sector_count - 2 - table_sectors,
+            entry_size: GPT_ENTRY_SIZE,
+            entries: vec![None; GPT_ENTRY_COUNT as usize],
+        });
+    }
+
+    // Loads whichever copy of the GPT on the disk is intact
+    pub fn load(device: Arc<dyn BlockDevice>) -> Result<Self, ()> {
+        let block_device = device.as_ref();
+
+        let (gpt, table) = match read_gpt(block_device, 1) {
+            Ok(gpt) => gpt,
+            Err(_) => read_gpt(block_device, block_device.sector_count().saturating_sub(1))?,
+        };
+
+        let entries = table
+            .chunks_exact(gpt.partition_entry_size as usize)
+            .take(gpt.partition_entry_count as usize)
+            .map(|entry| {
+                if entry[0..16].iter().all(|&byte| byte == 0) {
+                    return None;
+                }
+
+                return Some(GPTPartitionEntry::new(entry));
+            })
+            .collect();
+
+        return Ok(Self {
+            device,
+            disk_guid: gpt.guid,
+            first_usable_block: gpt.first_usable_block,
+            last_usable_block: gpt.last_usable_block,
+            entry_size: gpt.partition_entry_size,
+            entries,
+        });
+    }
+
+    pub fn disk_guid(&self) -> Uuid {
+        return self.disk_guid;
+    }
+
+    pub fn usable_range(&self) -> (u64, u64) {
+        return (self.first_usable_block, self.last_usable_block);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (usize, &GPTPartitionEntry)> {
+        return self
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| entry.as_ref().map(|entry| (i, entry)));
+    }
+
+    // Gaps between partitions as inclusive (start, end) sector ranges
+    pub fn free_space(&self) -> Vec<(u64, u64)> {
+        let mut used: Vec<(u64, u64)> = self
+            .entries()
+            .map(|(_, entry)| (entry.start_sector, entry.end_sector))
+            .collect();
+        used.sort_unstable();
+
+        let mut free = Vec::new();
+        let mut next = self.first_usable_block;
+
+        for (start, end) in used {
+            if start > next {
+                free.push((next, start - 1));
+            }
+
+            next = next.max(end + 1);
+        }
+
+        if next <= self.last_usable_block {
+            free.push((next, self.last_usable_block));
+        }
+
+        return free;
+    }
+
+    // Adds a partition in the first free slot and returns its index. There is no RNG yet, so the
+    // caller has to come up with the unique GUID.
+    pub fn create(
+        &mut self,
+        partition_type_guid: Uuid,
+        unique_partition_guid: Uuid,
+        start_sector: u64,
+        end_sector: u64,
+        name: &str,
+    ) -> Result<usize, ()> {
+        if is_unused(partition_type_guid) || !self.range_is_free(start_sector, end_sector, None) {
+            return Err(());
+        }
+
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.is_none())
+            .ok_or(())?;
+
+        let mut entry = GPTPartitionEntry {
+            partition_type_guid,
+            unique_partition_guid,
+            start_sector,
+            end_sector,
+            attributes: 0,
+            partition_name: [0u8; 72],
+        };
+
+        entry.set_name(name)?;
+
+        self.entries[index] = Some(entry);
+
+        return Ok(index);
+    }
+
+    pub fn delete(&mut self, index: usize) -> Result<(), ()> {
+        let slot = self.entries.get_mut(index).ok_or(())?;
+
+        if slot.take().is_none() {
+            return Err(());
+        }
+
+        return Ok(());
+    }
+
+    pub fn rename(&mut self, index: usize, name: &str) -> Result<(), ()> {
+        let entry = self.entry_mut(index)?;
+
+        return entry.set_name(name);
+    }
+
+    // Moves the end of a partition, the start stays put so the filesystem on it is left intact
+    pub fn resize(&mut self, index: usize, end_sector: u64) -> Result<(), ()> {
+        let start_sector = self.entry_mut(index)?.start_sector;
+
+        if !self.range_is_free(start_sector, end_sector, Some(index)) {
+            return Err(());
+        }
+
+        self.entry_mut(index)?.end_sector = end_sector;
+
+        return Ok(());
+    }
+
+    // Writes the protective MBR, then the backup table and header, then the primary ones. The
+    // primary goes last so a write that dies half way still leaves one consistent copy.
+    pub fn write(&self) -> Result<(), ()> {
+        let block_device = self.device.as_ref();
+        let sector_size = block_device.sector_size();
+
+        let table_sectors = table_sectors(block_device, self.entries.len() as u32, self.entry_size);
+        let last_sector = block_device.sector_count() - 1;
+
+        let mut table = vec![0u8; table_sectors as usize * sector_size];
+
+        for (i, entry) in self.entries() {
+            let offset = i * self.entry_size as usize;
+            table[offset..offset + GPT_ENTRY_SIZE as usize].copy_from_slice(&entry.to_bytes());
+        }
+
+        let table_crc = crc32(&table[..self.entries.len() * self.entry_size as usize]);
+
+        let primary = self.header(1, last_sector, 2, table_crc);
+        let backup = self.header(last_sector, 1, last_sector - table_sectors, table_crc);
+
+        self.write_protective_mbr()?;
+
+        block_device.write(backup.guid_lba, &table)?;
+        block_device.write(backup.header_lba, &header_sector(&backup, sector_size))?;
+
+        block_device.write(primary.guid_lba, &table)?;
+        block_device.write(primary.header_lba, &header_sector(&primary, sector_size))?;
+
+        crate::log!(
+            LogLevel::Info,
+            "GPT: Wrote {} partition(s) to disk {}",
+            self.entries().count(),
+            self.disk_guid
+        );
+
+        return Ok(());
+    }
+
+    fn header(&self, header_lba: u64, header_lba_alt: u64, guid_lba: u64, crc: u32) -> GPTHeader {
+        return GPTHeader {
+            header: *GPT_SIGNATURE,
+            revision: GPT_REVISION,
+            header_size: GPT_HEADER_SIZE,
+            header_checksum: 0,
+            _reserved: [0u8; 4],
+            header_lba,
+            header_lba_alt,
+            first_usable_block: self.first_usable_block,
+            last_usable_block: self.last_usable_block,
+            guid: self.disk_guid,
+            guid_lba,
+            partition_entry_count: self.entries.len() as u32,
+            partition_entry_size: self.entry_size,
+            partition_table_crc: crc,
+        };
+    }
+
+    // Keeps whatever boot code is in the first 440 bytes, only the table is replaced with a
+    // single 0xEE partition covering the disk
+    fn write_protective_mbr(&self) -> Result<(), ()> {
+        let block_device = self.device.as_ref();
+
+        let mut mbr = block_device.read(0, 1)?.to_vec();
+
+        let size = (block_device.sector_count() - 1).min(u32::MAX as u64) as u32;
+
+        mbr[446..510].fill(0);
+        // Start CHS is 0/0/2, end CHS is maxed out since the disk is addressed by LBA
+        mbr[446..462].copy_from_slice(&[
+            0x00, 0x00, 0x02, 0x00, 0xEE, 0xFF, 0xFF, 0xFF, 0x01, 0x00, 0x00, 0x00, 0, 0, 0, 0,
+        ]);
+        mbr[458..462].copy_from_slice(&size.to_le_bytes());
+        mbr[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+        return block_device.write(0, &mbr);
+    }
+
+    fn entry_mut(&mut self, index: usize) -> Result<&mut GPTPartitionEntry, ()> {
+        return self
+            .entries
+            .get_mut(index)
+            .and_then(|entry| entry.as_mut())
+            .ok_or(());
+    }
+
+    fn range_is_free(&self, start_sector: u64, end_sector: u64, ignore: Option<usize>) -> bool {
+        if start_sector > end_sector
+            || start_sector < self.first_usable_block
+            || end_sector > self.last_usable_block
+        {
+            return false;
+        }
+
+        return self.entries().all(|(i, entry)| {
+            Some(i) == ignore || end_sector < entry.start_sector || start_sector > entry.end_sector
+        });
+    }
+}
+
+fn table_sectors(device: &dyn BlockDevice, entry_count: u32, entry_size: u32) -> u64 {
+    let table_size = entry_count as usize * entry_size as usize;
+
+    return table_size.div_ceil(device.sector_size()) as u64;
+}
+
+fn header_sector(header: &GPTHeader, sector_size: usize) -> Vec<u8> {
+    let mut sector = vec![0u8; sector_size];
+
+    let bytes = header.to_bytes();
+    sector[..bytes.len()].copy_from_slice(&bytes);
+
+    let checksum = crc32(&sector[..header.header_size as usize]);
+    sector[0x10..0x14].copy_from_slice(&checksum.to_le_bytes());
+
+    return sector;
+}
+
+fn is_unused(guid: Uuid) -> bool {
+    return <[u8; 16]>::from(guid).iter().all(|&byte| byte == 0);
+}
@@ -1,23 +1,75 @@
 use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use alloc::vec;
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
 
 use crate::mem::VirtualPtr;
 use crate::{
-    arch::io::{inb, insw, inw, outb, outsw},
+    arch::io::{inb, insw, inw, outb, outl, outsw, outw},
     drivers::{
-        fs::{fat, vfs::add_vfs},
-        storage::{GPTHeader, GPTPartitionEntry, Partition, MBR},
+        dma::DmaRegion,
+        fs::{detect_fs, mount_partition, vfs::del_vfs, FsType},
+        pci::find_devices,
+        pit,
+        storage::registry::{get_disk, partitions_of, register_disk},
     },
-    libs::{sync::Mutex, uuid::Uuid},
-    mem::LabelBytes,
+    libs::sync::Mutex,
+    mem::{LabelBytes, PAGE_SIZE},
     LogLevel,
 };
 
-use super::BlockDevice;
+use super::{BlockDevice, Partition};
 
 const ATA_SECTOR_SIZE: usize = 512;
+const ATAPI_SECTOR_SIZE: usize = 2048;
+
+// SCSI commands carried in ATAPI packets
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+
+// Sense keys, reported in the top nibble of the error register
+const SENSE_NOT_READY: u8 = 0x02;
+const SENSE_UNIT_ATTENTION: u8 = 0x06;
+
+// Sectors per READ(10), keeps every transfer under the 64K byte count limit
+const ATAPI_MAX_SECTORS: usize = 16;
+
+// Bus master IDE registers, relative to the channel's base in BAR4
+const BM_COMMAND: u16 = 0x00;
+const BM_STATUS: u16 = 0x02;
+const BM_PRDT: u16 = 0x04;
+
+const BM_COMMAND_START: u8 = 1 << 0;
+// Set when the controller writes to memory, so for reads from the drive
+const BM_COMMAND_READ: u8 = 1 << 3;
+
+const BM_STATUS_ERROR: u8 = 1 << 1;
+const BM_STATUS_INTERRUPT: u8 = 1 << 2;
+
+const PRD_END_OF_TABLE: u16 = 1 << 15;
+
+// DMA goes through a bounce buffer, 256 sectors also happens to be the most one LBA28 command
+// can move
+const DMA_BUFFER_PAGES: usize = 32;
+const DMA_MAX_SECTORS: usize = DMA_BUFFER_PAGES * PAGE_SIZE / ATA_SECTOR_SIZE;
+
+const DMA_TIMEOUT: u64 = 5 * pit::TICKS_PER_SECOND;
+
+// Set by the IRQ 14 and 15 handlers, cleared before a transfer is started
+static IRQ_RECEIVED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+// Whether the IRQs actually reach us, without them DMA completion is polled
+static IRQS_ROUTED: AtomicBool = AtomicBool::new(false);
+
+// Packet drives by name, along with the media generation their volumes were mounted for
+static PACKET_DRIVES: Mutex<Vec<(String, Arc<ATAPIDrive>, u64)>> = Mutex::new(Vec::new());
+static LAST_MEDIA_POLL: AtomicU64 = AtomicU64::new(0);
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug)]
@@ -102,6 +154,7 @@ enum ATADriveCommand {
 // }
 
 #[repr(u16)]
+#[derive(Clone, Copy, PartialEq)]
 enum IDEDriveType {
     Pata,
     PataPi,
@@ -157,36 +210,214 @@ enum ATADriveControlRegister {
 }
 
 #[repr(u8)]
-#[allow(dead_code)]
+#[derive(Clone, Copy)]
 enum ATADriveChannels {
     Primary = 0x00,
     Secondary = 0x01,
 }
 
 #[repr(u8)]
+#[derive(Clone, Copy)]
 enum ATADriveDirection {
     Read = 0x00,
     Write = 0x01,
 }
 
+// Programming interface bits of an IDE controller
+const PROG_IF_PRIMARY_NATIVE: u8 = 1 << 0;
+const PROG_IF_SECONDARY_NATIVE: u8 = 1 << 2;
+const PROG_IF_BUS_MASTER: u8 = 1 << 7;
+
 pub fn init() {
-    ide_initialize(0x1F0, 0x3F6, 0x170, 0x376, 0x000);
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    route_interrupts();
+
+    // Mass storage controller, IDE interface
+    let controllers = find_devices(0x01, 0x01);
+
+    if controllers.is_empty() {
+        // No PCI controller to ask, just try the legacy ports
+        ide_initialize(0x1F0, 0x3F6, 0x170, 0x376, 0x000, 0x00);
+        return;
+    }
+
+    // Only one controller can own the legacy ports, any others in compatibility mode would just
+    // find the same drives again
+    let mut legacy_ports_claimed = false;
+
+    for device in controllers {
+        let prog_if = device.prog_if;
+
+        device.enable_bus_mastering();
+
+        let (bar0, bar1) = if prog_if & PROG_IF_PRIMARY_NATIVE != 0 {
+            (device.bar(0), device.bar(1))
+        } else if !legacy_ports_claimed {
+            (0x1F0, 0x3F6)
+        } else {
+            (0, 0)
+        };
+
+        let (bar2, bar3) = if prog_if & PROG_IF_SECONDARY_NATIVE != 0 {
+            (device.bar(2), device.bar(3))
+        } else if !legacy_ports_claimed {
+            (0x170, 0x376)
+        } else {
+            (0, 0)
+        };
+
+        if prog_if & (PROG_IF_PRIMARY_NATIVE | PROG_IF_SECONDARY_NATIVE)
+            != (PROG_IF_PRIMARY_NATIVE | PROG_IF_SECONDARY_NATIVE)
+        {
+            legacy_ports_claimed = true;
+        }
+
+        // BAR4 only holds the bus master registers on controllers that can bus master
+        let bar4 = match prog_if & PROG_IF_BUS_MASTER != 0 && device.bar(4) & 0x1 == 1 {
+            true => device.bar(4),
+            false => 0,
+        };
+
+        crate::log!(
+            LogLevel::Trace,
+            "ATA: IDE controller {device} (primary {}, secondary {})",
+            if prog_if & PROG_IF_PRIMARY_NATIVE != 0 {
+                "native"
+            } else {
+                "compatibility"
+            },
+            if prog_if & PROG_IF_SECONDARY_NATIVE != 0 {
+                "native"
+            } else {
+                "compatibility"
+            }
+        );
+
+        ide_initialize(bar0, bar1, bar2, bar3, bar4, prog_if);
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn route_interrupts() {
+    use crate::arch::interrupts::{apic::APIC, idt_set_gate, InterruptIndex};
+
+    let Ok(apic) = APIC.get() else {
+        return;
+    };
+
+    idt_set_gate(
+        InterruptIndex::PrimaryAta.as_u8(),
+        primary_interrupt_handler as usize,
+    );
+    idt_set_gate(
+        InterruptIndex::SecondaryAta.as_u8(),
+        secondary_interrupt_handler as usize,
+    );
+
+    // Firmware is free to remap these, same as the PIT's IRQ 0
+    apic.set_interrupt(apic.irq_to_gsi(14), 14);
+    apic.set_interrupt(apic.irq_to_gsi(15), 15);
+
+    IRQS_ROUTED.store(true, Ordering::Release);
+}
+
+// The handlers only note that the drive is done, the status is read by whoever is waiting
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+extern "x86-interrupt" fn primary_interrupt_handler() {
+    IRQ_RECEIVED[ATADriveChannels::Primary as usize].store(true, Ordering::Release);
+
+    crate::arch::interrupts::signal_end_of_interrupt();
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+extern "x86-interrupt" fn secondary_interrupt_handler() {
+    IRQ_RECEIVED[ATADriveChannels::Secondary as usize].store(true, Ordering::Release);
+
+    crate::arch::interrupts::signal_end_of_interrupt();
+}
+
+struct DmaState {
+    bus_master: u16,
+    prdt: DmaRegion,
+    buffer: DmaRegion,
+}
+
+impl DmaState {
+    fn new(bus_master: u16) -> Result<Self, ()> {
+        let prdt = DmaRegion::new(1)?;
+        let buffer = DmaRegion::new(DMA_BUFFER_PAGES)?;
+
+        // PRDs only hold 32 bit addresses
+        if prdt.phys_addr(0) > u32::MAX as u64 || buffer.phys_addr(buffer.len()) > u32::MAX as u64 {
+            return Err(());
+        }
+
+        return Ok(Self {
+            bus_master,
+            prdt,
+            buffer,
+        });
+    }
+
+    // One PRD per page, so none of them cross the 64K boundary a PRD isn't allowed to cross
+    fn build_prdt(&mut self, len: usize) {
+        let entries = len.div_ceil(PAGE_SIZE);
+
+        for i in 0..entries {
+            let address = self.buffer.phys_addr(i * PAGE_SIZE) as u32;
+            let size = (len - i * PAGE_SIZE).min(PAGE_SIZE) as u16;
+            let flags = if i == entries - 1 {
+                PRD_END_OF_TABLE
+            } else {
+                0
+            };
+
+            let entry = self.prdt.slice(i * 8, 8);
+            entry[0..4].copy_from_slice(&address.to_le_bytes());
+            entry[4..6].copy_from_slice(&size.to_le_bytes());
+            entry[6..8].copy_from_slice(&flags.to_le_bytes());
+        }
+    }
 }
 
-#[derive(Debug)]
 struct ATABus {
     io_bar: u16,
     control_bar: u16,
+    channel: ATADriveChannels,
+    // Native mode channels interrupt on the controller's PCI line rather than IRQ 14/15, which
+    // we don't route, so their DMA completion is polled
+    native: bool,
+    dma: Option<Mutex<DmaState>>,
 }
 
 impl ATABus {
-    fn new(io_bar: u16, control_bar: u16) -> Arc<Self> {
+    fn new(
+        io_bar: u16,
+        control_bar: u16,
+        channel: ATADriveChannels,
+        native: bool,
+        bus_master: Option<u16>,
+    ) -> Arc<Self> {
         let io_bar = io_bar & 0xFFFC;
         let control_bar = control_bar & 0xFFFC;
 
+        let dma = bus_master.and_then(|bus_master| match DmaState::new(bus_master) {
+            Ok(state) => Some(Mutex::new(state)),
+            Err(()) => {
+                crate::log!(
+                    LogLevel::Warn,
+                    "ATA: Failed to set up DMA, falling back to PIO"
+                );
+                None
+            }
+        });
+
         return Arc::from(Self {
             io_bar,
             control_bar,
+            channel,
+            native,
+            dma,
         });
     }
 
@@ -241,7 +472,10 @@ impl ATABus {
         }
     }
 
-    pub fn identify(&self, drive: ATADriveType) -> Result<Arc<[u8; ATA_SECTOR_SIZE]>, ()> {
+    pub fn identify(
+        &self,
+        drive: ATADriveType,
+    ) -> Result<(IDEDriveType, Arc<[u8; ATA_SECTOR_SIZE]>), ()> {
         self.select(0xA0 | drive as u8);
 
         outb(self.io_bar + ATADriveDataRegister::SectorCount0 as u16, 0);
@@ -249,8 +483,11 @@ impl ATABus {
         outb(self.io_bar + ATADriveDataRegister::LBA1 as u16, 0);
         outb(self.io_bar + ATADriveDataRegister::LBA2 as u16, 0);
 
-        // disable interrupts by setting bit 2 in the control port
-        outb(self.control_bar + 0x0C - 0x0A, 2);
+        // Leave interrupts enabled (nIEN clear), the handlers cope with PIO commands raising them
+        outb(
+            self.control_bar + ATADriveControlRegister::ControlAndAltStatus as u16,
+            0,
+        );
 
         self.send_command(ATADriveCommand::Identify);
 
@@ -265,16 +502,20 @@ impl ATABus {
             let lba_high = inb(self.io_bar + ATADriveDataRegister::LBA2 as u16);
 
             if lba_mid != 0 || lba_high != 0 {
-                return Err(());
+                break;
             }
         }
 
         let lba_mid = inb(self.io_bar + ATADriveDataRegister::LBA1 as u16);
         let lba_high = inb(self.io_bar + ATADriveDataRegister::LBA2 as u16);
 
-        match IDEDriveType::from_lba(lba_mid, lba_high) {
-            Some(IDEDriveType::Pata) => {
-                // The only type we support, for now :tm:
+        let drive_type = match IDEDriveType::from_lba(lba_mid, lba_high) {
+            Some(IDEDriveType::Pata) => IDEDriveType::Pata,
+            // Packet devices abort IDENTIFY DEVICE, leaving their signature behind, and answer
+            // IDENTIFY PACKET DEVICE instead
+            Some(drive_type @ (IDEDriveType::PataPi | IDEDriveType::SataPi)) => {
+                self.send_command(ATADriveCommand::IdentifyPacket);
+                drive_type
             }
             _ => return Err(()),
         };
@@ -290,7 +531,79 @@ impl ATABus {
             chunk.copy_from_slice(&word.to_le_bytes());
         }
 
-        return Ok(Arc::from(buffer));
+        return Ok((drive_type, Arc::from(buffer)));
+    }
+
+    // Sends a SCSI command in an ATAPI packet and reads `len` bytes of data back with PIO. On a
+    // check condition this fails with the sense key.
+    fn packet(&self, drive: ATADriveType, packet: &[u8; 12], len: usize) -> Result<Vec<u8>, u8> {
+        self.await_busy();
+
+        self.select(0xA0 | drive as u8);
+
+        // PIO, and the most the drive should hand over per DRQ block
+        let byte_count = len.clamp(2, 0xFFFE);
+        outb(
+            self.io_bar + ATADriveDataRegister::ErrorAndFeatures as u16,
+            0,
+        );
+        outb(
+            self.io_bar + ATADriveDataRegister::LBA1 as u16,
+            byte_count as u8,
+        );
+        outb(
+            self.io_bar + ATADriveDataRegister::LBA2 as u16,
+            (byte_count >> 8) as u8,
+        );
+
+        self.send_command(ATADriveCommand::Packet);
+
+        self.wait_for_drive_ready().map_err(|_| self.sense_key())?;
+
+        for word in packet.chunks_exact(2) {
+            outw(
+                self.io_bar + ATADriveDataRegister::Data as u16,
+                u16::from_le_bytes([word[0], word[1]]),
+            );
+        }
+
+        let mut data = Vec::with_capacity(len);
+
+        loop {
+            self.await_busy();
+
+            let status = self.status();
+
+            if status == ATADriveStatus::Error {
+                return Err(self.sense_key());
+            }
+
+            // DRQ drops once the command is done
+            if status != ATADriveStatus::DataReqReady {
+                break;
+            }
+
+            let count = inb(self.io_bar + ATADriveDataRegister::LBA1 as u16) as usize
+                | (inb(self.io_bar + ATADriveDataRegister::LBA2 as u16) as usize) << 8;
+
+            for _ in 0..count.div_ceil(size_of::<u16>()) {
+                let word = inw(self.io_bar + ATADriveDataRegister::Data as u16);
+
+                data.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+
+        if data.len() < len {
+            return Err(0);
+        }
+
+        data.truncate(len);
+
+        return Ok(data);
+    }
+
+    fn sense_key(&self) -> u8 {
+        return inb(self.io_bar + ATADriveDataRegister::ErrorAndFeatures as u16) >> 4;
     }
 
     pub fn read(
@@ -298,16 +611,26 @@ impl ATABus {
         drive: ATADriveType,
         sector: u64,
         sector_count: usize,
+        dma: bool,
     ) -> Result<Arc<[u8]>, ()> {
         let mut buffer: Vec<u8> = vec![0; ATA_SECTOR_SIZE * sector_count];
 
-        self.ide_access(
-            drive,
-            sector,
-            sector_count,
-            ATADriveDirection::Read,
-            &mut buffer,
-        )?;
+        match (dma, &self.dma) {
+            (true, Some(state)) => self.dma_access(
+                &mut state.lock(),
+                drive,
+                sector,
+                ATADriveDirection::Read,
+                &mut buffer,
+            )?,
+            _ => self.ide_access(
+                drive,
+                sector,
+                sector_count,
+                ATADriveDirection::Read,
+                &mut buffer,
+            )?,
+        }
 
         return Ok(Arc::from(buffer));
     }
@@ -318,11 +641,24 @@ impl ATABus {
         sector: u64,
         sector_count: usize,
         buffer: &[u8],
+        dma: bool,
     ) -> Result<(), ()> {
         if buffer.len() < ATA_SECTOR_SIZE * sector_count {
             return Err(());
         }
 
+        if let (true, Some(state)) = (dma, &self.dma) {
+            let mut buffer = buffer[..ATA_SECTOR_SIZE * sector_count].to_vec();
+
+            return self.dma_access(
+                &mut state.lock(),
+                drive,
+                sector,
+                ATADriveDirection::Write,
+                &mut buffer,
+            );
+        }
+
         let mut mut_buf: Vec<u8> = Vec::new();
         mut_buf.extend(buffer);
         self.ide_access(
@@ -348,7 +684,54 @@ impl ATABus {
 
         let using_lba48 = sector >= (1 << 28) - 1;
 
-        if using_lba48 {
+        self.write_taskfile(drive, sector, sector_count, using_lba48);
+
+        match (direction, using_lba48) {
+            (ATADriveDirection::Read, true) => self.send_command(ATADriveCommand::ReadPIOExt),
+            (ATADriveDirection::Write, true) => self.send_command(ATADriveCommand::WritePIOExt),
+            (ATADriveDirection::Read, false) => self.send_command(ATADriveCommand::ReadPIO),
+            (ATADriveDirection::Write, false) => self.send_command(ATADriveCommand::WritePIO),
+        }
+
+        // sector count * 512 = bytes in array
+        let array_size = (sector_count) * ATA_SECTOR_SIZE;
+
+        // Since this is an internal function, this should never fail
+        assert!(buffer.len() >= array_size);
+
+        let mut buffer_offset = 0;
+        for _ in 0..sector_count {
+            self.wait_for_drive_ready()
+                .map_err(|_| crate::log!(LogLevel::Error, "Error reading IDE Device"))?;
+
+            // # Safety
+            //
+            // We know that buffer is the exact size of count, so it will never panic:tm:
+            match direction {
+                ATADriveDirection::Read => unsafe {
+                    insw(
+                        self.io_bar + ATADriveDataRegister::Data as u16,
+                        VirtualPtr::from((buffer.as_mut_ptr().cast::<u16>()).add(buffer_offset)),
+                        ATA_SECTOR_SIZE / size_of::<u16>(),
+                    );
+                },
+                ATADriveDirection::Write => unsafe {
+                    outsw(
+                        self.io_bar + ATADriveDataRegister::Data as u16,
+                        VirtualPtr::from((buffer.as_mut_ptr().cast::<u16>()).add(buffer_offset)),
+                        ATA_SECTOR_SIZE / size_of::<u16>(),
+                    )
+                },
+            }
+
+            buffer_offset += ATA_SECTOR_SIZE / size_of::<u16>();
+        }
+
+        return Ok(());
+    }
+
+    fn write_taskfile(&self, drive: ATADriveType, sector: u64, sector_count: usize, lba48: bool) {
+        if lba48 {
             self.select(0x40 | (drive as u8));
 
             // High bytes
@@ -386,11 +769,6 @@ impl ATABus {
                 self.io_bar + ATADriveDataRegister::LBA2 as u16,
                 (sector >> 16) as u8,
             );
-
-            match direction {
-                ATADriveDirection::Read => self.send_command(ATADriveCommand::ReadPIOExt),
-                ATADriveDirection::Write => self.send_command(ATADriveCommand::WritePIOExt),
-            }
         } else {
             self.select(0xE0 | (drive as u8) | ((sector >> 24) as u8 & 0x0F));
 
@@ -410,50 +788,120 @@ impl ATABus {
                 self.io_bar + ATADriveDataRegister::LBA2 as u16,
                 (sector >> 16) as u8,
             );
+        }
+    }
 
-            match direction {
-                ATADriveDirection::Read => self.send_command(ATADriveCommand::ReadPIO),
-                ATADriveDirection::Write => self.send_command(ATADriveCommand::WritePIO),
+    // Moves `buffer` through the bus master engine in chunks of at most `DMA_MAX_SECTORS`,
+    // sleeping until the drive raises its IRQ for each of them
+    fn dma_access(
+        &self,
+        dma: &mut DmaState,
+        drive: ATADriveType,
+        sector: u64,
+        direction: ATADriveDirection,
+        buffer: &mut [u8],
+    ) -> Result<(), ()> {
+        let bus_master = dma.bus_master;
+
+        for (i, chunk) in buffer
+            .chunks_mut(DMA_MAX_SECTORS * ATA_SECTOR_SIZE)
+            .enumerate()
+        {
+            let sector = sector + (i * DMA_MAX_SECTORS) as u64;
+            let sector_count = chunk.len() / ATA_SECTOR_SIZE;
+            let using_lba48 = sector + sector_count as u64 >= (1 << 28) - 1;
+
+            dma.build_prdt(chunk.len());
+
+            let command = match direction {
+                ATADriveDirection::Read => BM_COMMAND_READ,
+                ATADriveDirection::Write => {
+                    dma.buffer.slice(0, chunk.len()).copy_from_slice(chunk);
+                    0
+                }
+            };
+
+            // Stop the engine, point it at the table and clear the error and interrupt bits
+            outb(bus_master + BM_COMMAND, command);
+            outl(bus_master + BM_PRDT, dma.prdt.phys_addr(0) as u32);
+            outb(
+                bus_master + BM_STATUS,
+                inb(bus_master + BM_STATUS) | BM_STATUS_ERROR | BM_STATUS_INTERRUPT,
+            );
+
+            self.await_busy();
+
+            IRQ_RECEIVED[self.channel as usize].store(false, Ordering::Release);
+
+            self.write_taskfile(drive, sector, sector_count, using_lba48);
+
+            match (direction, using_lba48) {
+                (ATADriveDirection::Read, true) => self.send_command(ATADriveCommand::ReadDMAExt),
+                (ATADriveDirection::Write, true) => self.send_command(ATADriveCommand::WriteDMAExt),
+                (ATADriveDirection::Read, false) => self.send_command(ATADriveCommand::ReadDMA),
+                (ATADriveDirection::Write, false) => self.send_command(ATADriveCommand::WriteDMA),
             }
-        }
 
-        // sector count * 512 = bytes in array
-        let array_size = (sector_count) * ATA_SECTOR_SIZE;
+            outb(bus_master + BM_COMMAND, command | BM_COMMAND_START);
 
-        // Since this is an internal function, this should never fail
-        assert!(buffer.len() >= array_size);
+            let completed = self.wait_for_dma(bus_master);
 
-        let mut buffer_offset = 0;
-        for _ in 0..sector_count {
-            self.wait_for_drive_ready()
-                .map_err(|_| crate::log!(LogLevel::Error, "Error reading IDE Device"))?;
+            outb(bus_master + BM_COMMAND, command);
 
-            // # Safety
-            //
-            // We know that buffer is the exact size of count, so it will never panic:tm:
-            match direction {
-                ATADriveDirection::Read => unsafe {
-                    insw(
-                        self.io_bar + ATADriveDataRegister::Data as u16,
-                        VirtualPtr::from((buffer.as_mut_ptr().cast::<u16>()).add(buffer_offset)),
-                        ATA_SECTOR_SIZE / size_of::<u16>(),
-                    );
-                },
-                ATADriveDirection::Write => unsafe {
-                    outsw(
-                        self.io_bar + ATADriveDataRegister::Data as u16,
-                        VirtualPtr::from((buffer.as_mut_ptr().cast::<u16>()).add(buffer_offset)),
-                        ATA_SECTOR_SIZE / size_of::<u16>(),
-                    )
-                },
+            let bm_status = inb(bus_master + BM_STATUS);
+            outb(bus_master + BM_STATUS, bm_status);
+
+            // Reading the status register also lowers the drive's interrupt line
+            let status = self.status();
+
+            if !completed
+                || bm_status & BM_STATUS_ERROR != 0
+                || status == ATADriveStatus::Error
+                || status == ATADriveStatus::WriteFault
+            {
+                crate::log!(
+                    LogLevel::Error,
+                    "ATA: DMA transfer at sector {sector} failed (status {status:#X}, bus master status {bm_status:#X})"
+                );
+                return Err(());
             }
 
-            buffer_offset += ATA_SECTOR_SIZE / size_of::<u16>();
+            if let ATADriveDirection::Read = direction {
+                chunk.copy_from_slice(dma.buffer.slice(0, chunk.len()));
+            }
         }
 
         return Ok(());
     }
 
+    // Returns false if the drive didn't finish in time
+    fn wait_for_dma(&self, bus_master: u16) -> bool {
+        let start = pit::ticks();
+
+        // The interrupt bit is checked as well, so a lost IRQ costs a timer tick and not the
+        // whole transfer
+        while !IRQ_RECEIVED[self.channel as usize].load(Ordering::Acquire)
+            && inb(bus_master + BM_STATUS) & BM_STATUS_INTERRUPT == 0
+        {
+            if pit::ticks() - start > DMA_TIMEOUT {
+                return false;
+            }
+
+            if !self.native && IRQS_ROUTED.load(Ordering::Acquire) {
+                // Sleep until the drive's IRQ (or the next timer tick) comes in
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                unsafe {
+                    core::arch::asm!("hlt");
+                }
+            } else {
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                crate::arch::pause();
+            }
+        }
+
+        return true;
+    }
+
     fn software_reset(&self) {
         // Procedure is (1) set the SRST bit, (2) wait 5us, (3) clear the SRST bit.
         outb(
@@ -472,17 +920,19 @@ impl ATABus {
     }
 }
 
-#[derive(Debug)]
 struct ATADrive {
     bus: Arc<ATABus>,
     identify_data: Arc<[u8; ATA_SECTOR_SIZE]>,
     drive_type: ATADriveType,
+    dma: bool,
 }
 
 impl ATADrive {
-    pub fn new(bus: Arc<ATABus>, drive: ATADriveType) -> Result<Self, ()> {
-        let identify_data = bus.identify(drive)?;
-
+    pub fn new(
+        bus: Arc<ATABus>,
+        drive: ATADriveType,
+        identify_data: Arc<[u8; ATA_SECTOR_SIZE]>,
+    ) -> Result<Self, ()> {
         let capabilities_bytes = &identify_data[98..100];
 
         assert_eq!(capabilities_bytes.len(), 2);
@@ -495,10 +945,14 @@ impl ATADrive {
             return Err(());
         }
 
+        // Bit 8 of the capabilities word says whether the drive can do DMA at all
+        let dma = capabilities & 0x100 != 0 && bus.dma.is_some();
+
         return Ok(Self {
             bus,
             identify_data,
             drive_type: drive,
+            dma,
         });
     }
 
@@ -507,9 +961,24 @@ impl ATADrive {
 
         return unsafe { *(sectors.cast::<u32>()) } as u64;
     }
+}
+
+// Words 27-46 of the identify data, each word holds two characters in big endian order
+fn identify_model(identify_data: &[u8; ATA_SECTOR_SIZE]) -> String {
+    let model: Vec<u8> = identify_data[54..94]
+        .chunks_exact(2)
+        .flat_map(|word| [word[1], word[0]])
+        .collect();
+
+    return String::from_utf8_lossy(&model).trim().to_string();
+}
 
-    pub fn as_ptr(&self) -> VirtualPtr<ATADrive> {
-        return unsafe { VirtualPtr::new(core::ptr::addr_of!(*self) as *mut ATADrive) };
+fn drive_position(channel: ATADriveChannels, drive_type: ATADriveType) -> &'static str {
+    match (channel, drive_type) {
+        (ATADriveChannels::Primary, ATADriveType::Parent) => "primary parent",
+        (ATADriveChannels::Primary, ATADriveType::Child) => "primary child",
+        (ATADriveChannels::Secondary, ATADriveType::Parent) => "secondary parent",
+        (ATADriveChannels::Secondary, ATADriveType::Child) => "secondary child",
     }
 }
 
@@ -521,7 +990,9 @@ impl BlockDevice for ATADrive {
 
         self.bus.software_reset();
 
-        return self.bus.read(self.drive_type, sector, sector_count);
+        return self
+            .bus
+            .read(self.drive_type, sector, sector_count, self.dma);
     }
 
     fn sector_count(&self) -> u64 {
@@ -533,190 +1004,359 @@ impl BlockDevice for ATADrive {
     fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), ()> {
         let sector_count = buffer.len() / 512;
 
+        if (sector + sector_count as u64) > self.sector_count() {
+            return Err(());
+        }
+
         self.bus.software_reset();
 
         return self
             .bus
-            .write(self.drive_type, sector, sector_count, buffer);
+            .write(self.drive_type, sector, sector_count, buffer, self.dma);
     }
 }
 
-static DRIVES: Mutex<Vec<ATADrive>> = Mutex::new(Vec::new());
+// A CD/DVD drive, talked to with SCSI commands wrapped in ATAPI packets
+struct ATAPIDrive {
+    bus: Arc<ATABus>,
+    identify_data: Arc<[u8; ATA_SECTOR_SIZE]>,
+    drive_type: ATADriveType,
+    // 0 while the drive is empty
+    sector_count: AtomicU64,
+    media_generation: AtomicU64,
+}
+
+impl ATAPIDrive {
+    fn new(
+        bus: Arc<ATABus>,
+        drive: ATADriveType,
+        identify_data: Arc<[u8; ATA_SECTOR_SIZE]>,
+    ) -> Self {
+        let drive = Self {
+            bus,
+            identify_data,
+            drive_type: drive,
+            sector_count: AtomicU64::new(0),
+            media_generation: AtomicU64::new(0),
+        };
+
+        // A fresh drive reports a unit attention first, which this clears
+        drive.check_media();
+
+        return drive;
+    }
+
+    fn read_capacity(&self) -> Result<u64, u8> {
+        let mut packet = [0u8; 12];
+        packet[0] = SCSI_READ_CAPACITY;
+
+        let data = self.bus.packet(self.drive_type, &packet, 8)?;
+
+        let last_sector = u32::from_be_bytes(data[0..4].try_into().unwrap());
+        let sector_size = u32::from_be_bytes(data[4..8].try_into().unwrap());
+
+        if sector_size as usize != ATAPI_SECTOR_SIZE {
+            crate::log!(
+                LogLevel::Warn,
+                "ATAPI: Disc has {sector_size} byte sectors, only {ATAPI_SECTOR_SIZE} are supported"
+            );
+            return Err(0);
+        }
+
+        return Ok(last_sector as u64 + 1);
+    }
+
+    // Picks up discs going in and out of the drive, returning true if the media changed
+    fn check_media(&self) -> bool {
+        let mut packet = [0u8; 12];
+        packet[0] = SCSI_TEST_UNIT_READY;
+
+        let (ready, attention) = match self.bus.packet(self.drive_type, &packet, 0) {
+            Ok(_) => (true, false),
+            Err(SENSE_UNIT_ATTENTION) => {
+                // The unit attention is only reported once, ask again for the new state
+                (self.bus.packet(self.drive_type, &packet, 0).is_ok(), true)
+            }
+            Err(_) => (false, false),
+        };
+
+        let sector_count = match ready {
+            true => self.read_capacity().unwrap_or(0),
+            false => 0,
+        };
+
+        let previous = self.sector_count.swap(sector_count, Ordering::AcqRel);
+
+        // A unit attention means the disc was swapped, even if the new one is the same size
+        if previous == sector_count && !attention {
+            return false;
+        }
+
+        self.media_generation.fetch_add(1, Ordering::AcqRel);
+
+        crate::log!(
+            LogLevel::Info,
+            "ATAPI: {} {}",
+            identify_model(&self.identify_data),
+            match sector_count {
+                0 => "is empty".to_string(),
+                _ => format!(
+                    "has a disc with {sector_count} sectors ({})",
+                    (sector_count as usize * ATAPI_SECTOR_SIZE).label_bytes()
+                ),
+            }
+        );
+
+        return true;
+    }
+}
+
+impl BlockDevice for ATAPIDrive {
+    fn sector_count(&self) -> u64 {
+        return self.sector_count.load(Ordering::Acquire);
+    }
+
+    fn sector_size(&self) -> usize {
+        return ATAPI_SECTOR_SIZE;
+    }
+
+    fn media_generation(&self) -> u64 {
+        return self.media_generation.load(Ordering::Acquire);
+    }
+
+    fn read(&self, sector: u64, sector_count: usize) -> Result<Arc<[u8]>, ()> {
+        // Maybe a disc went in since we last looked
+        if self.sector_count() == 0 {
+            self.check_media();
+        }
+
+        if (sector + sector_count as u64) > self.sector_count() {
+            return Err(());
+        }
+
+        let mut data = vec![0u8; sector_count * ATAPI_SECTOR_SIZE];
+
+        for (i, chunk) in data
+            .chunks_mut(ATAPI_MAX_SECTORS * ATAPI_SECTOR_SIZE)
+            .enumerate()
+        {
+            let lba = sector + (i * ATAPI_MAX_SECTORS) as u64;
+            let count = chunk.len() / ATAPI_SECTOR_SIZE;
+
+            let mut packet = [0u8; 12];
+            packet[0] = SCSI_READ_10;
+            packet[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+            packet[7..9].copy_from_slice(&(count as u16).to_be_bytes());
+
+            match self.bus.packet(self.drive_type, &packet, chunk.len()) {
+                Ok(sectors) => chunk.copy_from_slice(&sectors),
+                Err(SENSE_UNIT_ATTENTION | SENSE_NOT_READY) => {
+                    // Whatever was asked for was on the old disc
+                    self.check_media();
+                    return Err(());
+                }
+                Err(_) => return Err(()),
+            }
+        }
+
+        return Ok(Arc::from(data));
+    }
+
+    fn write(&self, _sector: u64, _data: &[u8]) -> Result<(), ()> {
+        return Err(());
+    }
+}
 
 // TODO: This code is pretty much just the C from @Moldytzu's mOS
 // This code could probably be made better and more device agnostic
-fn ide_initialize(bar0: u32, bar1: u32, _bar2: u32, _bar3: u32, _bar4: u32) {
-    let mut drives_lock = DRIVES.lock();
-    let io_port_base = bar0 as u16;
-    let control_port_base = bar1 as u16;
-
-    let bus = ATABus::new(io_port_base, control_port_base);
+fn ide_initialize(bar0: u32, bar1: u32, bar2: u32, bar3: u32, bar4: u32, prog_if: u8) {
+    let bus_master = (bar4 & 0xFFFC) as u16;
+
+    let channels = [
+        (
+            bar0,
+            bar1,
+            ATADriveChannels::Primary,
+            prog_if & PROG_IF_PRIMARY_NATIVE != 0,
+        ),
+        (
+            bar2,
+            bar3,
+            ATADriveChannels::Secondary,
+            prog_if & PROG_IF_SECONDARY_NATIVE != 0,
+        ),
+    ];
+
+    let mut drives: Vec<ATADrive> = Vec::new();
+    let mut packet_drives: Vec<ATAPIDrive> = Vec::new();
+
+    for (io_port_base, control_port_base, channel, native) in channels {
+        if io_port_base == 0 {
+            continue;
+        }
 
-    for i in 0..2 {
-        let drive_type = if i == 0 {
-            ATADriveType::Parent
-        } else {
-            ATADriveType::Child
+        // The secondary channel's bus master registers follow the primary's
+        let channel_bus_master = match channel {
+            ATADriveChannels::Primary => bus_master,
+            ATADriveChannels::Secondary => bus_master + 0x08,
         };
 
-        let drive = ATADrive::new(bus.clone(), drive_type);
+        // An empty channel floats the status register high, don't bother setting up DMA for it
+        let status =
+            inb((io_port_base as u16 & 0xFFFC) + ATADriveDataRegister::CommandAndStatus as u16);
+        if status == 0xFF {
+            continue;
+        }
+
+        let bus = ATABus::new(
+            io_port_base as u16,
+            control_port_base as u16,
+            channel,
+            native,
+            (bus_master != 0).then_some(channel_bus_master),
+        );
 
-        if let Ok(drive) = drive {
-            drives_lock.push(drive);
+        for drive_type in [ATADriveType::Parent, ATADriveType::Child] {
+            let Ok((device_type, identify_data)) = bus.identify(drive_type) else {
+                continue;
+            };
+
+            if device_type == IDEDriveType::Pata {
+                if let Ok(drive) = ATADrive::new(bus.clone(), drive_type, identify_data) {
+                    drives.push(drive);
+                }
+            } else {
+                packet_drives.push(ATAPIDrive::new(bus.clone(), drive_type, identify_data));
+            }
         }
     }
 
+    let drive_count = drives.len() + packet_drives.len();
+
     crate::log!(
         LogLevel::Trace,
         "ATA: Detected {} drive{}",
-        drives_lock.len(),
-        match drives_lock.len() {
+        drive_count,
+        match drive_count {
             1 => "",
             _ => "s",
         }
     );
 
-    for drive in drives_lock.iter() {
+    for drive in drives {
         let sectors = drive.sector_count();
+        let model = identify_model(&drive.identify_data);
+        let position = drive_position(drive.bus.channel, drive.drive_type);
+        let transfer_mode = if drive.dma { "DMA" } else { "PIO" };
+
+        let name = match register_disk("hd", Arc::new(drive)) {
+            Ok(name) => name,
+            Err(_) => {
+                crate::log!(
+                    LogLevel::Error,
+                    "ATA: Failed to register {model} ({position})"
+                );
+                continue;
+            }
+        };
 
         crate::log!(
             LogLevel::Trace,
-            "ATA: Drive 0 has {} sectors ({})",
-            sectors,
+            "ATA: {name} ({position}) is {model}, {sectors} sectors ({}) using {transfer_mode}",
             ((sectors as usize) * ATA_SECTOR_SIZE).label_bytes()
         );
 
-        let mbr_sector: MBR = (*drive.read(0, 1).expect("Failed to read first sector")).into();
-
-        assert_eq!(u16::from_le_bytes(mbr_sector.signature), 0xAA55);
+        for (name, partition) in partitions_of(&name) {
+            mount_partition(&name, partition);
+        }
+    }
 
-        let mbr_partitions = mbr_sector.partitions();
+    for drive in packet_drives {
+        let model = identify_model(&drive.identify_data);
+        let position = drive_position(drive.bus.channel, drive.drive_type);
+
+        let drive = Arc::new(drive);
+        let name = match register_disk("hd", drive.clone()) {
+            Ok(name) => name,
+            Err(_) => {
+                crate::log!(
+                    LogLevel::Error,
+                    "ATA: Failed to register {model} ({position})"
+                );
+                continue;
+            }
+        };
 
-        assert_eq!(
-            mbr_partitions[0].partition_type, 0xEE,
-            "MBR disks are unsupported!"
+        crate::log!(
+            LogLevel::Trace,
+            "ATA: {name} ({position}) is {model}, a packet device"
         );
 
-        let gpt_sector = drive.read(1, 1).expect("Failed to read sector 2");
+        mount_disc(&name);
 
-        let mut array = [0u8; 512];
-        array.copy_from_slice(&gpt_sector[..512]);
-
-        let gpt = GPTHeader::new(&array);
+        let generation = drive.media_generation();
+        PACKET_DRIVES.lock().push((name, drive, generation));
+    }
+}
 
-        let mut partitions: Vec<Partition> = Vec::with_capacity(gpt.partition_entry_count as usize);
+// Called by the idle loop, unmounts the volumes of discs that were taken out and mounts the ones
+// that went in since the last look
+pub fn poll_media() {
+    let now = pit::ticks();
 
-        let partition_sector = drive
-            .read(
-                2,
-                (gpt.partition_entry_count * gpt.partition_entry_size) as usize / ATA_SECTOR_SIZE,
-            )
-            .expect("Failed to read partition table");
+    if now.saturating_sub(LAST_MEDIA_POLL.load(Ordering::Acquire)) < pit::TICKS_PER_SECOND {
+        return;
+    }
 
-        // crate::println!(
-        //     "{}, {}, {}, {:X?}",
-        //     (gpt.partition_entry_count * gpt.partition_entry_size) as usize / ATA_SECTOR_SIZE,
-        //     gpt.partition_entry_count,
-        //     gpt.partition_entry_size,
-        //     gpt.guid
-        // );
+    LAST_MEDIA_POLL.store(now, Ordering::Release);
 
-        for i in 0..gpt.partition_entry_count {
-            let entry_offset = (i * gpt.partition_entry_size) as usize;
+    let mut changed = Vec::new();
 
-            let partition_type_guid_bytes: [u8; 16] = partition_sector
-                [entry_offset..entry_offset + 16]
-                .try_into()
-                .unwrap();
+    for (name, drive, generation) in PACKET_DRIVES.lock().iter_mut() {
+        drive.check_media();
 
-            let partition_type_guid = Uuid::from(partition_type_guid_bytes);
+        let current = drive.media_generation();
+        if current == *generation {
+            continue;
+        }
 
-            if partition_type_guid == "00000000-0000-0000-0000-000000000000 " {
-                continue;
-            }
+        *generation = current;
+        changed.push((name.clone(), drive.clone()));
+    }
 
-            let unique_partition_guid_bytes: [u8; 16] = partition_sector
-                [entry_offset + 16..entry_offset + 32]
-                .try_into()
-                .unwrap();
+    // Mounting reads from the drives, so it happens with the list unlocked
+    for (name, drive) in changed {
+        // The registry still holds the partitions of the old disc, so only the volumes that were
+        // mounted from it get looked up and the new disc is only tried as a whole
+        for (partition_name, _) in partitions_of(&name) {
+            let _ = del_vfs(&format!("/mnt/{partition_name}"));
+        }
+        let _ = del_vfs(&format!("/mnt/{name}"));
 
-            let unique_partition_guid = Uuid::from(unique_partition_guid_bytes);
+        if drive.sector_count() == 0 {
+            continue;
+        }
 
-            let start_sector = u64::from_le_bytes(
-                partition_sector[entry_offset + 32..entry_offset + 40]
-                    .try_into()
-                    .unwrap(),
-            );
-            let end_sector = u64::from_le_bytes(
-                partition_sector[entry_offset + 40..entry_offset + 48]
-                    .try_into()
-                    .unwrap(),
-            );
+        let disc = Partition::Disk(drive);
+        if detect_fs(&disc) == Some(FsType::Iso9660) {
+            mount_partition(&name, disc);
+        }
+    }
+}
 
-            let attributes = u64::from_le_bytes(
-                partition_sector[entry_offset + 48..entry_offset + 56]
-                    .try_into()
-                    .unwrap(),
-            );
+fn mount_disc(name: &str) {
+    // Hybrid ISOs carry an MBR too, whose 512 byte LBAs mean nothing on a disc with 2048 byte
+    // sectors, so the whole disc is tried as ISO9660 before the partition table
+    if let Some(disk) = get_disk(name) {
+        let disc = Partition::Disk(disk);
 
-            let partition_name = partition_sector[entry_offset + 56..entry_offset + 128]
-                .try_into()
-                .unwrap();
-
-            // Store the parsed information in the partition_entries array
-            partitions.push(Partition::GPTPartition((
-                GPTPartitionEntry {
-                    partition_type_guid,
-                    unique_partition_guid,
-                    start_sector,
-                    end_sector,
-                    attributes,
-                    partition_name,
-                },
-                drive.as_ptr().as_raw_ptr(),
-            )));
-        }
-
-        for &partition in partitions.iter() {
-            match partition {
-                Partition::GPTPartition(gpt_partition) => {
-                    if gpt_partition.0.partition_type_guid != "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
-                    {
-                        continue;
-                    }
-
-                    let fat_fs = fat::FatFs::new(partition);
-
-                    if fat_fs.is_err() {
-                        continue;
-                    }
-
-                    let fat_fs = fat_fs.unwrap();
-
-                    // TODO
-                    let _ = add_vfs("/mnt", Box::new(fat_fs));
-
-                    // let vfs = crate::drivers::fs::vfs::Vfs::new(
-                    //     Box::new(fat_fs),
-                    //     &format!("{}", gpt_partition.0.partition_type_guid),
-                    // );
-
-                    // crate::drivers::fs::vfs::VFS_INSTANCES.lock().push(vfs);
-
-                    // crate::println!(
-                    //     "{:?}",
-                    //     crate::drivers::fs::vfs::VFS_INSTANCES
-                    //         .lock()
-                    //         .read()
-                    //         .last()
-                    //         .unwrap()
-                    //         .open("/example.txt")
-                    //         .unwrap()
-                    //         .read()
-                    // );
-                }
-                _ => todo!("Handle MBR!"),
-            }
+        if detect_fs(&disc) == Some(FsType::Iso9660) {
+            mount_partition(name, disc);
+            return;
         }
     }
+
+    for (name, partition) in partitions_of(name) {
+        mount_partition(&name, partition);
+    }
 }
@@ -1,13 +1,31 @@
+pub mod ahci;
+pub mod cache;
+pub mod gpt;
 pub mod ide;
+pub mod nvme;
+mod partitions;
+pub mod registry;
+pub mod virtio_blk;
 
-use alloc::{sync::Arc, vec::Vec};
+pub use partitions::scan_partitions;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
 
 use crate::libs::uuid::Uuid;
 
-pub trait BlockDevice {
+// Devices are shared between the registry, the buffer cache and every mounted filesystem
+pub trait BlockDevice: Send + Sync {
     fn sector_count(&self) -> u64;
+    fn sector_size(&self) -> usize {
+        512
+    }
     fn read(&self, sector: u64, sector_count: usize) -> Result<Arc<[u8]>, ()>;
     fn write(&self, sector: u64, data: &[u8]) -> Result<(), ()>;
+    // Bumped whenever removable media is swapped, so anything cached from the old media can
+    // be thrown away
+    fn media_generation(&self) -> u64 {
+        0
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -42,9 +60,8 @@ impl From<&[u8]> for MBR {
 }
 
 impl MBR {
-    pub fn partitions(&self) -> Arc<[MBRPartition]> {
-        let mut partitions = Vec::new();
-
+    // The four primary slots in order, unused slots are None
+    pub fn partition_slots(&self) -> [Option<MBRPartition>; 4] {
         let raw_partitions = [
             self.first_partition,
             self.second_partition,
@@ -52,63 +69,110 @@ impl MBR {
             self.fourth_partition,
         ];
 
-        for partition in raw_partitions.iter() {
-            // if partition bytes are empty
-            if partition.iter().filter(|&&x| x > 0).count() == 0 {
-                break;
+        return raw_partitions.map(|partition| {
+            // a type of 0 is an unused slot, later slots can still be in use
+            if partition[4] == 0 {
+                return None;
             }
 
-            let partition = MBRPartition {
+            return Some(MBRPartition {
                 boot_indicator: partition[0],
                 partition_start_chs: partition[1..4].try_into().unwrap(),
                 partition_type: partition[4],
-                partition_end_chs: partition[4..7].try_into().unwrap(),
+                partition_end_chs: partition[5..8].try_into().unwrap(),
                 partition_start_lba: u32::from_le_bytes(partition[8..12].try_into().unwrap()),
                 partition_sectors: u32::from_le_bytes(partition[12..16].try_into().unwrap()),
-            };
+            });
+        });
+    }
 
-            partitions.push(partition)
-        }
+    pub fn partitions(&self) -> Arc<[MBRPartition]> {
+        let partitions: Vec<MBRPartition> = self.partition_slots().into_iter().flatten().collect();
 
         return Arc::from(partitions);
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone)]
 pub enum Partition {
-    MBRPartition((MBRPartition, *const dyn BlockDevice)),
-    GPTPartition((GPTPartitionEntry, *const dyn BlockDevice)),
+    MBRPartition((MBRPartition, Arc<dyn BlockDevice>)),
+    GPTPartition((GPTPartitionEntry, Arc<dyn BlockDevice>)),
+    // A disk with no partition table used as one volume, like a CD
+    Disk(Arc<dyn BlockDevice>),
 }
 
-impl Partition {
-    pub fn read(&self, sector: u64, sector_count: usize) -> Result<Arc<[u8]>, ()> {
+impl core::fmt::Debug for Partition {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
-            Partition::GPTPartition((partition, block_device)) => {
-                if partition.start_sector + sector + sector_count as u64 > partition.end_sector {
-                    return Err(());
-                }
-
-                return unsafe {
-                    (**block_device).read(partition.start_sector + sector, sector_count)
-                };
+            Partition::MBRPartition((partition, _)) => {
+                f.debug_tuple("MBRPartition").field(partition).finish()
             }
-            Partition::MBRPartition((partition, block_device)) => {
-                if partition.partition_start_lba as u64 + sector + sector_count as u64
-                    > partition.partition_start_lba as u64 + partition.partition_sectors as u64
-                {
-                    return Err(());
-                }
-
-                return unsafe {
-                    (**block_device)
-                        .read(partition.partition_start_lba as u64 + sector, sector_count)
-                };
+            Partition::GPTPartition((partition, _)) => {
+                f.debug_tuple("GPTPartition").field(partition).finish()
             }
+            Partition::Disk(_) => f.debug_tuple("Disk").finish(),
         }
     }
+}
+
+impl Partition {
+    pub fn read(&self, sector: u64, sector_count: usize) -> Result<Arc<[u8]>, ()> {
+        let start = self.device_sector(sector, sector_count)?;
 
-    pub fn write(&self, _sector: u64, _data: &[u8]) -> Result<(), ()> {
-        todo!();
+        return self.block_device().read(start, sector_count);
+    }
+
+    // `data` has to be a whole number of sectors, a partial sector would leave the rest of it
+    // up to the drive
+    pub fn write(&self, sector: u64, data: &[u8]) -> Result<(), ()> {
+        let sector_size = self.block_device().sector_size();
+
+        if data.len() % sector_size != 0 {
+            return Err(());
+        }
+
+        let start = self.device_sector(sector, data.len() / sector_size)?;
+
+        return self.block_device().write(start, data);
+    }
+
+    pub fn block_device(&self) -> &Arc<dyn BlockDevice> {
+        match self {
+            Partition::GPTPartition((_, block_device)) => block_device,
+            Partition::MBRPartition((_, block_device)) => block_device,
+            Partition::Disk(block_device) => block_device,
+        }
+    }
+
+    pub fn start_sector(&self) -> u64 {
+        match self {
+            Partition::GPTPartition((partition, _)) => partition.start_sector,
+            Partition::MBRPartition((partition, _)) => partition.partition_start_lba as u64,
+            Partition::Disk(_) => 0,
+        }
+    }
+
+    // Turns a partition relative range into a sector on the drive, rejecting anything that
+    // would spill out of the partition
+    fn device_sector(&self, sector: u64, sector_count: usize) -> Result<u64, ()> {
+        let end = sector.checked_add(sector_count as u64).ok_or(())?;
+
+        if end > self.sector_count() {
+            return Err(());
+        }
+
+        return Ok(self.start_sector() + sector);
+    }
+
+    pub fn sector_count(&self) -> u64 {
+        match self {
+            // GPT end sectors are inclusive
+            Partition::GPTPartition((partition, _)) => {
+                partition.end_sector - partition.start_sector + 1
+            }
+            Partition::MBRPartition((partition, _)) => partition.partition_sectors as u64,
+            Partition::Disk(block_device) => block_device.sector_count(),
+        }
     }
 }
 
@@ -132,6 +196,63 @@ pub struct GPTPartitionEntry {
     pub partition_name: [u8; 72],
 }
 
+impl GPTPartitionEntry {
+    pub fn new(data: &[u8]) -> Self {
+        assert!(data.len() >= 128);
+
+        return Self {
+            partition_type_guid: Uuid::from(<[u8; 16]>::try_from(&data[0..16]).unwrap()),
+            unique_partition_guid: Uuid::from(<[u8; 16]>::try_from(&data[16..32]).unwrap()),
+            start_sector: u64::from_le_bytes(data[32..40].try_into().unwrap()),
+            end_sector: u64::from_le_bytes(data[40..48].try_into().unwrap()),
+            attributes: u64::from_le_bytes(data[48..56].try_into().unwrap()),
+            partition_name: data[56..128].try_into().unwrap(),
+        };
+    }
+
+    pub fn to_bytes(&self) -> [u8; 128] {
+        let mut bytes = [0u8; 128];
+
+        bytes[0..16].copy_from_slice(&<[u8; 16]>::from(self.partition_type_guid));
+        bytes[16..32].copy_from_slice(&<[u8; 16]>::from(self.unique_partition_guid));
+        bytes[32..40].copy_from_slice(&self.start_sector.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.end_sector.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+        bytes[56..128].copy_from_slice(&self.partition_name);
+
+        return bytes;
+    }
+
+    // Partition names are UTF-16LE, padded out with NULs
+    pub fn name(&self) -> String {
+        let units = self
+            .partition_name
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0);
+
+        return char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+    }
+
+    pub fn set_name(&mut self, name: &str) -> Result<(), ()> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+
+        if units.len() > self.partition_name.len() / 2 {
+            return Err(());
+        }
+
+        self.partition_name = [0u8; 72];
+
+        for (i, unit) in units.iter().enumerate() {
+            self.partition_name[i * 2..i * 2 + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+
+        return Ok(());
+    }
+}
+
 #[derive(Debug)]
 pub struct GPTHeader {
     pub header: [u8; 8], // 0x45 0x46 0x49 0x20 0x50 0x41 0x52 0x54
@@ -187,4 +308,26 @@ impl GPTHeader {
             partition_table_crc,
         }
     }
+
+    // Serializes the header with `header_checksum` as is, callers fill it in afterwards
+    pub fn to_bytes(&self) -> [u8; 92] {
+        let mut bytes = [0u8; 92];
+
+        bytes[0x00..0x08].copy_from_slice(&self.header);
+        bytes[0x08..0x0C].copy_from_slice(&self.revision.to_le_bytes());
+        bytes[0x0C..0x10].copy_from_slice(&self.header_size.to_le_bytes());
+        bytes[0x10..0x14].copy_from_slice(&self.header_checksum.to_le_bytes());
+        bytes[0x14..0x18].copy_from_slice(&self._reserved);
+        bytes[0x18..0x20].copy_from_slice(&self.header_lba.to_le_bytes());
+        bytes[0x20..0x28].copy_from_slice(&self.header_lba_alt.to_le_bytes());
+        bytes[0x28..0x30].copy_from_slice(&self.first_usable_block.to_le_bytes());
+        bytes[0x30..0x38].copy_from_slice(&self.last_usable_block.to_le_bytes());
+        bytes[0x38..0x48].copy_from_slice(&<[u8; 16]>::from(self.guid));
+        bytes[0x48..0x50].copy_from_slice(&self.guid_lba.to_le_bytes());
+        bytes[0x50..0x54].copy_from_slice(&self.partition_entry_count.to_le_bytes());
+        bytes[0x54..0x58].copy_from_slice(&self.partition_entry_size.to_le_bytes());
+        bytes[0x58..0x5C].copy_from_slice(&self.partition_table_crc.to_le_bytes());
+
+        return bytes;
+    }
 }
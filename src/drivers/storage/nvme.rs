@@ -0,0 +1,568 @@
+use alloc::{format, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    drivers::{
+        dma::{DmaRegion, Mmio},
+        fs::mount_partition,
+        pci::{find_devices, PciDevice},
+        storage::registry::{partitions_of, register_named_disk},
+    },
+    libs::sync::Mutex,
+    mem::{LabelBytes, PAGE_SIZE},
+    LogLevel,
+};
+
+use super::BlockDevice;
+
+// Controller registers
+const NVME_CAP: usize = 0x00;
+const NVME_VS: usize = 0x08;
+const NVME_CC: usize = 0x14;
+const NVME_CSTS: usize = 0x1C;
+const NVME_AQA: usize = 0x24;
+const NVME_ASQ: usize = 0x28;
+const NVME_ACQ: usize = 0x30;
+const NVME_DOORBELLS: usize = 0x1000;
+
+const NVME_CC_ENABLE: u32 = 1 << 0;
+// 64 byte submission entries and 16 byte completion entries, as powers of two
+const NVME_CC_IOSQES: u32 = 6 << 16;
+const NVME_CC_IOCQES: u32 = 4 << 20;
+
+const NVME_CSTS_READY: u32 = 1 << 0;
+const NVME_CSTS_FATAL: u32 = 1 << 1;
+
+const ADMIN_DELETE_IO_CQ: u8 = 0x04;
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+
+const SUBMISSION_ENTRY_SIZE: usize = 64;
+const COMPLETION_ENTRY_SIZE: usize = 16;
+// One page of submission entries
+const QUEUE_DEPTH: usize = PAGE_SIZE / SUBMISSION_ENTRY_SIZE;
+
+const IO_QUEUE_ID: u16 = 1;
+
+// Transfers go through a bounce buffer of this many pages, bigger requests are split up
+const DMA_BUFFER_PAGES: usize = 32;
+
+// Loop iterations to wait for a completion before giving up
+const SPIN_TIMEOUT: usize = 10_000_000;
+
+static NEXT_CONTROLLER: AtomicUsize = AtomicUsize::new(0);
+
+// A submission queue and the completion queue it posts to. Commands are polled for, so only one
+// is ever in flight.
+struct QueuePair {
+    id: u16,
+    submission: DmaRegion,
+    completion: DmaRegion,
+    submission_tail: u16,
+    completion_head: u16,
+    phase: bool,
+    command_id: u16,
+}
+
+impl QueuePair {
+    fn new(id: u16) -> Result<Self, ()> {
+        return Ok(Self {
+            id,
+            submission: DmaRegion::new(1)?,
+            completion: DmaRegion::new(1)?,
+            submission_tail: 0,
+            completion_head: 0,
+            // The controller flips the phase bit to 1 on its first pass through the queue
+            phase: true,
+            command_id: 0,
+        });
+    }
+}
+
+struct Command {
+    opcode: u8,
+    nsid: u32,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+}
+
+impl Command {
+    fn new(opcode: u8) -> Self {
+        return Self {
+            opcode,
+            nsid: 0,
+            prp1: 0,
+            prp2: 0,
+            cdw10: 0,
+            cdw11: 0,
+            cdw12: 0,
+        };
+    }
+}
+
+struct IoState {
+    queue: QueuePair,
+    buffer: DmaRegion,
+    // PRP list for the pages of `buffer` after the first one
+    prp_list: DmaRegion,
+}
+
+pub struct NvmeController {
+    registers: Mmio,
+    doorbell_stride: usize,
+    // Largest transfer the controller takes in one command, in bytes
+    max_transfer: usize,
+    admin: Mutex<QueuePair>,
+    io: Mutex<IoState>,
+}
+
+impl NvmeController {
+    fn new(registers: Mmio) -> Result<Self, ()> {
+        let cap = registers.read_u64(NVME_CAP);
+
+        let doorbell_stride = 4 << ((cap >> 32) & 0xF);
+        let max_entries = (cap & 0xFFFF) as usize + 1;
+
+        // CAP.MPSMIN, the controller has to support 4 KiB pages for our PRPs to work
+        if (cap >> 48) & 0xF != 0 || max_entries < QUEUE_DEPTH {
+            return Err(());
+        }
+
+        let mut prp_list = DmaRegion::new(1)?;
+
+        let buffer = DmaRegion::new(DMA_BUFFER_PAGES)?;
+
+        // The PRP list never changes, it always describes the same bounce buffer
+        for page in 1..DMA_BUFFER_PAGES {
+            let offset = (page - 1) * 8;
+            prp_list
+                .slice(offset, 8)
+                .copy_from_slice(&buffer.phys_addr(page * PAGE_SIZE).to_le_bytes());
+        }
+
+        let controller = Self {
+            registers,
+            doorbell_stride,
+            max_transfer: DMA_BUFFER_PAGES * PAGE_SIZE,
+            admin: Mutex::new(QueuePair::new(0)?),
+            io: Mutex::new(IoState {
+                queue: QueuePair::new(IO_QUEUE_ID)?,
+                buffer,
+                prp_list,
+            }),
+        };
+
+        controller.reset(cap)?;
+
+        return Ok(controller);
+    }
+
+    // Disables the controller, hands it the admin queues and turns it back on
+    fn reset(&self, cap: u64) -> Result<(), ()> {
+        // CAP.TO is in 500ms units, we can't time it so it only scales the spin count
+        let timeout = SPIN_TIMEOUT * ((cap >> 24) & 0xFF).max(1) as usize;
+
+        self.registers
+            .write(NVME_CC, self.registers.read(NVME_CC) & !NVME_CC_ENABLE);
+        self.spin_until(timeout, || {
+            self.registers.read(NVME_CSTS) & NVME_CSTS_READY == 0
+        })?;
+
+        let admin = self.admin.lock();
+
+        let depth = (QUEUE_DEPTH - 1) as u32;
+        self.registers.write(NVME_AQA, depth << 16 | depth);
+        self.registers
+            .write_u64(NVME_ASQ, admin.submission.phys_addr(0));
+        self.registers
+            .write_u64(NVME_ACQ, admin.completion.phys_addr(0));
+
+        drop(admin);
+
+        self.registers
+            .write(NVME_CC, NVME_CC_IOSQES | NVME_CC_IOCQES | NVME_CC_ENABLE);
+
+        self.spin_until(timeout, || {
+            self.registers.read(NVME_CSTS) & (NVME_CSTS_READY | NVME_CSTS_FATAL) != 0
+        })?;
+
+        if self.registers.read(NVME_CSTS) & NVME_CSTS_FATAL != 0 {
+            crate::log!(LogLevel::Error, "NVMe: Controller failed to start");
+            return Err(());
+        }
+
+        return Ok(());
+    }
+
+    fn spin_until<F: Fn() -> bool>(&self, timeout: usize, condition: F) -> Result<(), ()> {
+        for _ in 0..timeout {
+            if condition() {
+                return Ok(());
+            }
+
+            core::hint::spin_loop();
+        }
+
+        crate::log!(LogLevel::Error, "NVMe: Controller timed out");
+
+        return Err(());
+    }
+
+    fn doorbell(&self, queue: u16, completion: bool) -> usize {
+        return NVME_DOORBELLS + (2 * queue as usize + completion as usize) * self.doorbell_stride;
+    }
+
+    // Submits a command and polls for its completion, returning dword 0 of the completion entry
+    fn submit(&self, queue: &mut QueuePair, command: Command) -> Result<u32, ()> {
+        let command_id = queue.command_id;
+        queue.command_id = queue.command_id.wrapping_add(1);
+
+        let mut entry = [0u8; SUBMISSION_ENTRY_SIZE];
+        let cdw0 = command.opcode as u32 | (command_id as u32) << 16;
+        entry[0..4].copy_from_slice(&cdw0.to_le_bytes());
+        entry[4..8].copy_from_slice(&command.nsid.to_le_bytes());
+        entry[24..32].copy_from_slice(&command.prp1.to_le_bytes());
+        entry[32..40].copy_from_slice(&command.prp2.to_le_bytes());
+        entry[40..44].copy_from_slice(&command.cdw10.to_le_bytes());
+        entry[44..48].copy_from_slice(&command.cdw11.to_le_bytes());
+        entry[48..52].copy_from_slice(&command.cdw12.to_le_bytes());
+
+        let tail = queue.submission_tail as usize;
+        queue
+            .submission
+            .slice(tail * SUBMISSION_ENTRY_SIZE, SUBMISSION_ENTRY_SIZE)
+            .copy_from_slice(&entry);
+
+        queue.submission_tail = ((tail + 1) % QUEUE_DEPTH) as u16;
+        self.registers
+            .write(self.doorbell(queue.id, false), queue.submission_tail as u32);
+
+        let head = queue.completion_head as usize * COMPLETION_ENTRY_SIZE;
+        let phase = queue.phase;
+
+        self.spin_until(SPIN_TIMEOUT, || {
+            let status = queue.completion.read_volatile::<u16>(head + 14);
+            (status & 1 != 0) == phase
+        })?;
+
+        let result = queue.completion.read_volatile::<u32>(head);
+        let status = queue.completion.read_volatile::<u16>(head + 14) >> 1;
+
+        queue.completion_head += 1;
+        if queue.completion_head as usize == QUEUE_DEPTH {
+            queue.completion_head = 0;
+            queue.phase = !queue.phase;
+        }
+
+        self.registers
+            .write(self.doorbell(queue.id, true), queue.completion_head as u32);
+
+        if status != 0 {
+            crate::log!(
+                LogLevel::Error,
+                "NVMe: Command {:#04X} on queue {} failed with status {status:#X}",
+                command.opcode,
+                queue.id
+            );
+
+            return Err(());
+        }
+
+        return Ok(result);
+    }
+
+    fn identify(&self, cns: u32, nsid: u32) -> Result<[u8; PAGE_SIZE], ()> {
+        // Admin commands go through the I/O bounce buffer, they only happen during setup
+        let mut io = self.io.lock();
+        let mut admin = self.admin.lock();
+
+        let mut command = Command::new(ADMIN_IDENTIFY);
+        command.nsid = nsid;
+        command.prp1 = io.buffer.phys_addr(0);
+        command.cdw10 = cns;
+
+        self.submit(&mut admin, command)?;
+
+        let mut data = [0u8; PAGE_SIZE];
+        data.copy_from_slice(io.buffer.slice(0, PAGE_SIZE));
+
+        return Ok(data);
+    }
+
+    fn create_io_queues(&self) -> Result<(), ()> {
+        let io = self.io.lock();
+        let mut admin = self.admin.lock();
+
+        let size = (QUEUE_DEPTH as u32 - 1) << 16;
+
+        // Physically contiguous, interrupts disabled
+        let mut command = Command::new(ADMIN_CREATE_IO_CQ);
+        command.prp1 = io.queue.completion.phys_addr(0);
+        command.cdw10 = size | IO_QUEUE_ID as u32;
+        command.cdw11 = 1;
+
+        self.submit(&mut admin, command)?;
+
+        // Physically contiguous, posting to the completion queue we just made
+        let mut command = Command::new(ADMIN_CREATE_IO_SQ);
+        command.prp1 = io.queue.submission.phys_addr(0);
+        command.cdw10 = size | IO_QUEUE_ID as u32;
+        command.cdw11 = (IO_QUEUE_ID as u32) << 16 | 1;
+
+        if self.submit(&mut admin, command).is_err() {
+            let mut command = Command::new(ADMIN_DELETE_IO_CQ);
+            command.cdw10 = IO_QUEUE_ID as u32;
+            let _ = self.submit(&mut admin, command);
+
+            return Err(());
+        }
+
+        return Ok(());
+    }
+
+    // Reads or writes `count` blocks at `lba` using the first `len` bytes of the bounce buffer
+    fn transfer(
+        &self,
+        io: &mut IoState,
+        write: bool,
+        nsid: u32,
+        lba: u64,
+        count: usize,
+        len: usize,
+    ) -> Result<(), ()> {
+        let pages = len.div_ceil(PAGE_SIZE);
+
+        let mut command = Command::new(if write { IO_WRITE } else { IO_READ });
+        command.nsid = nsid;
+        command.prp1 = io.buffer.phys_addr(0);
+        command.prp2 = match pages {
+            1 => 0,
+            2 => io.buffer.phys_addr(PAGE_SIZE),
+            _ => io.prp_list.phys_addr(0),
+        };
+        command.cdw10 = lba as u32;
+        command.cdw11 = (lba >> 32) as u32;
+        command.cdw12 = (count - 1) as u32;
+
+        self.submit(&mut io.queue, command)?;
+
+        return Ok(());
+    }
+}
+
+pub struct NvmeNamespace {
+    controller: Arc<NvmeController>,
+    nsid: u32,
+    sector_count: u64,
+    sector_size: usize,
+}
+
+impl NvmeNamespace {
+    // Sectors that fit in one transfer, limited by both the bounce buffer and MDTS
+    fn max_sectors(&self) -> usize {
+        return self.controller.max_transfer / self.sector_size;
+    }
+}
+
+impl BlockDevice for NvmeNamespace {
+    fn sector_count(&self) -> u64 {
+        return self.sector_count;
+    }
+
+    fn sector_size(&self) -> usize {
+        return self.sector_size;
+    }
+
+    fn read(&self, sector: u64, sector_count: usize) -> Result<Arc<[u8]>, ()> {
+        if (sector + sector_count as u64) > self.sector_count {
+            return Err(());
+        }
+
+        let mut data = vec![0u8; sector_count * self.sector_size];
+        let max_sectors = self.max_sectors();
+
+        let mut io = self.controller.io.lock();
+
+        for (i, chunk) in data.chunks_mut(max_sectors * self.sector_size).enumerate() {
+            let lba = sector + (i * max_sectors) as u64;
+            let count = chunk.len() / self.sector_size;
+
+            self.controller
+                .transfer(&mut io, false, self.nsid, lba, count, chunk.len())?;
+
+            chunk.copy_from_slice(io.buffer.slice(0, chunk.len()));
+        }
+
+        return Ok(Arc::from(data));
+    }
+
+    fn write(&self, sector: u64, data: &[u8]) -> Result<(), ()> {
+        if data.len() % self.sector_size != 0
+            || sector + (data.len() / self.sector_size) as u64 > self.sector_count
+        {
+            return Err(());
+        }
+
+        let max_sectors = self.max_sectors();
+
+        let mut io = self.controller.io.lock();
+
+        for (i, chunk) in data.chunks(max_sectors * self.sector_size).enumerate() {
+            let lba = sector + (i * max_sectors) as u64;
+            let count = chunk.len() / self.sector_size;
+
+            io.buffer.slice(0, chunk.len()).copy_from_slice(chunk);
+
+            self.controller
+                .transfer(&mut io, true, self.nsid, lba, count, chunk.len())?;
+        }
+
+        return Ok(());
+    }
+}
+
+pub fn init() {
+    // Mass storage controller, non-volatile memory controller, NVM Express
+    for device in find_devices(0x01, 0x08) {
+        if device.prog_if != 0x02 {
+            continue;
+        }
+
+        nvme_initialize(device);
+    }
+}
+
+fn nvme_initialize(device: PciDevice) {
+    let Some(bar0) = device.memory_bar(0) else {
+        crate::log!(LogLevel::Error, "NVMe: Controller has no memory BAR");
+        return;
+    };
+
+    device.enable_bus_mastering();
+
+    let registers = Mmio::new(bar0);
+    let version = registers.read(NVME_VS);
+
+    let mut controller = match NvmeController::new(registers) {
+        Ok(controller) => controller,
+        Err(_) => {
+            crate::log!(
+                LogLevel::Error,
+                "NVMe: Failed to reset controller at {bar0:#X}"
+            );
+            return;
+        }
+    };
+
+    let Ok(identify) = controller.identify(IDENTIFY_CONTROLLER, 0) else {
+        return;
+    };
+
+    let model = core::str::from_utf8(&identify[24..64]).unwrap_or("").trim();
+
+    // MDTS is a power of two in units of the minimum page size, 0 means no limit
+    let mdts = identify[77];
+    if mdts != 0 {
+        // A limit too big for a usize is no limit either
+        let limit = 1usize
+            .checked_shl(mdts as u32)
+            .and_then(|pages| PAGE_SIZE.checked_mul(pages));
+
+        if let Some(limit) = limit {
+            controller.max_transfer = controller.max_transfer.min(limit);
+        }
+    }
+
+    crate::log!(
+        LogLevel::Trace,
+        "NVMe: {model} (NVMe {}.{}) at {bar0:#X}",
+        version >> 16,
+        (version >> 8) & 0xFF
+    );
+
+    if controller.create_io_queues().is_err() {
+        crate::log!(LogLevel::Error, "NVMe: Failed to create I/O queues");
+        return;
+    }
+
+    let Ok(namespace_list) = controller.identify(IDENTIFY_ACTIVE_NAMESPACES, 0) else {
+        return;
+    };
+
+    let controller = Arc::new(controller);
+    let controller_index = NEXT_CONTROLLER.fetch_add(1, Ordering::Relaxed);
+
+    let namespaces: Vec<u32> = namespace_list
+        .chunks_exact(4)
+        .map(|nsid| u32::from_le_bytes(nsid.try_into().unwrap()))
+        .take_while(|&nsid| nsid != 0)
+        .collect();
+
+    for nsid in namespaces {
+        let Ok(identify) = controller.identify(IDENTIFY_NAMESPACE, nsid) else {
+            continue;
+        };
+
+        let sector_count = u64::from_le_bytes(identify[0..8].try_into().unwrap());
+
+        // The formatted LBA size picks one of the LBA formats, each of which gives the block
+        // size as a power of two
+        let format = (identify[26] & 0xF) as usize;
+        let lba_format = u32::from_le_bytes(
+            identify[128 + format * 4..132 + format * 4]
+                .try_into()
+                .unwrap(),
+        );
+        let sector_size = 1usize.checked_shl((lba_format >> 16) & 0xFF).unwrap_or(0);
+
+        if sector_count == 0 || sector_size > controller.max_transfer {
+            continue;
+        }
+
+        // The filesystems all still assume 512 byte sectors, they'd misread a 4Kn namespace
+        if sector_size != 512 {
+            crate::log!(
+                LogLevel::Warn,
+                "NVMe: Skipping namespace {nsid}, {sector_size} byte sectors aren't supported yet"
+            );
+            continue;
+        }
+
+        let name = format!("nvme{controller_index}n{nsid}");
+
+        crate::log!(
+            LogLevel::Trace,
+            "NVMe: Namespace {name} has {} sectors of {} bytes ({})",
+            sector_count,
+            sector_size,
+            ((sector_count as usize) * sector_size).label_bytes()
+        );
+
+        let namespace = Arc::new(NvmeNamespace {
+            controller: controller.clone(),
+            nsid,
+            sector_count,
+            sector_size,
+        });
+
+        if register_named_disk(&name, namespace).is_err() {
+            crate::log!(LogLevel::Error, "NVMe: {name} is already registered");
+            continue;
+        }
+
+        for (name, partition) in partitions_of(&name) {
+            mount_partition(&name, partition);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{libs::crc32::crc32, LogLevel};
+
+use super::{BlockDevice, GPTHeader, GPTPartitionEntry, MBRPartition, Partition, MBR};
+
+pub(super) const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: u32 = 128;
+
+const MBR_TYPE_PROTECTIVE: u8 = 0xEE;
+// CHS, LBA and Linux flavours of the extended partition
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+
+// An EBR chain that goes on longer than this has looped back on itself
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+// Reads the partition table off of a block device, GPT if the MBR says the disk is protective
+// (or hybrid), otherwise the four MBR slots and any logical partitions in an extended one.
+// Partitions are numbered like Linux does, GPT and primary MBR partitions by their slot and
+// logical partitions from 5 onwards.
+pub fn scan_partitions(device: Arc<dyn BlockDevice>) -> Result<Vec<(u32, Partition)>, ()> {
+    let mbr: MBR = (*device.read(0, 1)?).into();
+
+    if u16::from_le_bytes(mbr.signature) != 0xAA55 {
+        return Err(());
+    }
+
+    let mbr_partitions = mbr.partitions();
+
+    if mbr_partitions
+        .iter()
+        .any(|partition| partition.partition_type == MBR_TYPE_PROTECTIVE)
+    {
+        match scan_gpt(&device) {
+            Ok(partitions) => return Ok(partitions),
+            Err(_) => crate::log!(
+                LogLevel::Warn,
+                "Disk has a protective MBR, but no valid GPT, using the MBR instead"
+            ),
+        }
+    }
+
+    let mut partitions = Vec::new();
+
+    for (slot, partition) in mbr.partition_slots().into_iter().enumerate() {
+        let Some(partition) = partition else {
+            continue;
+        };
+
+        match partition.partition_type {
+            MBR_TYPE_PROTECTIVE => continue,
+            partition_type if MBR_EXTENDED_TYPES.contains(&partition_type) => {
+                // A broken EBR only loses the logical partitions from there on
+                if scan_extended(&device, &partition, &mut partitions).is_err() {
+                    crate::log!(
+                        LogLevel::Warn,
+                        "MBR: Extended partition chain is broken, ignoring the rest of it"
+                    );
+                }
+            }
+            _ => partitions.push((
+                slot as u32 + 1,
+                Partition::MBRPartition((partition, device.clone())),
+            )),
+        }
+    }
+
+    // Logical partitions were numbered as they were found, put them after the primary ones
+    partitions.sort_by_key(|&(number, _)| number);
+
+    return Ok(partitions);
+}
+
+// Reads and verifies one copy of the GPT, the header at `lba` and the entry array it points at
+pub(super) fn read_gpt(device: &dyn BlockDevice, lba: u64) -> Result<(GPTHeader, Arc<[u8]>), ()> {
+    let sector_size = device.sector_size();
+
+    let header_sector = device.read(lba, 1)?;
+
+    if &header_sector[0..8] != GPT_SIGNATURE {
+        return Err(());
+    }
+
+    let gpt = GPTHeader::new(&header_sector);
+
+    if (gpt.header_size as usize) < GPT_HEADER_MIN_SIZE
+        || gpt.header_size as usize > sector_size
+        || gpt.header_lba != lba
+    {
+        return Err(());
+    }
+
+    // The checksum covers the header with its own checksum field zeroed
+    let mut header = header_sector[..gpt.header_size as usize].to_vec();
+    header[0x10..0x14].fill(0);
+
+    if crc32(&header) != gpt.header_checksum {
+        return Err(());
+    }
+
+    if gpt.partition_entry_size < GPT_ENTRY_MIN_SIZE || !gpt.partition_entry_size.is_power_of_two()
+    {
+        return Err(());
+    }
+
+    let table_size = gpt.partition_entry_count as usize * gpt.partition_entry_size as usize;
+
+    // The spec only lets the table be at most this big, bigger is a broken header
+    if table_size > 0x8000 * GPT_ENTRY_MIN_SIZE as usize {
+        return Err(());
+    }
+
+    let table = device.read(gpt.guid_lba, table_size.div_ceil(sector_size))?;
+
+    if crc32(&table[..table_size]) != gpt.partition_table_crc {
+        return Err(());
+    }
+
+    return Ok((gpt, table));
+}
+
+// Picks the primary GPT if it is intact, otherwise the backup at the end of the disk. When both
+// are readable but describe different tables the primary wins, but it is worth shouting about.
+fn scan_gpt(device: &Arc<dyn BlockDevice>) -> Result<Vec<(u32, Partition)>, ()> {
+    let block_device = device.as_ref();
+
+    let primary = read_gpt(block_device, 1);
+
+    let backup_lba = match primary {
+        Ok((ref gpt, _)) => gpt.header_lba_alt,
+        Err(_) => block_device.sector_count().saturating_sub(1),
+    };
+
+    let backup = read_gpt(block_device, backup_lba);
+
+    let (gpt, table) = match (primary, backup) {
+        (Ok(primary), Ok(backup)) => {
+            if primary.0.guid != backup.0.guid
+                || primary.0.first_usable_block != backup.0.first_usable_block
+                || primary.0.last_usable_block != backup.0.last_usable_block
+                || primary.0.header_lba_alt != backup.0.header_lba
+                || primary.0.partition_table_crc != backup.0.partition_table_crc
+            {
+                crate::log!(
+                    LogLevel::Warn,
+                    "GPT: Primary and backup headers disagree, using the primary"
+                );
+            }
+
+            primary
+        }
+        (Ok(primary), Err(_)) => {
+            crate::log!(LogLevel::Warn, "GPT: Backup header is missing or damaged");
+
+            primary
+        }
+        (Err(_), Ok(backup)) => {
+            crate::log!(
+                LogLevel::Warn,
+                "GPT: Primary header is damaged, recovering from the backup at LBA {}",
+                backup_lba
+            );
+
+            backup
+        }
+        (Err(_), Err(_)) => return Err(()),
+    };
+
+    let mut partitions = Vec::new();
+
+    for (slot, entry) in table
+        .chunks_exact(gpt.partition_entry_size as usize)
+        .take(gpt.partition_entry_count as usize)
+        .enumerate()
+    {
+        // An all zero type GUID is an unused entry
+        if entry[0..16].iter().all(|&byte| byte == 0) {
+            continue;
+        }
+
+        let partition = GPTPartitionEntry::new(entry);
+
+        if partition.end_sector < partition.start_sector
+            || partition.start_sector < gpt.first_usable_block
+            || partition.end_sector > gpt.last_usable_block
+        {
+            crate::log!(
+                LogLevel::Warn,
+                "GPT: Skipping partition {} with bad bounds",
+                partition.unique_partition_guid
+            );
+            continue;
+        }
+
+        partitions.push((
+            slot as u32 + 1,
+            Partition::GPTPartition((partition, device.clone())),
+        ));
+    }
+
+    return Ok(partitions);
+}
+
+// Logical partitions are a linked list of EBRs. The first slot of each EBR is the partition,
+// relative to that EBR, and the second slot points at the next EBR, relative to the start of
+// the extended partition.
+fn scan_extended(
+    device: &Arc<dyn BlockDevice>,
+    extended: &MBRPartition,
+    partitions: &mut Vec<(u32, Partition)>,
+) -> Result<(), ()> {
+    let block_device = device.as_ref();
+    let mut number = 5;
+
+    let extended_start = extended.partition_start_lba;
+    let mut ebr_lba = extended_start;
+
+    for _ in 0..MAX_LOGICAL_PARTITIONS {
+        let ebr: MBR = (*block_device.read(ebr_lba as u64, 1)?).into();
+
+        if u16::from_le_bytes(ebr.signature) != 0xAA55 {
+            return Err(());
+        }
+
+        let entries = ebr.partitions();
+        let mut next = None;
+
+        for entry in entries.iter() {
+            if MBR_EXTENDED_TYPES.contains(&entry.partition_type) {
+                next = Some(
+                    extended_start
+                        .checked_add(entry.partition_start_lba)
+                        .ok_or(())?,
+                );
+                continue;
+            }
+
+            let mut logical = *entry;
+            logical.partition_start_lba =
+                ebr_lba.checked_add(entry.partition_start_lba).ok_or(())?;
+
+            partitions.push((number, Partition::MBRPartition((logical, device.clone()))));
+            number += 1;
+        }
+
+        match next {
+            Some(next) if next != ebr_lba => ebr_lba = next,
+            _ => return Ok(()),
+        }
+    }
+
+    crate::log!(LogLevel::Warn, "MBR: Extended partition chain is too long");
+
+    return Ok(());
+}
@@ -0,0 +1,201 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{libs::sync::Mutex, LogLevel};
+
+use super::{cache::CachedBlockDevice, scan_partitions, BlockDevice, Partition};
+
+#[derive(Clone)]
+pub enum BlockDeviceKind {
+    Disk(Arc<dyn BlockDevice>),
+    Partition(Partition),
+}
+
+// A named entry in the registry, either a whole disk (`hda`) or one of its partitions (`hda1`)
+#[derive(Clone)]
+pub struct RegisteredDevice {
+    pub name: String,
+    pub kind: BlockDeviceKind,
+}
+
+// Entries are never removed, so a name handed out stays valid until reboot
+static BLOCK_DEVICES: Mutex<Vec<RegisteredDevice>> = Mutex::new(Vec::new());
+
+// Adds a disk under the next free name for `prefix` (`hd` gives hda, hdb, ...) and registers
+// every partition found on it after it. Returns the name the disk was given.
+pub fn register_disk(prefix: &str, device: Arc<dyn BlockDevice>) -> Result<String, ()> {
+    let index = BLOCK_DEVICES
+        .lock()
+        .iter()
+        .filter(|entry| {
+            matches!(entry.kind, BlockDeviceKind::Disk(_))
+                && entry.name.strip_prefix(prefix).is_some_and(|suffix| {
+                    !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_lowercase())
+                })
+        })
+        .count();
+
+    let name = format!("{prefix}{}", disk_suffix(index));
+
+    register_named_disk(&name, device)?;
+
+    return Ok(name);
+}
+
+// Like Linux, the letters carry on past z, sdz is followed by sdaa and sdzz by sdaaa
+fn disk_suffix(index: usize) -> String {
+    let mut letters = Vec::new();
+    let mut remaining = index + 1;
+
+    while remaining > 0 {
+        remaining -= 1;
+        letters.push((b'a' + (remaining % 26) as u8) as char);
+        remaining /= 26;
+    }
+
+    return letters.iter().rev().collect();
+}
+
+// Like `register_disk`, for devices that come with their own name (`nvme0n1`). Fails if the name
+// is already taken.
+pub fn register_named_disk(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), ()> {
+    // Everything, partition scanning included, goes through the buffer cache
+    let device: Arc<dyn BlockDevice> = Arc::new(CachedBlockDevice::new(device));
+
+    let partitions = match scan_partitions(device.clone()) {
+        Ok(partitions) => partitions,
+        Err(_) => {
+            crate::log!(
+                LogLevel::Warn,
+                "{name}: Disk has no readable partition table"
+            );
+            Vec::new()
+        }
+    };
+
+    let mut devices = BLOCK_DEVICES.lock();
+
+    if devices.iter().any(|entry| entry.name == name) {
+        return Err(());
+    }
+
+    devices.push(RegisteredDevice {
+        name: name.to_string(),
+        kind: BlockDeviceKind::Disk(device),
+    });
+
+    for (number, partition) in partitions {
+        devices.push(RegisteredDevice {
+            name: partition_name(name, number),
+            kind: BlockDeviceKind::Partition(partition),
+        });
+    }
+
+    crate::log!(LogLevel::Trace, "Registered block device {name}");
+
+    return Ok(());
+}
+
+// Names ending in a digit need a separator, nvme0n1 becomes nvme0n1p1 rather than nvme0n11
+fn partition_name(disk: &str, number: u32) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        return format!("{disk}p{number}");
+    }
+
+    return format!("{disk}{number}");
+}
+
+pub fn devices() -> Vec<RegisteredDevice> {
+    return BLOCK_DEVICES.lock().clone();
+}
+
+pub fn get_device(name: &str) -> Option<RegisteredDevice> {
+    return BLOCK_DEVICES
+        .lock()
+        .iter()
+        .find(|entry| entry.name == name)
+        .cloned();
+}
+
+pub fn get_disk(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    match get_device(name)?.kind {
+        BlockDeviceKind::Disk(device) => Some(device),
+        BlockDeviceKind::Partition(_) => None,
+    }
+}
+
+pub fn get_partition(name: &str) -> Option<Partition> {
+    match get_device(name)?.kind {
+        BlockDeviceKind::Partition(partition) => Some(partition),
+        BlockDeviceKind::Disk(_) => None,
+    }
+}
+
+// Every partition on the named disk, in partition number order
+pub fn partitions_of(disk: &str) -> Vec<(String, Partition)> {
+    return BLOCK_DEVICES
+        .lock()
+        .iter()
+        .filter_map(|entry| match &entry.kind {
+            BlockDeviceKind::Partition(partition)
+                if entry
+                    .name
+                    .strip_prefix(disk)
+                    .is_some_and(|number| is_partition_suffix(disk, number)) =>
+            {
+                Some((entry.name.clone(), partition.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+}
+
+fn is_partition_suffix(disk: &str, suffix: &str) -> bool {
+    let number = match disk.ends_with(|c: char| c.is_ascii_digit()) {
+        true => suffix.strip_prefix('p'),
+        false => Some(suffix),
+    };
+
+    return number
+        .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()));
+}
+
+// GUIDs are compared as strings so callers can pass them straight from a config or cmdline
+pub fn find_by_partition_guid(guid: &str) -> Option<(String, Partition)> {
+    return find_gpt_partitions(|entry| entry.unique_partition_guid == guid)
+        .into_iter()
+        .next();
+}
+
+pub fn find_by_type_guid(guid: &str) -> Vec<(String, Partition)> {
+    return find_gpt_partitions(|entry| entry.partition_type_guid == guid);
+}
+
+pub fn find_by_label(label: &str) -> Option<(String, Partition)> {
+    return find_gpt_partitions(|entry| entry.name() == label)
+        .into_iter()
+        .next();
+}
+
+// Only GPT partitions carry GUIDs and labels, MBR ones are only reachable by name
+fn find_gpt_partitions<F>(predicate: F) -> Vec<(String, Partition)>
+where
+    F: Fn(&super::GPTPartitionEntry) -> bool,
+{
+    return BLOCK_DEVICES
+        .lock()
+        .iter()
+        .filter_map(|entry| match &entry.kind {
+            BlockDeviceKind::Partition(partition @ Partition::GPTPartition((gpt_entry, _)))
+                if predicate(gpt_entry) =>
+            {
+                Some((entry.name.to_string(), partition.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+}
@@ -0,0 +1,218 @@
+use alloc::{sync::Arc, vec};
+
+use crate::{
+    drivers::{
+        dma::DmaRegion,
+        fs::mount_partition,
+        storage::registry::{partitions_of, register_disk},
+        virtio::{self, VirtQueue, VirtioDevice, VirtqBuffer},
+    },
+    libs::sync::Mutex,
+    mem::{LabelBytes, PAGE_SIZE},
+    LogLevel,
+};
+
+use super::BlockDevice;
+
+const VIRTIO_DEVICE_TYPE_BLOCK: u16 = 2;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+// Config space: capacity in 512 byte sectors, regardless of the logical block size
+const CONFIG_CAPACITY: usize = 0x00;
+
+const REQUEST_QUEUE: u16 = 0;
+
+// virtio-blk always counts in 512 byte sectors
+const SECTOR_SIZE: usize = 512;
+
+// Transfers go through a bounce buffer of this many pages, bigger requests are split up
+const DMA_BUFFER_PAGES: usize = 32;
+
+// Offsets into the header page: the 16 byte request header, then the status byte
+const HEADER_OFFSET: usize = 0;
+const STATUS_OFFSET: usize = 16;
+
+struct RequestState {
+    queue: VirtQueue,
+    header: DmaRegion,
+    buffer: DmaRegion,
+}
+
+pub struct VirtioBlockDevice {
+    device: VirtioDevice,
+    state: Mutex<RequestState>,
+    sector_count: u64,
+    read_only: bool,
+}
+
+impl VirtioBlockDevice {
+    fn request(
+        &self,
+        state: &mut RequestState,
+        request_type: u32,
+        sector: u64,
+        len: usize,
+    ) -> Result<(), ()> {
+        let header = state.header.slice(HEADER_OFFSET, 16);
+        header[0..4].copy_from_slice(&request_type.to_le_bytes());
+        header[4..8].fill(0);
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+
+        // Anything but OK once the request is done means it failed
+        state.header.slice(STATUS_OFFSET, 1)[0] = 0xFF;
+
+        let buffers = [
+            VirtqBuffer {
+                address: state.header.phys_addr(HEADER_OFFSET),
+                len: 16,
+                writable: false,
+            },
+            VirtqBuffer {
+                address: state.buffer.phys_addr(0),
+                len: len as u32,
+                writable: request_type == VIRTIO_BLK_T_IN,
+            },
+            VirtqBuffer {
+                address: state.header.phys_addr(STATUS_OFFSET),
+                len: 1,
+                writable: true,
+            },
+        ];
+
+        self.device.submit_and_wait(&mut state.queue, &buffers)?;
+
+        let status = state.header.read_volatile::<u8>(STATUS_OFFSET);
+
+        if status != VIRTIO_BLK_S_OK {
+            crate::log!(
+                LogLevel::Error,
+                "virtio-blk: Request for sector {sector} failed with status {status}"
+            );
+            return Err(());
+        }
+
+        return Ok(());
+    }
+}
+
+impl BlockDevice for VirtioBlockDevice {
+    fn sector_count(&self) -> u64 {
+        return self.sector_count;
+    }
+
+    fn read(&self, sector: u64, sector_count: usize) -> Result<Arc<[u8]>, ()> {
+        if (sector + sector_count as u64) > self.sector_count {
+            return Err(());
+        }
+
+        let mut data = vec![0u8; sector_count * SECTOR_SIZE];
+        let max_sectors = DMA_BUFFER_PAGES * PAGE_SIZE / SECTOR_SIZE;
+
+        let mut state = self.state.lock();
+
+        for (i, chunk) in data.chunks_mut(max_sectors * SECTOR_SIZE).enumerate() {
+            let lba = sector + (i * max_sectors) as u64;
+
+            self.request(&mut state, VIRTIO_BLK_T_IN, lba, chunk.len())?;
+
+            chunk.copy_from_slice(state.buffer.slice(0, chunk.len()));
+        }
+
+        return Ok(Arc::from(data));
+    }
+
+    fn write(&self, sector: u64, data: &[u8]) -> Result<(), ()> {
+        if self.read_only
+            || data.len() % SECTOR_SIZE != 0
+            || sector + (data.len() / SECTOR_SIZE) as u64 > self.sector_count
+        {
+            return Err(());
+        }
+
+        let max_sectors = DMA_BUFFER_PAGES * PAGE_SIZE / SECTOR_SIZE;
+
+        let mut state = self.state.lock();
+
+        for (i, chunk) in data.chunks(max_sectors * SECTOR_SIZE).enumerate() {
+            let lba = sector + (i * max_sectors) as u64;
+
+            state.buffer.slice(0, chunk.len()).copy_from_slice(chunk);
+
+            self.request(&mut state, VIRTIO_BLK_T_OUT, lba, chunk.len())?;
+        }
+
+        return Ok(());
+    }
+}
+
+pub fn init() {
+    for device in virtio::find_devices(VIRTIO_DEVICE_TYPE_BLOCK) {
+        let Ok(device) = virtio_blk_initialize(device) else {
+            crate::log!(LogLevel::Error, "virtio-blk: Failed to initialize device");
+            continue;
+        };
+
+        crate::log!(
+            LogLevel::Trace,
+            "virtio-blk: {} disk with {} sectors ({}){}",
+            if device.device.is_modern() {
+                "Modern"
+            } else {
+                "Legacy"
+            },
+            device.sector_count,
+            ((device.sector_count as usize) * SECTOR_SIZE).label_bytes(),
+            if device.read_only { ", read only" } else { "" }
+        );
+
+        let name = match register_disk("vd", Arc::new(device)) {
+            Ok(name) => name,
+            Err(_) => {
+                crate::log!(LogLevel::Error, "virtio-blk: Failed to register device");
+                continue;
+            }
+        };
+
+        for (name, partition) in partitions_of(&name) {
+            mount_partition(&name, partition);
+        }
+    }
+}
+
+fn virtio_blk_initialize(mut device: VirtioDevice) -> Result<VirtioBlockDevice, ()> {
+    let features = device.begin_init(VIRTIO_BLK_F_RO)?;
+
+    let queue = match device.setup_queue(REQUEST_QUEUE) {
+        Ok(queue) => queue,
+        Err(()) => {
+            device.fail();
+            return Err(());
+        }
+    };
+
+    let (Ok(header), Ok(buffer)) = (DmaRegion::new(1), DmaRegion::new(DMA_BUFFER_PAGES)) else {
+        device.fail();
+        return Err(());
+    };
+
+    device.driver_ok();
+
+    let sector_count = device.read_config_u64(CONFIG_CAPACITY);
+
+    return Ok(VirtioBlockDevice {
+        device,
+        state: Mutex::new(RequestState {
+            queue,
+            header,
+            buffer,
+        }),
+        sector_count,
+        read_only: features & VIRTIO_BLK_F_RO != 0,
+    });
+}
@@ -0,0 +1,499 @@
+// Virtio over PCI, both the legacy I/O port interface and the modern capability based one, plus
+// split virtqueues. Device drivers (virtio-blk so far) sit on top of `VirtioDevice`.
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::{
+    arch::io::{inb, inl, inw, outb, outl, outw},
+    drivers::{
+        dma::{DmaRegion, Mmio},
+        pci::{find_devices_by_vendor, PciDevice},
+    },
+    mem::PAGE_SIZE,
+    LogLevel,
+};
+
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+// Transitional devices use 0x1000 + (device type - 1 for the old IDs), modern ones 0x1040 + type
+const LEGACY_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+// Legacy I/O port registers
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+// Without MSI-X the device specific config follows straight after the common header
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+// Modern common config structure
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// Vendor specific PCI capability types
+const PCI_CAP_VENDOR: u8 = 0x09;
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+// We only ever have a handful of requests in flight, so modern devices get a smaller queue
+const MAX_QUEUE_SIZE: u16 = 128;
+
+// Loop iterations to wait for the device to use a buffer before giving up
+const SPIN_TIMEOUT: usize = 10_000_000;
+
+enum Transport {
+    Legacy {
+        io_base: u16,
+    },
+    Modern {
+        common: Mmio,
+        notify: Mmio,
+        notify_multiplier: u32,
+        device: Mmio,
+    },
+}
+
+// One buffer in a descriptor chain, `writable` buffers are filled in by the device
+pub struct VirtqBuffer {
+    pub address: u64,
+    pub len: u32,
+    pub writable: bool,
+}
+
+// A split virtqueue laid out the legacy way (descriptors, available ring, then the used ring
+// on the next page), which modern devices accept as well
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    ring: DmaRegion,
+    used_offset: usize,
+    notify_offset: u16,
+    free: Vec<u16>,
+    available_index: u16,
+    last_used_index: u16,
+}
+
+impl VirtQueue {
+    fn new(index: u16, size: u16) -> Result<Self, ()> {
+        let available_end = 16 * size as usize + 6 + 2 * size as usize;
+        let used_offset = available_end.next_multiple_of(PAGE_SIZE);
+        let used_size = 6 + 8 * size as usize;
+
+        let ring = DmaRegion::new((used_offset + used_size).div_ceil(PAGE_SIZE))?;
+
+        return Ok(Self {
+            index,
+            size,
+            ring,
+            used_offset,
+            notify_offset: 0,
+            free: (0..size).rev().collect(),
+            available_index: 0,
+            last_used_index: 0,
+        });
+    }
+
+    fn descriptors_address(&self) -> u64 {
+        return self.ring.phys_addr(0);
+    }
+
+    fn available_address(&self) -> u64 {
+        return self.ring.phys_addr(16 * self.size as usize);
+    }
+
+    fn used_address(&self) -> u64 {
+        return self.ring.phys_addr(self.used_offset);
+    }
+
+    // Queues a descriptor chain for the device, the caller still has to notify it
+    pub fn push(&mut self, buffers: &[VirtqBuffer]) -> Result<u16, ()> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return Err(());
+        }
+
+        let descriptors: Vec<u16> = (0..buffers.len())
+            .map(|_| self.free.pop().unwrap())
+            .collect();
+
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut flags = 0;
+            let mut next = 0;
+
+            if buffer.writable {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+
+            if let Some(&next_descriptor) = descriptors.get(i + 1) {
+                flags |= VIRTQ_DESC_F_NEXT;
+                next = next_descriptor;
+            }
+
+            let descriptor = self.ring.slice(descriptors[i] as usize * 16, 16);
+            descriptor[0..8].copy_from_slice(&buffer.address.to_le_bytes());
+            descriptor[8..12].copy_from_slice(&buffer.len.to_le_bytes());
+            descriptor[12..14].copy_from_slice(&flags.to_le_bytes());
+            descriptor[14..16].copy_from_slice(&next.to_le_bytes());
+        }
+
+        let available = 16 * self.size as usize;
+        let slot = available + 4 + 2 * (self.available_index % self.size) as usize;
+        self.ring
+            .slice(slot, 2)
+            .copy_from_slice(&descriptors[0].to_le_bytes());
+
+        // The descriptors have to be visible before the device sees the new index
+        fence(Ordering::SeqCst);
+
+        self.available_index = self.available_index.wrapping_add(1);
+        self.ring
+            .slice(available + 2, 2)
+            .copy_from_slice(&self.available_index.to_le_bytes());
+
+        fence(Ordering::SeqCst);
+
+        return Ok(descriptors[0]);
+    }
+
+    // Takes the next chain the device is done with, returning its head and how many bytes the
+    // device wrote, and puts its descriptors back on the free list
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index = self.ring.read_volatile::<u16>(self.used_offset + 2);
+
+        if used_index == self.last_used_index {
+            return None;
+        }
+
+        fence(Ordering::SeqCst);
+
+        let element = self.used_offset + 4 + 8 * (self.last_used_index % self.size) as usize;
+        let head = self.ring.read_volatile::<u32>(element) as u16;
+        let len = self.ring.read_volatile::<u32>(element + 4);
+
+        self.last_used_index = self.last_used_index.wrapping_add(1);
+
+        let mut descriptor = head;
+        loop {
+            self.free.push(descriptor);
+
+            let flags = self
+                .ring
+                .read_volatile::<u16>(descriptor as usize * 16 + 12);
+
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+
+            descriptor = self
+                .ring
+                .read_volatile::<u16>(descriptor as usize * 16 + 14);
+        }
+
+        return Some((head, len));
+    }
+}
+
+pub struct VirtioDevice {
+    pub pci: PciDevice,
+    transport: Transport,
+}
+
+impl VirtioDevice {
+    pub fn new(pci: PciDevice) -> Result<Self, ()> {
+        if pci.vendor_id != VIRTIO_VENDOR_ID {
+            return Err(());
+        }
+
+        pci.enable_bus_mastering();
+
+        // Transitional devices have both, prefer the modern interface when it's there
+        if let Some(transport) = Self::modern_transport(&pci) {
+            return Ok(Self { pci, transport });
+        }
+
+        let bar0 = pci.bar(0);
+
+        // Legacy devices always put their registers in an I/O BAR
+        if bar0 & 0x1 == 0 || !LEGACY_DEVICE_IDS.contains(&pci.device_id) {
+            return Err(());
+        }
+
+        return Ok(Self {
+            pci,
+            transport: Transport::Legacy {
+                io_base: (bar0 & 0xFFFC) as u16,
+            },
+        });
+    }
+
+    fn modern_transport(pci: &PciDevice) -> Option<Transport> {
+        let mut common = None;
+        let mut notify = None;
+        let mut device = None;
+
+        for (id, offset) in pci.capabilities() {
+            if id != PCI_CAP_VENDOR {
+                continue;
+            }
+
+            let cfg_type = pci.read_config_u8(offset + 3);
+            let bar = pci.read_config_u8(offset + 4);
+            let bar_offset = pci.read_config(offset + 8) as u64;
+
+            if bar > 5 {
+                continue;
+            }
+
+            let Some(bar_address) = pci.memory_bar(bar) else {
+                continue;
+            };
+
+            let registers = Mmio::new(bar_address + bar_offset);
+
+            match cfg_type {
+                VIRTIO_PCI_CAP_COMMON_CFG => common = common.or(Some(registers)),
+                VIRTIO_PCI_CAP_NOTIFY_CFG => {
+                    let multiplier = pci.read_config(offset + 16);
+                    notify = notify.or(Some((registers, multiplier)));
+                }
+                VIRTIO_PCI_CAP_DEVICE_CFG => device = device.or(Some(registers)),
+                _ => {}
+            }
+        }
+
+        let (notify, notify_multiplier) = notify?;
+
+        return Some(Transport::Modern {
+            common: common?,
+            notify,
+            notify_multiplier,
+            device: device?,
+        });
+    }
+
+    pub fn is_modern(&self) -> bool {
+        return matches!(self.transport, Transport::Modern { .. });
+    }
+
+    // The virtio device type, 2 for block devices and so on
+    pub fn device_type(&self) -> u16 {
+        if self.pci.device_id >= MODERN_DEVICE_ID_BASE {
+            return self.pci.device_id - MODERN_DEVICE_ID_BASE;
+        }
+
+        // Subsystem ID holds the device type for transitional devices
+        return (self.pci.read_config(0x2C) >> 16) as u16;
+    }
+
+    fn status(&self) -> u8 {
+        match &self.transport {
+            Transport::Legacy { io_base } => inb(io_base + LEGACY_DEVICE_STATUS),
+            Transport::Modern { common, .. } => common.read_u8(COMMON_DEVICE_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match &self.transport {
+            Transport::Legacy { io_base } => outb(io_base + LEGACY_DEVICE_STATUS, status),
+            Transport::Modern { common, .. } => common.write_u8(COMMON_DEVICE_STATUS, status),
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match &self.transport {
+            Transport::Legacy { io_base } => inl(io_base + LEGACY_DEVICE_FEATURES) as u64,
+            Transport::Modern { common, .. } => {
+                common.write(COMMON_DEVICE_FEATURE_SELECT, 0);
+                let low = common.read(COMMON_DEVICE_FEATURE) as u64;
+                common.write(COMMON_DEVICE_FEATURE_SELECT, 1);
+                let high = common.read(COMMON_DEVICE_FEATURE) as u64;
+
+                low | high << 32
+            }
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match &self.transport {
+            Transport::Legacy { io_base } => {
+                outl(io_base + LEGACY_DRIVER_FEATURES, features as u32)
+            }
+            Transport::Modern { common, .. } => {
+                common.write(COMMON_DRIVER_FEATURE_SELECT, 0);
+                common.write(COMMON_DRIVER_FEATURE, features as u32);
+                common.write(COMMON_DRIVER_FEATURE_SELECT, 1);
+                common.write(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            }
+        }
+    }
+
+    // Resets the device and negotiates features, returning the ones both sides agreed on. Queues
+    // have to be set up after this and before `driver_ok`.
+    pub fn begin_init(&mut self, wanted_features: u64) -> Result<u64, ()> {
+        self.set_status(0);
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let mut wanted_features = wanted_features;
+
+        // Modern devices refuse to work with drivers that don't accept VERSION_1
+        if self.is_modern() {
+            wanted_features |= VIRTIO_F_VERSION_1;
+        }
+
+        let features = self.device_features() & wanted_features;
+
+        self.set_driver_features(features);
+
+        if self.is_modern() {
+            self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.set_status(STATUS_FAILED);
+                return Err(());
+            }
+        }
+
+        return Ok(features);
+    }
+
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    pub fn fail(&self) {
+        self.set_status(self.status() | STATUS_FAILED);
+    }
+
+    pub fn setup_queue(&mut self, index: u16) -> Result<VirtQueue, ()> {
+        match &self.transport {
+            Transport::Legacy { io_base } => {
+                let io_base = *io_base;
+
+                outw(io_base + LEGACY_QUEUE_SELECT, index);
+                let size = inw(io_base + LEGACY_QUEUE_SIZE);
+
+                if size == 0 {
+                    return Err(());
+                }
+
+                // Legacy devices don't let us pick the size, the ring has to match theirs
+                let queue = VirtQueue::new(index, size)?;
+
+                outl(
+                    io_base + LEGACY_QUEUE_ADDRESS,
+                    (queue.descriptors_address() / PAGE_SIZE as u64) as u32,
+                );
+
+                return Ok(queue);
+            }
+            Transport::Modern { common, .. } => {
+                common.write_u16(COMMON_QUEUE_SELECT, index);
+                let size = common.read_u16(COMMON_QUEUE_SIZE);
+
+                if size == 0 {
+                    return Err(());
+                }
+
+                let mut queue = VirtQueue::new(index, size.min(MAX_QUEUE_SIZE))?;
+
+                common.write_u16(COMMON_QUEUE_SIZE, queue.size);
+                common.write_u64(COMMON_QUEUE_DESC, queue.descriptors_address());
+                common.write_u64(COMMON_QUEUE_DRIVER, queue.available_address());
+                common.write_u64(COMMON_QUEUE_DEVICE, queue.used_address());
+                queue.notify_offset = common.read_u16(COMMON_QUEUE_NOTIFY_OFF);
+                common.write_u16(COMMON_QUEUE_ENABLE, 1);
+
+                return Ok(queue);
+            }
+        }
+    }
+
+    pub fn notify(&self, queue: &VirtQueue) {
+        match &self.transport {
+            Transport::Legacy { io_base } => outw(io_base + LEGACY_QUEUE_NOTIFY, queue.index),
+            Transport::Modern {
+                notify,
+                notify_multiplier,
+                ..
+            } => notify.write_u16(
+                queue.notify_offset as usize * *notify_multiplier as usize,
+                queue.index,
+            ),
+        }
+    }
+
+    // Hands a chain to the device and spins until it's been used, returning the bytes written
+    pub fn submit_and_wait(
+        &self,
+        queue: &mut VirtQueue,
+        buffers: &[VirtqBuffer],
+    ) -> Result<u32, ()> {
+        let head = queue.push(buffers)?;
+
+        self.notify(queue);
+
+        for _ in 0..SPIN_TIMEOUT {
+            if let Some((used_head, len)) = queue.pop_used() {
+                if used_head != head {
+                    crate::log!(LogLevel::Warn, "virtio: Device used an unexpected chain");
+                    continue;
+                }
+
+                return Ok(len);
+            }
+
+            core::hint::spin_loop();
+        }
+
+        crate::log!(LogLevel::Error, "virtio: Device timed out");
+
+        return Err(());
+    }
+
+    pub fn read_config_u32(&self, offset: usize) -> u32 {
+        match &self.transport {
+            Transport::Legacy { io_base } => inl(io_base + LEGACY_DEVICE_CONFIG + offset as u16),
+            Transport::Modern { device, .. } => device.read(offset),
+        }
+    }
+
+    pub fn read_config_u64(&self, offset: usize) -> u64 {
+        return self.read_config_u32(offset) as u64
+            | (self.read_config_u32(offset + 4) as u64) << 32;
+    }
+}
+
+// Every virtio function of the given device type on the PCI bus
+pub fn find_devices(device_type: u16) -> Vec<VirtioDevice> {
+    return find_devices_by_vendor(VIRTIO_VENDOR_ID)
+        .into_iter()
+        .filter_map(|pci| VirtioDevice::new(pci).ok())
+        .filter(|device| device.device_type() == device_type)
+        .collect();
+}
@@ -36,7 +36,10 @@ pub enum CompressionErrors {
 // RFC 1950: "ZLIB Compressed Data Format Specification"
 // RFC 1951: "DEFLATE Compressed Data Format Specification"
 pub fn uncompress_data(bytes: &[u8]) -> Result<Vec<u8>, ()> {
-    assert!(bytes.len() > 2);
+    // 2 byte header and 4 byte checksum around the deflate data
+    if bytes.len() < 6 {
+        return Err(());
+    }
 
     // Compression Method and flags
     let cmf = bytes[0];
@@ -90,6 +93,75 @@ pub fn uncompress_data(bytes: &[u8]) -> Result<Vec<u8>, ()> {
     return Ok(data);
 }
 
+// gzip member header flags
+const GZIP_FLAG_HCRC: u8 = 0x02;
+const GZIP_FLAG_EXTRA: u8 = 0x04;
+const GZIP_FLAG_NAME: u8 = 0x08;
+const GZIP_FLAG_COMMENT: u8 = 0x10;
+
+pub fn is_gzip(bytes: &[u8]) -> bool {
+    bytes.len() >= 2 && bytes[0] == 0x1F && bytes[1] == 0x8B
+}
+
+// RFC 1952: "GZIP file format specification"
+// Only single member files are supported, which is what every tool produces anyways
+pub fn uncompress_gzip(bytes: &[u8]) -> Result<Vec<u8>, ()> {
+    // 10 byte header and 8 byte trailer
+    if bytes.len() < 18 || !is_gzip(bytes) {
+        return Err(());
+    }
+
+    // Compression Method, always deflate
+    if bytes[2] != 0x08 {
+        return Err(());
+    }
+
+    let flags = bytes[3];
+    let mut offset = 10;
+
+    if flags & GZIP_FLAG_EXTRA != 0 {
+        let extra_len =
+            u16::from_le_bytes(bytes.get(offset..offset + 2).ok_or(())?.try_into().unwrap());
+        offset += 2 + extra_len as usize;
+    }
+
+    // file name and comment are both zero terminated
+    for flag in [GZIP_FLAG_NAME, GZIP_FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let len = bytes
+                .get(offset..)
+                .ok_or(())?
+                .iter()
+                .position(|&byte| byte == 0)
+                .ok_or(())?;
+            offset += len + 1;
+        }
+    }
+
+    if flags & GZIP_FLAG_HCRC != 0 {
+        offset += 2;
+    }
+
+    if offset >= bytes.len() - 8 {
+        return Err(());
+    }
+
+    let trailer = &bytes[bytes.len() - 8..];
+    let checksum = u32::from_le_bytes(trailer[0..4].try_into().unwrap());
+    let size = u32::from_le_bytes(trailer[4..8].try_into().unwrap());
+
+    let mut inflate_context = InflateContext::new(&bytes[offset..bytes.len() - 8]);
+
+    let data = inflate_context.decompress()?;
+
+    // ISIZE is the uncompressed size modulo 2^32
+    if data.len() as u32 != size || super::crc32::crc32(&data) != checksum {
+        return Err(());
+    }
+
+    return Ok(data);
+}
+
 fn adler32(bytes: &[u8]) -> u32 {
     let mut a = 1_u32;
     let mut b = 0_u32;
@@ -123,6 +195,7 @@ impl HuffRing {
 
 struct InflateContext {
     input_buf: Vec<u8>,
+    byte_index: usize,
     bit_index: usize,
     output_buf: alloc::vec::Vec<u8>,
     ring: HuffRing,
@@ -132,6 +205,7 @@ impl InflateContext {
     fn new(bytes: &[u8]) -> Self {
         return Self {
             input_buf: bytes.to_vec(),
+            byte_index: 0,
             bit_index: 0,
             output_buf: Vec::new(),
             ring: HuffRing::new(),
@@ -141,36 +215,32 @@ impl InflateContext {
     // read from right-to-left NOT, and I cannot stress this enough, left-to-right
     // probably because it's way simpler computationally to get the right-most bit,
     // but still, wasted weeks on this because I read it from left-to-right ;~;
-    pub fn get_bit(&mut self) -> bool {
+    pub fn get_bit(&mut self) -> Result<bool, ()> {
         if self.bit_index == 8 {
-            self.input_buf.remove(0);
-            assert!(
-                !self.input_buf.is_empty(),
-                "Not enough data! {:X?}",
-                self.output_buf
-            );
-
+            self.byte_index += 1;
             self.bit_index = 0;
         }
 
-        let byte = self.input_buf[0] & (1 << self.bit_index) != 0;
+        // Running out of data means the stream was cut short
+        let byte = *self.input_buf.get(self.byte_index).ok_or(())?;
+        let bit = byte & (1 << self.bit_index) != 0;
         self.bit_index += 1;
 
-        return byte;
+        return Ok(bit);
     }
 
-    pub fn get_bits(&mut self, num_bits: usize) -> u32 {
+    pub fn get_bits(&mut self, num_bits: usize) -> Result<u32, ()> {
         let mut byte = 0_u32;
 
         for bit in 0..num_bits {
-            byte |= (self.get_bit() as u32) << bit;
+            byte |= (self.get_bit()? as u32) << bit;
         }
 
-        return byte;
+        return Ok(byte);
     }
 
-    fn get_bits_base(&mut self, num: usize, base: usize) -> u32 {
-        return (base + if num != 0 { self.get_bits(num) } else { 0 } as usize) as u32;
+    fn get_bits_base(&mut self, num: usize, base: usize) -> Result<u32, ()> {
+        return Ok((base + if num != 0 { self.get_bits(num)? } else { 0 } as usize) as u32);
     }
 
     pub fn decompress(&mut self) -> Result<Vec<u8>, ()> {
@@ -186,8 +256,8 @@ impl InflateContext {
         build_fixed(&mut lengths, &mut dists);
 
         loop {
-            let is_final = self.get_bit();
-            let block_type = self.get_bits(2);
+            let is_final = self.get_bit()?;
+            let block_type = self.get_bits(2)?;
 
             match block_type {
                 0x00 => {
@@ -209,18 +279,21 @@ impl InflateContext {
             }
         }
 
-        return Ok(self.output_buf.clone());
+        return Ok(core::mem::take(&mut self.output_buf));
     }
 
-    fn decode(&mut self, huff: &mut Huff) -> u32 {
+    // Err on a code that isn't in the table, which only happens with corrupt data
+    fn decode(&mut self, huff: &mut Huff) -> Result<u32, ()> {
         let mut base: i32 = 0;
         let mut offs: i32 = 0;
 
         let mut i = 1;
         loop {
-            offs = 2 * offs + self.get_bit() as i32;
+            offs = 2 * offs + self.get_bit()? as i32;
 
-            assert!(i <= 15);
+            if i > 15 {
+                return Err(());
+            }
 
             if offs < huff.counts[i] as i32 {
                 break;
@@ -231,9 +304,11 @@ impl InflateContext {
             i += 1;
         }
 
-        assert!(base + offs >= 0 && base + offs < 288);
+        if base + offs < 0 || base + offs >= 288 {
+            return Err(());
+        }
 
-        return huff.symbols[(base + offs) as usize] as u32;
+        return Ok(huff.symbols[(base + offs) as usize] as u32);
     }
 
     fn emit(&mut self, byte: u8) {
@@ -252,8 +327,13 @@ impl InflateContext {
     }
 
     fn uncompressed(&mut self) -> Result<(), ()> {
-        let len = u16::from_le(self.get_bits(16).try_into().unwrap());
-        let nlen = u16::from_le(self.get_bits(16).try_into().unwrap());
+        // Stored blocks start at the next byte boundary
+        if self.bit_index != 0 {
+            self.bit_index = 8;
+        }
+
+        let len = u16::from_le(self.get_bits(16)?.try_into().unwrap());
+        let nlen = u16::from_le(self.get_bits(16)?.try_into().unwrap());
 
         if nlen != !len {
             return Err(());
@@ -261,7 +341,7 @@ impl InflateContext {
 
         for _ in 0..len {
             // TODO: is this right?
-            let byte = self.get_bits(8) as u8;
+            let byte = self.get_bits(8)? as u8;
             self.emit(byte);
         }
 
@@ -288,7 +368,7 @@ impl InflateContext {
         ];
 
         loop {
-            let mut symbol = self.decode(huff_len);
+            let mut symbol = self.decode(huff_len)?;
 
             if symbol < 256 {
                 self.emit(symbol as u8);
@@ -297,13 +377,23 @@ impl InflateContext {
                     break;
                 }
 
+                // 286 and 287 never show up in valid data
+                if symbol > 285 {
+                    return Err(());
+                }
+
                 symbol -= 257;
 
                 let length =
-                    self.get_bits_base(length_bits[symbol as usize], length_base[symbol as usize]);
-                let distance = self.decode(huff_dist);
+                    self.get_bits_base(length_bits[symbol as usize], length_base[symbol as usize])?;
+                let distance = self.decode(huff_dist)?;
+
+                if distance as usize >= dist_base.len() {
+                    return Err(());
+                }
+
                 let offset =
-                    self.get_bits_base(dist_bits[distance as usize], dist_base[distance as usize]);
+                    self.get_bits_base(dist_bits[distance as usize], dist_base[distance as usize])?;
 
                 for _ in 0..length {
                     let b = self.peek(offset as usize);
@@ -322,12 +412,12 @@ impl InflateContext {
 
         let mut lengths = [0_u8; 320];
 
-        let literals = self.get_bits_base(5, 257);
-        let distances = self.get_bits_base(5, 1);
-        let clengths = self.get_bits_base(4, 4);
+        let literals = self.get_bits_base(5, 257)?;
+        let distances = self.get_bits_base(5, 1)?;
+        let clengths = self.get_bits_base(4, 4)?;
 
         for i in 0..clengths {
-            lengths[clens[i as usize] as usize] = self.get_bits(3) as u8;
+            lengths[clens[i as usize] as usize] = self.get_bits(3)? as u8;
         }
 
         let mut codes = Huff {
@@ -338,7 +428,7 @@ impl InflateContext {
 
         let mut count = 0_u32;
         while count < literals + distances {
-            let symbol = self.decode(&mut codes);
+            let symbol = self.decode(&mut codes)?;
 
             if symbol < 16 {
                 lengths[count as usize] = symbol as u8;
@@ -348,12 +438,21 @@ impl InflateContext {
                 let mut length;
 
                 if symbol == 16 {
+                    // There's nothing to repeat before the first length
+                    if count == 0 {
+                        return Err(());
+                    }
+
                     rep = lengths[count as usize - 1] as u32;
-                    length = self.get_bits_base(2, 3);
+                    length = self.get_bits_base(2, 3)?;
                 } else if symbol == 17 {
-                    length = self.get_bits_base(3, 3);
+                    length = self.get_bits_base(3, 3)?;
                 } else {
-                    length = self.get_bits_base(7, 11);
+                    length = self.get_bits_base(7, 11)?;
+                }
+
+                if count + length > literals + distances {
+                    return Err(());
                 }
 
                 while length != 0 {
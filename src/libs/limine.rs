@@ -77,6 +77,12 @@ pub fn get_module<'a>(module_name: &str) -> Option<&'a File> {
     return file;
 }
 
+pub fn get_modules<'a>() -> &'a [&'a File] {
+    return MODULE_REQUEST
+        .get_response()
+        .map_or(&[], |module_response| module_response.modules());
+}
+
 pub fn get_rdsp_ptr() -> Option<*const ()> {
     return Some(RSDP_REQ.get_response()?.address());
 }
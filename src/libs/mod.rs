@@ -1,4 +1,5 @@
 pub mod cell;
+pub mod crc32;
 pub mod gzip;
 pub mod limine;
 pub mod sync;
@@ -21,6 +21,19 @@ impl From<[u8; 16]> for Uuid {
     }
 }
 
+impl From<Uuid> for [u8; 16] {
+    fn from(value: Uuid) -> Self {
+        let mut bytes = [0u8; 16];
+
+        bytes[0..4].copy_from_slice(&value.a.to_le_bytes());
+        bytes[4..6].copy_from_slice(&value.b.to_le_bytes());
+        bytes[6..8].copy_from_slice(&value.c.to_le_bytes());
+        bytes[8..16].copy_from_slice(&value.d);
+
+        return bytes;
+    }
+}
+
 impl PartialEq for Uuid {
     fn eq(&self, other: &Self) -> bool {
         return self.a == other.a && self.b == other.b && self.c == other.c && self.d == other.d;
@@ -5,7 +5,10 @@
 
 use core::arch::x86_64::__cpuid;
 
-use alloc::vec::Vec;
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
 use libs::limine::{get_hhdm_offset, get_kernel_file};
 use mem::{pmm::total_memory, LabelBytes};
 
@@ -38,6 +41,8 @@ pub extern "C" fn _start() -> ! {
     mem::init_allocator();
     mem::vmm::vmm_init();
     drivers::acpi::init_acpi();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    drivers::pit::init();
 
     parse_kernel_cmdline();
 
@@ -47,7 +52,9 @@ pub extern "C" fn _start() -> ! {
 pub fn kmain() -> ! {
     print_boot_info();
 
-    let _ = drivers::fs::vfs::add_vfs("/", alloc::boxed::Box::new(initramfs::init()));
+    let _ = drivers::fs::vfs::add_vfs("/", initramfs::init());
+
+    drivers::fs::tar::mount_modules();
 
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     drivers::pci::enumerate_pci_bus();
@@ -61,6 +68,18 @@ pub fn kmain() -> ! {
     );
 
     drivers::storage::ide::init();
+    drivers::storage::ahci::init();
+    drivers::storage::nvme::init();
+    drivers::storage::virtio_blk::init();
+
+    if let Some(path) = &KERNEL_FEATURES.mount_tar {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        let mount_point = alloc::format!("/mnt/{}", drivers::fs::tar::archive_stem(file_name));
+
+        if drivers::fs::tar::mount_file(path, &mount_point).is_err() {
+            crate::log!(LogLevel::Error, "Failed to mount {path}");
+        }
+    }
 
     let limine_dir = vfs_open("/mnt/boot/limine").unwrap();
 
@@ -126,7 +145,19 @@ pub fn kmain() -> ! {
     //     }
     // }
 
-    hcf();
+    // Nothing left to do but the deferred work, the timer wakes us up every tick
+    loop {
+        drivers::storage::cache::writeback_if_due();
+        drivers::storage::ide::poll_media();
+
+        unsafe {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            core::arch::asm!("hlt");
+
+            #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+            core::arch::asm!("wfi");
+        }
+    }
 }
 
 fn draw_gradient() {
@@ -258,7 +289,14 @@ macro_rules! log {
 #[derive(Debug)]
 pub struct KernelFeatures {
     pub log_level: u8,
-    pub fat_in_mem: bool,
+    // How much of the FAT each FAT volume may keep in memory, in KiB
+    pub fat_cache_size: usize,
+    // How much disk data the block buffer cache may hold, in KiB
+    pub block_cache_size: usize,
+    // Format an empty Linux data partition with CappFS so it can be mounted at /sysroot
+    pub format_sysroot: bool,
+    // A tar archive on disk to mount at /mnt/<name> once the disks are up
+    pub mount_tar: Option<String>,
 }
 
 impl KernelFeatures {
@@ -266,18 +304,31 @@ impl KernelFeatures {
         #[allow(clippy::single_match)]
         match option {
             "log_level" => self.log_level = value.parse().unwrap_or(crate::LOG_LEVEL),
-            "fat_in_mem" => self.fat_in_mem = value == "true",
+            "fat_cache_size" => {
+                self.fat_cache_size = value.parse().unwrap_or(DEFAULT_FAT_CACHE_SIZE)
+            }
+            "block_cache_size" => {
+                self.block_cache_size = value.parse().unwrap_or(DEFAULT_BLOCK_CACHE_SIZE)
+            }
+            "format_sysroot" => self.format_sysroot = value.parse().unwrap_or(false),
+            "mount_tar" => self.mount_tar = Some(value.to_string()),
             _ => {}
         }
     }
 }
 
+const DEFAULT_FAT_CACHE_SIZE: usize = 256;
+const DEFAULT_BLOCK_CACHE_SIZE: usize = 512;
+
 // TODO: Do this vastly differently
 pub static KERNEL_FEATURES: libs::cell::OnceCell<KernelFeatures> = libs::cell::OnceCell::new();
 
 fn parse_kernel_cmdline() {
     let mut kernel_features: KernelFeatures = KernelFeatures {
-        fat_in_mem: true,
+        fat_cache_size: DEFAULT_FAT_CACHE_SIZE,
+        block_cache_size: DEFAULT_BLOCK_CACHE_SIZE,
+        format_sysroot: false,
+        mount_tar: None,
         log_level: crate::LOG_LEVEL,
     };
 
@@ -145,6 +145,18 @@ impl LinkedListAllocator {
         region_count
     }
 
+    pub fn free_bytes(&self) -> usize {
+        let mut free = 0;
+        let mut cur_region = &self.head;
+
+        while let Some(next) = cur_region.next {
+            cur_region = unsafe { next.as_ref() };
+            free += cur_region.len();
+        }
+
+        free
+    }
+
     pub fn debug_regions(&self, buf: &mut [(usize, usize)]) {
         let mut i = 0;
         let mut cur_region = &self.head;